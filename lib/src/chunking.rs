@@ -25,11 +25,58 @@ pub(crate) const MAX_CHUNKS: u32 = 64;
 
 type RcStr = Rc<str>;
 
+/// The tar block size; headers are a block, and content is padded to it.
+const TAR_BLOCK: u64 = 512;
+
+/// How to group components into chunks when there are more of them than
+/// the layer budget allows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PackingStrategy {
+    /// Give the largest components their own chunk, and group the smaller ones.
+    SizeBalanced,
+    /// Give the most recently changed components their own chunk, and merge
+    /// the least recently changed ones, so that updates touch fewer layers.
+    /// This uses [`ObjectSourceMeta::change_time_offset`].
+    ChangeFrequency,
+}
+
+impl Default for PackingStrategy {
+    fn default() -> Self {
+        Self::SizeBalanced
+    }
+}
+
 #[derive(Debug, Default)]
 pub(crate) struct Chunk {
     pub(crate) name: String,
     pub(crate) content: BTreeMap<RcStr, (u64, Vec<Utf8PathBuf>)>,
     pub(crate) size: u64,
+    /// Names of the components in this chunk.
+    pub(crate) components: Vec<String>,
+}
+
+/// A layer in a [`ChunkingPlan`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PlannedLayer {
+    /// Names of the components (e.g. packages) in this layer; empty for the final
+    /// layer, which holds the commit, metadata and any content not owned by a component.
+    pub components: Vec<String>,
+    /// Number of objects in this layer.
+    pub objects: usize,
+    /// Estimated size of the uncompressed tar stream.
+    pub size: u64,
+    /// Estimated size of the compressed layer.
+    pub compressed_size: u64,
+}
+
+/// How a commit would be split into container image layers; see
+/// [`crate::container::encapsulate_plan`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChunkingPlan {
+    /// The commit checksum.
+    pub commit: String,
+    /// The layers, in order.
+    pub layers: Vec<PlannedLayer>,
 }
 
 #[derive(Debug)]
@@ -54,7 +101,7 @@ impl Meta {
     }
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 /// Object metadata, but with additional size data
 pub struct ObjectSourceMetaSized {
     /// The original metadata
@@ -65,7 +112,7 @@ pub struct ObjectSourceMetaSized {
 }
 
 /// Extend content source metadata with sizes.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct ObjectMetaSized {
     /// Mapping from content object to source.
     pub map: ObjectMetaMap,
@@ -99,7 +146,12 @@ impl ObjectMetaSized {
             })
             .collect();
         let mut sizes = sized?;
-        sizes.sort_by(|a, b| b.size.cmp(&a.size));
+        // Break ties by identifier so that the result is reproducible.
+        sizes.sort_by(|a, b| {
+            b.size
+                .cmp(&a.size)
+                .then_with(|| a.meta.identifier.cmp(&b.meta.identifier))
+        });
         Ok(ObjectMetaSized { map, sizes })
     }
 }
@@ -216,6 +268,18 @@ impl Chunk {
         }
     }
 
+    /// Estimate the size of this chunk as a tar stream; each object is followed
+    /// by a hardlink per path.
+    fn estimated_tar_size(&self) -> u64 {
+        self.content
+            .values()
+            .map(|(size, paths)| {
+                let padded = (size + TAR_BLOCK - 1) / TAR_BLOCK * TAR_BLOCK;
+                TAR_BLOCK + padded + TAR_BLOCK * paths.len() as u64
+            })
+            .sum()
+    }
+
     fn move_obj(&mut self, dest: &mut Self, checksum: &str) -> bool {
         // In most cases, we expect the object to exist in the source.  However, it's
         // conveneient here to simply ignore objects which were already moved into
@@ -277,9 +341,28 @@ impl Chunking {
         rev: &str,
         meta: ObjectMetaSized,
         max_layers: Option<NonZeroU32>,
+        strategy: PackingStrategy,
     ) -> Result<Self> {
         let mut r = Self::new(repo, rev)?;
-        r.process_mapping(meta, max_layers)?;
+        r.process_mapping(meta, max_layers, strategy)?;
+        Ok(r)
+    }
+
+    /// Generate a chunking with a chunk per set of content objects, e.g. as recorded
+    /// for the layers of a stored image.  Content not in any set is in the remainder.
+    pub(crate) fn from_object_sets(
+        repo: &ostree::Repo,
+        rev: &str,
+        sets: Vec<BTreeSet<String>>,
+    ) -> Result<Self> {
+        let mut r = Self::new(repo, rev)?;
+        for (i, set) in sets.into_iter().enumerate() {
+            let mut chunk = Chunk::new(&format!("layer {}", i));
+            for checksum in set {
+                r.remainder.move_obj(&mut chunk, &checksum);
+            }
+            r.chunks.push(chunk);
+        }
         Ok(r)
     }
 
@@ -294,6 +377,7 @@ impl Chunking {
         &mut self,
         meta: ObjectMetaSized,
         max_layers: Option<NonZeroU32>,
+        strategy: PackingStrategy,
     ) -> Result<()> {
         self.max = max_layers
             .unwrap_or(NonZeroU32::new(MAX_CHUNKS).unwrap())
@@ -323,8 +407,12 @@ impl Chunking {
             .try_into()
             .unwrap();
 
-        // TODO: Compute bin packing in a better way
-        let packing = basic_packing(sizes, NonZeroU32::new(self.max).unwrap());
+        let bins = NonZeroU32::new(self.max).unwrap();
+        let packing = match strategy {
+            // TODO: Compute bin packing in a better way
+            PackingStrategy::SizeBalanced => basic_packing(sizes, bins),
+            PackingStrategy::ChangeFrequency => change_frequency_packing(sizes, bins),
+        };
 
         for bin in packing.into_iter() {
             let first = bin[0];
@@ -345,6 +433,7 @@ impl Chunking {
                 n => Cow::Owned(format!("{n} components")),
             };
             let mut chunk = Chunk::new(&*name);
+            chunk.components = bin.iter().map(|v| v.meta.name.to_string()).collect();
             for szmeta in bin {
                 for &obj in rmap.get(&szmeta.meta.identifier).unwrap() {
                     self.remainder.move_obj(&mut chunk, obj.as_str());
@@ -366,6 +455,33 @@ impl Chunking {
         r
     }
 
+    /// Describe the layers which would be generated.  Compressed sizes are left
+    /// at zero, as they depend on the compression in use.
+    pub(crate) fn plan(&self) -> ChunkingPlan {
+        let mut layers: Vec<_> = self
+            .chunks
+            .iter()
+            .map(|chunk| PlannedLayer {
+                components: chunk.components.clone(),
+                objects: chunk.content.len(),
+                size: chunk.estimated_tar_size(),
+                compressed_size: 0,
+            })
+            .collect();
+        // The final layer also has the commit object and directory metadata.
+        let n_meta = self.meta.len() as u64 + 1;
+        layers.push(PlannedLayer {
+            components: Vec::new(),
+            objects: self.remainder.content.len() + n_meta as usize,
+            size: self.remainder.estimated_tar_size() + self.metadata_size + n_meta * TAR_BLOCK,
+            compressed_size: 0,
+        });
+        ChunkingPlan {
+            commit: self.commit.to_string(),
+            layers,
+        }
+    }
+
     /// Print information about chunking to standard output.
     pub fn print(&self) {
         println!("Metadata: {}", glib::format_size(self.metadata_size));
@@ -439,7 +555,7 @@ fn basic_packing(components: &[ObjectSourceMetaSized], bins: NonZeroU32) -> Vec<
     // Create a mutable copy
     let mut components: Vec<_> = components.iter().collect();
     // Iterate over the component tail, folding by source id
-    let mut by_src = HashMap::<_, Vec<&ObjectSourceMetaSized>>::new();
+    let mut by_src = BTreeMap::<_, Vec<&ObjectSourceMetaSized>>::new();
     // Take the tail off components, then build up mapping from srcid -> Vec<component>
     for component in components.split_off(bins.get() as usize) {
         by_src
@@ -472,6 +588,30 @@ fn basic_packing(components: &[ObjectSourceMetaSized], bins: NonZeroU32) -> Vec<
     r
 }
 
+/// Given a set of components and a number of bins, give each of the most recently
+/// changed components its own bin, and merge all of the remaining (i.e. least
+/// recently changed) ones into the last bin.
+fn change_frequency_packing(
+    components: &[ObjectSourceMetaSized],
+    bins: NonZeroU32,
+) -> Vec<ChunkedComponents> {
+    let mut components: Vec<_> = components.iter().collect();
+    if components.len() <= bins.get() as usize {
+        return components.into_iter().map(|v| vec![v]).collect();
+    }
+    components.sort_by(|a, b| {
+        b.meta
+            .change_time_offset
+            .cmp(&a.meta.change_time_offset)
+            .then_with(|| b.size.cmp(&a.size))
+            .then_with(|| a.meta.identifier.cmp(&b.meta.identifier))
+    });
+    let tail = components.split_off((bins.get() - 1) as usize);
+    let mut r: Vec<_> = components.into_iter().map(|v| vec![v]).collect();
+    r.push(tail);
+    r
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -487,6 +627,44 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn test_packing_change_frequency() -> Result<()> {
+        let components = [
+            ("a", 10, 0),
+            ("b", 5, 3),
+            ("c", 1, 7),
+            ("d", 100, 1),
+            ("e", 2, 3),
+        ]
+        .iter()
+        .map(|&(name, size, change_time_offset)| ObjectSourceMetaSized {
+            meta: ObjectSourceMeta {
+                identifier: Rc::from(name),
+                name: Rc::from(name),
+                srcid: Rc::from(name),
+                change_time_offset,
+            },
+            size,
+        })
+        .collect::<Vec<_>>();
+        let names = |packing: Vec<ChunkedComponents>| {
+            packing
+                .into_iter()
+                .map(|bin| {
+                    bin.iter()
+                        .map(|v| &*v.meta.name)
+                        .collect::<Vec<_>>()
+                        .join(",")
+                })
+                .collect::<Vec<_>>()
+        };
+        let packing = change_frequency_packing(&components, NonZeroU32::new(3).unwrap());
+        assert_eq!(names(packing), &["c", "b", "e,d,a"]);
+        let packing = change_frequency_packing(&components, NonZeroU32::new(5).unwrap());
+        assert_eq!(packing.len(), 5);
+        Ok(())
+    }
+
     #[test]
     fn test_packing_fcos() -> Result<()> {
         let contentmeta: Vec<ObjectSourceMetaSized> =
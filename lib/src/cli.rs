@@ -21,7 +21,7 @@ use crate::commit::container_commit;
 use crate::container as ostree_container;
 use crate::container::{Config, ImageReference, OstreeImageReference, UnencapsulateOptions};
 use ostree_container::store::{ImageImporter, PrepareResult};
-use ostree_container::UnencapsulationProgress;
+use ostree_container::{ImportWarning, UnencapsulationProgress};
 
 /// Parse an [`OstreeImageReference`] from a CLI arguemnt.
 pub fn parse_imgref(s: &str) -> Result<OstreeImageReference> {
@@ -33,6 +33,59 @@ pub fn parse_base_imgref(s: &str) -> Result<ImageReference> {
     ImageReference::try_from(s)
 }
 
+/// Parse registry credentials in the form `USERNAME:PASSWORD` from a CLI argument.
+fn parse_creds(s: &str) -> Result<(String, String)> {
+    let (username, password) = s
+        .split_once(':')
+        .ok_or_else(|| anyhow::anyhow!("Expected USERNAME:PASSWORD"))?;
+    Ok((username.to_string(), password.to_string()))
+}
+
+/// Parse a rate in bytes per second from a CLI argument, e.g. `5MB`, `512KiB/s` or `1000`.
+/// Units without `i` are decimal; a plain number is in bytes.
+pub fn parse_rate_limit(s: &str) -> Result<u64> {
+    let v = s.trim();
+    let v = v.strip_suffix("/s").unwrap_or(v);
+    let split = v
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or_else(|| v.len());
+    let (num, unit) = v.split_at(split);
+    let num: f64 = num
+        .parse()
+        .with_context(|| format!("Invalid rate limit: {}", s))?;
+    let mult: u64 = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kb" => 1_000,
+        "m" | "mb" => 1_000_000,
+        "g" | "gb" => 1_000_000_000,
+        "kib" => 1 << 10,
+        "mib" => 1 << 20,
+        "gib" => 1 << 30,
+        o => anyhow::bail!("Invalid unit in rate limit {}: {}", s, o),
+    };
+    let rate = (num * mult as f64) as u64;
+    if rate == 0 {
+        anyhow::bail!("Rate limit must be positive: {}", s);
+    }
+    Ok(rate)
+}
+
+/// Output formats for commands printing structured data.
+#[derive(Debug, Clone, Copy)]
+enum OutputFormat {
+    Table,
+    Json,
+}
+
+/// Parse an [`OutputFormat`] from a CLI argument.
+fn parse_output_format(s: &str) -> Result<OutputFormat> {
+    match s {
+        "table" => Ok(OutputFormat::Table),
+        "json" => Ok(OutputFormat::Json),
+        o => anyhow::bail!("Unknown format: {} (expected table or json)", o),
+    }
+}
+
 /// Parse an [`ostree::Repo`] from a CLI arguemnt.
 pub fn parse_repo(s: &str) -> Result<ostree::Repo> {
     let repofd = cap_std::fs::Dir::open_ambient_dir(s, cap_std::ambient_authority())?;
@@ -47,8 +100,12 @@ struct ImportOpts {
     #[structopt(parse(try_from_str = parse_repo))]
     repo: ostree::Repo,
 
-    /// Path to a tar archive; if unspecified, will be stdin.  Currently the tar archive must not be compressed.
+    /// Path to a tar archive; if unspecified, will be stdin.  The archive may be compressed with gzip or zstd.
     path: Option<String>,
+
+    /// Set this ref to the imported commit
+    #[structopt(long = "ref")]
+    ref_name: Option<String>,
 }
 
 /// Options for exporting a tar archive.
@@ -59,9 +116,9 @@ struct ExportOpts {
     #[structopt(parse(try_from_str = parse_repo))]
     repo: ostree::Repo,
 
-    /// The format version.  Must be 0 or 1.
+    /// The format version.  Must be 0 or 1; defaults to the latest.
     #[structopt(long)]
-    format_version: u32,
+    format_version: Option<u32>,
 
     /// The ostree ref or commit to export
     rev: String,
@@ -70,7 +127,7 @@ struct ExportOpts {
 /// Options for import/export to tar archives.
 #[derive(Debug, StructOpt)]
 enum TarOpts {
-    /// Import a tar archive (currently, must not be compressed)
+    /// Import a tar archive (optionally compressed with gzip or zstd)
     Import(ImportOpts),
 
     /// Write a tar archive to stdout
@@ -99,6 +156,18 @@ enum ContainerOpts {
         /// Don't display progress
         #[structopt(long)]
         quiet: bool,
+
+        /// Path to Docker-formatted authentication file.
+        #[structopt(long)]
+        authfile: Option<PathBuf>,
+
+        /// Credentials for the registry, in the form USERNAME:PASSWORD
+        #[structopt(long, parse(try_from_str = parse_creds))]
+        creds: Option<(String, String)>,
+
+        /// Limit the download rate, e.g. 5MB or 512KiB (per second)
+        #[structopt(long, parse(try_from_str = parse_rate_limit))]
+        rate_limit: Option<u64>,
     },
 
     /// Print information about an exported ostree-container image.
@@ -108,6 +177,13 @@ enum ContainerOpts {
         imgref: OstreeImageReference,
     },
 
+    /// Verify the digests and sizes of all blobs referenced by an OCI directory.
+    Verify {
+        /// Image reference, e.g. oci:/path/to/dir
+        #[structopt(parse(try_from_str = parse_base_imgref))]
+        imgref: ImageReference,
+    },
+
     ///  Wrap an ostree commit into a container
     #[structopt(alias = "export")]
     Encapsulate {
@@ -131,9 +207,56 @@ enum ContainerOpts {
         #[structopt(name = "copymeta", long)]
         copy_meta_keys: Vec<String>,
 
+        /// Propagate the OSTree commit metadata keys matching a pattern (e.g. `buildsys.*`)
+        /// to container labels, if present
+        #[structopt(name = "copymeta-opt", long)]
+        copy_meta_opt_patterns: Vec<String>,
+
         /// Corresponds to the Dockerfile `CMD` instruction.
         #[structopt(long)]
         cmd: Option<Vec<String>>,
+
+        /// Corresponds to the Dockerfile `ENTRYPOINT` instruction.
+        #[structopt(long)]
+        entrypoint: Option<Vec<String>>,
+
+        /// Environment variable for the container, in the form KEY=VALUE
+        #[structopt(long)]
+        env: Vec<String>,
+
+        /// Corresponds to the Dockerfile `WORKDIR` instruction.
+        #[structopt(long)]
+        workdir: Option<String>,
+
+        /// Corresponds to the Dockerfile `STOPSIGNAL` instruction.
+        #[structopt(long)]
+        stop_signal: Option<String>,
+
+        /// Architecture of the commit; defaults to the `ostree.architecture` commit metadata,
+        /// or the host architecture.
+        #[structopt(long)]
+        arch: Option<String>,
+
+        /// Commit for another architecture, in the form REV or REV=ARCH; generates
+        /// a multi-architecture image index.
+        #[structopt(long = "additional-rev")]
+        additional_revs: Vec<String>,
+
+        /// Ref name to record in the image, e.g. when REV is a commit checksum
+        #[structopt(long)]
+        ref_name: Option<String>,
+
+        /// Print the planned layers as JSON and exit, without generating the image
+        #[structopt(long)]
+        print_plan: bool,
+
+        /// Path to Docker-formatted authentication file for the destination.
+        #[structopt(long)]
+        authfile: Option<PathBuf>,
+
+        /// Credentials for the destination registry, in the form USERNAME:PASSWORD
+        #[structopt(long, parse(try_from_str = parse_creds))]
+        creds: Option<(String, String)>,
     },
 
     #[structopt(alias = "commit")]
@@ -175,6 +298,10 @@ enum ContainerImageOpts {
         #[structopt(long)]
         #[structopt(parse(try_from_str = parse_repo))]
         repo: ostree::Repo,
+
+        /// Output format: table or json
+        #[structopt(long, default_value = "table", parse(try_from_str = parse_output_format))]
+        format: OutputFormat,
     },
 
     /// Pull (or update) a container image.
@@ -189,6 +316,10 @@ enum ContainerImageOpts {
 
         #[structopt(flatten)]
         proxyopts: ContainerProxyOpts,
+
+        /// Limit the download rate, e.g. 5MB or 512KiB (per second)
+        #[structopt(long, parse(try_from_str = parse_rate_limit))]
+        rate_limit: Option<u64>,
     },
 
     /// Pull (or update) a container image.
@@ -202,6 +333,40 @@ enum ContainerImageOpts {
         imgref: OstreeImageReference,
     },
 
+    /// Show the ostree ref and commit caching each layer of a pulled container image.
+    Layers {
+        /// Path to the repository
+        #[structopt(long, parse(try_from_str = parse_repo))]
+        repo: ostree::Repo,
+
+        /// Image reference, e.g. registry:quay.io/exampleos/exampleos:latest
+        #[structopt(parse(try_from_str = parse_base_imgref))]
+        imgref: ImageReference,
+
+        /// Output format: table or json
+        #[structopt(long, default_value = "table", parse(try_from_str = parse_output_format))]
+        format: OutputFormat,
+    },
+
+    /// Show the differences between two pulled container images.
+    Diff {
+        /// Path to the repository
+        #[structopt(long, parse(try_from_str = parse_repo))]
+        repo: ostree::Repo,
+
+        /// The old image reference, e.g. registry:quay.io/exampleos/exampleos:stable
+        #[structopt(parse(try_from_str = parse_base_imgref))]
+        old: ImageReference,
+
+        /// The new image reference
+        #[structopt(parse(try_from_str = parse_base_imgref))]
+        new: ImageReference,
+
+        /// Output format: table or json
+        #[structopt(long, default_value = "table", parse(try_from_str = parse_output_format))]
+        format: OutputFormat,
+    },
+
     /// Copy a pulled container image from one repo to another.
     Copy {
         /// Path to the source repository
@@ -219,6 +384,30 @@ enum ContainerImageOpts {
         imgref: OstreeImageReference,
     },
 
+    /// Remove a pulled container image.
+    Remove {
+        /// Path to the repository
+        #[structopt(long, required_unless = "sysroot", conflicts_with = "sysroot")]
+        #[structopt(parse(try_from_str = parse_repo))]
+        repo: Option<ostree::Repo>,
+
+        /// Path to a system root; the image is not removed if it is deployed.
+        #[structopt(long)]
+        sysroot: Option<String>,
+
+        /// Image reference, e.g. registry:quay.io/exampleos/exampleos:latest
+        #[structopt(parse(try_from_str = parse_base_imgref))]
+        imgref: ImageReference,
+
+        /// Also remove layers which are not used by other images
+        #[structopt(long)]
+        prune_layers: bool,
+
+        /// Remove the image even if it is deployed
+        #[structopt(long)]
+        force: bool,
+    },
+
     /// Perform initial deployment for a container image
     Deploy {
         /// Path to the system root
@@ -249,6 +438,27 @@ enum ContainerImageOpts {
         /// Add a kernel argument
         karg: Option<Vec<String>>,
 
+        /// Start from the kernel arguments of the booted (or previous) deployment,
+        /// replacing or appending those given via `--karg`
+        #[structopt(long)]
+        inherit_kargs: bool,
+
+        /// Initialize the stateroot if it doesn't exist
+        #[structopt(long)]
+        create_stateroot: bool,
+
+        /// Stage the deployment, to be finalized when shutting down
+        #[structopt(long)]
+        stage: bool,
+
+        /// Only use an already pulled image, without network access
+        #[structopt(long)]
+        offline: bool,
+
+        /// Deploy even if the image is marked as requiring configuration
+        #[structopt(long)]
+        allow_unconfigured: bool,
+
         /// Write the deployed checksum to this file
         #[structopt(long)]
         write_commitid_to: Option<Utf8PathBuf>,
@@ -316,22 +526,63 @@ impl Into<ostree_container::store::ImageProxyConfig> for ContainerProxyOpts {
 
 /// Import a tar archive containing an ostree commit.
 async fn tar_import(opts: &ImportOpts) -> Result<()> {
-    let imported = if let Some(path) = opts.path.as_ref() {
-        let instream = tokio::fs::File::open(path).await?;
-        crate::tar::import_tar(&opts.repo, instream, None).await?
-    } else {
-        let stdin = tokio::io::stdin();
-        crate::tar::import_tar(&opts.repo, stdin, None).await?
+    let instream: Box<dyn tokio::io::AsyncRead + Send + Unpin> =
+        if let Some(path) = opts.path.as_ref() {
+            Box::new(tokio::fs::File::open(path).await?)
+        } else {
+            Box::new(tokio::io::stdin())
+        };
+    // The progress bar is hidden if stderr is not a terminal.
+    let target = indicatif::ProgressDrawTarget::stderr();
+    let (tx_progress, mut rx_progress) = tokio::sync::watch::channel(Default::default());
+    let pb = (!target.is_hidden()).then(|| {
+        let pb = indicatif::ProgressBar::new_spinner();
+        pb.set_draw_target(target);
+        pb.set_style(indicatif::ProgressStyle::default_bar().template("{spinner} {msg}"));
+        pb.enable_steady_tick(200);
+        pb
+    });
+    let options = crate::tar::TarImportOptions {
+        progress: pb.as_ref().map(|_| tx_progress),
+        ref_name: opts.ref_name.clone(),
+        ..Default::default()
     };
+    let import = crate::tar::import_tar(&opts.repo, instream, Some(options));
+    tokio::pin!(import);
+    let imported = loop {
+        tokio::select! {
+            r = &mut import => break r?,
+            // This is disabled once the sender is gone.
+            Ok(()) = rx_progress.changed() => {
+                let progress: crate::tar::ImportProgress = *rx_progress.borrow();
+                if let Some(pb) = pb.as_ref() {
+                    pb.set_message(format!(
+                        "Processed: {} ({} entries, {} objects)",
+                        indicatif::HumanBytes(progress.bytes_read),
+                        progress.entries_processed,
+                        progress.objects_written
+                    ));
+                }
+            }
+        }
+    };
+    if let Some(pb) = pb.as_ref() {
+        pb.finish_and_clear();
+    }
     println!("Imported: {}", imported);
     Ok(())
 }
 
 /// Export a tar archive containing an ostree commit.
 fn tar_export(opts: &ExportOpts) -> Result<()> {
+    let version = opts
+        .format_version
+        .map(crate::tar::ExportFormatVersion::try_from)
+        .transpose()?
+        .unwrap_or(crate::tar::LATEST_FORMAT_VERSION);
     #[allow(clippy::needless_update)]
     let subopts = crate::tar::ExportOptions {
-        format_version: opts.format_version,
+        version,
         ..Default::default()
     };
     crate::tar::export_commit(
@@ -354,6 +605,9 @@ async fn container_import(
     imgref: &OstreeImageReference,
     write_ref: Option<&str>,
     quiet: bool,
+    authfile: Option<PathBuf>,
+    credentials: Option<(String, String)>,
+    rate_limit: Option<u64>,
 ) -> Result<()> {
     let (tx_progress, rx_progress) = tokio::sync::watch::channel(Default::default());
     let target = indicatif::ProgressDrawTarget::stdout();
@@ -361,13 +615,17 @@ async fn container_import(
     let pb = (!quiet).then(|| {
         let pb = indicatif::ProgressBar::new_spinner();
         pb.set_draw_target(target);
-        pb.set_style(style.template("{spinner} {prefix} {msg}"));
+        pb.set_style(style.clone().template("{spinner} {prefix} {msg}"));
         pb.enable_steady_tick(200);
         pb.set_message("Downloading...");
         pb
     });
     let opts = UnencapsulateOptions {
         progress: Some(tx_progress),
+        authfile,
+        credentials,
+        rate_limit,
+        ..Default::default()
     };
     let rx_progress_stream =
         tokio_stream::wrappers::WatchStream::new(rx_progress).map(ProgressOrFinish::Progress);
@@ -377,11 +635,40 @@ async fn container_import(
     let stream = rx_progress_stream.merge(import);
     tokio::pin!(stream);
     let mut import_result = None;
+    // Switched to a determinate bar once the size of a layer is known.
+    let mut determinate = false;
     while let Some(value) = stream.next().await {
         match value {
             ProgressOrFinish::Progress(progress) => {
-                let n = progress.borrow().processed_bytes;
-                if let Some(pb) = pb.as_ref() {
+                let pb = if let Some(pb) = pb.as_ref() {
+                    pb
+                } else {
+                    continue;
+                };
+                // The total is only known if it is for all layers
+                let (fetched, total) = progress.layers.iter().fold((0, Some(0)), |(f, t), l| {
+                    (f + l.fetched, t.zip(l.total).map(|(t, n)| t + n))
+                });
+                if let Some(total) = total.filter(|&t| t > 0) {
+                    if !determinate {
+                        pb.set_style(style.clone().template(
+                            "{prefix} [{bar:30}] {bytes}/{total_bytes} ({bytes_per_sec}) {msg}",
+                        ));
+                        determinate = true;
+                    }
+                    pb.set_length(total);
+                    pb.set_position(fetched);
+                    let layers = progress.layers.iter().map(|l| {
+                        let digest = l.digest.as_str();
+                        let digest = digest.strip_prefix("sha256:").unwrap_or(digest);
+                        &digest[..digest.len().min(12)]
+                    });
+                    pb.set_message(format!(
+                        "Fetching {}",
+                        layers.collect::<Vec<_>>().join(", ")
+                    ));
+                } else {
+                    let n = progress.processed_bytes;
                     pb.set_message(format!("Processed: {}", indicatif::HumanBytes(n)));
                 }
             }
@@ -395,6 +682,7 @@ async fn container_import(
     }
     // It must have been set
     let import = import_result.unwrap();
+    print_import_warnings(&import.warnings);
     if let Some(write_ref) = write_ref {
         repo.set_ref_immediate(
             None,
@@ -419,21 +707,179 @@ async fn container_export(
     repo: &ostree::Repo,
     rev: &str,
     imgref: &ImageReference,
-    labels: BTreeMap<String, String>,
-    copy_meta_keys: Vec<String>,
-    cmd: Option<Vec<String>>,
+    config: Config,
+    mut opts: crate::container::ExportOpts,
 ) -> Result<()> {
-    let config = Config {
-        labels: Some(labels),
-        cmd,
-    };
-    let opts = crate::container::ExportOpts {
-        copy_meta_keys,
-        ..Default::default()
-    };
-    let pushed =
-        crate::container::encapsulate(repo, rev, &config, Some(opts), None, imgref).await?;
-    println!("{}", pushed);
+    use crate::container::EncapsulateProgress;
+    // The progress bar is hidden if stderr is not a terminal.
+    let target = indicatif::ProgressDrawTarget::stderr();
+    let pb = (!target.is_hidden()).then(|| {
+        let pb = indicatif::ProgressBar::new_spinner();
+        pb.set_draw_target(target);
+        pb.set_style(indicatif::ProgressStyle::default_bar().template("{spinner} {msg}"));
+        pb.enable_steady_tick(200);
+        pb
+    });
+    // Layers are written synchronously, so render progress from a separate task.
+    let render = pb.clone().map(|pb| {
+        let (tx_progress, mut rx_progress) = tokio::sync::watch::channel(Default::default());
+        opts.progress = Some(tx_progress);
+        tokio::task::spawn(async move {
+            // This exits once the sender is dropped.
+            while rx_progress.changed().await.is_ok() {
+                let msg = match *rx_progress.borrow() {
+                    EncapsulateProgress::Starting => continue,
+                    EncapsulateProgress::Chunking => "Computing chunks".to_string(),
+                    EncapsulateProgress::WritingLayer { n, total, bytes } => format!(
+                        "Writing layer {}/{}: {}",
+                        n,
+                        total,
+                        indicatif::HumanBytes(bytes)
+                    ),
+                    EncapsulateProgress::WritingManifest => "Writing manifest".to_string(),
+                    EncapsulateProgress::Pushing => "Copying image".to_string(),
+                };
+                pb.set_message(msg);
+            }
+        })
+    });
+    let pushed = crate::container::encapsulate(repo, rev, &config, Some(opts), None, imgref).await;
+    if let Some(render) = render {
+        render.await?;
+    }
+    if let Some(pb) = pb.as_ref() {
+        pb.finish_and_clear();
+    }
+    println!("{}", pushed?);
+    Ok(())
+}
+
+/// Print a value as JSON to stdout.
+fn print_json(v: &impl serde::Serialize) -> Result<()> {
+    let stdout = std::io::stdout();
+    let mut stdout = stdout.lock();
+    serde_json::to_writer_pretty(&mut stdout, v)?;
+    std::io::Write::write_all(&mut stdout, b"\n")?;
+    Ok(())
+}
+
+/// List the stored container images, as a table or JSON.
+fn container_image_list(repo: &ostree::Repo, format: OutputFormat) -> Result<()> {
+    let images = crate::container::store::list_images(repo)?;
+    if let OutputFormat::Json = format {
+        return print_json(&images);
+    }
+    let width = images
+        .iter()
+        .map(|i| i.image.len())
+        .chain(std::iter::once("IMAGE".len()))
+        .max()
+        .unwrap();
+    println!(
+        "{:width$}  {:12}  {:20}  {:>6}  {:>10}",
+        "IMAGE",
+        "DIGEST",
+        "CREATED",
+        "LAYERS",
+        "SIZE",
+        width = width
+    );
+    for image in images {
+        if let Some(err) = image.error.as_deref() {
+            println!("{:width$}  error: {}", image.image, err, width = width);
+            continue;
+        }
+        let digest = image.manifest_digest.as_deref().unwrap_or_default();
+        let digest = digest.split_once(':').map(|s| s.1).unwrap_or(digest);
+        let created = image
+            .created
+            .as_deref()
+            .and_then(|c| chrono::DateTime::parse_from_rfc3339(c).ok())
+            .map(|c| c.format("%Y-%m-%dT%H:%M:%SZ").to_string())
+            .unwrap_or_else(|| "-".to_string());
+        println!(
+            "{:width$}  {:12}  {:20}  {:>6}  {:>10}",
+            image.image,
+            &digest[..digest.len().min(12)],
+            created,
+            image.n_layers.unwrap_or_default(),
+            indicatif::HumanBytes(image.size.unwrap_or_default()).to_string(),
+            width = width
+        );
+    }
+    Ok(())
+}
+
+/// Print the differences between two stored container images.
+fn container_image_diff(
+    repo: &ostree::Repo,
+    old: &ImageReference,
+    new: &ImageReference,
+    format: OutputFormat,
+) -> Result<()> {
+    let diff = crate::container::store::image_diff(repo, old, new)?;
+    if let OutputFormat::Json = format {
+        return print_json(&diff);
+    }
+    let layers = &diff.layers;
+    println!(
+        "Layers: {} added ({}), {} removed ({}), {} unchanged",
+        layers.added.len(),
+        indicatif::HumanBytes(layers.added_size),
+        layers.removed.len(),
+        indicatif::HumanBytes(layers.removed_size),
+        layers.unchanged.len()
+    );
+    for layer in layers.added.iter() {
+        println!(
+            "  + {} ({})",
+            layer.digest(),
+            indicatif::HumanBytes(layer.size() as u64)
+        );
+    }
+    for layer in layers.removed.iter() {
+        println!(
+            "  - {} ({})",
+            layer.digest(),
+            indicatif::HumanBytes(layer.size() as u64)
+        );
+    }
+    if !diff.changed_components.is_empty() {
+        let components: Vec<_> = diff.changed_components.iter().map(|s| s.as_str()).collect();
+        println!("Changed components: {}", components.join(", "));
+    }
+    println!("Content: {}", diff.files);
+    Ok(())
+}
+
+/// Print the ostree ref and commit for each layer of a stored container image.
+fn container_image_layers(
+    repo: &ostree::Repo,
+    imgref: &ImageReference,
+    format: OutputFormat,
+) -> Result<()> {
+    let layers = crate::container::store::layer_refs(repo, imgref)?;
+    if let OutputFormat::Json = format {
+        return print_json(&layers);
+    }
+    println!(
+        "{:7}  {:71}  {:>10}  {:64}  REF",
+        "KIND", "DIGEST", "SIZE", "COMMIT"
+    );
+    for layer in layers {
+        let kind = match layer.kind {
+            crate::container::store::LayerKind::Base => "base",
+            crate::container::store::LayerKind::Derived => "derived",
+        };
+        println!(
+            "{:7}  {:71}  {:>10}  {:64}  {}",
+            kind,
+            layer.digest,
+            indicatif::HumanBytes(layer.size).to_string(),
+            layer.commit.as_deref().unwrap_or("(missing)"),
+            layer.ostree_ref
+        );
+    }
     Ok(())
 }
 
@@ -444,13 +890,42 @@ async fn container_info(imgref: &OstreeImageReference) -> Result<()> {
     Ok(())
 }
 
+/// Verify the blobs of an OCI directory, printing and failing on any which are missing
+/// or corrupt.
+fn container_verify(imgref: &ImageReference) -> Result<()> {
+    if imgref.transport != ostree_container::Transport::OciDir {
+        anyhow::bail!("Only oci: directories can be verified, not {}", imgref);
+    }
+    // All images in the directory are verified, so ignore any tag.
+    let path = imgref
+        .name
+        .split_once(':')
+        .map_or(imgref.name.as_str(), |(p, _)| p);
+    let dir = openat::Dir::open(path).with_context(|| format!("Opening {}", path))?;
+    let report = ostree_container::ocidir::OciDir::open(dir)?.fsck()?;
+    for (digest, err) in report.errors.iter() {
+        println!("{}: {}", digest, err);
+    }
+    if !report.is_ok() {
+        anyhow::bail!(
+            "Found {} missing or corrupt blobs in {}",
+            report.errors.len(),
+            path
+        );
+    }
+    println!("Verified {} blobs", report.checked_blobs);
+    Ok(())
+}
+
 /// Write a layered container image into an OSTree commit.
 async fn container_store(
     repo: &ostree::Repo,
     imgref: &OstreeImageReference,
     proxyopts: ContainerProxyOpts,
+    rate_limit: Option<u64>,
 ) -> Result<()> {
     let mut imp = ImageImporter::new(repo, imgref, proxyopts.into()).await?;
+    imp.set_rate_limit(rate_limit);
     let prep = match imp.prepare().await? {
         PrepareResult::AlreadyPresent(c) => {
             println!("No changes in {} => {}", imgref, c.merge_commit);
@@ -459,13 +934,17 @@ async fn container_store(
         PrepareResult::Ready(r) => r,
     };
     for layer in prep.all_layers() {
-        if layer.commit.is_some() {
-            println!("Using layer: {}", layer.digest());
-        } else {
+        if layer.needed() {
             let size = crate::glib::format_size(layer.size());
             println!("Downloading layer: {} ({})", layer.digest(), size);
+        } else {
+            println!("Using layer: {}", layer.digest());
         }
     }
+    if prep.previous_manifest_digest.is_some() {
+        let size = crate::glib::format_size(prep.bytes_to_fetch());
+        println!("Update size: {}", size);
+    }
     let import = imp.import(prep).await?;
     let commit = &repo.load_commit(&import.merge_commit)?.0;
     let commit_meta = &glib::VariantDict::new(Some(&commit.child_value(0)));
@@ -480,10 +959,26 @@ async fn container_store(
             }
         }
     }
+    print_import_warnings(&import.warnings);
+    if let Some(version) = import.version.as_deref() {
+        println!("Version: {}", version);
+    }
     println!("Wrote: {} => {}", imgref, import.merge_commit);
     Ok(())
 }
 
+/// Print the warnings of an import to stderr, with a summary.
+fn print_import_warnings(warnings: &[ImportWarning]) {
+    for warning in warnings {
+        eprintln!("warning: {}", warning);
+    }
+    match warnings.len() {
+        0 => {}
+        1 => eprintln!("1 warning"),
+        n => eprintln!("{} warnings", n),
+    }
+}
+
 fn print_column(s: &str, clen: usize, remaining: &mut usize) {
     let l = s.len().min(*remaining);
     print!("{}", &s[0..l]);
@@ -522,16 +1017,28 @@ async fn container_history(repo: &ostree::Repo, imgref: &OstreeImageReference) -
 
             let mut remaining = width;
 
-            let digest = layer.digest().as_str();
+            let full_digest = layer.digest().as_str();
             // Verify it's OK to slice, this should all be ASCII
-            assert!(digest.chars().all(|c| c.is_ascii()));
+            assert!(full_digest.chars().all(|c| c.is_ascii()));
             let digest_max = columns[0].1;
-            let digest = &digest[0..digest_max];
+            let digest = &full_digest[0..digest_max];
             print_column(digest, digest_max, &mut remaining);
             let size = glib::format_size(layer.size() as u64);
             print_column(size.as_str(), columns[1].1, &mut remaining);
             print_column(created_by, columns[2].1, &mut remaining);
             println!();
+            if let Some(stats) = img.layer_stats.get(full_digest) {
+                println!(
+                    "  {} files ({}), {} symlinks, {} whiteouts",
+                    stats.regfiles,
+                    glib::format_size(stats.total_bytes),
+                    stats.symlinks,
+                    stats.whiteouts
+                );
+                if let Some((path, size)) = stats.largest_files.first() {
+                    println!("  largest: {} ({})", path, glib::format_size(*size));
+                }
+            }
         }
         Ok(())
     } else {
@@ -566,7 +1073,8 @@ fn testing(opts: &TestingOpts) -> Result<()> {
         }
         TestingOpts::Run => crate::integrationtest::run_tests(),
         TestingOpts::FilterTar => {
-            crate::tar::filter_tar(std::io::stdin(), std::io::stdout()).map(|_| {})
+            crate::tar::filter_tar(std::io::stdin(), std::io::stdout(), &Default::default())
+                .map(|_| {})
         }
     }
 }
@@ -584,20 +1092,46 @@ where
         Opt::Tar(TarOpts::Export(ref opt)) => tar_export(opt),
         Opt::Container(o) => match o {
             ContainerOpts::Info { imgref } => container_info(&imgref).await,
+            ContainerOpts::Verify { imgref } => container_verify(&imgref),
             ContainerOpts::Commit {} => container_commit().await,
             ContainerOpts::Unencapsulate {
                 repo,
                 imgref,
                 write_ref,
                 quiet,
-            } => container_import(&repo, &imgref, write_ref.as_deref(), quiet).await,
+                authfile,
+                creds,
+                rate_limit,
+            } => {
+                container_import(
+                    &repo,
+                    &imgref,
+                    write_ref.as_deref(),
+                    quiet,
+                    authfile,
+                    creds,
+                    rate_limit,
+                )
+                .await
+            }
             ContainerOpts::Encapsulate {
                 repo,
                 rev,
                 imgref,
                 labels,
                 copy_meta_keys,
+                copy_meta_opt_patterns,
                 cmd,
+                entrypoint,
+                env,
+                workdir,
+                stop_signal,
+                arch,
+                additional_revs,
+                ref_name,
+                print_plan,
+                authfile,
+                creds,
             } => {
                 let labels: Result<BTreeMap<_, _>> = labels
                     .into_iter()
@@ -608,34 +1142,118 @@ where
                         Ok((k.to_string(), v.to_string()))
                     })
                     .collect();
-                container_export(&repo, &rev, &imgref, labels?, copy_meta_keys, cmd).await
+                let additional_arches = additional_revs
+                    .into_iter()
+                    .map(|r| match r.split_once('=') {
+                        Some((rev, arch)) => crate::container::ArchCommit {
+                            ostree_ref: rev.to_string(),
+                            arch: Some(arch.to_string()),
+                        },
+                        None => crate::container::ArchCommit {
+                            ostree_ref: r,
+                            arch: None,
+                        },
+                    })
+                    .collect();
+                let config = Config {
+                    labels: Some(labels?),
+                    cmd,
+                    entrypoint,
+                    env: (!env.is_empty()).then(|| env),
+                    working_dir: workdir,
+                    stop_signal,
+                };
+                let opts = crate::container::ExportOpts {
+                    copy_meta_keys,
+                    copy_meta_opt_patterns,
+                    arch,
+                    additional_arches,
+                    ref_name,
+                    authfile,
+                    credentials: creds,
+                    ..Default::default()
+                };
+                if print_plan {
+                    // There is no content metadata here, so this is a single layer.
+                    let meta = crate::chunking::ObjectMetaSized {
+                        map: Default::default(),
+                        sizes: Vec::new(),
+                    };
+                    let plan = crate::container::encapsulate_plan(&repo, &rev, &meta, Some(&opts))?;
+                    println!("{}", serde_json::to_string_pretty(&plan)?);
+                    return Ok(());
+                }
+                container_export(&repo, &rev, &imgref, config, opts).await
             }
             ContainerOpts::Image(opts) => match opts {
-                ContainerImageOpts::List { repo } => {
-                    for image in crate::container::store::list_images(&repo)? {
-                        println!("{}", image);
-                    }
-                    Ok(())
-                }
+                ContainerImageOpts::List { repo, format } => container_image_list(&repo, format),
                 ContainerImageOpts::Pull {
                     repo,
                     imgref,
                     proxyopts,
-                } => container_store(&repo, &imgref, proxyopts).await,
+                    rate_limit,
+                } => container_store(&repo, &imgref, proxyopts, rate_limit).await,
                 ContainerImageOpts::History { repo, imgref } => {
                     container_history(&repo, &imgref).await
                 }
+                ContainerImageOpts::Layers {
+                    repo,
+                    imgref,
+                    format,
+                } => container_image_layers(&repo, &imgref, format),
+                ContainerImageOpts::Diff {
+                    repo,
+                    old,
+                    new,
+                    format,
+                } => container_image_diff(&repo, &old, &new, format),
                 ContainerImageOpts::Copy {
                     src_repo,
                     dest_repo,
                     imgref,
                 } => crate::container::store::copy(&src_repo, &dest_repo, &imgref).await,
+                ContainerImageOpts::Remove {
+                    repo,
+                    sysroot,
+                    imgref,
+                    prune_layers,
+                    force,
+                } => {
+                    let stats = if let Some(sysroot) = sysroot {
+                        let sysroot = &ostree::Sysroot::new(Some(&gio::File::for_path(&sysroot)));
+                        sysroot.load(gio::NONE_CANCELLABLE)?;
+                        crate::container::deploy::remove_image(
+                            sysroot,
+                            &imgref,
+                            prune_layers,
+                            force,
+                        )?
+                    } else {
+                        // SAFETY: Required by the options if there's no sysroot
+                        let repo = repo.unwrap();
+                        crate::container::store::remove_image(&repo, &imgref, prune_layers)?
+                    };
+                    for r in stats.removed_refs.iter() {
+                        println!("Removed: {}", r);
+                    }
+                    println!(
+                        "Prunable: {} objects, {}",
+                        stats.objects_prunable,
+                        indicatif::HumanBytes(stats.bytes_prunable)
+                    );
+                    Ok(())
+                }
                 ContainerImageOpts::Deploy {
                     sysroot,
                     stateroot,
                     imgref,
                     target_imgref,
                     karg,
+                    inherit_kargs,
+                    create_stateroot,
+                    stage,
+                    offline,
+                    allow_unconfigured,
                     proxyopts,
                     write_commitid_to,
                 } => {
@@ -648,20 +1266,41 @@ where
                     });
                     let options = crate::container::deploy::DeployOpts {
                         kargs: kargs.as_deref(),
+                        inherit_kargs,
                         target_imgref: target_imgref.as_ref(),
                         proxy_cfg: Some(proxyopts.into()),
+                        create_stateroot,
+                        stage,
+                        offline,
+                        allow_unconfigured,
+                        ..Default::default()
                     };
-                    let state = crate::container::deploy::deploy(
+                    sysroot.lock()?;
+                    let r = crate::container::deploy::deploy(
                         sysroot,
                         &stateroot,
                         &imgref,
                         Some(options),
                     )
-                    .await?;
+                    .await;
+                    sysroot.unlock();
+                    let r = r?;
                     if let Some(p) = write_commitid_to {
-                        std::fs::write(&p, state.merge_commit.as_bytes())
+                        std::fs::write(&p, r.state.merge_commit.as_bytes())
                             .with_context(|| format!("Failed to write commitid to {}", p))?;
                     }
+                    let status = if r.staged {
+                        "staged; it is finalized when shutting down"
+                    } else {
+                        "written"
+                    };
+                    println!(
+                        "Deployment {}.{} (index {}) {}",
+                        r.deployment.csum(),
+                        r.serial,
+                        r.index,
+                        status
+                    );
                     Ok(())
                 }
             },
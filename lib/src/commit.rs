@@ -9,6 +9,23 @@ use std::fs;
 use std::path::Path;
 use tokio::task;
 
+/// Maximum number of offending paths to print.
+pub(crate) const MAX_REPORTED_PATHS: usize = 20;
+
+/// Return a tmpfiles.d(5) line which creates the directory at `path` on boot;
+/// this is how content in `/var` is expected to be provided.
+pub(crate) fn tmpfiles_dir_entry(path: &str, mode: u32, uid: u64, gid: u64) -> String {
+    let mut escaped = String::with_capacity(path.len());
+    for c in path.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_whitespace() => escaped.push_str(&format!("\\x{:02x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    format!("d {} {:04o} {} {} - -", escaped, mode & 0o7777, uid, gid)
+}
+
 /// Check if there are any files that are not directories and error out if
 /// we find any, /var should not contain any files to commit in a container
 /// as it is where we expect user data to reside.
@@ -24,7 +41,7 @@ fn validate_directories_only(path: &Path, error_count: &mut i32) -> Result<()> {
             validate_directories_only(&path, error_count)?;
         } else {
             *error_count += 1;
-            if *error_count < 20 {
+            if (*error_count as usize) < MAX_REPORTED_PATHS {
                 eprintln!("Found file: {:?}", path)
             }
         }
@@ -51,3 +68,20 @@ pub(crate) async fn container_commit() -> Result<()> {
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tmpfiles_dir_entry() {
+        assert_eq!(
+            tmpfiles_dir_entry("/var/lib/foo", 0o40755, 0, 0),
+            "d /var/lib/foo 0755 0 0 - -"
+        );
+        assert_eq!(
+            tmpfiles_dir_entry("/var/lib/my dir", 0o700, 42, 43),
+            "d /var/lib/my\\x20dir 0700 42 43 - -"
+        );
+    }
+}
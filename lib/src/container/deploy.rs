@@ -1,20 +1,31 @@
 //! Perform initial setup for a container image based system root
 
-use super::store::LayeredImageState;
-use super::OstreeImageReference;
+use super::store::{LayeredImageState, PruneStats, RefPrefix};
+use super::{ImageReference, OstreeImageReference, UnencapsulationProgress};
 use crate::container::store::PrepareResult;
 use anyhow::Result;
 use fn_error_context::context;
 use ostree::glib;
+use ostree::prelude::FileExt;
+use std::collections::BTreeSet;
+use std::convert::TryFrom;
 
 /// The key in the OSTree origin which holds a serialized [`super::OstreeImageReference`].
 pub const ORIGIN_CONTAINER: &str = "container-image-reference";
 
+/// The key in the OSTree origin which holds the manifest digest the image reference
+/// resolved to when deploying.
+pub const ORIGIN_MANIFEST_DIGEST: &str = "container-image-manifest-digest";
+
 /// Options configuring deployment.
 #[derive(Debug, Default)]
 pub struct DeployOpts<'a> {
     /// Kernel arguments to use.
     pub kargs: Option<&'a [&'a str]>,
+    /// Start from the kernel arguments of the booted deployment, or if not booted,
+    /// of the previous deployment in the stateroot; any [`Self::kargs`] replace
+    /// arguments with the same key, or are appended.
+    pub inherit_kargs: bool,
     /// Target image reference, as distinct from the source.
     ///
     /// In many cases, one may want a workflow where a system is provisioned from
@@ -27,46 +38,483 @@ pub struct DeployOpts<'a> {
 
     /// Configuration for fetching containers.
     pub proxy_cfg: Option<super::store::ImageProxyConfig>,
+
+    /// Initialize the stateroot if it doesn't exist, like `ostree admin os-init`.
+    pub create_stateroot: bool,
+
+    /// Stage the deployment, to be finalized when shutting down; this requires
+    /// the system to be booted into the sysroot.
+    pub stage: bool,
+
+    /// Progress while pulling the image; see [`super::store::ImageImporter::set_progress`].
+    pub progress: Option<tokio::sync::watch::Sender<UnencapsulationProgress>>,
+
+    /// The phases of the deployment.
+    pub phase: Option<tokio::sync::watch::Sender<DeployPhase>>,
+
+    /// Only use an image which was already pulled, without fetching anything; e.g. when
+    /// provisioning from a preloaded repository.  If the image reference has a digest,
+    /// the stored image must match it.
+    pub offline: bool,
+
+    /// Deploy even if the image is marked as requiring configuration via the
+    /// [`super::OSTREE_UNCONFIGURED_STATE_LABEL`] label.
+    pub allow_unconfigured: bool,
+}
+
+/// The phase of [`deploy`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeployPhase {
+    /// Pulling the image, if not already present.
+    Pulling,
+    /// Checking out the commit, and merging (and relabeling for SELinux) `/etc`.
+    CheckingOut,
+    /// Writing the deployment and the bootloader configuration.
+    WritingBootloader,
+    /// The bootloader configuration was written; the deployment is used on the next boot.
+    Finalized,
+    /// The deployment was staged, and is finalized when shutting down.
+    Staged,
+}
+
+impl Default for DeployPhase {
+    fn default() -> Self {
+        Self::Pulling
+    }
+}
+
+/// The result of [`deploy`].
+#[derive(Debug)]
+pub struct DeployResult {
+    /// The deployed image.
+    pub state: Box<LayeredImageState>,
+    /// The deployment.
+    pub deployment: ostree::Deployment,
+    /// Whether the deployment was staged, i.e. it is only finalized when shutting
+    /// down; otherwise, the bootloader configuration was written.
+    pub staged: bool,
+    /// The index of the deployment, i.e. 0 if it is the default.
+    pub index: i32,
+    /// The serial of the deployment, distinguishing deployments of the same commit.
+    pub serial: i32,
+}
+
+fn send_phase(phase: Option<&tokio::sync::watch::Sender<DeployPhase>>, v: DeployPhase) {
+    if let Some(phase) = phase {
+        // There may be no receivers left; that's fine.
+        let _ = phase.send(v);
+    }
+}
+
+/// Whether the stateroot has been initialized.
+fn stateroot_exists(sysroot: &ostree::Sysroot, stateroot: &str) -> bool {
+    sysroot
+        .path()
+        .resolve_relative_path(format!("ostree/deploy/{}", stateroot))
+        .query_exists(ostree::gio::NONE_CANCELLABLE)
 }
 
 /// Write a container image to an OSTree deployment.
 ///
-/// This API is currently intended for only an initial deployment.
+/// This API is currently intended for only an initial deployment.  The caller
+/// must hold the sysroot lock (see [`ostree::Sysroot::lock`]).
 #[context("Performing deployment")]
 pub async fn deploy(
     sysroot: &ostree::Sysroot,
     stateroot: &str,
     imgref: &OstreeImageReference,
     options: Option<DeployOpts<'_>>,
-) -> Result<Box<LayeredImageState>> {
+) -> Result<DeployResult> {
     let cancellable = ostree::gio::NONE_CANCELLABLE;
     let options = options.unwrap_or_default();
-    let repo = &sysroot.repo().unwrap();
-    let mut imp =
-        super::store::ImageImporter::new(repo, imgref, options.proxy_cfg.unwrap_or_default())
-            .await?;
-    if let Some(target) = options.target_imgref {
-        imp.set_target(target);
+    let kargs = options.kargs.unwrap_or_default();
+    validate_kargs(kargs)?;
+    if !options.create_stateroot && !stateroot_exists(sysroot, stateroot) {
+        anyhow::bail!(
+            "Stateroot {} does not exist; use the create_stateroot option to initialize it",
+            stateroot
+        );
     }
-    let state = match imp.prepare().await? {
-        PrepareResult::AlreadyPresent(r) => r,
-        PrepareResult::Ready(prep) => imp.import(prep).await?,
+    let repo = &sysroot.repo().unwrap();
+    let phase = options.phase.as_ref();
+    send_phase(phase, DeployPhase::Pulling);
+    let allow_unconfigured = options.allow_unconfigured;
+    let state = if options.offline {
+        let state = query_offline(repo, imgref, options.target_imgref)?;
+        check_unconfigured(state.unconfigured_state(), allow_unconfigured)?;
+        state
+    } else {
+        let mut imp =
+            super::store::ImageImporter::new(repo, imgref, options.proxy_cfg.unwrap_or_default())
+                .await?;
+        if let Some(target) = options.target_imgref {
+            imp.set_target(target);
+        }
+        if let Some(progress) = options.progress {
+            imp.set_progress(progress);
+        }
+        match imp.prepare().await? {
+            PrepareResult::AlreadyPresent(r) => {
+                check_unconfigured(r.unconfigured_state(), allow_unconfigured)?;
+                r
+            }
+            PrepareResult::Ready(prep) => {
+                check_unconfigured(prep.unconfigured_state.as_deref(), allow_unconfigured)?;
+                imp.import(prep).await?
+            }
+        }
     };
     let commit = state.get_commit();
     let origin = glib::KeyFile::new();
     let target_imgref = options.target_imgref.unwrap_or(imgref);
+    super::store::touch_image(repo, &target_imgref.imgref)?;
     origin.set_string("origin", ORIGIN_CONTAINER, &target_imgref.to_string());
-    let deployment = &sysroot.deploy_tree(
-        Some(stateroot),
-        commit,
-        Some(&origin),
-        None,
-        options.kargs.unwrap_or_default(),
-        cancellable,
-    )?;
-    let flags = ostree::SysrootSimpleWriteDeploymentFlags::NONE;
-    sysroot.simple_write_deployment(Some(stateroot), deployment, None, flags, cancellable)?;
-    sysroot.cleanup(cancellable)?;
-
-    Ok(state)
+    origin.set_string("origin", ORIGIN_MANIFEST_DIGEST, &state.manifest_digest);
+    let kargs: Vec<String> = if options.inherit_kargs {
+        inherited_kargs(sysroot, stateroot, kargs)
+    } else {
+        kargs.iter().map(|s| s.to_string()).collect()
+    };
+    let kargs: Vec<&str> = kargs.iter().map(|s| s.as_str()).collect();
+    let deployed_digests = |sysroot: &ostree::Sysroot| -> BTreeSet<String> {
+        query_deployments(sysroot)
+            .into_iter()
+            .filter_map(|d| d.manifest_digest)
+            .collect()
+    };
+    if !stateroot_exists(sysroot, stateroot) {
+        sysroot.init_osname(stateroot, cancellable)?;
+    }
+    send_phase(phase, DeployPhase::CheckingOut);
+    let (deployment, staged) = if options.stage {
+        let deployment = sysroot.stage_tree(
+            Some(stateroot),
+            commit,
+            Some(&origin),
+            None,
+            &kargs,
+            cancellable,
+        )?;
+        super::store::set_deployed_images(
+            repo,
+            &Default::default(),
+            &state,
+            &deployed_digests(sysroot),
+        )?;
+        send_phase(phase, DeployPhase::Staged);
+        (deployment, true)
+    } else {
+        let deployment = sysroot.deploy_tree(
+            Some(stateroot),
+            commit,
+            Some(&origin),
+            None,
+            &kargs,
+            cancellable,
+        )?;
+        send_phase(phase, DeployPhase::WritingBootloader);
+        let flags = ostree::SysrootSimpleWriteDeploymentFlags::NONE;
+        sysroot.simple_write_deployment(Some(stateroot), &deployment, None, flags, cancellable)?;
+        // Before the cleanup, which prunes unreferenced commits
+        super::store::set_deployed_images(
+            repo,
+            &Default::default(),
+            &state,
+            &deployed_digests(sysroot),
+        )?;
+        sysroot.cleanup(cancellable)?;
+        send_phase(phase, DeployPhase::Finalized);
+        (deployment, false)
+    };
+    // The deployment was reloaded with its index when written
+    let deployment = sysroot
+        .deployments()
+        .into_iter()
+        .find(|d| {
+            d.osname() == deployment.osname()
+                && d.csum() == deployment.csum()
+                && d.deployserial() == deployment.deployserial()
+        })
+        .unwrap_or(deployment);
+
+    Ok(DeployResult {
+        state,
+        index: deployment.index(),
+        serial: deployment.deployserial(),
+        deployment,
+        staged,
+    })
+}
+
+/// Refuse images which require configuration, unless allowed.
+fn check_unconfigured(message: Option<&str>, allow: bool) -> Result<()> {
+    match message {
+        Some(message) if !allow => Err(anyhow::anyhow!(
+            "Image is in an unconfigured state: {}",
+            message
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Find an already pulled image, stored under the target reference if any.
+fn query_offline(
+    repo: &ostree::Repo,
+    imgref: &OstreeImageReference,
+    target_imgref: Option<&OstreeImageReference>,
+) -> Result<Box<LayeredImageState>> {
+    let stored = target_imgref.unwrap_or(imgref);
+    let state = super::store::query_image(repo, stored)?
+        .ok_or_else(|| anyhow::anyhow!("Image {} is not available offline", stored))?;
+    match imgref.imgref.digest() {
+        Some(digest) if digest != state.manifest_digest => Err(anyhow::anyhow!(
+            "Image {} is not available offline; stored digest is {}",
+            imgref,
+            state.manifest_digest
+        )),
+        _ => Ok(state),
+    }
+}
+
+/// Reject kernel arguments which can't be written to the bootloader configuration.
+fn validate_kargs(kargs: &[&str]) -> Result<()> {
+    if let Some(karg) = kargs.iter().find(|k| k.contains('\n')) {
+        anyhow::bail!("Invalid kernel argument with newline: {:?}", karg);
+    }
+    Ok(())
+}
+
+/// Merge kernel arguments into those of the booted deployment, or the merge
+/// deployment of the stateroot.
+fn inherited_kargs(sysroot: &ostree::Sysroot, stateroot: &str, kargs: &[&str]) -> Vec<String> {
+    let base = sysroot
+        .booted_deployment()
+        .or_else(|| sysroot.merge_deployment(Some(stateroot)))
+        .and_then(|d| d.bootconfig())
+        .and_then(|b| b.get("options"));
+    let mut r = ostree::KernelArgs::from_string(base.as_deref().unwrap_or_default());
+    for karg in kargs {
+        r.replace(karg);
+    }
+    r.to_strv().into_iter().map(|s| s.to_string()).collect()
+}
+
+/// Find a deployment of the given image, either of its stored commit or with
+/// an origin referring to it.
+fn find_deployment(
+    sysroot: &ostree::Sysroot,
+    prefix: &RefPrefix,
+    imgref: &ImageReference,
+) -> Result<Option<ostree::Deployment>> {
+    let repo = &sysroot.repo().unwrap();
+    let commit = repo.resolve_rev(&super::store::ref_for_image(prefix, imgref)?, true)?;
+    for deployment in sysroot.deployments() {
+        if commit.as_deref() == Some(deployment.csum().as_str()) {
+            return Ok(Some(deployment));
+        }
+        if let Some(origin) = deployment_imgref(&deployment) {
+            if &origin.imgref == imgref {
+                return Ok(Some(deployment));
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// The image reference in the origin of a deployment, if it was created from a
+/// container image.
+fn deployment_imgref(deployment: &ostree::Deployment) -> Option<OstreeImageReference> {
+    query_deployment_origin(deployment).map(|o| o.imgref)
+}
+
+/// The container image a deployment was created from, as recorded in its origin.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeploymentOrigin {
+    /// The image reference, e.g. with a floating tag.
+    pub imgref: OstreeImageReference,
+    /// The manifest digest the image reference resolved to when deploying; unset
+    /// for deployments written by older versions.
+    pub manifest_digest: Option<String>,
+}
+
+/// Read the container image a deployment was created from; `None` if it was not
+/// created from a container image.
+pub fn query_deployment_origin(deployment: &ostree::Deployment) -> Option<DeploymentOrigin> {
+    let origin = deployment.origin()?;
+    let imgref = origin.string("origin", ORIGIN_CONTAINER).ok()?;
+    let imgref = OstreeImageReference::try_from(imgref.as_str()).ok()?;
+    let manifest_digest = origin
+        .string("origin", ORIGIN_MANIFEST_DIGEST)
+        .ok()
+        .map(|s| s.to_string());
+    Some(DeploymentOrigin {
+        imgref,
+        manifest_digest,
+    })
+}
+
+/// A deployment, with the container image it was created from.
+#[derive(Debug)]
+pub struct ImageDeployment {
+    /// The deployment.
+    pub deployment: ostree::Deployment,
+    /// The image reference from the deployment origin; unset if the deployment
+    /// was not created from a container image.
+    pub imgref: Option<OstreeImageReference>,
+    /// The manifest digest recorded in the deployment origin, if any.
+    pub manifest_digest: Option<String>,
+}
+
+impl ImageDeployment {
+    /// Whether an update is available, given the digest the image reference resolves
+    /// to now, e.g. from [`super::fetch_manifest`]; `None` if no digest was recorded.
+    pub fn update_available(&self, digest: &str) -> Option<bool> {
+        self.manifest_digest.as_deref().map(|d| d != digest)
+    }
+}
+
+/// List all deployments, in order (i.e. the default first), with the container
+/// image each was created from.
+pub fn query_deployments(sysroot: &ostree::Sysroot) -> Vec<ImageDeployment> {
+    sysroot
+        .deployments()
+        .into_iter()
+        .map(|deployment| {
+            let (imgref, manifest_digest) = match query_deployment_origin(&deployment) {
+                Some(o) => (Some(o.imgref), o.manifest_digest),
+                None => (None, None),
+            };
+            ImageDeployment {
+                deployment,
+                imgref,
+                manifest_digest,
+            }
+        })
+        .collect()
+}
+
+/// Make the previous deployment created from a container image in the stateroot of
+/// the default deployment the new default, and return it.  The other deployments
+/// are kept in order.  The caller must hold the sysroot lock.
+#[context("Rolling back")]
+pub fn rollback(sysroot: &ostree::Sysroot) -> Result<ImageDeployment> {
+    let cancellable = ostree::gio::NONE_CANCELLABLE;
+    sysroot.load_if_changed(cancellable)?;
+    let mut deployments = sysroot.deployments();
+    let default = deployments
+        .first()
+        .ok_or_else(|| anyhow::anyhow!("No deployments found"))?;
+    let stateroot = default.osname();
+    let idx = deployments
+        .iter()
+        .skip(1)
+        .position(|d| d.osname() == stateroot && deployment_imgref(d).is_some())
+        .ok_or_else(|| anyhow::anyhow!("No previous container image deployment"))?
+        + 1;
+    let deployment = deployments.remove(idx);
+    deployments.insert(0, deployment.clone());
+    sysroot.write_deployments(&deployments, cancellable)?;
+    let origin = query_deployment_origin(&deployment);
+    Ok(ImageDeployment {
+        imgref: origin.as_ref().map(|o| o.imgref.clone()),
+        manifest_digest: origin.and_then(|o| o.manifest_digest),
+        deployment,
+    })
+}
+
+/// Remove a stored image from the repository of a system root, failing if it is
+/// deployed unless `force` is set.  See [`super::store::remove_image`].
+pub fn remove_image(
+    sysroot: &ostree::Sysroot,
+    imgref: &ImageReference,
+    prune_layers: bool,
+    force: bool,
+) -> Result<PruneStats> {
+    remove_image_with_prefix(sysroot, &Default::default(), imgref, prune_layers, force)
+}
+
+/// Like [`remove_image`], for an image stored under the given ref prefix.
+#[context("Removing image {}", imgref)]
+pub fn remove_image_with_prefix(
+    sysroot: &ostree::Sysroot,
+    prefix: &RefPrefix,
+    imgref: &ImageReference,
+    prune_layers: bool,
+    force: bool,
+) -> Result<PruneStats> {
+    if !force {
+        if let Some(deployment) = find_deployment(sysroot, prefix, imgref)? {
+            anyhow::bail!(
+                "Image is used by deployment {}.{} in stateroot {}",
+                deployment.csum(),
+                deployment.deployserial(),
+                deployment.osname()
+            );
+        }
+    }
+    let repo = &sysroot.repo().unwrap();
+    super::store::remove_image_with_prefix(repo, prefix, imgref, prune_layers)
+}
+
+/// Remove the refs of layers which are not used by any stored image, like
+/// [`super::store::gc_image_layers`], also retaining the layers of all deployments
+/// created from container images, even if their image was removed or updated.
+/// For deployments written by older versions, only the layer holding the deployed
+/// commit is retained.
+pub fn gc_image_layers(sysroot: &ostree::Sysroot) -> Result<PruneStats> {
+    gc_image_layers_with_prefix(sysroot, &Default::default())
+}
+
+/// Like [`gc_image_layers`], for the layers and images under the given ref prefix.
+pub fn gc_image_layers_with_prefix(
+    sysroot: &ostree::Sysroot,
+    prefix: &RefPrefix,
+) -> Result<PruneStats> {
+    let repo = &sysroot.repo().unwrap();
+    let mut pinned = Vec::new();
+    for d in query_deployments(sysroot) {
+        if d.imgref.is_none() {
+            continue;
+        }
+        // The merge commit holds the manifest, which names all layers of the image.
+        if let Some(digest) = d.manifest_digest.as_deref() {
+            pinned.extend(super::store::deployed_image_commit(repo, prefix, digest)?);
+        }
+        pinned.push(d.deployment.csum().to_string());
+    }
+    super::store::gc_image_layers_pinned(repo, prefix, &pinned)
+}
+
+/// Remove least recently used images like [`super::store::prune_images`], also
+/// retaining the images of all deployments.
+pub fn prune_images(
+    sysroot: &ostree::Sysroot,
+    keep_last_n: usize,
+    older_than: Option<chrono::DateTime<chrono::Utc>>,
+    dry_run: bool,
+) -> Result<Vec<ImageReference>> {
+    prune_images_with_prefix(
+        sysroot,
+        &Default::default(),
+        keep_last_n,
+        older_than,
+        dry_run,
+    )
+}
+
+/// Like [`prune_images`], for the images under the given ref prefix.
+pub fn prune_images_with_prefix(
+    sysroot: &ostree::Sysroot,
+    prefix: &RefPrefix,
+    keep_last_n: usize,
+    older_than: Option<chrono::DateTime<chrono::Utc>>,
+    dry_run: bool,
+) -> Result<Vec<ImageReference>> {
+    let repo = &sysroot.repo().unwrap();
+    let deployed: Vec<String> = sysroot
+        .deployments()
+        .iter()
+        .map(|d| d.csum().to_string())
+        .collect();
+    super::store::prune_images_retaining(repo, prefix, keep_last_n, older_than, dry_run, &deployed)
 }
@@ -2,19 +2,21 @@
 
 use super::ocidir::OciDir;
 use super::{ocidir, OstreeImageReference, Transport};
-use super::{ImageReference, SignatureSource, OSTREE_COMMIT_LABEL};
-use crate::chunking::{Chunking, ObjectMetaSized};
+use super::{ContentInfo, ImageReference, SignatureSource, OSTREE_REF_LABEL, VERSION_LABEL};
+use crate::chunking::{Chunking, ChunkingPlan, ObjectMetaSized, PackingStrategy};
 use crate::container::skopeo;
+use crate::objectsource::ObjectMeta;
 use crate::tar as ostree_tar;
 use anyhow::{anyhow, Context, Result};
+use camino::Utf8PathBuf;
 use fn_error_context::context;
 use gio::glib;
 use oci_spec::image as oci_image;
 use ostree::gio;
 use std::borrow::Cow;
-use std::collections::{BTreeMap, HashMap};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::num::NonZeroU32;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
 use tracing::{instrument, Level};
 
@@ -23,13 +25,211 @@ use tracing::{instrument, Level};
 /// schema, it's not actually useful today.  But, we keep it
 /// out of principle.
 const BLOB_OSTREE_ANNOTATION: &str = "ostree.encapsulated";
+/// Annotation on the layers of a chunked image, listing the components
+/// (e.g. packages) whose content is in the layer, separated by commas.
+pub const COMPONENTS_ANNOTATION: &str = "ostree.components";
+/// Annotation on additional non-ostree layers; see [`ExportOpts::extra_layers`].
+pub const EXTRA_LAYER_ANNOTATION: &str = "ostree.extra-layer";
+/// Manifest annotation holding the base64 encoded detached metadata (e.g. GPG
+/// signatures) of the commit; see [`ExportOpts::detached_metadata_annotation`].
+pub const DETACHED_METADATA_ANNOTATION: &str = "ostree.detached-metadata";
+/// Commit metadata key for the CPU architecture of the content, e.g. `x86_64`.
+/// This determines the `platform` of the generated image, unless overridden
+/// via [`ExportOpts::arch`].
+pub const COMMIT_META_ARCH: &str = "ostree.architecture";
 /// Configuration for the generated container.
 #[derive(Debug, Default)]
 pub struct Config {
     /// Additional labels.
     pub labels: Option<BTreeMap<String, String>>,
     /// The equivalent of a `Dockerfile`'s `CMD` instruction.
+    /// If unset, the `ostree.container-cmd` commit metadata is used.
     pub cmd: Option<Vec<String>>,
+    /// The equivalent of a `Dockerfile`'s `ENTRYPOINT` instruction.
+    pub entrypoint: Option<Vec<String>>,
+    /// Environment variables, in the form `KEY=VALUE`.
+    pub env: Option<Vec<String>>,
+    /// The equivalent of a `Dockerfile`'s `WORKDIR` instruction.
+    pub working_dir: Option<String>,
+    /// The equivalent of a `Dockerfile`'s `STOPSIGNAL` instruction.
+    pub stop_signal: Option<String>,
+}
+
+impl Config {
+    fn validate(&self) -> Result<()> {
+        for v in self.env.iter().flatten() {
+            match v.split_once('=') {
+                Some((k, _)) if !k.is_empty() => {}
+                _ => {
+                    return Err(anyhow!(
+                        "Invalid environment variable (expected KEY=VALUE): {}",
+                        v
+                    ))
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Progress of [`encapsulate`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EncapsulateProgress {
+    /// Nothing has been done yet.
+    Starting,
+    /// Splitting the commit into chunks.
+    Chunking,
+    /// Writing a layer.
+    WritingLayer {
+        /// The layer being written, starting from 1.
+        n: u32,
+        /// The total number of layers in the image.
+        total: u32,
+        /// Uncompressed bytes written so far for this layer.
+        bytes: u64,
+    },
+    /// Writing the image configuration and manifest.
+    WritingManifest,
+    /// Copying the generated image to its destination.
+    Pushing,
+}
+
+impl Default for EncapsulateProgress {
+    fn default() -> Self {
+        Self::Starting
+    }
+}
+
+type ProgressSender = tokio::sync::watch::Sender<EncapsulateProgress>;
+
+/// Minimum number of bytes written to a layer between progress updates.
+const LAYER_PROGRESS_INTERVAL: u64 = 1 << 20;
+
+fn send_progress(progress: Option<&ProgressSender>, v: EncapsulateProgress) {
+    if let Some(progress) = progress {
+        // There may be no receivers left; that's fine.
+        let _ = progress.send(v);
+    }
+}
+
+/// A writer for a layer which reports the number of (uncompressed) bytes written.
+struct LayerProgressWriter<'a> {
+    inner: ocidir::RawLayerWriter<'a>,
+    progress: Option<&'a ProgressSender>,
+    n: u32,
+    total: u32,
+    reported: u64,
+}
+
+impl<'a> LayerProgressWriter<'a> {
+    fn new(
+        inner: ocidir::RawLayerWriter<'a>,
+        progress: Option<&'a ProgressSender>,
+        n: u32,
+        total: u32,
+    ) -> Self {
+        let r = Self {
+            inner,
+            progress,
+            n,
+            total,
+            reported: 0,
+        };
+        r.report();
+        r
+    }
+
+    fn report(&self) {
+        send_progress(
+            self.progress,
+            EncapsulateProgress::WritingLayer {
+                n: self.n,
+                total: self.total,
+                bytes: self.inner.uncompressed_size(),
+            },
+        );
+    }
+
+    fn into_inner(self) -> ocidir::RawLayerWriter<'a> {
+        self.report();
+        self.inner
+    }
+}
+
+impl<'a> std::io::Write for LayerProgressWriter<'a> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        let bytes = self.inner.uncompressed_size();
+        if bytes - self.reported >= LAYER_PROGRESS_INTERVAL {
+            self.reported = bytes;
+            self.report();
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Create a tar layer writer which reports its progress as layer `n` of `total`.
+fn create_layer<'a>(
+    ociw: &'a OciDir,
+    compression: ocidir::Compression,
+    progress: Option<&'a ProgressSender>,
+    n: u32,
+    total: u32,
+) -> Result<tar::Builder<LayerProgressWriter<'a>>> {
+    let w = ociw.create_raw_layer_compressed(compression)?;
+    Ok(tar::Builder::new(LayerProgressWriter::new(
+        w, progress, n, total,
+    )))
+}
+
+/// Resolve a ref or a full commit checksum.  A checksum is used as is, without
+/// looking up any refs.
+fn resolve_commit(repo: &ostree::Repo, rev: &str) -> Result<String> {
+    if rev.len() == 64 && rev.chars().all(|c| c.is_ascii_hexdigit()) {
+        if rev.chars().any(|c| c.is_ascii_uppercase()) {
+            return Err(anyhow!(
+                "Invalid commit checksum (must be lowercase): {}",
+                rev
+            ));
+        }
+        if !repo.has_object(ostree::ObjectType::Commit, rev, gio::NONE_CANCELLABLE)? {
+            return Err(anyhow!("Commit not found: {}", rev));
+        }
+        return Ok(rev.to_string());
+    }
+    repo.resolve_rev(rev, true)
+        .with_context(|| format!("Resolving {}", rev))?
+        .map(|c| c.to_string())
+        .ok_or_else(|| anyhow!("Ref not found: {}", rev))
+}
+
+/// Compute the size and number of objects of a commit.
+#[context("Computing content size")]
+fn commit_content_info(
+    repo: &ostree::Repo,
+    commit: &str,
+    commit_v: &glib::Variant,
+) -> Result<ContentInfo> {
+    let cancellable = gio::NONE_CANCELLABLE;
+    let objects = repo.traverse_commit(commit, 0, cancellable)?;
+    let mut size = 0;
+    for obj in objects.iter() {
+        if obj.object_type() != ostree::ObjectType::File {
+            continue;
+        }
+        let (_, finfo, _) = repo.load_file(&obj.checksum(), cancellable)?;
+        // SAFETY: This is always returned when requested
+        size += finfo.unwrap().size() as u64;
+    }
+    Ok(ContentInfo {
+        size,
+        object_count: objects.len() as u64,
+        commit_timestamp: ostree::commit_get_timestamp(commit_v),
+    })
 }
 
 /// Write an ostree commit to an OCI blob
@@ -38,19 +238,36 @@ fn export_ostree_ref(
     repo: &ostree::Repo,
     rev: &str,
     writer: &mut OciDir,
-    compression: Option<flate2::Compression>,
+    compression: ocidir::Compression,
+    progress: Option<&ProgressSender>,
 ) -> Result<ocidir::Layer> {
     let commit = repo.require_rev(rev)?;
-    let mut w = writer.create_raw_layer(compression)?;
+    let w = writer.create_raw_layer_compressed(compression)?;
+    let mut w = LayerProgressWriter::new(w, progress, 1, 1);
     ostree_tar::export_commit(repo, commit.as_str(), &mut w, None)?;
-    w.complete()
+    w.into_inner().complete()
+}
+
+/// Match `s` against a pattern where `*` matches any sequence of characters.
+fn glob_match(pattern: &str, s: &str) -> bool {
+    match pattern.split_once('*') {
+        None => pattern == s,
+        Some((prefix, rest)) => match s.strip_prefix(prefix) {
+            None => false,
+            Some(s) => (0..=s.len())
+                .filter(|&i| s.is_char_boundary(i))
+                .any(|i| glob_match(rest, &s[i..])),
+        },
+    }
 }
 
 fn commit_meta_to_labels<'a>(
-    meta: &glib::VariantDict,
+    meta_v: &glib::Variant,
     keys: impl IntoIterator<Item = &'a str>,
+    opt_patterns: &[String],
     labels: &mut HashMap<String, String>,
 ) -> Result<()> {
+    let meta = &glib::VariantDict::new(Some(meta_v));
     for k in keys {
         let v = meta
             .lookup::<String>(k)
@@ -58,6 +275,22 @@ fn commit_meta_to_labels<'a>(
             .ok_or_else(|| anyhow!("Could not find commit metadata key: {}", k))?;
         labels.insert(k.to_string(), v);
     }
+    if !opt_patterns.is_empty() {
+        for i in 0..meta_v.n_children() {
+            let entry = meta_v.child_value(i);
+            let k = entry.child_value(0);
+            let k = k.str().unwrap();
+            if !opt_patterns.iter().any(|p| glob_match(p, k)) {
+                continue;
+            }
+            let v = entry.child_value(1).as_variant().unwrap();
+            let v = match v.str() {
+                Some(v) => v.to_string(),
+                None => v.print(false).to_string(),
+            };
+            labels.insert(k.to_string(), v);
+        }
+    }
     // Copy standard metadata keys `ostree.bootable` and `ostree.linux`.
     // Bootable is an odd one out in being a boolean.
     if let Some(v) = meta.lookup::<bool>(*ostree::METADATA_KEY_BOOTABLE)? {
@@ -72,6 +305,203 @@ fn commit_meta_to_labels<'a>(
     Ok(())
 }
 
+/// A chunk layer of a previous build, which may be reused.
+#[derive(Debug)]
+struct ReusableLayer {
+    descriptor: oci_image::Descriptor,
+    diffid: String,
+}
+
+/// Compute the set of content objects in each chunk layer of the previous build
+/// (if any), using its [`COMPONENTS_ANNOTATION`] and content metadata.
+fn previous_layers(opts: &ExportOpts) -> Result<HashMap<BTreeSet<String>, ReusableLayer>> {
+    let manifest = if let Some(m) = opts.previous_manifest.as_ref() {
+        m
+    } else {
+        return Ok(HashMap::new());
+    };
+    let (config, meta) = match (opts.previous_config.as_ref(), opts.previous_meta.as_ref()) {
+        (Some(c), Some(m)) => (c, m),
+        _ => {
+            return Err(anyhow!(
+                "Reusing layers requires the previous image configuration and content metadata"
+            ))
+        }
+    };
+    let diffids = config.rootfs().diff_ids();
+    if diffids.len() != manifest.layers().len() {
+        return Err(anyhow!(
+            "Previous manifest has {} layers, but its configuration has {}",
+            manifest.layers().len(),
+            diffids.len()
+        ));
+    }
+    let mut ids_by_name = HashMap::<&str, Vec<&str>>::new();
+    for source in meta.set.iter() {
+        ids_by_name
+            .entry(&*source.name)
+            .or_default()
+            .push(&*source.identifier);
+    }
+    let mut objects_by_id = HashMap::<&str, BTreeSet<&str>>::new();
+    for (checksum, id) in meta.map.iter() {
+        objects_by_id
+            .entry(&**id)
+            .or_default()
+            .insert(checksum.as_str());
+    }
+    let mut r = HashMap::new();
+    for (layer, diffid) in manifest.layers().iter().zip(diffids) {
+        let components = layer
+            .annotations()
+            .as_ref()
+            .and_then(|a| a.get(COMPONENTS_ANNOTATION));
+        let components = if let Some(c) = components {
+            c
+        } else {
+            continue;
+        };
+        let objects: BTreeSet<String> = components
+            .split(',')
+            .filter_map(|name| ids_by_name.get(name))
+            .flatten()
+            .filter_map(|id| objects_by_id.get(id))
+            .flatten()
+            .map(|&o| o.to_string())
+            .collect();
+        if objects.is_empty() {
+            continue;
+        }
+        r.insert(
+            objects,
+            ReusableLayer {
+                descriptor: layer.clone(),
+                diffid: diffid.clone(),
+            },
+        );
+    }
+    Ok(r)
+}
+
+/// The content of an additional, non-ostree layer.
+pub enum LayerSource {
+    /// An uncompressed tar stream.
+    Tar(Box<dyn std::io::Read + Send>),
+    /// Regular files, as `(path, contents, mode)`.  Parent directories are created
+    /// with mode `0755`.
+    Files(Vec<(Utf8PathBuf, Vec<u8>, u32)>),
+}
+
+impl std::fmt::Debug for LayerSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LayerSource::Tar(_) => f.write_str("Tar"),
+            LayerSource::Files(files) => f
+                .debug_list()
+                .entries(files.iter().map(|(path, _, mode)| (path, mode)))
+                .finish(),
+        }
+    }
+}
+
+/// Write the files of a [`LayerSource::Files`] as a tar stream.
+fn write_layer_files<W: std::io::Write>(
+    files: &[(Utf8PathBuf, Vec<u8>, u32)],
+    out: &mut tar::Builder<W>,
+) -> Result<()> {
+    let mut dirs = BTreeSet::new();
+    for (path, contents, mode) in files {
+        let path = path.strip_prefix("/").unwrap_or(path);
+        let mut parents: Vec<_> = path
+            .ancestors()
+            .skip(1)
+            .filter(|p| !p.as_str().is_empty())
+            .collect();
+        parents.reverse();
+        for parent in parents {
+            if dirs.insert(parent.to_owned()) {
+                let mut h = tar::Header::new_gnu();
+                h.set_entry_type(tar::EntryType::Directory);
+                h.set_uid(0);
+                h.set_gid(0);
+                h.set_mode(0o755);
+                h.set_mtime(0);
+                h.set_size(0);
+                out.append_data(&mut h, parent, std::io::empty())?;
+            }
+        }
+        let mut h = tar::Header::new_gnu();
+        h.set_entry_type(tar::EntryType::Regular);
+        h.set_uid(0);
+        h.set_gid(0);
+        h.set_mode(*mode);
+        h.set_mtime(0);
+        h.set_size(contents.len() as u64);
+        out.append_data(&mut h, path, contents.as_slice())?;
+    }
+    Ok(())
+}
+
+/// Write the blobs of the additional layers, which may be shared by multiple
+/// manifests, returning their descriptors and diffids.
+#[context("Writing extra layers")]
+fn write_extra_layers(
+    ociw: &OciDir,
+    sources: Vec<LayerSource>,
+    compression: ocidir::Compression,
+) -> Result<Vec<(oci_image::Descriptor, String)>> {
+    sources
+        .into_iter()
+        .map(|source| {
+            let layer = match source {
+                LayerSource::Tar(mut r) => {
+                    let mut w = ociw.create_raw_layer_compressed(compression)?;
+                    std::io::copy(&mut r, &mut w)?;
+                    w.complete()?
+                }
+                LayerSource::Files(files) => {
+                    let mut w = ociw.create_layer_compressed(compression)?;
+                    write_layer_files(&files, &mut w)?;
+                    w.into_inner()?.complete()?
+                }
+            };
+            let mut annotations = layer.annotations;
+            annotations.insert(EXTRA_LAYER_ANNOTATION.to_string(), "true".to_string());
+            let desc = layer
+                .blob
+                .descriptor()
+                .media_type(layer.media_type)
+                .annotations(annotations)
+                .build()
+                .unwrap();
+            Ok((desc, format!("sha256:{}", layer.uncompressed_sha256)))
+        })
+        .collect()
+}
+
+/// Maximum number of components listed in the history entry of a chunk layer.
+const MAX_HISTORY_COMPONENTS: usize = 5;
+/// The default for [`ExportOpts::history_template`].
+const DEFAULT_HISTORY_TEMPLATE: &str = "ostree chunk: {components}";
+
+/// Generate the history entry for a chunk layer with the given components.
+fn chunk_history(template: Option<&str>, components: &[String]) -> String {
+    let shown = components
+        .iter()
+        .take(MAX_HISTORY_COMPONENTS)
+        .map(|c| c.as_str())
+        .collect::<Vec<_>>()
+        .join(", ");
+    let list = match components.len().saturating_sub(MAX_HISTORY_COMPONENTS) {
+        0 => shown,
+        n => format!("{} and {} more", shown, n),
+    };
+    template
+        .unwrap_or(DEFAULT_HISTORY_TEMPLATE)
+        .replace("{components}", &list)
+        .replace("{n}", &components.len().to_string())
+}
+
 /// Write an ostree commit to an OCI blob
 #[context("Writing ostree root to blob")]
 #[allow(clippy::too_many_arguments)]
@@ -82,28 +512,56 @@ fn export_chunked(
     imgcfg: &mut oci_image::ImageConfiguration,
     labels: &mut HashMap<String, String>,
     mut chunking: Chunking,
-    compression: Option<flate2::Compression>,
+    previous: &HashMap<BTreeSet<String>, ReusableLayer>,
+    compression: ocidir::Compression,
+    progress: Option<&ProgressSender>,
+    history_template: Option<&str>,
     description: &str,
 ) -> Result<()> {
-    let layers: Result<Vec<_>> = chunking
-        .take_chunks()
-        .into_iter()
-        .enumerate()
-        .map(|(i, chunk)| -> Result<_> {
-            let mut w = ociw.create_layer(compression)?;
-            ostree_tar::export_chunk(repo, &chunk, &mut w)
-                .with_context(|| format!("Exporting chunk {i}"))?;
-            let w = w.into_inner()?;
-            Ok((w.complete()?, chunk.name))
-        })
-        .collect();
-    for (layer, name) in layers? {
-        ociw.push_layer(manifest, imgcfg, layer, &name);
+    let chunks = chunking.take_chunks();
+    // The final layer holds the commit and metadata.
+    let total = chunks.len() as u32 + 1;
+    for (i, chunk) in chunks.into_iter().enumerate() {
+        let n = i as u32 + 1;
+        let history = chunk_history(history_template, &chunk.components);
+        let objects: BTreeSet<String> = chunk.content.keys().map(|k| k.to_string()).collect();
+        let reused = previous
+            .get(&objects)
+            .filter(|p| p.descriptor.media_type() == &compression.media_type());
+        if let Some(reused) = reused {
+            tracing::event!(
+                Level::DEBUG,
+                "Reusing layer {} for chunk {}",
+                reused.descriptor.digest(),
+                i
+            );
+            send_progress(
+                progress,
+                EncapsulateProgress::WritingLayer { n, total, bytes: 0 },
+            );
+            ociw.push_existing_layer(
+                manifest,
+                imgcfg,
+                reused.descriptor.clone(),
+                &reused.diffid,
+                &history,
+            );
+            continue;
+        }
+        let mut w = create_layer(ociw, compression, progress, n, total)?;
+        ostree_tar::export_chunk(repo, &chunk, &mut w)
+            .with_context(|| format!("Exporting chunk {i}"))?;
+        let layer = w.into_inner()?.into_inner().complete()?;
+        let mut annotations = HashMap::new();
+        annotations.insert(
+            COMPONENTS_ANNOTATION.to_string(),
+            chunk.components.join(","),
+        );
+        ociw.push_layer_annotated(manifest, imgcfg, layer, Some(annotations), &history);
     }
-    let mut w = ociw.create_layer(compression)?;
+    let mut w = create_layer(ociw, compression, progress, total, total)?;
     ostree_tar::export_final_chunk(repo, &chunking, &mut w)?;
-    let w = w.into_inner()?;
-    let final_layer = w.complete()?;
+    let final_layer = w.into_inner()?.into_inner().complete()?;
     labels.insert(
         crate::container::OSTREE_DIFFID_LABEL.into(),
         format!("sha256:{}", final_layer.uncompressed_sha256),
@@ -112,22 +570,35 @@ fn export_chunked(
     Ok(())
 }
 
-/// Generate an OCI image from a given ostree root
-#[context("Building oci")]
-fn build_oci(
+/// Convert an architecture name as used by e.g. `uname` or RPM into the
+/// (Go-derived) form used in OCI platforms.
+pub(crate) fn oci_arch(arch: &str) -> oci_image::Arch {
+    let arch = match arch {
+        "x86_64" => "amd64",
+        "aarch64" => "arm64",
+        "x86" | "i686" => "386",
+        "armv7hl" => "arm",
+        "powerpc64" if cfg!(target_endian = "little") => "ppc64le",
+        "powerpc64" => "ppc64",
+        o => o,
+    };
+    oci_image::Arch::from(arch)
+}
+
+/// Generate a manifest (and its config) for a single ostree commit, returning
+/// a descriptor for the manifest.
+#[allow(clippy::too_many_arguments)]
+fn build_manifest(
     repo: &ostree::Repo,
     rev: &str,
-    ocidir_path: &Path,
+    writer: &mut OciDir,
     config: &Config,
-    opts: ExportOpts,
+    opts: &ExportOpts,
+    arch: Option<&str>,
     contentmeta: Option<crate::chunking::ObjectMetaSized>,
-) -> Result<ImageReference> {
-    // Explicitly error if the target exists
-    std::fs::create_dir(ocidir_path).context("Creating OCI dir")?;
-    let ocidir = Rc::new(openat::Dir::open(ocidir_path)?);
-    let mut writer = ocidir::OciDir::create(ocidir)?;
-
-    let commit = repo.require_rev(rev)?;
+    extra_layers: &[(oci_image::Descriptor, String)],
+) -> Result<oci_image::Descriptor> {
+    let commit = resolve_commit(repo, rev)?;
     let commit = commit.as_str();
     let (commit_v, _) = repo.load_commit(commit)?;
     let commit_subject = commit_v.child_value(3);
@@ -137,42 +608,75 @@ fn build_oci(
             commit
         )
     })?;
-    let commit_meta = &commit_v.child_value(0);
-    let commit_meta = glib::VariantDict::new(Some(commit_meta));
+    let commit_meta_v = &commit_v.child_value(0);
+    let commit_meta = glib::VariantDict::new(Some(commit_meta_v));
+
+    let arch = match arch {
+        Some(arch) => arch.to_string(),
+        None => commit_meta
+            .lookup::<String>(COMMIT_META_ARCH)?
+            .unwrap_or_else(|| std::env::consts::ARCH.to_string()),
+    };
+    let arch = oci_arch(&arch);
+    let platform = oci_image::PlatformBuilder::default()
+        .architecture(arch.clone())
+        .os(oci_image::Os::Linux)
+        .build()
+        .unwrap();
 
     let mut ctrcfg = oci_image::Config::default();
     let mut imgcfg = oci_image::ImageConfiguration::default();
+    imgcfg.set_architecture(arch);
+    imgcfg.set_os(oci_image::Os::Linux);
     let labels = ctrcfg.labels_mut().get_or_insert_with(Default::default);
 
     commit_meta_to_labels(
-        &commit_meta,
+        commit_meta_v,
         opts.copy_meta_keys.iter().map(|k| k.as_str()),
+        &opts.copy_meta_opt_patterns,
         labels,
     )?;
 
     let mut manifest = ocidir::new_empty_manifest().build().unwrap();
 
+    let progress = opts.progress.as_ref();
+    if contentmeta.is_some() {
+        send_progress(progress, EncapsulateProgress::Chunking);
+    }
     let chunking = contentmeta
-        .map(|meta| crate::chunking::Chunking::from_mapping(repo, commit, meta, opts.max_layers))
+        .map(|meta| {
+            crate::chunking::Chunking::from_mapping(
+                repo,
+                commit,
+                meta,
+                opts.max_layers,
+                opts.packing,
+            )
+        })
         .transpose()?;
 
     if let Some(version) =
-        commit_meta.lookup_value("version", Some(glib::VariantTy::new("s").unwrap()))
+        commit_meta.lookup_value(VERSION_LABEL, Some(glib::VariantTy::new("s").unwrap()))
     {
-        let version = version.str().unwrap();
-        labels.insert("version".into(), version.into());
+        super::set_version(labels, version.str().unwrap());
+    }
+    super::set_ostree_commit(labels, commit);
+    let content_info = commit_content_info(repo, commit, &commit_v)?;
+    for (k, v) in content_info.to_labels() {
+        labels.insert(k.into(), v);
+    }
+    if let Some(ref_name) = opts.ref_name.as_deref() {
+        labels.insert(OSTREE_REF_LABEL.into(), ref_name.into());
     }
-    labels.insert(OSTREE_COMMIT_LABEL.into(), commit.into());
 
     for (k, v) in config.labels.iter().flat_map(|k| k.iter()) {
         labels.insert(k.into(), v.into());
     }
+    for (k, v) in opts.labels.iter() {
+        labels.insert(k.into(), v.into());
+    }
 
-    let compression = if opts.compress {
-        flate2::Compression::default()
-    } else {
-        flate2::Compression::none()
-    };
+    let compression = opts.compression.to_ocidir(opts.compress);
 
     let mut annos = HashMap::new();
     annos.insert(BLOB_OSTREE_ANNOTATION.to_string(), "true".to_string());
@@ -183,18 +687,29 @@ fn build_oci(
     };
 
     if let Some(chunking) = chunking {
+        let previous = previous_layers(opts)?;
+        // The final layer holds the commit and all metadata objects, plus any
+        // content not assigned to a component.
+        let description = if commit_subject.is_empty() {
+            format!("ostree commit {} and metadata", commit)
+        } else {
+            format!("ostree commit {} and metadata: {}", commit, commit_subject)
+        };
         export_chunked(
             repo,
-            &mut writer,
+            writer,
             &mut manifest,
             &mut imgcfg,
             labels,
             chunking,
-            Some(compression),
+            &previous,
+            compression,
+            progress,
+            opts.history_template.as_deref(),
             &description,
         )?;
     } else {
-        let rootfs_blob = export_ostree_ref(repo, commit, &mut writer, Some(compression))?;
+        let rootfs_blob = export_ostree_ref(repo, commit, writer, compression, progress)?;
         labels.insert(
             crate::container::OSTREE_DIFFID_LABEL.into(),
             format!("sha256:{}", rootfs_blob.uncompressed_sha256),
@@ -208,6 +723,16 @@ fn build_oci(
         );
     }
 
+    for (i, (desc, diffid)) in extra_layers.iter().enumerate() {
+        writer.push_existing_layer(
+            &mut manifest,
+            &mut imgcfg,
+            desc.clone(),
+            diffid,
+            &format!("extra layer {}", i + 1),
+        );
+    }
+
     // Lookup the cmd embedded in commit metadata
     let cmd = commit_meta.lookup::<Vec<String>>(ostree::COMMIT_META_CONTAINER_CMD)?;
     // But support it being overridden by CLI options
@@ -218,16 +743,147 @@ fn build_oci(
     if let Some(cmd) = cmd {
         ctrcfg.set_cmd(Some(cmd.clone()));
     }
+    ctrcfg.set_entrypoint(config.entrypoint.clone());
+    ctrcfg.set_env(config.env.clone());
+    ctrcfg.set_working_dir(config.working_dir.clone());
+    ctrcfg.set_stop_signal(config.stop_signal.clone());
 
     imgcfg.set_config(Some(ctrcfg));
+    // Use the commit timestamp rather than the current time by default, so that
+    // the generated image is reproducible.
+    let created = if opts.use_build_time {
+        chrono::Utc::now()
+    } else {
+        let ts = ostree::commit_get_timestamp(&commit_v) as i64;
+        chrono::DateTime::from_utc(chrono::NaiveDateTime::from_timestamp(ts, 0), chrono::Utc)
+    };
+    let created = created.to_rfc3339_opts(chrono::SecondsFormat::Secs, true);
+    for h in imgcfg.history_mut().iter_mut() {
+        h.set_created(Some(created.clone()));
+    }
+    imgcfg.set_created(Some(created));
+
+    send_progress(progress, EncapsulateProgress::WritingManifest);
     let ctrcfg = writer.write_config(imgcfg)?;
     manifest.set_config(ctrcfg);
-    writer.write_manifest(manifest, oci_image::Platform::default())?;
+    let mut annotations = opts
+        .annotations
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect::<HashMap<_, _>>();
+    for (k, v) in content_info.to_labels() {
+        annotations.insert(k.into(), v);
+    }
+    if opts.detached_metadata_annotation {
+        if let Some(v) = repo.read_commit_detached_metadata(commit, gio::NONE_CANCELLABLE)? {
+            let v = glib::base64_encode(&v.data_as_bytes());
+            annotations.insert(DETACHED_METADATA_ANNOTATION.to_string(), v.to_string());
+        }
+    }
+    manifest.set_annotations(Some(annotations));
+    writer.write_manifest_blob(manifest, platform)
+}
+
+/// Keys in the `ostree.` namespace are generated by us.
+fn validate_user_keys(kind: &str, keys: &BTreeMap<String, String>) -> Result<()> {
+    if let Some(k) = keys.keys().find(|k| k.starts_with("ostree.")) {
+        return Err(anyhow!("Reserved {} key: {}", kind, k));
+    }
+    Ok(())
+}
 
-    Ok(ImageReference {
+/// Generate an OCI image from a given ostree root, returning a reference to it
+/// and the digest of its toplevel manifest or image index.
+#[context("Building oci")]
+fn build_oci(
+    repo: &ostree::Repo,
+    rev: &str,
+    ocidir_path: &Path,
+    config: &Config,
+    opts: &ExportOpts,
+    contentmeta: Option<crate::chunking::ObjectMetaSized>,
+    extra_layers: Vec<LayerSource>,
+) -> Result<(ImageReference, String)> {
+    config.validate()?;
+    validate_user_keys("label", &opts.labels)?;
+    validate_user_keys("annotation", &opts.annotations)?;
+
+    // Explicitly error if the target exists
+    std::fs::create_dir(ocidir_path).context("Creating OCI dir")?;
+    let ocidir = Rc::new(openat::Dir::open(ocidir_path)?);
+    let mut writer = ocidir::OciDir::create(ocidir)?;
+    let extra_layers = write_extra_layers(
+        &writer,
+        extra_layers,
+        opts.compression.to_ocidir(opts.compress),
+    )?;
+
+    let manifest = build_manifest(
+        repo,
+        rev,
+        &mut writer,
+        config,
+        opts,
+        opts.arch.as_deref(),
+        contentmeta,
+        &extra_layers,
+    )?;
+    let toplevel = if opts.additional_arches.is_empty() {
+        manifest
+    } else {
+        let mut manifests = vec![manifest];
+        for additional in opts.additional_arches.iter() {
+            let manifest = build_manifest(
+                repo,
+                additional.ostree_ref.as_str(),
+                &mut writer,
+                config,
+                opts,
+                additional.arch.as_deref(),
+                None,
+                &extra_layers,
+            )
+            .with_context(|| format!("Building {}", additional.ostree_ref))?;
+            let arch = manifest.platform().as_ref().map(|p| p.architecture());
+            if manifests
+                .iter()
+                .any(|m| m.platform().as_ref().map(|p| p.architecture()) == arch)
+            {
+                return Err(anyhow!(
+                    "Duplicate architecture {:?} for {}",
+                    arch.unwrap(),
+                    additional.ostree_ref
+                ));
+            }
+            manifests.push(manifest);
+        }
+        writer.write_index_blob(manifests)?
+    };
+    let digest = toplevel.digest().to_string();
+    writer.write_index(vec![toplevel])?;
+
+    let imgref = ImageReference {
         transport: Transport::OciDir,
         name: ocidir_path.to_str().unwrap().to_string(),
-    })
+    };
+    Ok((imgref, digest))
+}
+
+/// A `docker-archive:` destination without a tag would be loaded as an untagged
+/// image, so default to tagging it as `localhost/<file stem>:latest`.
+pub(crate) fn docker_archive_dest(dest: &ImageReference) -> Result<Cow<ImageReference>> {
+    if dest.name.contains(':') {
+        return Ok(Cow::Borrowed(dest));
+    }
+    let stem = Path::new(dest.name.as_str())
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .filter(|s| !s.is_empty())
+        .ok_or_else(|| anyhow!("Invalid docker-archive path: {}", dest.name))?;
+    Ok(Cow::Owned(ImageReference {
+        transport: Transport::DockerArchive,
+        name: format!("{}:localhost/{}:latest", dest.name, stem.to_lowercase()),
+    }))
 }
 
 /// Helper for `build()` that avoids generics
@@ -241,76 +897,247 @@ async fn build_impl(
     dest: &ImageReference,
 ) -> Result<String> {
     let mut opts = opts.unwrap_or_default();
+    // Check this before doing any work
+    if let Some(authfile) = opts.authfile.as_deref() {
+        skopeo::validate_authfile(authfile)?;
+    }
     if dest.transport == Transport::ContainerStorage {
         opts.compress = false;
     }
-    let digest = if dest.transport == Transport::OciDir {
-        let _copied: ImageReference = build_oci(
+    let multiarch = !opts.additional_arches.is_empty();
+    let extra_layers = std::mem::take(&mut opts.extra_layers);
+    let dest = if dest.transport == Transport::DockerArchive {
+        if multiarch {
+            return Err(anyhow!(
+                "Multi-architecture images are not supported by docker-archive"
+            ));
+        }
+        docker_archive_dest(dest)?
+    } else {
+        Cow::Borrowed(dest)
+    };
+    if dest.transport == Transport::OciDir {
+        let (_, digest) = build_oci(
             repo,
             ostree_ref,
             Path::new(dest.name.as_str()),
             config,
-            opts,
+            &opts,
             contentmeta,
+            extra_layers,
         )?;
-        None
+        Ok(digest)
     } else {
         let tempdir = tempfile::tempdir_in("/var/tmp")?;
         let tempdest = tempdir.path().join("d");
         let tempdest = tempdest.to_str().unwrap();
-        let digestfile = tempdir.path().join("digestfile");
 
-        let src = build_oci(
+        let (src, _) = build_oci(
             repo,
             ostree_ref,
             Path::new(tempdest),
             config,
-            opts,
+            &opts,
             contentmeta,
+            extra_layers,
         )?;
 
-        let mut cmd = skopeo::new_cmd();
-        tracing::event!(Level::DEBUG, "Copying {} to {}", src, dest);
-        cmd.stdout(std::process::Stdio::null()).arg("copy");
-        cmd.arg("--digestfile");
-        cmd.arg(&digestfile);
-        cmd.args(&[src.to_string(), dest.to_string()]);
-        let proc = super::skopeo::spawn(cmd)?;
-        let output = proc.wait_with_output().await?;
-        if !output.status.success() {
-            let stderr = String::from_utf8_lossy(&output.stderr);
-            return Err(anyhow::anyhow!("skopeo failed: {}\n", stderr));
-        }
-        Some(std::fs::read_to_string(digestfile)?.trim().to_string())
-    };
-    if let Some(digest) = digest {
-        Ok(digest)
-    } else {
+        send_progress(opts.progress.as_ref(), EncapsulateProgress::Pushing);
+        push_image(&src, &dest, multiarch, &opts).await
+    }
+}
+
+/// Copy an image (or with `all`, an image index) to `dest` using the credentials
+/// in `opts`, returning the digest of the copy.
+pub(crate) async fn push_image(
+    src: &ImageReference,
+    dest: &ImageReference,
+    all: bool,
+    opts: &ExportOpts,
+) -> Result<String> {
+    let tempdir = tempfile::tempdir()?;
+    let digestfile = tempdir.path().join("digestfile");
+    let mut cmd = skopeo::new_cmd();
+    tracing::event!(Level::DEBUG, "Copying {} to {}", src, dest);
+    cmd.stdout(std::process::Stdio::null()).arg("copy");
+    if all {
+        cmd.arg("--all");
+    }
+    cmd.arg("--digestfile");
+    cmd.arg(&digestfile);
+    if let Some(authfile) = opts.authfile.as_deref() {
+        cmd.arg("--authfile");
+        cmd.arg(authfile);
+    }
+    if let Some((username, password)) = opts.credentials.as_ref() {
+        cmd.arg("--dest-creds");
+        cmd.arg(format!("{}:{}", username, password));
+    }
+    cmd.args(&[src.to_string(), dest.to_string()]);
+    let proc = super::skopeo::spawn(cmd)?;
+    let output = proc.wait_with_output().await?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(anyhow::anyhow!("skopeo failed: {}\n", stderr));
+    }
+    match std::fs::read_to_string(&digestfile) {
+        Ok(digest) => Ok(digest.trim().to_string()),
         // If `skopeo copy` doesn't have `--digestfile` yet, then fall back
         // to running an inspect cycle.
-        let imgref = OstreeImageReference {
-            sigverify: SignatureSource::ContainerPolicyAllowInsecure,
-            imgref: dest.to_owned(),
-        };
-        let (_, digest) = super::unencapsulate::fetch_manifest(&imgref).await?;
-        Ok(digest)
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            let imgref = OstreeImageReference {
+                sigverify: SignatureSource::ContainerPolicyAllowInsecure,
+                imgref: dest.clone(),
+            };
+            let (_, digest) = super::unencapsulate::fetch_manifest(&imgref).await?;
+            Ok(digest)
+        }
+        Err(e) => Err(e.into()),
     }
 }
 
 /// Options controlling commit export into OCI
 #[derive(Debug, Default)]
 pub struct ExportOpts {
-    /// If true, perform gzip compression of the tar layers.
+    /// If true, compress the tar layers; otherwise the fastest compression is used.
     pub compress: bool,
+    /// The compression format of the tar layers.
+    pub compression: LayerCompression,
     /// A set of commit metadata keys to copy as image labels.
     pub copy_meta_keys: Vec<String>,
+    /// Patterns for commit metadata keys to copy as image labels, where `*` matches
+    /// any sequence of characters.  Unlike `copy_meta_keys`, keys need not exist,
+    /// and values which are not strings are copied in GVariant text format.
+    pub copy_meta_opt_patterns: Vec<String>,
     /// Maximum number of layers to use
     pub max_layers: Option<NonZeroU32>,
+    /// How to group components into layers when there are more components
+    /// than `max_layers`.  The components in each layer are listed in its
+    /// [`COMPONENTS_ANNOTATION`].
+    pub packing: PackingStrategy,
+    /// The architecture of the commit, e.g. `x86_64` or `arm64`.  By default this is
+    /// taken from the [`COMMIT_META_ARCH`] commit metadata, falling back to the
+    /// architecture of the host.
+    pub arch: Option<String>,
+    /// Commits for other architectures.  If non-empty, an image index is generated
+    /// with one manifest per architecture.  Content metadata for chunking only
+    /// applies to the primary commit.
+    pub additional_arches: Vec<ArchCommit>,
+    /// Additional annotations for the manifest.  Keys starting with `ostree.` are reserved.
+    pub annotations: BTreeMap<String, String>,
+    /// Additional labels for the image configuration, in addition to [`Config::labels`].
+    /// Keys starting with `ostree.` are reserved.
+    pub labels: BTreeMap<String, String>,
+    /// A previously generated chunked image.  Chunk layers whose set of content objects
+    /// is unchanged are reused from it instead of being regenerated.  The blobs of
+    /// reused layers are not written; they must already be present at the destination
+    /// (for example, the registry the previous image was pushed to).
+    /// This requires [`Self::previous_config`] and [`Self::previous_meta`].
+    pub previous_manifest: Option<oci_image::ImageManifest>,
+    /// The image configuration of [`Self::previous_manifest`], which provides the
+    /// uncompressed digests (diffids) of its layers.
+    pub previous_config: Option<oci_image::ImageConfiguration>,
+    /// The content metadata used to generate [`Self::previous_manifest`].
+    pub previous_meta: Option<ObjectMeta>,
+    /// By default, the creation time of the image and of its layers is the commit
+    /// timestamp, so that encapsulating the same commit with the same options always
+    /// generates the same image.  If true, the current time is used instead.
+    pub use_build_time: bool,
+    /// Also store the detached metadata of the commit, which holds its GPG signatures,
+    /// in the [`DETACHED_METADATA_ANNOTATION`] of the manifest.  This makes the signatures
+    /// available without fetching the layers, and on import they are used if the commit
+    /// layer does not include them.
+    pub detached_metadata_annotation: bool,
+    /// Template for the history entries of chunk layers, as shown by e.g. `podman history`.
+    /// `{components}` is replaced by the names of the components in the layer (truncated,
+    /// with a count of the others) and `{n}` by their number.  The default is
+    /// `ostree chunk: {components}`.
+    pub history_template: Option<String>,
+    /// Ref name recorded in the [`OSTREE_REF_LABEL`] label.  This is useful when
+    /// encapsulating a commit by checksum, e.g. from a repository without refs.
+    pub ref_name: Option<String>,
+    /// Additional non-ostree layers, e.g. for provenance data, appended after the ostree
+    /// layers.  Each has the [`EXTRA_LAYER_ANNOTATION`].  When pulled with
+    /// [`super::store::ImageImporter`], these are imported like derived layers.
+    pub extra_layers: Vec<LayerSource>,
+    /// Channel which will receive progress updates.
+    pub progress: Option<tokio::sync::watch::Sender<EncapsulateProgress>>,
+    /// Path to a Docker-formatted authentication file used when copying the image
+    /// to its destination.  It must be readable.
+    pub authfile: Option<PathBuf>,
+    /// Username and password for the destination registry.
+    pub credentials: Option<(String, String)>,
+}
+
+/// The compression format of generated layers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LayerCompression {
+    /// `tar+gzip` layers, which are supported everywhere.
+    Gzip,
+    /// `tar+zstd` layers.
+    Zstd,
+    /// `tar+zstd` layers in the `zstd:chunked` format, which allows clients
+    /// such as containers/storage to fetch only the files they are missing.
+    ZstdChunked,
+}
+
+impl Default for LayerCompression {
+    fn default() -> Self {
+        Self::Gzip
+    }
+}
+
+impl LayerCompression {
+    /// A rough compression ratio for typical operating system content, used to
+    /// estimate layer sizes.
+    fn estimated_ratio(self, compress: bool) -> f64 {
+        match self {
+            LayerCompression::Gzip if compress => 0.45,
+            LayerCompression::Gzip => 1.0,
+            LayerCompression::Zstd | LayerCompression::ZstdChunked if compress => 0.4,
+            LayerCompression::Zstd | LayerCompression::ZstdChunked => 0.45,
+        }
+    }
+
+    /// Use the default compression level, or the fastest one if `compress` is false.
+    pub(crate) fn to_ocidir(self, compress: bool) -> ocidir::Compression {
+        let zstd_level = if compress {
+            zstd::DEFAULT_COMPRESSION_LEVEL
+        } else {
+            1
+        };
+        match self {
+            LayerCompression::Gzip if compress => {
+                ocidir::Compression::Gzip(flate2::Compression::default())
+            }
+            LayerCompression::Gzip => ocidir::Compression::Gzip(flate2::Compression::none()),
+            LayerCompression::Zstd => ocidir::Compression::Zstd(zstd_level),
+            LayerCompression::ZstdChunked => ocidir::Compression::ZstdChunked(zstd_level),
+        }
+    }
+}
+
+/// A commit for a specific architecture in a multi-architecture image.
+#[derive(Debug, Clone, Default)]
+pub struct ArchCommit {
+    /// The ostree ref or commit.
+    pub ostree_ref: String,
+    /// The architecture, defaulting as for [`ExportOpts::arch`].
+    pub arch: Option<String>,
 }
 
 /// Given an OSTree repository and ref, generate a container image.
 ///
+/// Instead of a ref, a full commit checksum may be given; it is used directly.
+/// To still record a ref name in the image, use [`ExportOpts::ref_name`].
+///
 /// The returned `ImageReference` will contain a digested (e.g. `@sha256:`) version of the destination.
+///
+/// If [`ExportOpts::additional_arches`] is set, the destination is a multi-architecture
+/// image index, and the returned digest is that of the index.
+///
+/// The destination may also be an `oci-archive:` or `docker-archive:` file.  If a
+/// `docker-archive:` destination has no tag, `localhost/<file stem>:latest` is used.
 pub async fn encapsulate<S: AsRef<str>>(
     repo: &ostree::Repo,
     ostree_ref: S,
@@ -321,3 +1148,69 @@ pub async fn encapsulate<S: AsRef<str>>(
 ) -> Result<String> {
     build_impl(repo, ostree_ref.as_ref(), config, opts, contentmeta, dest).await
 }
+
+/// Compute how [`encapsulate`] would split a commit into layers, without writing
+/// any blobs.  The content metadata may be empty, in which case the commit is a single
+/// layer.  Compressed sizes are a rough estimate.
+pub fn encapsulate_plan(
+    repo: &ostree::Repo,
+    rev: &str,
+    meta: &ObjectMetaSized,
+    opts: Option<&ExportOpts>,
+) -> Result<ChunkingPlan> {
+    let default_opts = ExportOpts::default();
+    let opts = opts.unwrap_or(&default_opts);
+    let commit = resolve_commit(repo, rev)?;
+    let mut chunking = Chunking::new(repo, &commit)?;
+    if !meta.map.is_empty() {
+        chunking.process_mapping(meta.clone(), opts.max_layers, opts.packing)?;
+    }
+    let mut plan = chunking.plan();
+    let ratio = opts.compression.estimated_ratio(opts.compress);
+    for layer in plan.layers.iter_mut() {
+        layer.compressed_size = (layer.size as f64 * ratio) as u64;
+    }
+    Ok(plan)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chunk_history() {
+        let components: Vec<_> = ["bash", "kernel", "glibc", "systemd", "rpm", "dnf", "vim"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        assert_eq!(
+            chunk_history(None, &components[..2]),
+            "ostree chunk: bash, kernel"
+        );
+        assert_eq!(
+            chunk_history(None, &components),
+            "ostree chunk: bash, kernel, glibc, systemd, rpm and 2 more"
+        );
+        assert_eq!(
+            chunk_history(Some("{n} packages: {components}"), &components[..1]),
+            "1 packages: bash"
+        );
+    }
+
+    #[test]
+    fn test_glob_match() {
+        for (pattern, s, expected) in [
+            ("buildsys.*", "buildsys.checksum", true),
+            ("buildsys.*", "buildsys.", true),
+            ("buildsys.*", "buildsys", false),
+            ("*.checksum", "buildsys.checksum", true),
+            ("b*s.*m", "buildsys.checksum", true),
+            ("b*s.*m", "buildsys.checksums", false),
+            ("*", "", true),
+            ("version", "version", true),
+            ("version", "versions", false),
+        ] {
+            assert_eq!(glob_match(pattern, s), expected, "{} {}", pattern, s);
+        }
+    }
+}
@@ -25,15 +25,41 @@
 //! A key feature of container images is support for layering.  At the moment, support
 //! for this is [planned but not implemented](https://github.com/ostreedev/ostree-rs-ext/issues/12).
 
-use anyhow::anyhow;
+use anyhow::{anyhow, Context};
 use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
 use std::convert::{TryFrom, TryInto};
 use std::ops::Deref;
 
 /// The label injected into a container image that contains the ostree commit SHA-256.
 pub const OSTREE_COMMIT_LABEL: &str = "ostree.commit";
+/// The label injected into a container image with the name of the ostree ref, if known.
+pub const OSTREE_REF_LABEL: &str = "ostree.ref";
 /// The label/annotation which contains the sha256 of the final commit.
 const OSTREE_DIFFID_LABEL: &str = "ostree.diffid";
+/// The label/annotation with the total size in bytes of the content objects of the commit.
+pub const OSTREE_SIZE_LABEL: &str = "ostree.size";
+/// The label/annotation with the number of objects in the commit, including metadata.
+pub const OSTREE_OBJECT_COUNT_LABEL: &str = "ostree.object-count";
+/// The label/annotation with the commit timestamp, in seconds since the Unix epoch.
+pub const OSTREE_COMMIT_TIMESTAMP_LABEL: &str = "ostree.commit-timestamp";
+/// The label marking an image as requiring configuration before it can be used,
+/// with a message explaining what is needed.
+pub const OSTREE_UNCONFIGURED_STATE_LABEL: &str = "ostree.unconfigured-state";
+/// The label with the version of an image, matching the ostree commit metadata key.
+pub const VERSION_LABEL: &str = "version";
+/// A label with the version of an image, used if [`VERSION_LABEL`] is not set.
+pub const OSTREE_VERSION_LABEL: &str = "ostree.version";
+/// The prefix of the standard OCI annotations (and labels), e.g. for the source of an
+/// image.
+pub const OCI_ANNOTATION_PREFIX: &str = "org.opencontainers.image.";
+/// The standard annotation with the version of an image.
+pub const OCI_VERSION_ANNOTATION: &str = "org.opencontainers.image.version";
+/// The standard annotation with the tag of a manifest in an image index.
+pub const OCI_REF_NAME_ANNOTATION: &str = "org.opencontainers.image.ref.name";
+/// The standard manifest annotation with the manifest digest of the image a derived
+/// image was built from.
+pub const BASE_DIGEST_ANNOTATION: &str = "org.opencontainers.image.base.digest";
 
 /// Our generic catchall fatal error, expected to be converted
 /// to a string to output to a terminal or logs.
@@ -48,6 +74,8 @@ pub enum Transport {
     OciDir,
     /// A local OCI archive tarball (`oci-archive:`)
     OciArchive,
+    /// A local Docker archive tarball, as used by `docker save` (`docker-archive:`)
+    DockerArchive,
     /// Local container storage (`containers-storage:`)
     ContainerStorage,
 }
@@ -84,6 +112,197 @@ pub struct OstreeImageReference {
     pub imgref: ImageReference,
 }
 
+/// Summary of an encapsulated commit, as recorded in the [`OSTREE_SIZE_LABEL`],
+/// [`OSTREE_OBJECT_COUNT_LABEL`] and [`OSTREE_COMMIT_TIMESTAMP_LABEL`] labels
+/// and manifest annotations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ContentInfo {
+    /// Total size in bytes of the uncompressed content objects.
+    pub size: u64,
+    /// Number of objects, including metadata.
+    pub object_count: u64,
+    /// The commit timestamp, in seconds since the Unix epoch.
+    pub commit_timestamp: u64,
+}
+
+impl ContentInfo {
+    /// Parse from image labels or manifest annotations.  This returns `None` if any
+    /// are missing, e.g. for images generated by older versions.
+    pub fn from_labels(labels: &HashMap<String, String>) -> Result<Option<Self>> {
+        let get = |k: &str| -> Result<Option<u64>> {
+            labels
+                .get(k)
+                .map(|v| v.parse().with_context(|| format!("Parsing {}", k)))
+                .transpose()
+        };
+        let size = get(OSTREE_SIZE_LABEL)?;
+        let object_count = get(OSTREE_OBJECT_COUNT_LABEL)?;
+        let commit_timestamp = get(OSTREE_COMMIT_TIMESTAMP_LABEL)?;
+        match (size, object_count, commit_timestamp) {
+            (Some(size), Some(object_count), Some(commit_timestamp)) => Ok(Some(Self {
+                size,
+                object_count,
+                commit_timestamp,
+            })),
+            _ => Ok(None),
+        }
+    }
+
+    /// Parse from the annotations of a manifest; see [`Self::from_labels`].
+    pub fn from_manifest(manifest: &oci_spec::image::ImageManifest) -> Result<Option<Self>> {
+        manifest
+            .annotations()
+            .as_ref()
+            .map(Self::from_labels)
+            .transpose()
+            .map(Option::flatten)
+    }
+
+    /// The labels (or annotations) recording this.
+    pub(crate) fn to_labels(self) -> [(&'static str, String); 3] {
+        [
+            (OSTREE_SIZE_LABEL, self.size.to_string()),
+            (OSTREE_OBJECT_COUNT_LABEL, self.object_count.to_string()),
+            (
+                OSTREE_COMMIT_TIMESTAMP_LABEL,
+                self.commit_timestamp.to_string(),
+            ),
+        ]
+    }
+}
+
+/// Typed access to the well-known metadata of an image, which may be recorded in the
+/// labels of its configuration and in the annotations of its manifest.
+///
+/// Where both hold a value, the configuration label takes precedence: it is what
+/// container runtimes show, and it is covered by the configuration digest, whereas tools
+/// which rewrite manifests (e.g. to recompress layers) may drop annotations.  The manifest
+/// annotation is used for images without a configuration or without the label.
+#[derive(Debug, Clone, Copy)]
+pub struct ImageMetadata<'a> {
+    manifest: &'a oci_spec::image::ImageManifest,
+    config: Option<&'a oci_spec::image::ImageConfiguration>,
+}
+
+impl<'a> ImageMetadata<'a> {
+    /// Access the metadata of an image; the configuration may be unavailable, e.g. for
+    /// images stored by old versions.
+    pub fn new(
+        manifest: &'a oci_spec::image::ImageManifest,
+        config: Option<&'a oci_spec::image::ImageConfiguration>,
+    ) -> Self {
+        Self { manifest, config }
+    }
+
+    fn labels(&self) -> Option<&'a HashMap<String, String>> {
+        self.config?.config().as_ref()?.labels().as_ref()
+    }
+
+    fn annotations(&self) -> Option<&'a HashMap<String, String>> {
+        self.manifest.annotations().as_ref()
+    }
+
+    /// The value of the first of the keys found in the labels, or otherwise in the
+    /// annotations.
+    fn lookup(&self, keys: &[&str]) -> Option<&'a str> {
+        [self.labels(), self.annotations()]
+            .iter()
+            .copied()
+            .flatten()
+            .find_map(|m| keys.iter().find_map(|k| m.get(*k)))
+            .map(|v| v.as_str())
+    }
+
+    /// The ostree commit encapsulated in the image, from [`OSTREE_COMMIT_LABEL`].
+    pub fn ostree_commit(&self) -> Option<&'a str> {
+        self.lookup(&[OSTREE_COMMIT_LABEL])
+    }
+
+    /// The version of the image, from [`VERSION_LABEL`], [`OSTREE_VERSION_LABEL`] or
+    /// [`OCI_VERSION_ANNOTATION`], in order of preference.
+    pub fn version(&self) -> Option<&'a str> {
+        self.lookup(&[VERSION_LABEL, OSTREE_VERSION_LABEL, OCI_VERSION_ANNOTATION])
+    }
+
+    /// The standard `org.opencontainers.image.*` values (e.g. `source`, `revision`) by
+    /// key; labels override annotations with the same key.
+    pub fn source_annotations(&self) -> BTreeMap<&'a str, &'a str> {
+        let mut r = BTreeMap::new();
+        for m in [self.annotations(), self.labels()]
+            .iter()
+            .copied()
+            .flatten()
+        {
+            r.extend(
+                m.iter()
+                    .filter(|(k, _)| k.starts_with(OCI_ANNOTATION_PREFIX))
+                    .map(|(k, v)| (k.as_str(), v.as_str())),
+            );
+        }
+        r
+    }
+}
+
+/// Record the ostree commit encapsulated in an image in its labels; see
+/// [`ImageMetadata::ostree_commit`].
+pub(crate) fn set_ostree_commit(labels: &mut HashMap<String, String>, commit: &str) {
+    labels.insert(OSTREE_COMMIT_LABEL.to_string(), commit.to_string());
+}
+
+/// Record the version of an image in its labels; see [`ImageMetadata::version`].
+pub(crate) fn set_version(labels: &mut HashMap<String, String>, version: &str) {
+    labels.insert(VERSION_LABEL.to_string(), version.to_string());
+}
+
+/// The difference between the layers of two manifests, as computed by [`manifest_diff`].
+#[derive(Debug, Clone, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ManifestDiff {
+    /// Layers only in the new manifest.
+    pub added: Vec<oci_spec::image::Descriptor>,
+    /// Layers only in the old manifest.
+    pub removed: Vec<oci_spec::image::Descriptor>,
+    /// Layers in both manifests.
+    pub unchanged: Vec<oci_spec::image::Descriptor>,
+    /// Total size of the added layers; this is what must be fetched to update.
+    pub added_size: u64,
+    /// Total size of the removed layers.
+    pub removed_size: u64,
+    /// Total size of the unchanged layers.
+    pub unchanged_size: u64,
+}
+
+/// Compare the layers of two manifests by digest.
+pub fn manifest_diff(
+    old: &oci_spec::image::ImageManifest,
+    new: &oci_spec::image::ImageManifest,
+) -> ManifestDiff {
+    let digests = |m: &oci_spec::image::ImageManifest| -> std::collections::HashSet<String> {
+        m.layers().iter().map(|l| l.digest().to_string()).collect()
+    };
+    let (old_digests, new_digests) = (digests(old), digests(new));
+    let (unchanged, added) = new
+        .layers()
+        .iter()
+        .cloned()
+        .partition::<Vec<_>, _>(|l| old_digests.contains(l.digest().as_str()));
+    let removed = old
+        .layers()
+        .iter()
+        .filter(|l| !new_digests.contains(l.digest().as_str()))
+        .cloned()
+        .collect::<Vec<_>>();
+    let size = |v: &[oci_spec::image::Descriptor]| v.iter().map(|l| l.size() as u64).sum();
+    ManifestDiff {
+        added_size: size(&added),
+        removed_size: size(&removed),
+        unchanged_size: size(&unchanged),
+        added,
+        removed,
+        unchanged,
+    }
+}
+
 impl TryFrom<&str> for Transport {
     type Error = anyhow::Error;
 
@@ -92,6 +311,7 @@ impl TryFrom<&str> for Transport {
             "registry" | "docker" => Self::Registry,
             "oci" => Self::OciDir,
             "oci-archive" => Self::OciArchive,
+            "docker-archive" => Self::DockerArchive,
             "containers-storage" => Self::ContainerStorage,
             o => return Err(anyhow!("Unknown transport '{}'", o)),
         })
@@ -121,6 +341,73 @@ impl TryFrom<&str> for ImageReference {
     }
 }
 
+impl ImageReference {
+    /// The manifest digest this reference is pinned to, as in
+    /// `quay.io/exampleos/blah@sha256:...`.  Only registry references can be pinned.
+    pub fn digest(&self) -> Option<&str> {
+        if self.transport != Transport::Registry {
+            return None;
+        }
+        self.name.rsplit_once('@').map(|(_, digest)| digest)
+    }
+
+    /// Return a reference pinned to the given manifest digest, replacing any tag or
+    /// previous digest.
+    pub fn with_digest(&self, digest: &str) -> Result<Self> {
+        if self.transport != Transport::Registry {
+            return Err(anyhow!(
+                "Cannot pin {} to a digest; only registry references are supported",
+                self
+            ));
+        }
+        let hex = digest
+            .strip_prefix("sha256:")
+            .ok_or_else(|| anyhow!("Unsupported digest: {}", digest))?;
+        if hex.len() != 64 || !hex.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f')) {
+            return Err(anyhow!("Invalid digest: {}", digest));
+        }
+        let name = self
+            .name
+            .rsplit_once('@')
+            .map(|(name, _)| name)
+            .unwrap_or_else(|| self.name.as_str());
+        // A tag follows the last path component; an earlier ':' is a registry port.
+        let basename = name.rfind('/').map(|i| i + 1).unwrap_or_default();
+        let name = match name[basename..].find(':') {
+            Some(i) => &name[..basename + i],
+            None => name,
+        };
+        Ok(Self {
+            transport: self.transport,
+            name: format!("{}@{}", name, digest),
+        })
+    }
+
+    /// Verify that a fetched manifest digest matches the digest this reference is
+    /// pinned to, if any.
+    pub(crate) fn verify_digest(&self, manifest_digest: &str) -> Result<()> {
+        match self.digest() {
+            Some(expected) if expected != manifest_digest => Err(anyhow!(
+                "Manifest digest mismatch for {}: fetched {}",
+                self,
+                manifest_digest
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl OstreeImageReference {
+    /// Return a reference pinned to the given manifest digest, with the same signature
+    /// verification; see [`ImageReference::with_digest`].
+    pub fn with_digest(&self, digest: &str) -> Result<Self> {
+        Ok(Self {
+            sigverify: self.sigverify.clone(),
+            imgref: self.imgref.with_digest(digest)?,
+        })
+    }
+}
+
 impl TryFrom<&str> for SignatureSource {
     type Error = anyhow::Error;
 
@@ -188,6 +475,7 @@ impl std::fmt::Display for Transport {
             // TODO once skopeo supports this, canonicalize as registry:
             Self::Registry => "docker://",
             Self::OciArchive => "oci-archive:",
+            Self::DockerArchive => "docker-archive:",
             Self::OciDir => "oci:",
             Self::ContainerStorage => "containers-storage:",
         };
@@ -243,15 +531,18 @@ pub use unencapsulate::*;
 pub(crate) mod ocidir;
 mod skopeo;
 pub mod store;
+mod zstd_chunked;
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use oci_spec::image::{ImageConfiguration, ImageManifest};
 
     const INVALID_IRS: &[&str] = &["", "foo://", "docker:blah", "registry:", "foo:bar"];
     const VALID_IRS: &[&str] = &[
         "containers-storage:localhost/someimage",
         "docker://quay.io/exampleos/blah:sometag",
+        "docker-archive:/var/tmp/exampleos.tar:localhost/exampleos:latest",
     ];
 
     #[test]
@@ -273,6 +564,112 @@ mod tests {
         let ir: ImageReference = "oci:somedir".try_into().unwrap();
         assert_eq!(ir.transport, Transport::OciDir);
         assert_eq!(ir.name, "somedir");
+        let ir: ImageReference = "docker-archive:foo.tar".try_into().unwrap();
+        assert_eq!(ir.transport, Transport::DockerArchive);
+        assert_eq!(ir.name, "foo.tar");
+        assert_eq!(ir.to_string(), "docker-archive:foo.tar");
+    }
+
+    #[test]
+    fn test_imagereference_digest() {
+        let digest = format!("sha256:{}", "a".repeat(64));
+        let other = format!("sha256:{}", "b".repeat(64));
+        let cases = [
+            ("quay.io/exampleos/blah", "quay.io/exampleos/blah"),
+            ("quay.io/exampleos/blah:sometag", "quay.io/exampleos/blah"),
+            ("localhost:5000/blah:sometag", "localhost:5000/blah"),
+            ("localhost:5000/blah", "localhost:5000/blah"),
+        ];
+        for (name, expected) in cases {
+            let ir = ImageReference {
+                transport: Transport::Registry,
+                name: name.to_string(),
+            };
+            assert_eq!(ir.digest(), None);
+            ir.verify_digest(&other).unwrap();
+            let pinned = ir.with_digest(&digest).unwrap();
+            assert_eq!(pinned.name, format!("{}@{}", expected, digest));
+            assert_eq!(pinned.digest(), Some(digest.as_str()));
+            pinned.verify_digest(&digest).unwrap();
+            assert!(pinned.verify_digest(&other).is_err());
+            let repinned = pinned.with_digest(&other).unwrap();
+            assert_eq!(repinned.name, format!("{}@{}", expected, other));
+        }
+
+        let ir: ImageReference = "registry:quay.io/exampleos/blah".try_into().unwrap();
+        assert!(ir.with_digest("sha256:abc").is_err());
+        assert!(ir.with_digest(&"a".repeat(64)).is_err());
+        let ir: ImageReference = "oci:somedir".try_into().unwrap();
+        assert!(ir.with_digest(&digest).is_err());
+
+        let ir: OstreeImageReference =
+            "ostree-remote-registry:myremote:quay.io/exampleos/blah:latest"
+                .try_into()
+                .unwrap();
+        let pinned = ir.with_digest(&digest).unwrap();
+        assert_eq!(pinned.sigverify, ir.sigverify);
+        assert_eq!(
+            pinned.to_string(),
+            format!(
+                "ostree-remote-image:myremote:docker://quay.io/exampleos/blah@{}",
+                digest
+            )
+        );
+    }
+
+    #[test]
+    fn test_content_info() {
+        let info = ContentInfo {
+            size: 4096,
+            object_count: 42,
+            commit_timestamp: 1650000000,
+        };
+        let labels: HashMap<_, _> = info
+            .to_labels()
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.clone()))
+            .collect();
+        assert_eq!(ContentInfo::from_labels(&labels).unwrap(), Some(info));
+
+        let mut partial = labels.clone();
+        partial.remove(OSTREE_OBJECT_COUNT_LABEL);
+        assert_eq!(ContentInfo::from_labels(&partial).unwrap(), None);
+
+        let mut invalid = labels;
+        invalid.insert(OSTREE_SIZE_LABEL.to_string(), "big".to_string());
+        assert!(ContentInfo::from_labels(&invalid).is_err());
+    }
+
+    #[test]
+    fn test_manifest_diff() {
+        use oci_spec::image::{DescriptorBuilder, ImageManifest, MediaType};
+        let desc = |c: char, size: i64| {
+            DescriptorBuilder::default()
+                .media_type(MediaType::ImageLayerGzip)
+                .digest(format!("sha256:{}", c.to_string().repeat(64)))
+                .size(size)
+                .build()
+                .unwrap()
+        };
+        let manifest = |layers: Vec<_>| -> ImageManifest {
+            ocidir::new_empty_manifest().layers(layers).build().unwrap()
+        };
+        let old = manifest(vec![desc('a', 10), desc('b', 20), desc('c', 30)]);
+        let new = manifest(vec![desc('a', 10), desc('d', 40), desc('c', 30)]);
+        let diff = manifest_diff(&old, &new);
+        assert_eq!(diff.added, vec![desc('d', 40)]);
+        assert_eq!(diff.removed, vec![desc('b', 20)]);
+        assert_eq!(diff.unchanged, vec![desc('a', 10), desc('c', 30)]);
+        assert_eq!(
+            (diff.added_size, diff.removed_size, diff.unchanged_size),
+            (40, 20, 40)
+        );
+
+        let diff = manifest_diff(&old, &old);
+        assert!(diff.added.is_empty() && diff.removed.is_empty());
+        assert_eq!(diff.unchanged.len(), 3);
+        let v = serde_json::to_value(&diff).unwrap();
+        assert_eq!(v["unchanged-size"], 60);
     }
 
     #[test]
@@ -323,4 +720,70 @@ mod tests {
                 .unwrap();
         assert_eq!(&ir_shorthand, &ir);
     }
+
+    #[test]
+    fn test_image_metadata() {
+        let manifest: ImageManifest = serde_json::from_value(serde_json::json!({
+            "schemaVersion": 2,
+            "config": {
+                "mediaType": "application/vnd.oci.image.config.v1+json",
+                "digest": "sha256:0000000000000000000000000000000000000000000000000000000000000000",
+                "size": 0
+            },
+            "layers": [],
+            "annotations": {
+                "ostree.commit": "annotated",
+                "org.opencontainers.image.version": "1.0",
+                "org.opencontainers.image.source": "https://example.com/src",
+                "org.opencontainers.image.revision": "abc",
+            }
+        }))
+        .unwrap();
+
+        // Without a config, everything comes from the manifest annotations.
+        let meta = ImageMetadata::new(&manifest, None);
+        assert_eq!(meta.ostree_commit(), Some("annotated"));
+        assert_eq!(meta.version(), Some("1.0"));
+        assert_eq!(meta.source_annotations().len(), 3);
+
+        let mut labels = HashMap::new();
+        set_ostree_commit(&mut labels, "labeled");
+        labels.insert(OSTREE_VERSION_LABEL.to_string(), "2.0".to_string());
+        labels.insert(
+            "org.opencontainers.image.revision".to_string(),
+            "def".to_string(),
+        );
+        let config: ImageConfiguration = serde_json::from_value(serde_json::json!({
+            "architecture": "amd64",
+            "os": "linux",
+            "rootfs": { "type": "layers", "diff_ids": [] },
+            "config": { "Labels": labels },
+        }))
+        .unwrap();
+
+        // Labels take precedence over annotations, and any version label
+        // over the version annotation.
+        let meta = ImageMetadata::new(&manifest, Some(&config));
+        assert_eq!(meta.ostree_commit(), Some("labeled"));
+        assert_eq!(meta.version(), Some("2.0"));
+        let sources = meta.source_annotations();
+        assert_eq!(sources.len(), 3);
+        assert_eq!(sources["org.opencontainers.image.revision"], "def");
+        assert_eq!(
+            sources["org.opencontainers.image.source"],
+            "https://example.com/src"
+        );
+
+        // The plain version label is preferred.
+        labels.insert(VERSION_LABEL.to_string(), "3.0".to_string());
+        let config: ImageConfiguration = serde_json::from_value(serde_json::json!({
+            "architecture": "amd64",
+            "os": "linux",
+            "rootfs": { "type": "layers", "diff_ids": [] },
+            "config": { "Labels": labels },
+        }))
+        .unwrap();
+        let meta = ImageMetadata::new(&manifest, Some(&config));
+        assert_eq!(meta.version(), Some("3.0"));
+    }
 }
@@ -9,7 +9,7 @@ use oci_image::MediaType;
 use oci_spec::image as oci_image;
 use openat_ext::*;
 use openssl::hash::{Hasher, MessageDigest};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::io::prelude::*;
 use std::path::Path;
 use std::rc::Rc;
@@ -17,6 +17,11 @@ use std::rc::Rc;
 /// Path inside an OCI directory to the blobs
 const BLOBDIR: &str = "blobs/sha256";
 
+/// The media type of Docker manifest lists, as written by e.g. skopeo.
+const DOCKER_MANIFEST_LIST: &str = "application/vnd.docker.distribution.manifest.list.v2+json";
+/// The media type of Docker (schema 2) manifests.
+const DOCKER_MANIFEST: &str = "application/vnd.docker.distribution.manifest.v2+json";
+
 /// Completed blob metadata
 #[derive(Debug)]
 pub(crate) struct Blob {
@@ -41,6 +46,10 @@ impl Blob {
 pub(crate) struct Layer {
     pub(crate) blob: Blob,
     pub(crate) uncompressed_sha256: String,
+    /// The media type, which depends on the compression.
+    pub(crate) media_type: MediaType,
+    /// Annotations required by the compression format.
+    pub(crate) annotations: HashMap<String, String>,
 }
 
 impl Layer {
@@ -49,6 +58,34 @@ impl Layer {
     }
 }
 
+/// The compression format and level for a layer.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Compression {
+    Gzip(flate2::Compression),
+    /// zstd with the given level.
+    Zstd(i32),
+    /// zstd with the given level, in the `zstd:chunked` format.
+    ZstdChunked(i32),
+}
+
+impl Compression {
+    /// The media type of layers compressed in this format.
+    pub(crate) fn media_type(&self) -> MediaType {
+        match self {
+            Compression::Gzip(_) => MediaType::ImageLayerGzip,
+            Compression::Zstd(_) | Compression::ZstdChunked(_) => MediaType::ImageLayerZstd,
+        }
+    }
+}
+
+enum Compressor {
+    Gzip(GzEncoder<Vec<u8>>),
+    Zstd(zstd::stream::write::Encoder<'static, Vec<u8>>),
+    /// The uncompressed stream is spooled to a temporary file, as the
+    /// whole stream is needed to generate its table of contents.
+    ZstdChunked(std::fs::File, i32),
+}
+
 /// Create an OCI blob.
 pub(crate) struct BlobWriter<'a> {
     pub(crate) hash: Hasher,
@@ -60,13 +97,73 @@ pub(crate) struct BlobWriter<'a> {
 pub(crate) struct RawLayerWriter<'a> {
     bw: BlobWriter<'a>,
     uncompressed_hash: Hasher,
-    compressor: GzEncoder<Vec<u8>>,
+    uncompressed_size: u64,
+    compressor: Compressor,
 }
 
 pub(crate) struct OciDir {
     pub(crate) dir: Rc<openat::Dir>,
 }
 
+/// A problem with a blob found by [`OciDir::fsck`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum BlobError {
+    /// The blob does not exist.
+    Missing,
+    /// The blob has a different size than its descriptor.
+    SizeMismatch {
+        /// The size from the descriptor.
+        expected: u64,
+        /// The size of the blob.
+        found: u64,
+    },
+    /// The content of the blob does not match its digest.
+    DigestMismatch {
+        /// The digest of the blob content.
+        found: String,
+    },
+    /// The digest uses an algorithm other than `sha256`, so it can't be verified.
+    UnsupportedAlgorithm,
+}
+
+impl std::fmt::Display for BlobError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BlobError::Missing => write!(f, "missing"),
+            BlobError::SizeMismatch { expected, found } => {
+                write!(f, "expected size {}, found {}", expected, found)
+            }
+            BlobError::DigestMismatch { found } => write!(f, "content has digest {}", found),
+            BlobError::UnsupportedAlgorithm => write!(f, "unsupported digest algorithm"),
+        }
+    }
+}
+
+/// The result of [`OciDir::fsck`].
+#[derive(Debug, Default)]
+pub(crate) struct FsckReport {
+    /// The number of distinct blobs which were checked.
+    pub(crate) checked_blobs: u64,
+    /// The blobs with problems, by digest.
+    pub(crate) errors: Vec<(String, BlobError)>,
+}
+
+impl FsckReport {
+    /// Whether no problems were found.
+    pub(crate) fn is_ok(&self) -> bool {
+        self.errors.is_empty()
+    }
+}
+
+/// Statistics from [`OciDir::prune`].
+#[derive(Debug, Default, PartialEq, Eq)]
+pub(crate) struct PruneStats {
+    /// The number of blobs which were removed.
+    pub(crate) removed_blobs: u64,
+    /// The total size of the removed blobs, in bytes.
+    pub(crate) freed_bytes: u64,
+}
+
 /// Write a serializable data (JSON) as an OCI blob
 #[context("Writing json blob")]
 pub(crate) fn write_json_blob<S: serde::Serialize>(
@@ -118,6 +215,14 @@ pub(crate) fn new_empty_manifest() -> oci_image::ImageManifestBuilder {
         .layers(Vec::new())
 }
 
+fn new_index(manifests: Vec<oci_image::Descriptor>) -> oci_image::ImageIndex {
+    oci_image::ImageIndexBuilder::default()
+        .schema_version(oci_image::SCHEMA_VERSION)
+        .manifests(manifests)
+        .build()
+        .unwrap()
+}
+
 impl OciDir {
     /// Create a new, empty OCI directory at the target path, which should be empty.
     pub(crate) fn create(dir: impl Into<Rc<openat::Dir>>) -> Result<Self> {
@@ -145,15 +250,25 @@ impl OciDir {
         Ok(Self { dir: dir.into() })
     }
 
-    /// Create a writer for a new blob (expected to be a tar stream)
+    /// Create a writer for a new blob; its digest and size are computed as it is written.
+    pub(crate) fn create_blob(&self) -> Result<BlobWriter> {
+        BlobWriter::new(&self.dir)
+    }
+
+    /// Create a writer for a new gzip compressed blob (expected to be a tar stream)
     pub(crate) fn create_raw_layer(
         &self,
         c: Option<flate2::Compression>,
     ) -> Result<RawLayerWriter> {
+        self.create_raw_layer_compressed(Compression::Gzip(c.unwrap_or_default()))
+    }
+
+    /// Create a writer for a new blob (expected to be a tar stream) with the given compression.
+    pub(crate) fn create_raw_layer_compressed(&self, c: Compression) -> Result<RawLayerWriter> {
         RawLayerWriter::new(&self.dir, c)
     }
 
-    /// Create a tar output stream, backed by a blob
+    /// Create a tar output stream, backed by a gzip compressed blob
     pub(crate) fn create_layer(
         &self,
         c: Option<flate2::Compression>,
@@ -161,6 +276,14 @@ impl OciDir {
         Ok(tar::Builder::new(self.create_raw_layer(c)?))
     }
 
+    /// Create a tar output stream, backed by a blob with the given compression
+    pub(crate) fn create_layer_compressed(
+        &self,
+        c: Compression,
+    ) -> Result<tar::Builder<RawLayerWriter>> {
+        Ok(tar::Builder::new(self.create_raw_layer_compressed(c)?))
+    }
+
     /// Add a layer to the top of the image stack.  The firsh pushed layer becomes the root.
 
     pub(crate) fn push_layer(
@@ -185,16 +308,32 @@ impl OciDir {
         annotations: Option<impl Into<HashMap<String, String>>>,
         description: &str,
     ) {
-        let mut builder = layer.descriptor().media_type(MediaType::ImageLayerGzip);
-        if let Some(annotations) = annotations {
+        let mut builder = layer.descriptor().media_type(layer.media_type.clone());
+        let mut annotations: HashMap<String, String> =
+            annotations.map(Into::into).unwrap_or_default();
+        annotations.extend(layer.annotations);
+        if !annotations.is_empty() {
             builder = builder.annotations(annotations);
         }
         let blobdesc = builder.build().unwrap();
+        let diffid = format!("sha256:{}", layer.uncompressed_sha256);
+        self.push_existing_layer(manifest, config, blobdesc, &diffid, description);
+    }
+
+    /// Add a layer with the given descriptor and uncompressed digest to the top
+    /// of the image stack, without writing its blob.  This is used to reference
+    /// layers which are already present at the destination.
+    pub(crate) fn push_existing_layer(
+        &self,
+        manifest: &mut oci_image::ImageManifest,
+        config: &mut oci_image::ImageConfiguration,
+        blobdesc: oci_image::Descriptor,
+        diffid: &str,
+        description: &str,
+    ) {
         manifest.layers_mut().push(blobdesc);
         let mut rootfs = config.rootfs().clone();
-        rootfs
-            .diff_ids_mut()
-            .push(format!("sha256:{}", layer.uncompressed_sha256));
+        rootfs.diff_ids_mut().push(diffid.to_string());
         config.set_rootfs(rootfs);
         let now = chrono::offset::Utc::now();
         let h = oci_image::HistoryBuilder::default()
@@ -238,16 +377,39 @@ impl OciDir {
         manifest: oci_image::ImageManifest,
         platform: oci_image::Platform,
     ) -> Result<()> {
-        let manifest = write_json_blob(&self.dir, &manifest, MediaType::ImageManifest)?
-            .platform(platform)
-            .build()
-            .unwrap();
+        let manifest = self.write_manifest_blob(manifest, platform)?;
+        self.write_index(vec![manifest])
+    }
+
+    /// Write a manifest as a blob, returning a descriptor for it.
+    pub(crate) fn write_manifest_blob(
+        &self,
+        manifest: oci_image::ImageManifest,
+        platform: oci_image::Platform,
+    ) -> Result<oci_image::Descriptor> {
+        Ok(
+            write_json_blob(&self.dir, &manifest, MediaType::ImageManifest)?
+                .platform(platform)
+                .build()
+                .unwrap(),
+        )
+    }
 
-        let index_data = oci_image::ImageIndexBuilder::default()
-            .schema_version(oci_image::SCHEMA_VERSION)
-            .manifests(vec![manifest])
+    /// Write an image index referring to the given manifests (e.g. one per
+    /// architecture) as a blob, returning a descriptor for it.
+    pub(crate) fn write_index_blob(
+        &self,
+        manifests: Vec<oci_image::Descriptor>,
+    ) -> Result<oci_image::Descriptor> {
+        let index = new_index(manifests);
+        Ok(write_json_blob(&self.dir, &index, MediaType::ImageIndex)?
             .build()
-            .unwrap();
+            .unwrap())
+    }
+
+    /// Replace the index with references to the given descriptors.
+    pub(crate) fn write_index(&self, manifests: Vec<oci_image::Descriptor>) -> Result<()> {
+        let index_data = new_index(manifests);
         self.dir
             .write_file_with("index.json", 0o644, |w| -> Result<()> {
                 cjson::to_writer(w, &index_data).map_err(|e| anyhow::anyhow!("{:?}", e))?;
@@ -256,9 +418,14 @@ impl OciDir {
         Ok(())
     }
 
+    /// Read the toplevel index.
+    pub(crate) fn read_index(&self) -> Result<oci_image::ImageIndex> {
+        deserialize_json_path(&self.dir, "index.json")
+    }
+
     /// If this OCI directory has a single manifest, return it.  Otherwise, an error is returned.
     pub(crate) fn read_manifest(&self) -> Result<oci_image::ImageManifest> {
-        let idx: oci_image::ImageIndex = deserialize_json_path(&self.dir, "index.json")?;
+        let idx = self.read_index()?;
         let desc = match idx.manifests().as_slice() {
             [] => anyhow::bail!("No manifests found"),
             [desc] => desc,
@@ -266,6 +433,351 @@ impl OciDir {
         };
         self.read_json_blob(desc)
     }
+
+    /// Write a manifest as a blob, and add a reference to it to the index with the
+    /// given tag; see [`Self::insert_tagged`].
+    pub(crate) fn insert_manifest(
+        &self,
+        manifest: oci_image::ImageManifest,
+        tag: Option<&str>,
+        platform: oci_image::Platform,
+    ) -> Result<oci_image::Descriptor> {
+        let desc = self.write_manifest_blob(manifest, platform)?;
+        self.insert_tagged(desc, tag)
+    }
+
+    /// Add a reference to a manifest or a (nested) image index to the index, annotated
+    /// with the given tag; an existing entry with the same tag is replaced, while the
+    /// other entries are kept.  Returns the added descriptor.
+    #[context("Adding {} to index", tag.unwrap_or("untagged manifest"))]
+    pub(crate) fn insert_tagged(
+        &self,
+        mut desc: oci_image::Descriptor,
+        tag: Option<&str>,
+    ) -> Result<oci_image::Descriptor> {
+        let mut manifests = if self.dir.exists("index.json")? {
+            self.read_index()?.manifests().clone()
+        } else {
+            Vec::new()
+        };
+        if let Some(tag) = tag {
+            manifests.retain(|m| descriptor_tag(m) != Some(tag));
+            let mut annotations = desc.annotations().clone().unwrap_or_default();
+            annotations.insert(super::OCI_REF_NAME_ANNOTATION.to_string(), tag.to_string());
+            desc.set_annotations(Some(annotations));
+        }
+        manifests.push(desc.clone());
+        self.write_index(manifests)?;
+        Ok(desc)
+    }
+
+    /// The tags of the entries of the index, in order.
+    pub(crate) fn tags(&self) -> Result<Vec<String>> {
+        Ok(self
+            .read_index()?
+            .manifests()
+            .iter()
+            .filter_map(descriptor_tag)
+            .map(ToOwned::to_owned)
+            .collect())
+    }
+
+    /// Read the manifest with the given tag, if any; for a nested image index, the
+    /// manifest for the host platform is returned.
+    pub(crate) fn read_manifest_by_tag(
+        &self,
+        tag: &str,
+    ) -> Result<Option<oci_image::ImageManifest>> {
+        let platform = oci_image::PlatformBuilder::default()
+            .architecture(super::encapsulate::oci_arch(std::env::consts::ARCH))
+            .os(oci_image::Os::Linux)
+            .build()
+            .unwrap();
+        self.read_manifest_by_tag_for_platform(tag, &platform)
+    }
+
+    /// Read the manifest with the given tag, if any; for a nested image index, the
+    /// manifest for the given platform (architecture and OS) is returned.
+    #[context("Reading manifest {}", tag)]
+    pub(crate) fn read_manifest_by_tag_for_platform(
+        &self,
+        tag: &str,
+        platform: &oci_image::Platform,
+    ) -> Result<Option<oci_image::ImageManifest>> {
+        let idx = self.read_index()?;
+        let desc = match idx
+            .manifests()
+            .iter()
+            .find(|m| descriptor_tag(m) == Some(tag))
+        {
+            Some(d) => d,
+            None => return Ok(None),
+        };
+        if desc.media_type() != &MediaType::ImageIndex {
+            return Ok(Some(self.read_json_blob(desc)?));
+        }
+        let nested: oci_image::ImageIndex = self.read_json_blob(desc)?;
+        let desc = nested
+            .manifests()
+            .iter()
+            .find(|m| {
+                m.platform().as_ref().map_or(false, |p| {
+                    p.architecture() == platform.architecture() && p.os() == platform.os()
+                })
+            })
+            .ok_or_else(|| {
+                anyhow!(
+                    "No manifest for platform {:?}/{:?}",
+                    platform.os(),
+                    platform.architecture()
+                )
+            })?;
+        Ok(Some(self.read_json_blob(desc)?))
+    }
+
+    /// Visit the descriptors of all blobs reachable from the index, once per digest:
+    /// manifests, nested indexes, configurations and layers.  Manifests and indexes are
+    /// only descended into if `f` returns `true`.  Returns the visited digests.
+    fn walk_blobs(
+        &self,
+        mut f: impl FnMut(&oci_image::Descriptor) -> Result<bool>,
+    ) -> Result<HashSet<String>> {
+        let mut r = HashSet::new();
+        let mut pending = self.read_index()?.manifests().clone();
+        pending.reverse();
+        while let Some(desc) = pending.pop() {
+            if !r.insert(desc.digest().to_string()) || !f(&desc)? {
+                continue;
+            }
+            if is_index(&desc) {
+                let index: oci_image::ImageIndex = self.read_json_blob(&desc)?;
+                pending.extend(index.manifests().iter().cloned());
+            } else if is_manifest(&desc) {
+                let manifest: oci_image::ImageManifest = self.read_json_blob(&desc)?;
+                pending.extend(manifest.layers().iter().rev().cloned());
+                pending.push(manifest.config().clone());
+            }
+        }
+        Ok(r)
+    }
+
+    /// Check a blob against the size and digest of its descriptor.
+    fn verify_blob(&self, desc: &oci_image::Descriptor) -> Result<Option<BlobError>> {
+        let hash = match desc.digest().split_once(':') {
+            Some(("sha256", hash)) => parse_one_filename(hash)?,
+            _ => return Ok(Some(BlobError::UnsupportedAlgorithm)),
+        };
+        let mut f = match self
+            .dir
+            .open_file_optional(&Path::new(BLOBDIR).join(hash))?
+        {
+            Some(f) => std::io::BufReader::new(f),
+            None => return Ok(Some(BlobError::Missing)),
+        };
+        let mut hasher = Hasher::new(MessageDigest::sha256())?;
+        let size = std::io::copy(&mut f, &mut hasher)?;
+        let expected = desc.size() as u64;
+        if size != expected {
+            return Ok(Some(BlobError::SizeMismatch {
+                expected,
+                found: size,
+            }));
+        }
+        let found = hex::encode(hasher.finish()?);
+        if found != hash {
+            return Ok(Some(BlobError::DigestMismatch {
+                found: format!("sha256:{}", found),
+            }));
+        }
+        Ok(None)
+    }
+
+    /// Verify the size and digest of all blobs reachable from the index; see
+    /// [`Self::prune`].  Manifests and indexes with problems are not descended into.
+    #[context("Checking OCI directory")]
+    pub(crate) fn fsck(&self) -> Result<FsckReport> {
+        let mut report = FsckReport::default();
+        self.walk_blobs(|desc| {
+            report.checked_blobs += 1;
+            let err = self.verify_blob(desc)?;
+            let ok = err.is_none();
+            report
+                .errors
+                .extend(err.map(|e| (desc.digest().to_string(), e)));
+            Ok(ok)
+        })?;
+        Ok(report)
+    }
+
+    /// The digests of all blobs reachable from the index.
+    fn referenced_blobs(&self) -> Result<HashSet<String>> {
+        self.walk_blobs(|_| Ok(true))
+    }
+
+    /// Remove the blobs which are not reachable from the index, e.g. the layers of
+    /// images which were replaced.  Only `sha256` blobs are considered; blobs using other
+    /// digest algorithms and unexpected files are left alone.
+    #[context("Pruning OCI directory")]
+    pub(crate) fn prune(&self) -> Result<PruneStats> {
+        let referenced = self.referenced_blobs()?;
+        let mut stats = PruneStats::default();
+        for ent in self.dir.list_dir(BLOBDIR)? {
+            let ent = ent?;
+            let name = match ent.file_name().to_str() {
+                Some(n) => n,
+                None => continue,
+            };
+            let is_digest = name.len() == 64
+                && name
+                    .bytes()
+                    .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b));
+            if !is_digest || referenced.contains(&format!("sha256:{}", name)) {
+                continue;
+            }
+            let path = Path::new(BLOBDIR).join(name);
+            let meta = self.dir.metadata(&path)?;
+            if !meta.is_file() {
+                continue;
+            }
+            tracing::debug!("Removing unreferenced blob {}", name);
+            self.dir.remove_file(&path)?;
+            stats.removed_blobs += 1;
+            stats.freed_bytes += meta.len();
+        }
+        Ok(stats)
+    }
+
+    /// Write this directory as an `oci-archive`, i.e. a tar stream of its `oci-layout`,
+    /// `index.json` and all blobs, which are streamed from disk.  The output only
+    /// depends on the content.
+    #[context("Packing oci-archive")]
+    pub(crate) fn pack_archive(&self, w: impl Write) -> Result<()> {
+        let mut archive = tar::Builder::new(w);
+        for name in ["oci-layout", "index.json"] {
+            self.append_to_archive(&mut archive, Path::new(name))?;
+        }
+        let blobs = Path::new("blobs");
+        let mut h = archive_header(tar::EntryType::Directory, 0, 0o755);
+        archive.append_data(&mut h, blobs, std::io::empty())?;
+        for alg in sorted_entries(&self.dir, blobs)? {
+            let algdir = blobs.join(alg);
+            if !self.dir.metadata(&algdir)?.is_dir() {
+                continue;
+            }
+            let mut h = archive_header(tar::EntryType::Directory, 0, 0o755);
+            archive.append_data(&mut h, &algdir, std::io::empty())?;
+            for blob in sorted_entries(&self.dir, &algdir)? {
+                self.append_to_archive(&mut archive, &algdir.join(blob))?;
+            }
+        }
+        archive.into_inner()?.flush()?;
+        Ok(())
+    }
+
+    /// Append a regular file to an archive.
+    fn append_to_archive<W: Write>(
+        &self,
+        archive: &mut tar::Builder<W>,
+        path: &Path,
+    ) -> Result<()> {
+        let f = self.dir.open_file(path)?;
+        let mut h = archive_header(tar::EntryType::Regular, f.metadata()?.len(), 0o644);
+        archive.append_data(&mut h, path, std::io::BufReader::new(f))?;
+        Ok(())
+    }
+
+    /// Unpack an `oci-archive` (see [`Self::pack_archive`]) into the given directory,
+    /// which should be empty; entries are streamed to disk.
+    #[context("Unpacking oci-archive")]
+    pub(crate) fn unpack_archive(r: impl Read, dir: impl Into<Rc<openat::Dir>>) -> Result<Self> {
+        let dir = dir.into();
+        let mut archive = tar::Archive::new(r);
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            let path = archive_entry_path(&entry.path()?)?;
+            if path.as_os_str().is_empty() {
+                continue;
+            }
+            match entry.header().entry_type() {
+                tar::EntryType::Directory => dir.ensure_dir_all(&path, 0o755)?,
+                tar::EntryType::Regular => {
+                    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+                        dir.ensure_dir_all(parent, 0o755)?;
+                    }
+                    let mut w = dir.new_file_writer(0o644)?;
+                    std::io::copy(&mut entry, &mut w.writer)?;
+                    w.complete(&path)?;
+                }
+                o => anyhow::bail!("Unsupported entry type {:?}: {}", o, path.display()),
+            }
+        }
+        if !dir.exists("oci-layout")? {
+            anyhow::bail!("Missing oci-layout");
+        }
+        Self::open(dir)
+    }
+}
+
+/// A header for an entry of an oci-archive; the ownership and timestamp are omitted.
+fn archive_header(entry_type: tar::EntryType, size: u64, mode: u32) -> tar::Header {
+    let mut h = tar::Header::new_gnu();
+    h.set_entry_type(entry_type);
+    h.set_size(size);
+    h.set_mode(mode);
+    h.set_uid(0);
+    h.set_gid(0);
+    h.set_mtime(0);
+    h
+}
+
+/// The path of an oci-archive entry, which must stay within the archive.
+fn archive_entry_path(p: &Path) -> Result<std::path::PathBuf> {
+    use std::path::Component;
+    p.components()
+        .try_fold(std::path::PathBuf::new(), |mut r, c| match c {
+            Component::Normal(name) => {
+                r.push(name);
+                Ok(r)
+            }
+            Component::CurDir => Ok(r),
+            _ => Err(anyhow!("Invalid path in oci-archive: {}", p.display())),
+        })
+}
+
+/// The names of the entries of a directory, sorted for reproducible output.
+fn sorted_entries(dir: &openat::Dir, path: &Path) -> Result<Vec<std::ffi::OsString>> {
+    let mut r = dir
+        .list_dir(path)?
+        .map(|e| Ok(e?.file_name().to_owned()))
+        .collect::<Result<Vec<_>>>()?;
+    r.sort();
+    Ok(r)
+}
+
+/// Whether a descriptor refers to an image index (or Docker manifest list).
+fn is_index(desc: &oci_image::Descriptor) -> bool {
+    match desc.media_type() {
+        MediaType::ImageIndex => true,
+        MediaType::Other(t) => t == DOCKER_MANIFEST_LIST,
+        _ => false,
+    }
+}
+
+/// Whether a descriptor refers to an image manifest (or Docker manifest).
+fn is_manifest(desc: &oci_image::Descriptor) -> bool {
+    match desc.media_type() {
+        MediaType::ImageManifest => true,
+        MediaType::Other(t) => t == DOCKER_MANIFEST,
+        _ => false,
+    }
+}
+
+/// The tag of an entry of an image index, from its annotations.
+fn descriptor_tag(desc: &oci_image::Descriptor) -> Option<&str> {
+    desc.annotations()
+        .as_ref()?
+        .get(super::OCI_REF_NAME_ANNOTATION)
+        .map(|s| s.as_str())
 }
 
 impl<'a> BlobWriter<'a> {
@@ -279,6 +791,11 @@ impl<'a> BlobWriter<'a> {
         })
     }
 
+    /// The number of bytes written so far.
+    pub(crate) fn size(&self) -> u64 {
+        self.size
+    }
+
     #[context("Completing blob")]
     /// Finish writing this blob object.
     pub(crate) fn complete(mut self) -> Result<Blob> {
@@ -305,39 +822,120 @@ impl<'a> std::io::Write for BlobWriter<'a> {
     }
 }
 
+/// Writes go to a local file, and are performed synchronously.
+impl<'a> tokio::io::AsyncWrite for BlobWriter<'a> {
+    fn poll_write(
+        self: std::pin::Pin<&mut Self>,
+        _cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        std::task::Poll::Ready(self.get_mut().write(buf))
+    }
+
+    fn poll_flush(
+        self: std::pin::Pin<&mut Self>,
+        _cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        std::task::Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(
+        self: std::pin::Pin<&mut Self>,
+        _cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        std::task::Poll::Ready(Ok(()))
+    }
+}
+
 impl<'a> RawLayerWriter<'a> {
-    /// Create a writer for a gzip compressed layer blob.
-    fn new(ocidir: &'a openat::Dir, c: Option<flate2::Compression>) -> Result<Self> {
+    /// Create a writer for a compressed layer blob.
+    fn new(ocidir: &'a openat::Dir, c: Compression) -> Result<Self> {
         let bw = BlobWriter::new(ocidir)?;
+        let compressor = match c {
+            Compression::Gzip(c) => Compressor::Gzip(
+                // A fixed mtime keeps the output reproducible
+                flate2::GzBuilder::new()
+                    .mtime(0)
+                    .write(Vec::with_capacity(8192), c),
+            ),
+            Compression::Zstd(level) => Compressor::Zstd(zstd::stream::write::Encoder::new(
+                Vec::with_capacity(8192),
+                level,
+            )?),
+            Compression::ZstdChunked(level) => {
+                Compressor::ZstdChunked(tempfile::tempfile()?, level)
+            }
+        };
         Ok(Self {
             bw,
             uncompressed_hash: Hasher::new(MessageDigest::sha256())?,
-            compressor: GzEncoder::new(Vec::with_capacity(8192), c.unwrap_or_default()),
+            uncompressed_size: 0,
+            compressor,
         })
     }
 
+    /// The number of uncompressed bytes written so far.
+    pub(crate) fn uncompressed_size(&self) -> u64 {
+        self.uncompressed_size
+    }
+
+    /// The number of compressed bytes written to the blob so far; compressors buffer
+    /// data, so this lags behind.
+    pub(crate) fn compressed_size(&self) -> u64 {
+        self.bw.size()
+    }
+
     #[context("Completing layer")]
     /// Consume this writer, flushing buffered data and put the blob in place.
     pub(crate) fn complete(mut self) -> Result<Layer> {
-        self.compressor.get_mut().clear();
-        let buf = self.compressor.finish()?;
-        self.bw.write_all(&buf)?;
+        let (media_type, annotations) = match self.compressor {
+            Compressor::Gzip(mut c) => {
+                c.get_mut().clear();
+                let buf = c.finish()?;
+                self.bw.write_all(&buf)?;
+                (MediaType::ImageLayerGzip, HashMap::new())
+            }
+            Compressor::Zstd(mut c) => {
+                c.get_mut().clear();
+                let buf = c.finish()?;
+                self.bw.write_all(&buf)?;
+                (MediaType::ImageLayerZstd, HashMap::new())
+            }
+            Compressor::ZstdChunked(mut spool, level) => {
+                spool.seek(std::io::SeekFrom::Start(0))?;
+                let spool = std::io::BufReader::new(spool);
+                let annotations = super::zstd_chunked::compress(spool, &mut self.bw, level)?;
+                (MediaType::ImageLayerZstd, annotations)
+            }
+        };
         let blob = self.bw.complete()?;
         let uncompressed_sha256 = hex::encode(self.uncompressed_hash.finish()?);
         Ok(Layer {
             blob,
             uncompressed_sha256,
+            media_type,
+            annotations,
         })
     }
 }
 
 impl<'a> std::io::Write for RawLayerWriter<'a> {
     fn write(&mut self, srcbuf: &[u8]) -> std::io::Result<usize> {
-        self.compressor.get_mut().clear();
-        self.compressor.write_all(srcbuf).unwrap();
         self.uncompressed_hash.update(srcbuf)?;
-        let compressed_buf = self.compressor.get_mut().as_slice();
-        self.bw.write_all(compressed_buf)?;
+        self.uncompressed_size += srcbuf.len() as u64;
+        match &mut self.compressor {
+            Compressor::Gzip(c) => {
+                c.get_mut().clear();
+                c.write_all(srcbuf)?;
+                self.bw.write_all(c.get_mut().as_slice())?;
+            }
+            Compressor::Zstd(c) => {
+                c.get_mut().clear();
+                c.write_all(srcbuf)?;
+                self.bw.write_all(c.get_mut().as_slice())?;
+            }
+            Compressor::ZstdChunked(spool, _) => spool.write_all(srcbuf)?,
+        }
         Ok(srcbuf.len())
     }
 
@@ -408,4 +1006,282 @@ mod tests {
         w.write_manifest(manifest, oci_image::Platform::default())?;
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_create_blob() -> Result<()> {
+        use tokio::io::AsyncWriteExt;
+        let td = tempfile::tempdir()?;
+        let w = OciDir::create(openat::Dir::open(td.path())?)?;
+        let mut bw = w.create_blob()?;
+        bw.write_all(b"hello ").await?;
+        assert_eq!(bw.size(), 6);
+        bw.write_all(b"world").await?;
+        let blob = bw.complete()?;
+        assert_eq!(blob.size, 11);
+        assert_eq!(
+            blob.digest_id(),
+            "sha256:b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9"
+        );
+        let contents = std::fs::read(td.path().join(BLOBDIR).join(&blob.sha256))?;
+        assert_eq!(contents, b"hello world");
+
+        let mut layerw = w.create_raw_layer_compressed(Compression::Zstd(3))?;
+        layerw.write_all(b"pretend this is a tarball")?;
+        assert_eq!(layerw.uncompressed_size(), 25);
+        let layer = layerw.complete()?;
+        assert_eq!(
+            layer.uncompressed_sha256,
+            "349438e5faf763e8875b43de4d7101540ef4d865190336c2cc549a11f33f8d7c"
+        );
+        assert!(layer.blob.size > 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_build_index() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let td = openat::Dir::open(td.path())?;
+        let w = OciDir::create(td)?;
+        let manifests = ["amd64", "arm64"]
+            .iter()
+            .map(|&arch| {
+                let platform = oci_image::PlatformBuilder::default()
+                    .architecture(oci_image::Arch::from(arch))
+                    .os(oci_image::Os::Linux)
+                    .build()
+                    .unwrap();
+                w.write_manifest_blob(new_empty_manifest().build().unwrap(), platform)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let index = w.write_index_blob(manifests)?;
+        w.write_index(vec![index.clone()])?;
+        let toplevel = w.read_index()?;
+        assert_eq!(toplevel.manifests().as_slice(), &[index.clone()]);
+        let index: oci_image::ImageIndex = w.read_json_blob(&index)?;
+        let arches = index
+            .manifests()
+            .iter()
+            .map(|m| m.platform().as_ref().unwrap().architecture().clone())
+            .collect::<Vec<_>>();
+        assert_eq!(arches, &[oci_image::Arch::Amd64, oci_image::Arch::ARM64]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_tagged_manifests() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let td = openat::Dir::open(td.path())?;
+        let w = OciDir::create(td)?;
+        let manifest = |version: &str| {
+            let mut m = new_empty_manifest().build().unwrap();
+            let mut annotations = HashMap::new();
+            annotations.insert("version".to_string(), version.to_string());
+            m.set_annotations(Some(annotations));
+            m
+        };
+        let version =
+            |m: oci_image::ImageManifest| m.annotations().as_ref().unwrap()["version"].clone();
+        w.insert_manifest(manifest("1"), Some("stable"), Default::default())?;
+        // A single manifest can still be read as before.
+        assert_eq!(version(w.read_manifest()?), "1");
+        w.insert_manifest(manifest("2"), Some("testing"), Default::default())?;
+        assert_eq!(w.tags()?, &["stable", "testing"]);
+        assert!(w.read_manifest().is_err());
+        assert_eq!(version(w.read_manifest_by_tag("stable")?.unwrap()), "1");
+        assert_eq!(version(w.read_manifest_by_tag("testing")?.unwrap()), "2");
+        assert!(w.read_manifest_by_tag("nosuchtag")?.is_none());
+
+        // Replacing a tag keeps the others.
+        w.insert_manifest(manifest("3"), Some("stable"), Default::default())?;
+        assert_eq!(w.tags()?, &["testing", "stable"]);
+        assert_eq!(version(w.read_manifest_by_tag("stable")?.unwrap()), "3");
+        assert_eq!(version(w.read_manifest_by_tag("testing")?.unwrap()), "2");
+
+        // A nested index is resolved by platform.
+        let platform = |arch: &str| {
+            oci_image::PlatformBuilder::default()
+                .architecture(oci_image::Arch::from(arch))
+                .os(oci_image::Os::Linux)
+                .build()
+                .unwrap()
+        };
+        let manifests = ["amd64", "arm64"]
+            .iter()
+            .map(|&arch| w.write_manifest_blob(manifest(arch), platform(arch)))
+            .collect::<Result<Vec<_>>>()?;
+        let index = w.write_index_blob(manifests)?;
+        w.insert_tagged(index, Some("multiarch"))?;
+        assert_eq!(w.tags()?, &["testing", "stable", "multiarch"]);
+        for arch in ["amd64", "arm64"] {
+            let m = w.read_manifest_by_tag_for_platform("multiarch", &platform(arch))?;
+            assert_eq!(version(m.unwrap()), arch);
+        }
+        assert!(w
+            .read_manifest_by_tag_for_platform("multiarch", &platform("s390x"))
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_prune() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let tdpath = td.path();
+        let w = OciDir::create(openat::Dir::open(tdpath)?)?;
+        let build = |content: &[u8]| -> Result<oci_image::ImageManifest> {
+            let mut layerw = w.create_raw_layer(None)?;
+            layerw.write_all(content)?;
+            let layer = layerw.complete()?;
+            let mut manifest = new_empty_manifest().build().unwrap();
+            let mut config = oci_image::ImageConfigurationBuilder::default()
+                .build()
+                .unwrap();
+            w.push_layer(&mut manifest, &mut config, layer, "root");
+            manifest.set_config(w.write_config(config)?);
+            Ok(manifest)
+        };
+        let blob_path = |d: &oci_image::Descriptor| {
+            tdpath
+                .join(BLOBDIR)
+                .join(d.digest().strip_prefix("sha256:").unwrap())
+        };
+
+        let old = build(b"old layer")?;
+        let old_desc = w.insert_manifest(old.clone(), Some("latest"), Default::default())?;
+        // Nothing is unreferenced yet
+        assert_eq!(w.prune()?, PruneStats::default());
+
+        // Replacing the tag orphans the manifest, configuration and layer.
+        let new = build(b"new layer")?;
+        w.insert_manifest(new.clone(), Some("latest"), Default::default())?;
+        // Blobs of other algorithms and unknown files are left alone.
+        std::fs::create_dir_all(tdpath.join("blobs/sha512"))?;
+        std::fs::write(tdpath.join("blobs/sha512").join("a".repeat(128)), "x")?;
+        std::fs::write(tdpath.join(BLOBDIR).join("notadigest"), "x")?;
+
+        let orphaned = [&old_desc, old.config(), &old.layers()[0]];
+        let expected = PruneStats {
+            removed_blobs: orphaned.len() as u64,
+            freed_bytes: orphaned.iter().map(|d| d.size() as u64).sum(),
+        };
+        assert_eq!(w.prune()?, expected);
+        for d in orphaned {
+            assert!(!blob_path(d).exists());
+        }
+        for d in [new.config(), &new.layers()[0]] {
+            assert!(blob_path(d).exists());
+        }
+        assert!(tdpath.join("blobs/sha512").join("a".repeat(128)).exists());
+        assert!(tdpath.join(BLOBDIR).join("notadigest").exists());
+        assert_eq!(w.read_manifest_by_tag("latest")?.unwrap(), new);
+
+        // Blobs reachable via a nested index are retained.
+        let platform = oci_image::PlatformBuilder::default()
+            .architecture(oci_image::Arch::Amd64)
+            .os(oci_image::Os::Linux)
+            .build()
+            .unwrap();
+        let nested = build(b"nested layer")?;
+        let nested_desc = w.write_manifest_blob(nested.clone(), platform)?;
+        let index = w.write_index_blob(vec![nested_desc.clone()])?;
+        w.insert_tagged(index, Some("multiarch"))?;
+        assert_eq!(w.prune()?, PruneStats::default());
+        for d in [&nested_desc, nested.config(), &nested.layers()[0]] {
+            assert!(blob_path(d).exists());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_fsck() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let tdpath = td.path();
+        let w = OciDir::create(openat::Dir::open(tdpath)?)?;
+        let mut manifest = new_empty_manifest().build().unwrap();
+        let mut config = oci_image::ImageConfigurationBuilder::default()
+            .build()
+            .unwrap();
+        for content in [&b"first layer"[..], b"second layer"] {
+            let mut layerw = w.create_raw_layer(None)?;
+            layerw.write_all(content)?;
+            w.push_layer(&mut manifest, &mut config, layerw.complete()?, "layer");
+        }
+        manifest.set_config(w.write_config(config)?);
+        w.write_manifest(manifest.clone(), Default::default())?;
+        let report = w.fsck()?;
+        assert!(report.is_ok());
+        // The manifest, its configuration and two layers
+        assert_eq!(report.checked_blobs, 4);
+
+        let blob_path = |d: &oci_image::Descriptor| {
+            tdpath
+                .join(BLOBDIR)
+                .join(d.digest().strip_prefix("sha256:").unwrap())
+        };
+        let (first, second) = (&manifest.layers()[0], &manifest.layers()[1]);
+        // Truncate one layer, and corrupt the other one in place.
+        let orig = std::fs::read(blob_path(first))?;
+        std::fs::write(blob_path(first), &orig[..orig.len() - 1])?;
+        let mut corrupt = std::fs::read(blob_path(second))?;
+        let last = corrupt.last_mut().unwrap();
+        *last = !*last;
+        std::fs::write(blob_path(second), &corrupt)?;
+        std::fs::remove_file(blob_path(manifest.config()))?;
+        let report = w.fsck()?;
+        assert!(!report.is_ok());
+        assert_eq!(report.checked_blobs, 4);
+        let errors: HashMap<_, _> = report.errors.into_iter().collect();
+        assert_eq!(errors.len(), 3);
+        assert_eq!(
+            errors[first.digest()],
+            BlobError::SizeMismatch {
+                expected: orig.len() as u64,
+                found: orig.len() as u64 - 1
+            }
+        );
+        assert!(matches!(
+            errors[second.digest()],
+            BlobError::DigestMismatch { .. }
+        ));
+        assert_eq!(errors[manifest.config().digest()], BlobError::Missing);
+        Ok(())
+    }
+
+    #[test]
+    fn test_archive() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let w = OciDir::create(openat::Dir::open(td.path())?)?;
+        let mut manifest = new_empty_manifest().build().unwrap();
+        let mut config = oci_image::ImageConfigurationBuilder::default()
+            .build()
+            .unwrap();
+        let mut layerw = w.create_raw_layer(None)?;
+        layerw.write_all(b"pretend this is a tarball")?;
+        w.push_layer(&mut manifest, &mut config, layerw.complete()?, "root");
+        manifest.set_config(w.write_config(config)?);
+        w.insert_manifest(manifest.clone(), Some("latest"), Default::default())?;
+
+        let mut archive = Vec::new();
+        w.pack_archive(&mut archive)?;
+        let mut again = Vec::new();
+        w.pack_archive(&mut again)?;
+        assert_eq!(archive, again);
+
+        let unpacked = tempfile::tempdir()?;
+        let u = OciDir::unpack_archive(archive.as_slice(), openat::Dir::open(unpacked.path())?)?;
+        assert_eq!(u.read_index()?, w.read_index()?);
+        assert_eq!(u.read_manifest_by_tag("latest")?.unwrap(), manifest);
+        let report = u.fsck()?;
+        assert!(report.is_ok());
+        assert_eq!(report.checked_blobs, 3);
+
+        // Only files and directories are supported.
+        let mut b = tar::Builder::new(Vec::new());
+        let mut h = archive_header(tar::EntryType::Symlink, 0, 0o777);
+        h.set_link_name("/etc/passwd")?;
+        b.append_data(&mut h, "oci-layout", std::io::empty())?;
+        let invalid = b.into_inner()?;
+        let td = tempfile::tempdir()?;
+        assert!(OciDir::unpack_archive(invalid.as_slice(), openat::Dir::open(td.path())?).is_err());
+        Ok(())
+    }
 }
@@ -1,7 +1,11 @@
 //! Fork skopeo as a subprocess
 
-use anyhow::{Context, Result};
+use super::{ImageReference, Transport};
+use anyhow::{anyhow, Context, Result};
+use ostree::glib;
 use serde::Deserialize;
+use std::io::Write;
+use std::path::Path;
 use std::process::Stdio;
 use tokio::process::Command;
 
@@ -41,6 +45,172 @@ pub(crate) fn container_policy_is_default_insecure() -> Result<bool> {
     Ok(policy.is_default_insecure())
 }
 
+/// Verify that a Docker-formatted authentication file can be read.
+pub(crate) fn validate_authfile(path: &Path) -> Result<()> {
+    let ctx = || format!("Reading authfile {}", path.display());
+    let r = std::io::BufReader::new(std::fs::File::open(path).with_context(ctx)?);
+    let _: serde_json::Value = serde_json::from_reader(r).with_context(ctx)?;
+    Ok(())
+}
+
+/// An image in the `manifest.json` of a `docker save` archive.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+struct DockerArchiveImage {
+    #[serde(default)]
+    repo_tags: Option<Vec<String>>,
+}
+
+/// Normalize an image name for comparison, as containers/image does for names
+/// without a registry or tag.
+fn normalize_image_name(name: &str) -> String {
+    let name = name
+        .strip_prefix("docker.io/library/")
+        .or_else(|| name.strip_prefix("docker.io/"))
+        .unwrap_or(name);
+    let basename = name.rsplit('/').next().unwrap_or(name);
+    if basename.contains(':') || basename.contains('@') {
+        name.to_string()
+    } else {
+        format!("{}:latest", name)
+    }
+}
+
+/// Check that `selector` (a `name:tag` or `@index`) picks exactly one image of a
+/// docker archive.
+fn select_docker_archive_image(
+    path: &str,
+    selector: Option<&str>,
+    images: &[DockerArchiveImage],
+) -> Result<()> {
+    let tags = || {
+        let tags: Vec<&str> = images
+            .iter()
+            .flat_map(|i| i.repo_tags.iter().flatten())
+            .map(|s| s.as_str())
+            .collect();
+        if tags.is_empty() {
+            "none".to_string()
+        } else {
+            tags.join(", ")
+        }
+    };
+    match selector {
+        None if images.len() > 1 => Err(anyhow!(
+            "docker-archive {} contains {} images; specify one as docker-archive:{}:<name:tag> or docker-archive:{}:@<index> (tags: {})",
+            path,
+            images.len(),
+            path,
+            path,
+            tags()
+        )),
+        None => Ok(()),
+        Some(selector) => {
+            if let Some(index) = selector.strip_prefix('@') {
+                let index: usize = index
+                    .parse()
+                    .with_context(|| format!("Invalid image index in {}", selector))?;
+                if index >= images.len() {
+                    return Err(anyhow!(
+                        "docker-archive {} contains {} images; index {} is out of range",
+                        path,
+                        images.len(),
+                        index
+                    ));
+                }
+                return Ok(());
+            }
+            let selector = normalize_image_name(selector);
+            let found = images
+                .iter()
+                .flat_map(|i| i.repo_tags.iter().flatten())
+                .any(|t| normalize_image_name(t) == selector);
+            if !found {
+                return Err(anyhow!(
+                    "Image {} not found in docker-archive {} (tags: {})",
+                    selector,
+                    path,
+                    tags()
+                ));
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Check that an archive reference (`oci-archive:` or `docker-archive:`) can be
+/// fetched from; in particular, a docker archive with multiple images requires
+/// a selector.  Other references are accepted.
+pub(crate) fn validate_archive_ref(imgref: &ImageReference) -> Result<()> {
+    let (path, selector) = match imgref.name.split_once(':') {
+        Some((path, selector)) => (path, Some(selector)),
+        None => (imgref.name.as_str(), None),
+    };
+    match imgref.transport {
+        Transport::OciArchive => {
+            if !Path::new(path).is_file() {
+                return Err(anyhow!("oci-archive not found: {}", path));
+            }
+            Ok(())
+        }
+        Transport::DockerArchive => {
+            let f = std::fs::File::open(path)
+                .with_context(|| format!("Opening docker-archive {}", path))?;
+            let mut archive = tar::Archive::new(f);
+            let mut images = None;
+            for entry in archive.entries_with_seek()? {
+                let entry = entry?;
+                if entry.path()?.as_ref() == Path::new("manifest.json") {
+                    let r = std::io::BufReader::new(entry);
+                    let v: Vec<DockerArchiveImage> = serde_json::from_reader(r)
+                        .with_context(|| format!("Parsing manifest.json in {}", path))?;
+                    images = Some(v);
+                    break;
+                }
+            }
+            let images = images
+                .ok_or_else(|| anyhow!("Missing manifest.json in docker-archive {}", path))?;
+            select_docker_archive_image(path, selector, &images)
+        }
+        _ => Ok(()),
+    }
+}
+
+/// The registry hostname of an image name, following the same rules as
+/// containers/image for names without one.
+fn registry_of(name: &str) -> &str {
+    match name.split_once('/') {
+        Some((first, _)) if first.contains(&['.', ':'][..]) || first == "localhost" => first,
+        _ => "docker.io",
+    }
+}
+
+/// Write a temporary Docker-formatted authentication file holding the given
+/// username and password for the registry of `imgref`.
+pub(crate) fn authfile_for_credentials(
+    imgref: &ImageReference,
+    (username, password): &(String, String),
+) -> Result<tempfile::NamedTempFile> {
+    if imgref.transport != Transport::Registry {
+        return Err(anyhow!(
+            "Credentials are only supported for registry images, not {}",
+            imgref
+        ));
+    }
+    let auth = glib::base64_encode(format!("{}:{}", username, password).as_bytes());
+    let v = serde_json::json!({
+        "auths": {
+            registry_of(&imgref.name): {
+                "auth": auth.as_str(),
+            }
+        }
+    });
+    let mut f = tempfile::NamedTempFile::new()?;
+    serde_json::to_writer(&mut f, &v)?;
+    f.flush()?;
+    Ok(f)
+}
+
 /// Create a Command builder for skopeo.
 pub(crate) fn new_cmd() -> tokio::process::Command {
     let mut cmd = Command::new("skopeo");
@@ -59,6 +229,66 @@ pub(crate) fn spawn(mut cmd: Command) -> Result<tokio::process::Child> {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_registry_of() {
+        for (name, expected) in [
+            ("quay.io/exampleos/blah:latest", "quay.io"),
+            ("localhost:5000/exampleos", "localhost:5000"),
+            ("localhost/exampleos", "localhost"),
+            ("library/busybox", "docker.io"),
+            ("busybox", "docker.io"),
+        ] {
+            assert_eq!(registry_of(name), expected);
+        }
+    }
+
+    #[test]
+    fn test_select_docker_archive_image() {
+        let image = |tags: &[&str]| DockerArchiveImage {
+            repo_tags: Some(tags.iter().map(|s| s.to_string()).collect()),
+        };
+        let single = [image(&["localhost/exampleos:latest"])];
+        select_docker_archive_image("a.tar", None, &single).unwrap();
+        select_docker_archive_image("a.tar", Some("localhost/exampleos"), &single).unwrap();
+        select_docker_archive_image("a.tar", Some("@0"), &single).unwrap();
+        assert!(select_docker_archive_image("a.tar", Some("@1"), &single).is_err());
+        assert!(select_docker_archive_image("a.tar", Some("@x"), &single).is_err());
+
+        let multi = [image(&["busybox:latest"]), image(&["quay.io/exampleos:v1"])];
+        let e = select_docker_archive_image("a.tar", None, &multi).unwrap_err();
+        let e = e.to_string();
+        assert!(e.contains("contains 2 images"), "{}", e);
+        assert!(e.contains("busybox:latest, quay.io/exampleos:v1"), "{}", e);
+        select_docker_archive_image("a.tar", Some("docker.io/library/busybox"), &multi).unwrap();
+        select_docker_archive_image("a.tar", Some("quay.io/exampleos:v1"), &multi).unwrap();
+        select_docker_archive_image("a.tar", Some("@1"), &multi).unwrap();
+        let e = select_docker_archive_image("a.tar", Some("quay.io/exampleos:v2"), &multi)
+            .unwrap_err()
+            .to_string();
+        assert!(e.contains("not found"), "{}", e);
+    }
+
+    #[test]
+    fn test_authfile_for_credentials() {
+        let creds = ("someuser".to_string(), "somepass".to_string());
+        let imgref = ImageReference {
+            transport: Transport::Registry,
+            name: "quay.io/exampleos/blah".to_string(),
+        };
+        let f = authfile_for_credentials(&imgref, &creds).unwrap();
+        validate_authfile(f.path()).unwrap();
+        let v: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(f.path()).unwrap()).unwrap();
+        let auth = v["auths"]["quay.io"]["auth"].as_str().unwrap();
+        assert_eq!(glib::base64_decode(auth), b"someuser:somepass");
+
+        let imgref = ImageReference {
+            transport: Transport::OciDir,
+            name: "/var/tmp/blah".to_string(),
+        };
+        assert!(authfile_for_credentials(&imgref, &creds).is_err());
+    }
+
     // Default value as of the Fedora 34 containers-common-1-21.fc34.noarch package.
     const DEFAULT_POLICY: &str = indoc::indoc! {r#"
     {
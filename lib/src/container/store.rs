@@ -8,13 +8,17 @@
 use super::*;
 use crate::refescape;
 use anyhow::{anyhow, Context};
+use cap_std_ext::rustix;
 use containers_image_proxy::{ImageProxy, OpenedImage};
 use fn_error_context::context;
 use oci_spec::image::{self as oci_image, Descriptor, History, ImageConfiguration, ImageManifest};
 use ostree::prelude::{Cast, ToVariant};
+use ostree::prelude::{FileEnumeratorExt, FileExt};
 use ostree::{gio, glib};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::iter::FromIterator;
+use std::path::Path;
+use std::rc::Rc;
 use std::sync::{Arc, Mutex};
 
 /// Configuration for the proxy.
@@ -23,10 +27,247 @@ use std::sync::{Arc, Mutex};
 /// in the interest of avoiding duplication.
 pub use containers_image_proxy::ImageProxyConfig;
 
-/// The ostree ref prefix for blobs.
-const LAYER_PREFIX: &str = "ostree/container/blob";
-/// The ostree ref prefix for image references.
-const IMAGE_PREFIX: &str = "ostree/container/image";
+/// Network settings for fetching images from registries.
+#[derive(Debug, Clone, Default)]
+pub struct NetworkConfig {
+    /// Proxy for HTTP requests, e.g. `http://proxy.example.com:3128`.
+    pub http_proxy: Option<String>,
+    /// Proxy for HTTPS requests.
+    pub https_proxy: Option<String>,
+    /// Comma-separated hosts which are not proxied.
+    pub no_proxy: Option<String>,
+    /// A file with the PEM-encoded CA certificates to trust, instead of the system ones.
+    pub ca_bundle: Option<std::path::PathBuf>,
+    /// The maximum time for each request to the image proxy, e.g. fetching the manifest
+    /// or starting to fetch a layer; streaming the layer content is not limited.
+    pub timeout: Option<std::time::Duration>,
+    /// Disable TLS certificate verification.  NOT RECOMMENDED.
+    pub insecure_skip_tls_verification: bool,
+}
+
+/// Check that a proxy URL is of the form `scheme://host[:port][/]`.
+fn validate_proxy_url(url: &str) -> Result<()> {
+    let err = || anyhow!("Invalid proxy URL: {}", url);
+    let (scheme, rest) = url.split_once("://").ok_or_else(err)?;
+    if !matches!(scheme, "http" | "https" | "socks5") {
+        return Err(anyhow!("Unsupported proxy URL scheme: {}", url));
+    }
+    // Credentials may precede the host
+    let rest = rest.rsplit_once('@').map(|(_, r)| r).unwrap_or(rest);
+    let hostport = rest.strip_suffix('/').unwrap_or(rest);
+    if hostport.contains('/') {
+        return Err(err());
+    }
+    // IPv6 addresses are bracketed, e.g. `[::1]:3128`
+    let (host, port) = if let Some(rest) = hostport.strip_prefix('[') {
+        let (host, rest) = rest.split_once(']').ok_or_else(err)?;
+        match rest {
+            "" => (host, None),
+            _ => (host, Some(rest.strip_prefix(':').ok_or_else(err)?)),
+        }
+    } else {
+        match hostport.split_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (hostport, None),
+        }
+    };
+    if let Some(port) = port {
+        port.parse::<u16>().map_err(|_| err())?;
+    }
+    if host.is_empty() || host.contains(char::is_whitespace) {
+        return Err(err());
+    }
+    Ok(())
+}
+
+impl NetworkConfig {
+    /// Check the settings, e.g. that the proxy URLs are valid.
+    pub fn validate(&self) -> Result<()> {
+        for url in self.http_proxy.iter().chain(self.https_proxy.iter()) {
+            validate_proxy_url(url)?;
+        }
+        if let Some(path) = self.ca_bundle.as_deref() {
+            if !path.is_file() {
+                return Err(anyhow!("CA bundle not found: {}", path.display()));
+            }
+        }
+        Ok(())
+    }
+
+    /// Apply the settings to the configuration of an image proxy.
+    pub(crate) fn apply(&self, config: &mut ImageProxyConfig) -> Result<()> {
+        self.validate()?;
+        if self.insecure_skip_tls_verification {
+            config.insecure_skip_tls_verification = Some(true);
+        }
+        let mut env = Vec::new();
+        for (k, v) in [
+            ("HTTP_PROXY", &self.http_proxy),
+            ("HTTPS_PROXY", &self.https_proxy),
+            ("NO_PROXY", &self.no_proxy),
+        ] {
+            if let Some(v) = v {
+                env.push((k.to_string(), v.clone()));
+                env.push((k.to_lowercase(), v.clone()));
+            }
+        }
+        if let Some(path) = self.ca_bundle.as_deref() {
+            env.push((
+                "SSL_CERT_FILE".to_string(),
+                path.to_string_lossy().into_owned(),
+            ));
+        }
+        if !env.is_empty() {
+            let cmd = config
+                .skopeo_cmd
+                .get_or_insert_with(|| std::process::Command::new("skopeo"));
+            cmd.envs(env);
+        }
+        Ok(())
+    }
+}
+
+/// How to fetch a layer: the blob to request, and its expected `diff_id` if verified.
+#[derive(Debug, Clone)]
+struct LayerFetch {
+    blob: Descriptor,
+    expected: Option<ExpectedDiffId>,
+}
+
+/// The `diff_id` of each layer from the image configuration, used to verify layers
+/// and to fetch them uncompressed.
+#[derive(Debug, Clone, Copy)]
+struct DiffIds<'a> {
+    layers: &'a [Descriptor],
+    diff_ids: &'a [String],
+    verify: bool,
+    /// Request the uncompressed blob of each layer by its `diff_id`, with the sizes
+    /// of those blobs where known.  This is used for containers-storage, which may
+    /// only hold uncompressed layers.
+    uncompressed: Option<&'a HashMap<String, i64>>,
+}
+
+impl<'a> DiffIds<'a> {
+    fn new(
+        manifest: &'a ImageManifest,
+        config: &'a ImageConfiguration,
+        verify: bool,
+        uncompressed: Option<&'a HashMap<String, i64>>,
+    ) -> Result<Self> {
+        let layers = manifest.layers().as_slice();
+        let diff_ids = config.rootfs().diff_ids().as_slice();
+        if (verify || uncompressed.is_some()) && diff_ids.len() != layers.len() {
+            return Err(anyhow!(
+                "Image configuration has {} diff_ids for {} layers",
+                diff_ids.len(),
+                layers.len()
+            ));
+        }
+        Ok(Self {
+            layers,
+            diff_ids,
+            verify,
+            uncompressed,
+        })
+    }
+
+    fn fetch(&self, layer: &Descriptor) -> Result<LayerFetch> {
+        if !(self.verify || self.uncompressed.is_some()) {
+            return Ok(LayerFetch {
+                blob: layer.clone(),
+                expected: None,
+            });
+        }
+        let index = self
+            .layers
+            .iter()
+            .position(|l| l.digest() == layer.digest())
+            .ok_or_else(|| anyhow!("Layer {} not found in manifest", layer.digest()))?;
+        let diff_id = &self.diff_ids[index];
+        let blob = if let Some(sizes) = self.uncompressed {
+            // The size in the manifest is that of the compressed blob; -1 is unknown.
+            oci_image::DescriptorBuilder::default()
+                .media_type(oci_image::MediaType::ImageLayer)
+                .digest(diff_id.as_str())
+                .size(sizes.get(diff_id).copied().unwrap_or(-1))
+                .build()?
+        } else {
+            layer.clone()
+        };
+        let expected = self.verify.then(|| (index, diff_id.clone()));
+        Ok(LayerFetch { blob, expected })
+    }
+}
+
+/// Run a request to the image proxy, failing if it exceeds the timeout.
+async fn with_timeout<T>(
+    timeout: Option<std::time::Duration>,
+    f: impl std::future::Future<Output = Result<T>>,
+) -> Result<T> {
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, f)
+            .await
+            .map_err(|_| anyhow!("Timed out after {:?}", timeout))?,
+        None => f.await,
+    }
+}
+
+/// The default ostree ref prefix for stored images and their layers.
+pub const DEFAULT_REF_PREFIX: &str = "ostree/container";
+
+/// The prefix of the ostree refs of stored images, their layers and pins; this
+/// allows multiple independent stores in one repository, e.g. of application
+/// images alongside OS images.  The default is [`DEFAULT_REF_PREFIX`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RefPrefix(String);
+
+impl RefPrefix {
+    /// Use the given prefix, e.g. `exampleapp/containers`; it must be a valid ref.
+    #[context("Validating ref prefix {}", prefix)]
+    pub fn new(prefix: &str) -> Result<Self> {
+        refescape::validate_ref(prefix)?;
+        Ok(Self(prefix.to_string()))
+    }
+
+    /// The prefix itself.
+    pub fn as_str(&self) -> &str {
+        self.0.as_str()
+    }
+
+    /// The ostree ref prefix for blobs.
+    fn layers(&self) -> String {
+        format!("{}/blob", self.0)
+    }
+
+    /// The ostree ref prefix for image references.
+    fn images(&self) -> String {
+        format!("{}/image", self.0)
+    }
+
+    /// The ref prefix for pinned images, see [`set_image_pinned`].
+    fn pinned(&self) -> String {
+        format!("{}/pinned", self.0)
+    }
+
+    /// The ref prefix for preserved base images, see [`ImageImporter::set_preserve_base`].
+    fn bases(&self) -> String {
+        format!("{}/base", self.0)
+    }
+
+    /// The ref prefix for the merge commits of deployed images, see [`set_deployed_images`].
+    fn deployed(&self) -> String {
+        format!("{}/deployed", self.0)
+    }
+}
+
+impl Default for RefPrefix {
+    fn default() -> Self {
+        Self(DEFAULT_REF_PREFIX.to_string())
+    }
+}
+
+/// The default number of derived layers which are fetched concurrently.
+pub const DEFAULT_LAYER_CONCURRENCY: usize = 3;
 
 /// The key injected into the merge commit for the manifest digest.
 const META_MANIFEST_DIGEST: &str = "ostree.manifest-digest";
@@ -34,24 +275,205 @@ const META_MANIFEST_DIGEST: &str = "ostree.manifest-digest";
 const META_MANIFEST: &str = "ostree.manifest";
 /// The key injected into the merge commit with the image configuration serialized as JSON.
 const META_CONFIG: &str = "ostree.container.image-config";
+/// The key injected into the merge commit for the creation timestamp of the image.
+const META_CREATED: &str = "ostree.container.created";
+/// The standard ostree key injected into the merge commit for the version of the image.
+const META_VERSION: &str = "version";
+/// The key in the detached metadata of the merge commit for when the image was last
+/// used, in seconds since the Unix epoch; see [`touch_image`].
+const META_LAST_USED: &str = "ostree.container.last-used";
+/// The key injected into the commit of a derived layer for the digest of its blob.
+/// This is outside of the `ostree.` namespace, which is reserved for [`crate::tar::write_tar`].
+pub const META_LAYER_DIGEST: &str = "ostree-ext.layer-digest";
 /// Value of type `a{sa{su}}` containing number of filtered out files
 pub const META_FILTERED: &str = "ostree.tar-filtered";
 /// The type used to store content filtering information with `META_FILTERED`.
 pub type MetaFilteredData = HashMap<String, HashMap<String, u32>>;
+/// Value of type `a{sa{su}}` containing the number of skipped entries
+/// of an unsupported type (e.g. `fifo`) per layer; uses [`MetaFilteredData`].
+pub const META_SKIPPED: &str = "ostree.tar-skipped";
 
 /// Convert e.g. sha256:12345... into `/ostree/container/blob/sha256_2B12345...`.
-fn ref_for_blob_digest(d: &str) -> Result<String> {
-    refescape::prefix_escape_for_ref(LAYER_PREFIX, d)
+fn ref_for_blob_digest(prefix: &RefPrefix, d: &str) -> Result<String> {
+    refescape::prefix_escape_for_ref(&prefix.layers(), d)
 }
 
 /// Convert e.g. sha256:12345... into `/ostree/container/blob/sha256_2B12345...`.
-fn ref_for_layer(l: &oci_image::Descriptor) -> Result<String> {
-    ref_for_blob_digest(l.digest().as_str())
+fn ref_for_layer(prefix: &RefPrefix, l: &oci_image::Descriptor) -> Result<String> {
+    ref_for_blob_digest(prefix, l.digest().as_str())
 }
 
 /// Convert e.g. sha256:12345... into `/ostree/container/blob/sha256_2B12345...`.
-fn ref_for_image(l: &ImageReference) -> Result<String> {
-    refescape::prefix_escape_for_ref(IMAGE_PREFIX, &l.to_string())
+pub(crate) fn ref_for_image(prefix: &RefPrefix, l: &ImageReference) -> Result<String> {
+    refescape::prefix_escape_for_ref(&prefix.images(), &l.to_string())
+}
+
+/// The ref preserving the base image of a derived image, from the manifest digest in
+/// its [`super::BASE_DIGEST_ANNOTATION`], if any.
+fn ref_for_base_image(prefix: &RefPrefix, manifest: &ImageManifest) -> Result<Option<String>> {
+    manifest
+        .annotations()
+        .as_ref()
+        .and_then(|a| a.get(super::BASE_DIGEST_ANNOTATION))
+        .map(|d| refescape::prefix_escape_for_ref(&prefix.bases(), d))
+        .transpose()
+}
+
+/// Returned (wrapped in an [`anyhow::Error`]) when another process is already
+/// pulling the same image, and [`ImageImporter::set_wait_for_lock`] is disabled.
+#[derive(Debug)]
+pub struct AlreadyInProgress {
+    /// The image being pulled.
+    pub imgref: ImageReference,
+}
+
+impl std::fmt::Display for AlreadyInProgress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Pull of {} is already in progress", self.imgref)
+    }
+}
+
+impl std::error::Error for AlreadyInProgress {}
+
+/// Returned (wrapped in an [`anyhow::Error`]) by [`ImageImporter::import`] when the
+/// repository filesystem does not have enough space for the layers to fetch; see
+/// [`PreparedImport::estimated_disk_usage`].
+#[derive(Debug)]
+pub struct InsufficientDiskSpace {
+    /// The estimated number of bytes required.
+    pub required: u64,
+    /// The number of bytes available to unprivileged users.
+    pub available: u64,
+}
+
+impl std::fmt::Display for InsufficientDiskSpace {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Insufficient disk space: {} bytes required, {} bytes available",
+            self.required, self.available
+        )
+    }
+}
+
+impl std::error::Error for InsufficientDiskSpace {}
+
+/// The assumed ratio of uncompressed to compressed size of a layer, where the
+/// uncompressed size is not known.
+const LAYER_EXPANSION_FACTOR: u64 = 3;
+
+/// The number of bytes available to unprivileged users on the repository filesystem.
+fn available_disk_space(repo: &ostree::Repo) -> Result<u64> {
+    let dir = std::fs::File::open(format!("/proc/self/fd/{}", repo.dfd()))?;
+    let stats = rustix::fs::fstatfs(&dir).context("fstatfs")?;
+    // The block size of the counts, as computed for statvfs()
+    let frsize = if stats.f_frsize > 0 {
+        stats.f_frsize
+    } else {
+        stats.f_bsize
+    };
+    Ok((stats.f_bavail as u64).saturating_mul(frsize as u64))
+}
+
+/// Open (creating if needed) a lock file in the repository tmp directory.
+fn open_lock_file(repo: &ostree::Repo, name: &str) -> Result<std::fs::File> {
+    let path = format!("/proc/self/fd/{}/tmp/{}", repo.dfd(), name);
+    Ok(std::fs::OpenOptions::new()
+        .create(true)
+        .write(true)
+        .open(&path)?)
+}
+
+/// Take a non-blocking `flock`, returning `false` if it is held elsewhere.
+fn try_flock(file: &std::fs::File, op: rustix::fs::FlockOperation) -> std::io::Result<bool> {
+    match rustix::fs::flock(file, op) {
+        Ok(()) => Ok(true),
+        Err(e) if e == rustix::io::Error::WOULDBLOCK => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Take a `flock`, waiting until it is available.
+fn flock_wait(file: &std::fs::File, op: rustix::fs::FlockOperation) -> std::io::Result<()> {
+    loop {
+        match rustix::fs::flock(file, op) {
+            Ok(()) => return Ok(()),
+            Err(e) if e == rustix::io::Error::INTR => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+/// An exclusive lock on pulling an image, released when dropped.
+#[derive(Debug)]
+struct ImageLock {
+    _file: std::fs::File,
+}
+
+impl ImageLock {
+    /// Lock a file in the repository tmp directory keyed by the escaped image reference.
+    #[context("Locking {}", imgref)]
+    async fn acquire(
+        repo: &ostree::Repo,
+        prefix: &RefPrefix,
+        imgref: &ImageReference,
+        wait: bool,
+    ) -> Result<Self> {
+        // The escaped ref may contain `/`; escape it in the same way as other characters.
+        let name = ref_for_image(prefix, imgref)?.replace('/', "_2F_");
+        let file = open_lock_file(repo, &format!("pull-{}.lock", name))?;
+        if try_flock(&file, rustix::fs::FlockOperation::NonBlockingLockExclusive)? {
+            return Ok(Self { _file: file });
+        }
+        if !wait {
+            return Err(AlreadyInProgress {
+                imgref: imgref.clone(),
+            }
+            .into());
+        }
+        let file = tokio::task::spawn_blocking(move || -> std::io::Result<_> {
+            flock_wait(&file, rustix::fs::FlockOperation::LockExclusive)?;
+            Ok(file)
+        })
+        .await??;
+        Ok(Self { _file: file })
+    }
+}
+
+/// The lock file for the layer refs of a repository.
+const LAYERS_LOCK: &str = "container-layers.lock";
+
+/// A lock on the layer refs of a repository, released when dropped.  Imports
+/// hold it shared while they write layer refs which are not yet referenced by
+/// an image, and [`gc_image_layers`] holds it exclusively while removing refs.
+#[derive(Debug)]
+struct LayersLock {
+    _file: std::fs::File,
+}
+
+impl LayersLock {
+    /// Take the lock shared, for an import.
+    #[context("Locking layers")]
+    async fn shared(repo: &ostree::Repo) -> Result<Self> {
+        let file = open_lock_file(repo, LAYERS_LOCK)?;
+        if try_flock(&file, rustix::fs::FlockOperation::NonBlockingLockShared)? {
+            return Ok(Self { _file: file });
+        }
+        let file = tokio::task::spawn_blocking(move || -> std::io::Result<_> {
+            flock_wait(&file, rustix::fs::FlockOperation::LockShared)?;
+            Ok(file)
+        })
+        .await??;
+        Ok(Self { _file: file })
+    }
+
+    /// Take the lock exclusively, waiting for imports in progress to complete.
+    #[context("Locking layers")]
+    fn exclusive(repo: &ostree::Repo) -> Result<Self> {
+        let file = open_lock_file(repo, LAYERS_LOCK)?;
+        flock_wait(&file, rustix::fs::FlockOperation::LockExclusive)?;
+        Ok(Self { _file: file })
+    }
 }
 
 /// State of an already pulled layered image.
@@ -69,9 +491,45 @@ pub struct LayeredImageState {
     pub manifest: ImageManifest,
     /// The image configuration; for v0 images, may not be available.
     pub configuration: Option<ImageConfiguration>,
+    /// Statistics on the content of derived layers, by layer digest.
+    pub layer_stats: BTreeMap<String, crate::tar::WriteTarStats>,
+    /// The summary of the encapsulated commit recorded in the manifest, if any.
+    pub content_info: Option<ContentInfo>,
+    /// The image reference pinned to [`Self::manifest_digest`], for registry images.
+    pub pinned_imgref: Option<OstreeImageReference>,
+    /// When the image was built, from its configuration.
+    pub created: Option<chrono::DateTime<chrono::Utc>>,
+    /// The version of the image; see [`ImageMetadata::version`].
+    pub version: Option<String>,
+    /// When the image was last pulled, deployed or explicitly used; see [`touch_image`].
+    pub last_used: chrono::DateTime<chrono::Utc>,
+    /// The ref preserving [`Self::base_commit`] as the base image, if it was pulled
+    /// with [`ImageImporter::set_preserve_base`].
+    pub base_image_ref: Option<String>,
+    /// Non-fatal issues found while importing; these are not stored, so this is empty
+    /// unless returned from [`ImageImporter::import`].
+    pub warnings: Vec<ImportWarning>,
 }
 
 impl LayeredImageState {
+    /// The runtime configuration of the image (e.g. `Env`, `Cmd`), if available.
+    pub fn container_config(&self) -> Option<&oci_image::Config> {
+        self.configuration
+            .as_ref()
+            .and_then(|c| c.config().as_ref())
+    }
+
+    /// The labels of the image, if available.
+    pub fn labels(&self) -> Option<&HashMap<String, String>> {
+        self.container_config().and_then(|c| c.labels().as_ref())
+    }
+
+    /// The message from the [`crate::container::OSTREE_UNCONFIGURED_STATE_LABEL`] label,
+    /// if the image requires configuration before use.
+    pub fn unconfigured_state(&self) -> Option<&str> {
+        unconfigured_state(self.labels())
+    }
+
     /// Return the default ostree commit digest for this image.
     ///
     /// If this is a non-layered image, the merge commit will be
@@ -95,6 +553,226 @@ pub struct ImageImporter {
     imgref: OstreeImageReference,
     target_imgref: Option<OstreeImageReference>,
     pub(crate) proxy_img: OpenedImage,
+    unsupported_file_types: crate::tar::UnsupportedFileTypePolicy,
+    warnings: Option<tokio::sync::mpsc::UnboundedSender<String>>,
+    selinux: bool,
+    toplevel_content: crate::tar::ToplevelContentPolicy,
+    content_policies: Vec<crate::tar::ContentPolicy>,
+    retry: RetryPolicy,
+    progress: Option<Arc<Mutex<super::unencapsulate::Progress>>>,
+    proxy_config: ImageProxyConfig,
+    network: NetworkConfig,
+    layer_concurrency: usize,
+    verify_diff_ids: bool,
+    fatal_warnings: Vec<ImportWarningKind>,
+    rate_limit: Option<Arc<RateLimiter>>,
+    wait_for_lock: bool,
+    lock: Option<ImageLock>,
+    ref_prefix: RefPrefix,
+    check_disk_space: bool,
+    preserve_base: bool,
+    /// The reference opened by the proxies, which differs from `imgref` for an
+    /// unpacked `oci-archive:`.
+    source: ImageReference,
+    /// The directory an `oci-archive:` was unpacked to, removed when dropped.
+    _archive_dir: Option<tempfile::TempDir>,
+}
+
+/// Unpack an `oci-archive:` into a temporary directory, returning an `oci:` reference to
+/// it and the directory; other references are returned as is.  The proxy would otherwise
+/// unpack the archive again for each opened image, i.e. for each concurrent layer fetch.
+///
+/// As container signature policies may differ between the transports, archives are only
+/// unpacked if no policy is enforced.
+async fn unpack_oci_archive(
+    imgref: &OstreeImageReference,
+) -> Result<(ImageReference, Option<tempfile::TempDir>)> {
+    if imgref.imgref.transport != Transport::OciArchive
+        || imgref.sigverify == SignatureSource::ContainerPolicy
+    {
+        return Ok((imgref.imgref.clone(), None));
+    }
+    let (path, tag) = match imgref.imgref.name.split_once(':') {
+        Some((path, tag)) => (path.to_string(), Some(tag.to_string())),
+        None => (imgref.imgref.name.clone(), None),
+    };
+    let tempdir = tokio::task::spawn_blocking(move || -> Result<_> {
+        let tempdir = tempfile::tempdir_in("/var/tmp")?;
+        let f = std::fs::File::open(&path).with_context(|| format!("Opening {}", path))?;
+        let dir = openat::Dir::open(tempdir.path())?;
+        ocidir::OciDir::unpack_archive(std::io::BufReader::new(f), dir)?;
+        Ok(tempdir)
+    })
+    .await??;
+    let dirpath = tempdir
+        .path()
+        .to_str()
+        .ok_or_else(|| anyhow!("Invalid temporary directory path"))?;
+    let name = match tag {
+        Some(tag) => format!("{}:{}", dirpath, tag),
+        None => dirpath.to_string(),
+    };
+    let source = ImageReference {
+        transport: Transport::OciDir,
+        name,
+    };
+    Ok((source, Some(tempdir)))
+}
+
+/// Copy a proxy configuration, for opening further proxies.
+fn copy_proxy_config(config: &ImageProxyConfig) -> ImageProxyConfig {
+    ImageProxyConfig {
+        auth_anonymous: config.auth_anonymous,
+        authfile: config.authfile.clone(),
+        certificate_directory: config.certificate_directory.clone(),
+        insecure_skip_tls_verification: config.insecure_skip_tls_verification,
+        ..Default::default()
+    }
+}
+
+/// The state shared by the concurrent fetches of derived layers.
+struct DerivedLayerWriter<'a> {
+    repo: &'a ostree::Repo,
+    base: &'a str,
+    selinux: bool,
+    unsupported_file_types: crate::tar::UnsupportedFileTypePolicy,
+    warnings: &'a Option<tokio::sync::mpsc::UnboundedSender<String>>,
+    toplevel_content: crate::tar::ToplevelContentPolicy,
+    content_policies: &'a [crate::tar::ContentPolicy],
+    retry: &'a RetryPolicy,
+    progress: Option<&'a Arc<Mutex<super::unencapsulate::Progress>>>,
+    timeout: Option<std::time::Duration>,
+    rate_limit: Option<&'a Arc<RateLimiter>>,
+    diff_ids: DiffIds<'a>,
+}
+
+impl<'a> DerivedLayerWriter<'a> {
+    /// Fetch a derived layer and commit it, retrying according to the policy.
+    async fn write(
+        &self,
+        proxy: &mut ImageProxy,
+        img: &OpenedImage,
+        layer: &ManifestLayerState,
+    ) -> Result<crate::tar::WriteTarResult> {
+        let fetch = self.diff_ids.fetch(&layer.layer)?;
+        let mut attempt = 1;
+        loop {
+            let e = match self.write_once(proxy, img, layer, fetch.clone()).await {
+                Ok(r) => return Ok(r),
+                Err(e) => e,
+            };
+            // The layer may have been committed before the failure, but the entries
+            // filtered or skipped from it are not recorded, so it can't be reused.
+            if self.repo.resolve_rev(&layer.ostree_ref, true)?.is_some() {
+                return Err(e);
+            }
+            wait_for_retry(self.retry, &layer.layer, attempt, e, self.progress).await?;
+            attempt += 1;
+        }
+    }
+
+    async fn write_once(
+        &self,
+        proxy: &mut ImageProxy,
+        img: &OpenedImage,
+        layer: &ManifestLayerState,
+        fetch: LayerFetch,
+    ) -> Result<crate::tar::WriteTarResult> {
+        let (blob, driver) = with_timeout(
+            self.timeout,
+            fetch_layer_decompress(proxy, img, &fetch.blob, self.progress, self.rate_limit),
+        )
+        .await?;
+        let blob = DiffIdVerifier::new(blob, fetch.expected);
+        // An important aspect of this is that we SELinux label the derived layers using
+        // the base policy.
+        let layer_metadata = glib::VariantDict::new(None);
+        layer_metadata.insert(META_LAYER_DIGEST, &layer.digest());
+        let opts = crate::tar::WriteTarOptions {
+            base: Some(self.base.to_string()),
+            selinux: self.selinux,
+            unsupported_file_types: self.unsupported_file_types,
+            warnings: self.warnings.clone(),
+            metadata: Some(layer_metadata),
+            toplevel_content: self.toplevel_content,
+            content_policies: self.content_policies.to_vec(),
+            ..Default::default()
+        };
+        let r = crate::tar::write_tar(self.repo, blob, layer.ostree_ref.as_str(), Some(opts));
+        super::unencapsulate::join_fetch(r, driver)
+            .await
+            .with_context(|| format!("Parsing layer blob {}", layer.digest()))
+    }
+}
+
+/// Rolls back the layer refs written by a failed or cancelled import, unless the
+/// layer was fully imported and can be reused by the next attempt.
+#[derive(Debug)]
+struct ImportCleanup {
+    repo: ostree::Repo,
+    refs: Vec<String>,
+    armed: bool,
+}
+
+impl ImportCleanup {
+    /// Track the refs of the layers which are not yet present.
+    fn new(repo: &ostree::Repo, import: &PreparedImport) -> Self {
+        let refs = import
+            .all_layers()
+            .filter(|l| l.needed())
+            .map(|l| l.ostree_ref.clone())
+            .collect();
+        Self {
+            repo: repo.clone(),
+            refs,
+            armed: true,
+        }
+    }
+
+    /// The import succeeded; keep everything.
+    fn disarm(mut self) {
+        self.armed = false;
+    }
+
+    /// Whether the layer commit is complete, i.e. not partial and with all objects present.
+    fn is_complete(&self, commit: &str) -> bool {
+        match self.repo.load_commit(commit) {
+            Ok((_, state)) if !state.contains(ostree::RepoCommitState::PARTIAL) => self
+                .repo
+                .traverse_commit(commit, 0, gio::NONE_CANCELLABLE)
+                .is_ok(),
+            _ => false,
+        }
+    }
+
+    fn cleanup(&self) -> Result<()> {
+        let cancellable = gio::NONE_CANCELLABLE;
+        for ostree_ref in self.refs.iter() {
+            // A layer may still be committed after a cancellation, but only if complete.
+            let commit = match self.repo.resolve_rev(ostree_ref, true)? {
+                Some(c) => c,
+                None => continue,
+            };
+            if self.is_complete(commit.as_str()) {
+                continue;
+            }
+            tracing::debug!("Removing partially imported {}", ostree_ref);
+            self.repo
+                .set_ref_immediate(None, ostree_ref, None, cancellable)?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for ImportCleanup {
+    fn drop(&mut self) {
+        if !self.armed {
+            return;
+        }
+        if let Err(e) = self.cleanup() {
+            tracing::warn!("Failed to clean up partial import: {:#}", e);
+        }
+    }
 }
 
 /// Result of invoking [`LayeredImageImporter::prepare`].
@@ -126,6 +804,13 @@ impl ManifestLayerState {
     pub fn size(&self) -> u64 {
         self.layer.size() as u64
     }
+
+    /// Whether this layer must be fetched, i.e. no commit caches it yet.  Layers
+    /// are cached by digest, so those unchanged from a previously pulled image
+    /// (or shared with another image) are not fetched again.
+    pub fn needed(&self) -> bool {
+        self.commit.is_none()
+    }
 }
 
 /// Information about which layers need to be downloaded.
@@ -147,9 +832,57 @@ pub struct PreparedImport {
     pub ostree_commit_layer: ManifestLayerState,
     /// Any further non-ostree (derived) layers.
     pub layers: Vec<ManifestLayerState>,
+    /// The message from the [`crate::container::OSTREE_UNCONFIGURED_STATE_LABEL`] label,
+    /// if the image requires configuration before use.
+    pub unconfigured_state: Option<String>,
 }
 
 impl PreparedImport {
+    /// The runtime configuration of the image (e.g. `Env`, `Cmd`), if any.
+    pub fn container_config(&self) -> Option<&oci_image::Config> {
+        self.config.config().as_ref()
+    }
+
+    /// The labels of the image, if any.
+    pub fn labels(&self) -> Option<&HashMap<String, String>> {
+        self.container_config().and_then(|c| c.labels().as_ref())
+    }
+
+    /// Estimate the disk space in bytes needed to store the layers which are not yet
+    /// present.  For the ostree layers, this uses the uncompressed content size from
+    /// the [`ContentInfo`] annotations if present; otherwise, and for derived layers,
+    /// the compressed size multiplied by an expansion factor.
+    pub fn estimated_disk_usage(&self) -> u64 {
+        let ostree_layers = || {
+            self.ostree_layers
+                .iter()
+                .chain(std::iter::once(&self.ostree_commit_layer))
+        };
+        let ostree_needed: u64 = ostree_layers()
+            .filter(|l| l.needed())
+            .map(|l| l.size())
+            .sum();
+        let content_info = ContentInfo::from_manifest(&self.manifest).ok().flatten();
+        let ostree_usage = match content_info {
+            // Assume the content is spread over the layers by compressed size.
+            Some(info) => {
+                let total: u64 = ostree_layers().map(|l| l.size()).sum();
+                let usage =
+                    u128::from(info.size) * u128::from(ostree_needed) / u128::from(total.max(1));
+                u64::try_from(usage).unwrap_or(u64::MAX)
+            }
+            None => ostree_needed.saturating_mul(LAYER_EXPANSION_FACTOR),
+        };
+        let derived_needed: u64 = self
+            .layers
+            .iter()
+            .filter(|l| l.needed())
+            .map(|l| l.size())
+            .sum();
+        let derived_usage = derived_needed.saturating_mul(LAYER_EXPANSION_FACTOR);
+        ostree_usage.saturating_add(derived_usage)
+    }
+
     /// Iterate over all layers; the ostree split object layers, the commit layer, and any non-ostree layers.
     pub fn all_layers(&self) -> impl Iterator<Item = &ManifestLayerState> {
         self.ostree_layers
@@ -171,11 +904,19 @@ impl PreparedImport {
             .map(|(s, h)| h.map(|h| (s, h)))
     }
 
+    /// The total (possibly compressed) size of the layers which must be fetched.
+    pub fn bytes_to_fetch(&self) -> u64 {
+        self.all_layers()
+            .filter(|l| l.needed())
+            .map(|l| l.size())
+            .sum()
+    }
+
     /// Iterate over all layers that are not present, along with their history description.
     pub fn layers_to_fetch(&self) -> impl Iterator<Item = Result<(&ManifestLayerState, &str)>> {
         self.layers_with_history().filter_map(|r| {
             r.map(|(l, h)| {
-                l.commit.is_none().then(|| {
+                l.needed().then(|| {
                     let comment = h.created_by().as_deref().unwrap_or("");
                     (l, comment)
                 })
@@ -188,9 +929,10 @@ impl PreparedImport {
 // Given a manifest, compute its ostree ref name and cached ostree commit
 pub(crate) fn query_layer(
     repo: &ostree::Repo,
+    prefix: &RefPrefix,
     layer: oci_image::Descriptor,
 ) -> Result<ManifestLayerState> {
-    let ostree_ref = ref_for_layer(&layer)?;
+    let ostree_ref = ref_for_layer(prefix, &layer)?;
     let commit = repo.resolve_rev(&ostree_ref, true)?.map(|s| s.to_string());
     Ok(ManifestLayerState {
         layer,
@@ -199,6 +941,29 @@ pub(crate) fn query_layer(
     })
 }
 
+/// Decode the detached commit metadata stored in the manifest, if any.
+fn detached_metadata_from_manifest(manifest: &ImageManifest) -> Result<Option<glib::Variant>> {
+    let v = manifest
+        .annotations()
+        .as_ref()
+        .and_then(|a| a.get(DETACHED_METADATA_ANNOTATION));
+    let v = if let Some(v) = v {
+        v
+    } else {
+        return Ok(None);
+    };
+    let v = glib::base64_decode(v);
+    let v = glib::Bytes::from_owned(v);
+    let v = glib::Variant::from_bytes::<HashMap<String, glib::Variant>>(&v);
+    if !v.is_normal_form() {
+        return Err(anyhow!(
+            "Invalid {} annotation",
+            DETACHED_METADATA_ANNOTATION
+        ));
+    }
+    Ok(Some(v))
+}
+
 fn manifest_data_from_commitmeta(
     commit_meta: &glib::VariantDict,
 ) -> Result<(oci_image::ImageManifest, String)> {
@@ -256,17 +1021,65 @@ fn layer_from_diffid<'a>(
     })
 }
 
+/// The [`crate::container::OSTREE_UNCONFIGURED_STATE_LABEL`] message from image labels.
+fn unconfigured_state(labels: Option<&HashMap<String, String>>) -> Option<&str> {
+    labels
+        .and_then(|l| l.get(crate::container::OSTREE_UNCONFIGURED_STATE_LABEL))
+        .map(|s| s.as_str())
+}
+
+/// Find the layer holding the ostree commit, via the [`OSTREE_DIFFID_LABEL`].
+fn ostree_commit_layer<'a>(
+    manifest: &'a ImageManifest,
+    config: &ImageConfiguration,
+) -> Result<&'a Descriptor> {
+    let label = crate::container::OSTREE_DIFFID_LABEL;
+    let config_labels = config.config().as_ref().and_then(|c| c.labels().as_ref());
+    // For backwards compatibility, if there's only 1 layer, don't require the label.
+    // This can be dropped when we drop format version 0 support.
+    if config.rootfs().diff_ids().len() == 1 {
+        return manifest
+            .layers()
+            .first()
+            .ok_or_else(|| anyhow!("No layers found"));
+    }
+    let diffid = config_labels
+        .and_then(|labels| labels.get(label))
+        .ok_or_else(|| {
+            anyhow!(
+                "Missing annotation {} (not an ostree-exported container?)",
+                label
+            )
+        })?;
+    layer_from_diffid(manifest, config, diffid.as_str())
+}
+
 impl ImageImporter {
     /// Create a new importer.
     pub async fn new(
+        repo: &ostree::Repo,
+        imgref: &OstreeImageReference,
+        config: ImageProxyConfig,
+    ) -> Result<Self> {
+        Self::new_with_network(repo, imgref, config, Default::default()).await
+    }
+
+    /// Create a new importer, using the given network settings for registry fetches.
+    pub async fn new_with_network(
         repo: &ostree::Repo,
         imgref: &OstreeImageReference,
         mut config: ImageProxyConfig,
+        network: NetworkConfig,
     ) -> Result<Self> {
         // Apply our defaults to the proxy config
         merge_default_container_proxy_opts(&mut config)?;
+        skopeo::validate_archive_ref(&imgref.imgref)?;
+        let (source, archive_dir) = unpack_oci_archive(imgref).await?;
+        let proxy_config = copy_proxy_config(&config);
+        network.apply(&mut config)?;
         let proxy = ImageProxy::new_with_config(config).await?;
-        let proxy_img = proxy.open_image(&imgref.imgref.to_string()).await?;
+        let proxy_img =
+            with_timeout(network.timeout, proxy.open_image(&source.to_string())).await?;
         let repo = repo.clone();
         Ok(ImageImporter {
             repo,
@@ -274,6 +1087,26 @@ impl ImageImporter {
             proxy_img,
             target_imgref: None,
             imgref: imgref.clone(),
+            unsupported_file_types: Default::default(),
+            warnings: None,
+            selinux: true,
+            toplevel_content: Default::default(),
+            content_policies: Vec::new(),
+            retry: Default::default(),
+            progress: None,
+            proxy_config,
+            network,
+            layer_concurrency: DEFAULT_LAYER_CONCURRENCY,
+            verify_diff_ids: true,
+            fatal_warnings: Vec::new(),
+            rate_limit: None,
+            wait_for_lock: true,
+            lock: None,
+            ref_prefix: Default::default(),
+            check_disk_space: true,
+            preserve_base: false,
+            source,
+            _archive_dir: archive_dir,
         })
     }
 
@@ -281,11 +1114,128 @@ impl ImageImporter {
     pub fn set_target(&mut self, target: &OstreeImageReference) {
         self.target_imgref = Some(target.clone())
     }
+
+    /// Store the image and its layers under the given ref prefix, rather than
+    /// [`DEFAULT_REF_PREFIX`]; the same prefix must be used to query it.
+    pub fn set_ref_prefix(&mut self, prefix: RefPrefix) {
+        self.ref_prefix = prefix;
+    }
+
+    /// Set how entries of an unsupported type (e.g. device nodes) in derived
+    /// layers are handled; see [`crate::tar::WriteTarOptions`].
+    pub fn set_unsupported_file_type_policy(
+        &mut self,
+        policy: crate::tar::UnsupportedFileTypePolicy,
+        warnings: Option<tokio::sync::mpsc::UnboundedSender<String>>,
+    ) {
+        self.unsupported_file_types = policy;
+        self.warnings = warnings;
+    }
+
+    /// Set whether files in derived layers are SELinux labeled using the policy
+    /// from the base image; this is enabled by default.
+    pub fn set_selinux(&mut self, selinux: bool) {
+        self.selinux = selinux;
+    }
+
+    /// Set how content outside of `/usr` and `/etc` in derived layers is handled;
+    /// by default it is discarded with a warning.
+    pub fn set_toplevel_content_policy(&mut self, policy: crate::tar::ToplevelContentPolicy) {
+        self.toplevel_content = policy;
+    }
+
+    /// Require all entries of derived layers to satisfy a policy, e.g.
+    /// [`crate::tar::ContentPolicy::no_setuid`]; otherwise the import fails with a
+    /// [`crate::tar::ContentPolicyViolation`].  The layers of the base image are not
+    /// checked, as they are covered by the image signature.
+    pub fn add_content_policy(&mut self, policy: crate::tar::ContentPolicy) {
+        self.content_policies.push(policy);
+    }
+
+    /// Set how failed layer fetches are retried; by default they are not.
+    pub fn set_retry_policy(&mut self, policy: RetryPolicy) {
+        self.retry = policy;
+    }
+
+    /// Set whether the uncompressed content of each layer is verified against its
+    /// `diff_id` in the image configuration; this is enabled by default, and should
+    /// only be disabled for legacy images with incorrect configurations.
+    pub fn set_verify_diff_ids(&mut self, verify: bool) {
+        self.verify_diff_ids = verify;
+    }
+
+    /// Limit the rate of layer fetches, in bytes per second; this applies to all
+    /// concurrent fetches together.
+    pub fn set_rate_limit(&mut self, bytes_per_sec: Option<u64>) {
+        self.rate_limit = bytes_per_sec.map(|v| Arc::new(RateLimiter::new(v)));
+    }
+
+    /// Set whether to wait if another process is already pulling the same image (or
+    /// the same target, see [`Self::set_target`]); by default this waits, otherwise
+    /// [`AlreadyInProgress`] is returned.
+    pub fn set_wait_for_lock(&mut self, wait: bool) {
+        self.wait_for_lock = wait;
+    }
+
+    /// Set whether [`Self::import`] first checks that the repository filesystem has
+    /// enough space for [`PreparedImport::estimated_disk_usage`]; this is enabled by
+    /// default, and fails with [`InsufficientDiskSpace`].
+    pub fn set_check_disk_space(&mut self, check: bool) {
+        self.check_disk_space = check;
+    }
+
+    /// Set whether the base commit of a derived image is preserved under its own ref,
+    /// named after the manifest digest of the base image from the
+    /// [`super::BASE_DIGEST_ANNOTATION`]; derived images built from the same base share
+    /// this ref.  It is removed by [`gc_image_layers`] once no stored image uses it.
+    /// Images without the annotation are unaffected.
+    pub fn set_preserve_base(&mut self, preserve: bool) {
+        self.preserve_base = preserve;
+    }
+
+    /// Fail the import instead of warning for the given kinds of [`ImportWarning`].
+    pub fn set_fatal_warnings(&mut self, kinds: &[ImportWarningKind]) {
+        self.fatal_warnings = kinds.to_vec();
+    }
+
+    /// Set the maximum number of derived layers which are fetched concurrently;
+    /// the default is [`DEFAULT_LAYER_CONCURRENCY`].
+    pub fn set_layer_concurrency(&mut self, n: usize) {
+        self.layer_concurrency = n;
+    }
+
+    /// Set a channel which receives progress updates while fetching layers.  This is
+    /// overridden by [`UnencapsulateOptions::progress`].
+    pub fn set_progress(&mut self, progress: tokio::sync::watch::Sender<UnencapsulationProgress>) {
+        self.progress = Some(Arc::new(Mutex::new(progress)));
+    }
+
     /// Determine if there is a new manifest, and if so return its digest.
     pub async fn prepare(&mut self) -> Result<PrepareResult> {
         self.prepare_internal(false).await
     }
 
+    /// Whether layers are fetched uncompressed by their `diff_id`; containers-storage
+    /// may only hold uncompressed layers, which don't match the manifest digests.
+    fn fetch_uncompressed(&self) -> bool {
+        self.imgref.imgref.transport == Transport::ContainerStorage
+    }
+
+    /// The sizes of the uncompressed layer blobs by `diff_id` if they are fetched
+    /// (see [`Self::fetch_uncompressed`]), as far as the proxy reports them.
+    async fn uncompressed_sizes(&self) -> Result<Option<HashMap<String, i64>>> {
+        if !self.fetch_uncompressed() {
+            return Ok(None);
+        }
+        let info = self.proxy.get_layer_info(&self.proxy_img).await?;
+        let sizes = info
+            .unwrap_or_default()
+            .into_iter()
+            .map(|l| (l.digest, l.size))
+            .collect();
+        Ok(Some(sizes))
+    }
+
     /// Determine if there is a new manifest, and if so return its digest.
     #[context("Fetching manifest")]
     pub(crate) async fn prepare_internal(&mut self, verify_layers: bool) -> Result<PrepareResult> {
@@ -301,55 +1251,56 @@ impl ImageImporter {
             _ => {}
         }
 
-        let (manifest_digest, manifest) = self.proxy.fetch_manifest(&self.proxy_img).await?;
+        // Held until the importer is dropped, i.e. after the import completes.  This
+        // is keyed by the image the data is written for, see `set_target`.
+        if self.lock.is_none() {
+            let target = self.target_imgref.as_ref().unwrap_or(&self.imgref);
+            self.lock = Some(
+                ImageLock::acquire(
+                    &self.repo,
+                    &self.ref_prefix,
+                    &target.imgref,
+                    self.wait_for_lock,
+                )
+                .await?,
+            );
+        }
+
+        let timeout = self.network.timeout;
+        let (manifest_digest, manifest) =
+            with_timeout(timeout, self.proxy.fetch_manifest(&self.proxy_img)).await?;
+        // The digest is computed by the proxy from the fetched manifest bytes.
+        self.imgref.imgref.verify_digest(&manifest_digest)?;
         let new_imageid = manifest.config().digest().as_str();
 
         // Query for previous stored state
 
-        let (previous_manifest_digest, previous_imageid) =
-            if let Some(previous_state) = query_image(&self.repo, &self.imgref)? {
-                // If the manifest digests match, we're done.
-                if previous_state.manifest_digest == manifest_digest {
-                    return Ok(PrepareResult::AlreadyPresent(previous_state));
-                }
-                // Failing that, if they have the same imageID, we're also done.
-                let previous_imageid = previous_state.manifest.config().digest().as_str();
-                if previous_imageid == new_imageid {
-                    return Ok(PrepareResult::AlreadyPresent(previous_state));
-                }
-                (
-                    Some(previous_state.manifest_digest),
-                    Some(previous_imageid.to_string()),
-                )
-            } else {
-                (None, None)
-            };
-
-        let config = self.proxy.fetch_config(&self.proxy_img).await?;
-
-        let label = crate::container::OSTREE_DIFFID_LABEL;
-        let config_labels = config.config().as_ref().and_then(|c| c.labels().as_ref());
-        // For backwards compatibility, if there's only 1 layer, don't require the label.
-        // This can be dropped when we drop format version 0 support.
-        let commit_layer_digest = if config.rootfs().diff_ids().len() == 1 {
-            manifest.layers()[0].digest()
+        let (previous_manifest_digest, previous_imageid) = if let Some(previous_state) =
+            query_image_with_prefix(&self.repo, &self.ref_prefix, &self.imgref)?
+        {
+            // If the manifest digests match, we're done.
+            // Failing that, if they have the same imageID, we're also done.
+            let previous_imageid = previous_state.manifest.config().digest().as_str();
+            if previous_state.manifest_digest == manifest_digest || previous_imageid == new_imageid
+            {
+                touch_image_with_prefix(&self.repo, &self.ref_prefix, &self.imgref.imgref)?;
+                return Ok(PrepareResult::AlreadyPresent(previous_state));
+            }
+            (
+                Some(previous_state.manifest_digest),
+                Some(previous_imageid.to_string()),
+            )
         } else {
-            let diffid = config_labels
-                .and_then(|labels| labels.get(label))
-                .ok_or_else(|| {
-                    anyhow!(
-                        "Missing annotation {} (not an ostree-exported container?)",
-                        label
-                    )
-                })?;
-
-            let layer = layer_from_diffid(&manifest, &config, diffid.as_str())?;
-            layer.digest()
+            (None, None)
         };
+
+        let config = with_timeout(timeout, self.proxy.fetch_config(&self.proxy_img)).await?;
+
+        let commit_layer_digest = ostree_commit_layer(&manifest, &config)?.digest();
         let mut component_layers = Vec::new();
         let mut commit_layer = None;
         let mut remaining_layers = Vec::new();
-        let query = |l: &Descriptor| query_layer(&self.repo, l.clone());
+        let query = |l: &Descriptor| query_layer(&self.repo, &self.ref_prefix, l.clone());
         for layer in manifest.layers() {
             if layer.digest() == commit_layer_digest {
                 commit_layer = Some(query(layer)?);
@@ -366,10 +1317,14 @@ impl ImageImporter {
             )
         })?;
 
+        let unconfigured_state =
+            unconfigured_state(config.config().as_ref().and_then(|c| c.labels().as_ref()))
+                .map(ToOwned::to_owned);
         let imp = PreparedImport {
             manifest,
             manifest_digest,
             config,
+            unconfigured_state,
             previous_manifest_digest,
             previous_imageid,
             ostree_layers: component_layers,
@@ -385,6 +1340,7 @@ impl ImageImporter {
         import: &mut store::PreparedImport,
         options: Option<UnencapsulateOptions>,
         write_refs: bool,
+        warnings: &mut ImportWarnings,
     ) -> Result<()> {
         tracing::debug!("Fetching base");
         if matches!(self.imgref.sigverify, SignatureSource::ContainerPolicy)
@@ -392,6 +1348,14 @@ impl ImageImporter {
         {
             return Err(anyhow!("containers-policy.json specifies a default of `insecureAcceptAnything`; refusing usage"));
         }
+        let bootable = import
+            .labels()
+            .and_then(|l| l.get(*ostree::METADATA_KEY_BOOTABLE))
+            .map(|v| v == "true")
+            .unwrap_or_default();
+        if !bootable {
+            warnings.warn(ImportWarning::NotBootable)?;
+        }
         let options = options.unwrap_or_default();
         let remote = match &self.imgref.sigverify {
             SignatureSource::OstreeRemote(remote) => Some(remote.clone()),
@@ -400,93 +1364,219 @@ impl ImageImporter {
             }
         };
 
-        let progress = options.progress.map(|v| Arc::new(Mutex::new(v)));
+        let detached_metadata = detached_metadata_from_manifest(&import.manifest)?;
+        let progress = options
+            .progress
+            .map(|v| Arc::new(Mutex::new(v)))
+            .or_else(|| self.progress.clone());
+        let retry = options.retry.unwrap_or(self.retry);
+        let verify = options.verify_diff_ids.unwrap_or(self.verify_diff_ids);
+        let uncompressed = self.uncompressed_sizes().await?;
+        let diff_ids = DiffIds::new(
+            &import.manifest,
+            &import.config,
+            verify,
+            uncompressed.as_ref(),
+        )?;
         for layer in import.ostree_layers.iter_mut() {
             if layer.commit.is_some() {
                 continue;
             }
-            let (blob, driver) =
-                fetch_layer_decompress(&mut self.proxy, &self.proxy_img, &layer.layer).await?;
-            let blob = super::unencapsulate::ProgressReader {
-                reader: blob,
-                progress: progress.as_ref().map(Arc::clone),
+            let fetch = diff_ids.fetch(&layer.layer)?;
+            let mut attempt = 1;
+            layer.commit = loop {
+                match self
+                    .fetch_object_set(layer, fetch.clone(), write_refs, progress.as_ref())
+                    .await
+                {
+                    Ok(commit) => break commit,
+                    Err(e) => {
+                        wait_for_retry(&retry, &layer.layer, attempt, e, progress.as_ref()).await?
+                    }
+                }
+                attempt += 1;
+                // The layer may have been committed before the failure
+                if let Some(commit) = self.repo.resolve_rev(&layer.ostree_ref, true)? {
+                    break Some(commit.to_string());
+                }
             };
-            let repo = self.repo.clone();
-            let target_ref = layer.ostree_ref.clone();
-            let import_task =
-                crate::tokio_util::spawn_blocking_cancellable_flatten(move |cancellable| {
-                    let txn = repo.auto_transaction(Some(cancellable))?;
-                    let mut importer = crate::tar::Importer::new_for_object_set(&repo);
-                    let blob = tokio_util::io::SyncIoBridge::new(blob);
-                    let mut archive = tar::Archive::new(blob);
-                    importer.import_objects(&mut archive, Some(cancellable))?;
-                    let commit = if write_refs {
-                        let commit = importer.finish_import_object_set()?;
-                        repo.transaction_set_ref(None, &target_ref, Some(commit.as_str()));
-                        tracing::debug!("Wrote {} => {}", target_ref, commit);
-                        Some(commit)
-                    } else {
-                        None
-                    };
-                    txn.commit(Some(cancellable))?;
-                    Ok::<_, anyhow::Error>(commit)
-                });
-            let commit = super::unencapsulate::join_fetch(import_task, driver).await?;
-            layer.commit = commit;
         }
         if import.ostree_commit_layer.commit.is_none() {
-            let (blob, driver) = fetch_layer_decompress(
-                &mut self.proxy,
-                &self.proxy_img,
-                &import.ostree_commit_layer.layer,
-            )
-            .await?;
-            let blob = ProgressReader {
-                reader: blob,
-                progress: progress.as_ref().map(Arc::clone),
-            };
-            let repo = self.repo.clone();
-            let target_ref = import.ostree_commit_layer.ostree_ref.clone();
-            let import_task =
-                crate::tokio_util::spawn_blocking_cancellable_flatten(move |cancellable| {
-                    let txn = repo.auto_transaction(Some(cancellable))?;
-                    let mut importer = crate::tar::Importer::new_for_commit(&repo, remote);
-                    let blob = tokio_util::io::SyncIoBridge::new(blob);
-                    let mut archive = tar::Archive::new(blob);
-                    importer.import_commit(&mut archive, Some(cancellable))?;
-                    let commit = importer.finish_import_commit();
-                    if write_refs {
-                        repo.transaction_set_ref(None, &target_ref, Some(commit.as_str()));
-                        tracing::debug!("Wrote {} => {}", target_ref, commit);
+            let layer = &import.ostree_commit_layer;
+            let fetch = diff_ids.fetch(&layer.layer)?;
+            let mut attempt = 1;
+            let commit = loop {
+                match self
+                    .fetch_commit_layer(
+                        layer,
+                        remote.clone(),
+                        detached_metadata.clone(),
+                        fetch.clone(),
+                        write_refs,
+                        progress.as_ref(),
+                    )
+                    .await
+                {
+                    Ok(commit) => break commit,
+                    Err(e) => {
+                        wait_for_retry(&retry, &layer.layer, attempt, e, progress.as_ref()).await?
                     }
-                    repo.mark_commit_partial(&commit, false)?;
-                    txn.commit(Some(cancellable))?;
-                    Ok::<_, anyhow::Error>(commit)
-                });
-            let commit = super::unencapsulate::join_fetch(import_task, driver).await?;
+                }
+                attempt += 1;
+                if let Some(commit) = self.repo.resolve_rev(&layer.ostree_ref, true)? {
+                    break commit.to_string();
+                }
+            };
             import.ostree_commit_layer.commit = Some(commit);
         };
         Ok(())
     }
 
+    /// Fetch and import a layer of split objects, returning its commit if `write_ref` is set.
+    async fn fetch_object_set(
+        &mut self,
+        layer: &ManifestLayerState,
+        fetch: LayerFetch,
+        write_ref: bool,
+        progress: Option<&Arc<Mutex<super::unencapsulate::Progress>>>,
+    ) -> Result<Option<String>> {
+        let (blob, driver) = with_timeout(
+            self.network.timeout,
+            fetch_layer_decompress(
+                &mut self.proxy,
+                &self.proxy_img,
+                &fetch.blob,
+                progress,
+                self.rate_limit.as_ref(),
+            ),
+        )
+        .await?;
+        let blob = super::unencapsulate::ProgressReader {
+            reader: DiffIdVerifier::new(blob, fetch.expected),
+            progress: progress.map(Arc::clone),
+        };
+        let repo = self.repo.clone();
+        let target_ref = layer.ostree_ref.clone();
+        let import_task =
+            crate::tokio_util::spawn_blocking_cancellable_flatten(move |cancellable| {
+                let txn = repo.auto_transaction(Some(cancellable))?;
+                let mut importer = crate::tar::Importer::new_for_object_set(&repo);
+                let blob = tokio_util::io::SyncIoBridge::new(blob);
+                let mut archive = tar::Archive::new(blob);
+                importer.import_objects(&mut archive, Some(cancellable))?;
+                // Read to the end, verifying the diff_id before anything is committed
+                std::io::copy(&mut archive.into_inner(), &mut std::io::sink())?;
+                let commit = if write_ref {
+                    let commit = importer.finish_import_object_set()?;
+                    repo.transaction_set_ref(None, &target_ref, Some(commit.as_str()));
+                    tracing::debug!("Wrote {} => {}", target_ref, commit);
+                    Some(commit)
+                } else {
+                    None
+                };
+                txn.commit(Some(cancellable))?;
+                Ok::<_, anyhow::Error>(commit)
+            });
+        super::unencapsulate::join_fetch(import_task, driver).await
+    }
+
+    /// Fetch and import the layer with the ostree commit.
+    async fn fetch_commit_layer(
+        &mut self,
+        layer: &ManifestLayerState,
+        remote: Option<String>,
+        detached_metadata: Option<glib::Variant>,
+        fetch: LayerFetch,
+        write_ref: bool,
+        progress: Option<&Arc<Mutex<super::unencapsulate::Progress>>>,
+    ) -> Result<String> {
+        let (blob, driver) = with_timeout(
+            self.network.timeout,
+            fetch_layer_decompress(
+                &mut self.proxy,
+                &self.proxy_img,
+                &fetch.blob,
+                progress,
+                self.rate_limit.as_ref(),
+            ),
+        )
+        .await?;
+        let blob = ProgressReader {
+            reader: DiffIdVerifier::new(blob, fetch.expected),
+            progress: progress.map(Arc::clone),
+        };
+        let repo = self.repo.clone();
+        let target_ref = layer.ostree_ref.clone();
+        let import_task =
+            crate::tokio_util::spawn_blocking_cancellable_flatten(move |cancellable| {
+                let txn = repo.auto_transaction(Some(cancellable))?;
+                let mut importer = crate::tar::Importer::new_for_commit(&repo, remote);
+                if let Some(v) = detached_metadata {
+                    importer.set_detached_metadata(v);
+                }
+                let blob = tokio_util::io::SyncIoBridge::new(blob);
+                let mut archive = tar::Archive::new(blob);
+                importer.import_commit(&mut archive, Some(cancellable))?;
+                // Read to the end, verifying the diff_id before anything is committed
+                std::io::copy(&mut archive.into_inner(), &mut std::io::sink())?;
+                let commit = importer.finish_import_commit();
+                if write_ref {
+                    repo.transaction_set_ref(None, &target_ref, Some(commit.as_str()));
+                    tracing::debug!("Wrote {} => {}", target_ref, commit);
+                }
+                repo.mark_commit_partial(&commit, false)?;
+                txn.commit(Some(cancellable))?;
+                Ok::<_, anyhow::Error>(commit)
+            });
+        super::unencapsulate::join_fetch(import_task, driver).await
+    }
+
     /// Retrieve an inner ostree commit.
     ///
     /// This does not write cached references for each blob, and errors out if
-    /// the image has any non-ostree layers.
+    /// the image has any non-ostree layers not marked with [`EXTRA_LAYER_ANNOTATION`],
+    /// unless [`UnencapsulateOptions::allow_unexpected_layers`] is set.
     pub async fn unencapsulate(
         mut self,
         mut import: Box<PreparedImport>,
         options: Option<UnencapsulateOptions>,
     ) -> Result<Import> {
-        if !import.layers.is_empty() {
-            anyhow::bail!("Image has {} non-ostree layers", import.layers.len());
+        let mut options = options.unwrap_or_default();
+        if let Some(progress) = options.progress.take() {
+            self.progress = Some(Arc::new(Mutex::new(progress)));
         }
-        self.unencapsulate_base(&mut import, options, false).await?;
+        let mut warnings = ImportWarnings::new(self.fatal_warnings.clone(), self.progress.clone());
+        // Extra layers added at build time can be ignored, but other layers are content
+        // which would be missing.
+        let is_extra = |l: &ManifestLayerState| {
+            l.layer
+                .annotations()
+                .as_ref()
+                .and_then(|a| a.get(EXTRA_LAYER_ANNOTATION))
+                .is_some()
+        };
+        let unexpected: Vec<String> = import
+            .layers
+            .iter()
+            .filter(|l| !is_extra(l))
+            .map(|l| l.digest().to_string())
+            .collect();
+        if !unexpected.is_empty() && !options.allow_unexpected_layers {
+            anyhow::bail!("Image has {} non-ostree layers", unexpected.len());
+        }
+        for digest in unexpected {
+            warnings.warn(ImportWarning::UnexpectedLayer { digest })?;
+        }
+        self.unencapsulate_base(&mut import, Some(options), false, &mut warnings)
+            .await?;
         let ostree_commit = import.ostree_commit_layer.commit.unwrap();
+        let pinned_imgref = self.imgref.with_digest(&import.manifest_digest).ok();
         let image_digest = import.manifest_digest;
         Ok(Import {
             ostree_commit,
             image_digest,
+            pinned_imgref,
+            warnings: warnings.warnings,
         })
     }
 
@@ -495,54 +1585,144 @@ impl ImageImporter {
         mut self,
         mut import: Box<PreparedImport>,
     ) -> Result<Box<LayeredImageState>> {
+        if self.check_disk_space {
+            let required = import.estimated_disk_usage();
+            let available = available_disk_space(&self.repo)?;
+            if required > available {
+                return Err(InsufficientDiskSpace {
+                    required,
+                    available,
+                }
+                .into());
+            }
+        }
+        // Keeps the layer refs written below from being garbage collected
+        // before the image ref referencing them is written.
+        let _layers_lock = LayersLock::shared(&self.repo).await?;
         // First download all layers for the base image (if necessary) - we need the SELinux policy
         // there to label all following layers.
-        self.unencapsulate_base(&mut import, None, true).await?;
-        let mut proxy = self.proxy;
+        let mut warnings = ImportWarnings::new(self.fatal_warnings.clone(), self.progress.clone());
+        // On error or cancellation, this removes the refs of any incomplete layers.
+        let cleanup = ImportCleanup::new(&self.repo, &import);
+        self.unencapsulate_base(&mut import, None, true, &mut warnings)
+            .await?;
         let target_imgref = self.target_imgref.as_ref().unwrap_or(&self.imgref);
         let base_commit = import.ostree_commit_layer.commit.clone().unwrap();
 
-        let ostree_ref = ref_for_image(&target_imgref.imgref)?;
+        let ostree_ref = ref_for_image(&self.ref_prefix, &target_imgref.imgref)?;
+        let base_ref = if self.preserve_base {
+            ref_for_base_image(&self.ref_prefix, &import.manifest)?
+        } else {
+            None
+        };
+
+        // Each derived layer is committed independently on top of the base, so they can be
+        // fetched concurrently; they're only merged (in manifest order) below.  Fetching a
+        // blob requires exclusive use of a proxy, so each further worker gets its own.
+        let pending: Vec<usize> = import
+            .layers
+            .iter()
+            .enumerate()
+            .filter(|(_, layer)| layer.needed())
+            .map(|(i, _)| i)
+            .collect();
+        let workers = self.layer_concurrency.max(1).min(pending.len()).max(1);
+        let uncompressed = self.uncompressed_sizes().await?;
+        let mut proxies = vec![(self.proxy, self.proxy_img)];
+        for _ in 1..workers {
+            let mut config = copy_proxy_config(&self.proxy_config);
+            self.network.apply(&mut config)?;
+            let proxy = ImageProxy::new_with_config(config).await?;
+            let imgref = self.source.to_string();
+            let img = with_timeout(self.network.timeout, proxy.open_image(&imgref)).await?;
+            proxies.push((proxy, img));
+        }
+        let writer = DerivedLayerWriter {
+            repo: &self.repo,
+            base: &base_commit,
+            selinux: self.selinux,
+            unsupported_file_types: self.unsupported_file_types,
+            warnings: &self.warnings,
+            toplevel_content: self.toplevel_content,
+            content_policies: &self.content_policies,
+            retry: &self.retry,
+            progress: self.progress.as_ref(),
+            timeout: self.network.timeout,
+            rate_limit: self.rate_limit.as_ref(),
+            diff_ids: DiffIds::new(
+                &import.manifest,
+                &import.config,
+                self.verify_diff_ids,
+                uncompressed.as_ref(),
+            )?,
+        };
+        let queue = &Mutex::new(pending.into_iter());
+        let writer = &writer;
+        let layers = &import.layers;
+        let fetched =
+            futures_util::future::try_join_all(proxies.iter_mut().map(|(proxy, img)| async move {
+                let mut done = Vec::new();
+                loop {
+                    let next = queue.lock().unwrap().next();
+                    let i = match next {
+                        Some(i) => i,
+                        None => break,
+                    };
+                    let r = writer.write(proxy, img, &layers[i]).await?;
+                    done.push((i, r));
+                }
+                Ok::<_, anyhow::Error>(done)
+            }))
+            .await?;
+        let mut results: Vec<Option<crate::tar::WriteTarResult>> =
+            layers.iter().map(|_| None).collect();
+        for (i, r) in fetched.into_iter().flatten() {
+            results[i] = Some(r);
+        }
 
         let mut layer_commits = Vec::new();
         let mut layer_filtered_content: MetaFilteredData = HashMap::new();
-        for layer in import.layers {
-            if let Some(c) = layer.commit {
+        let mut layer_skipped_content: MetaFilteredData = HashMap::new();
+        for (layer, r) in import.layers.iter().zip(results) {
+            let r = if let Some(r) = r {
+                r
+            } else {
+                let c = layer.commit.as_ref().unwrap();
                 tracing::debug!("Reusing fetched commit {}", c);
                 layer_commits.push(c.to_string());
-            } else {
-                let (blob, driver) = super::unencapsulate::fetch_layer_decompress(
-                    &mut proxy,
-                    &self.proxy_img,
-                    &layer.layer,
-                )
-                .await?;
-                // An important aspect of this is that we SELinux label the derived layers using
-                // the base policy.
-                let opts = crate::tar::WriteTarOptions {
-                    base: Some(base_commit.clone()),
-                    selinux: true,
-                };
-                let r =
-                    crate::tar::write_tar(&self.repo, blob, layer.ostree_ref.as_str(), Some(opts));
-                let r = super::unencapsulate::join_fetch(r, driver)
-                    .await
-                    .with_context(|| format!("Parsing layer blob {}", layer.digest()))?;
-                layer_commits.push(r.commit);
-                if !r.filtered.is_empty() {
-                    let filtered = HashMap::from_iter(r.filtered.into_iter());
-                    layer_filtered_content.insert(layer.digest().to_string(), filtered);
-                }
+                continue;
+            };
+            layer_commits.push(r.commit);
+            if !r.filtered.is_empty() {
+                let filtered = HashMap::from_iter(r.filtered.into_iter());
+                layer_filtered_content.insert(layer.digest().to_string(), filtered);
+            }
+            if !r.skipped.is_empty() {
+                warnings.warn(ImportWarning::SkippedEntries {
+                    digest: layer.digest().to_string(),
+                    skipped: r.skipped.clone(),
+                })?;
+                let skipped = HashMap::from_iter(r.skipped.into_iter());
+                layer_skipped_content.insert(layer.digest().to_string(), skipped);
             }
         }
 
-        // We're done with the proxy, make sure it didn't have any errors.
-        proxy.finalize().await?;
+        // We're done with the proxies, make sure they didn't have any errors.
+        for (proxy, _) in proxies {
+            proxy.finalize().await?;
+        }
         tracing::debug!("finalized proxy");
 
         let serialized_manifest = serde_json::to_string(&import.manifest)?;
         let serialized_config = serde_json::to_string(&import.config)?;
         let mut metadata = HashMap::new();
+        if let Some(created) = import.config.created() {
+            metadata.insert(META_CREATED, created.to_variant());
+        }
+        if let Some(version) = ImageMetadata::new(&import.manifest, Some(&import.config)).version()
+        {
+            metadata.insert(META_VERSION, version.to_variant());
+        }
         metadata.insert(META_MANIFEST_DIGEST, import.manifest_digest.to_variant());
         metadata.insert(META_MANIFEST, serialized_manifest.to_variant());
         metadata.insert(META_CONFIG, serialized_config.to_variant());
@@ -552,12 +1732,16 @@ impl ImageImporter {
         );
         let filtered = layer_filtered_content.to_variant();
         metadata.insert(META_FILTERED, filtered);
+        if !layer_skipped_content.is_empty() {
+            metadata.insert(META_SKIPPED, layer_skipped_content.to_variant());
+        }
         let metadata = metadata.to_variant();
 
         // Destructure to transfer ownership to thread
         let repo = self.repo;
         let imgref = self.target_imgref.unwrap_or(self.imgref);
-        let state = crate::tokio_util::spawn_blocking_cancellable_flatten(
+        let ref_prefix = self.ref_prefix;
+        let mut state = crate::tokio_util::spawn_blocking_cancellable_flatten(
             move |cancellable| -> Result<Box<LayeredImageState>> {
                 let cancellable = Some(cancellable);
                 let repo = &repo;
@@ -573,8 +1757,7 @@ impl ImageImporter {
                 );
                 // Layer all subsequent commits
                 for commit in layer_commits {
-                    let (layer_tree, _) = repo.read_commit(&commit, cancellable)?;
-                    repo.write_directory_to_mtree(&layer_tree, &mt, None, cancellable)?;
+                    crate::tar::write_layer_to_mtree(repo, &mt, &commit, cancellable)?;
                 }
 
                 let merged_root = repo.write_mtree(&mt, cancellable)?;
@@ -588,29 +1771,143 @@ impl ImageImporter {
                     cancellable,
                 )?;
                 repo.transaction_set_ref(None, &ostree_ref, Some(merged_commit.as_str()));
+                if let Some(base_ref) = base_ref.as_deref() {
+                    repo.transaction_set_ref(None, base_ref, Some(base_commit.as_str()));
+                }
                 txn.commit(cancellable)?;
                 // Here we re-query state just to run through the same code path,
                 // though it'd be cheaper to synthesize it from the data we already have.
-                let state = query_image(repo, &imgref)?.unwrap();
+                let state = query_image_with_prefix(repo, &ref_prefix, &imgref)?.unwrap();
                 Ok(state)
             },
         )
         .await?;
+        cleanup.disarm();
+        state.warnings = warnings.warnings;
         Ok(state)
     }
 }
 
-/// List all images stored
-pub fn list_images(repo: &ostree::Repo) -> Result<Vec<String>> {
+/// The names of all images stored under the prefix.
+fn list_image_names(repo: &ostree::Repo, prefix: &RefPrefix) -> Result<Vec<String>> {
     let cancellable = gio::NONE_CANCELLABLE;
+    let image_prefix = &prefix.images();
     let refs = repo.list_refs_ext(
-        Some(IMAGE_PREFIX),
+        Some(image_prefix),
         ostree::RepoListRefsExtFlags::empty(),
         cancellable,
     )?;
-    refs.keys()
-        .map(|imgname| refescape::unprefix_unescape_ref(IMAGE_PREFIX, imgname))
-        .collect()
+    let mut r = refs
+        .keys()
+        .map(|imgname| refescape::unprefix_unescape_ref(image_prefix, imgname))
+        .collect::<Result<Vec<_>>>()?;
+    r.sort();
+    Ok(r)
+}
+
+/// A stored image, as returned by [`list_images`].
+#[derive(Debug, Clone, PartialEq, Eq, Default, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ImageListEntry {
+    /// The image reference, e.g. `docker://quay.io/exampleos/exampleos:latest`.
+    pub image: String,
+    /// The manifest digest.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub manifest_digest: Option<String>,
+    /// The creation timestamp from the image configuration.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub created: Option<String>,
+    /// The base ostree commit.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub base_commit: Option<String>,
+    /// The number of layers, including the base.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub n_layers: Option<usize>,
+    /// Whether there are layers after the base.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub is_layered: Option<bool>,
+    /// The uncompressed size of the base commit if recorded, otherwise the
+    /// compressed size of all layers.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    /// Set if the stored metadata for this image could not be read; the other
+    /// fields are then unset.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl ImageListEntry {
+    fn new(repo: &ostree::Repo, prefix: &RefPrefix, image: String) -> Result<Self> {
+        let imgref = ImageReference::try_from(image.as_str())?;
+        let state = query_image_ref_with_prefix(repo, prefix, &imgref)?
+            .ok_or_else(|| anyhow!("Image was removed"))?;
+        let size = match state.content_info {
+            Some(info) => info.size,
+            None => state
+                .manifest
+                .layers()
+                .iter()
+                .map(|l| l.size() as u64)
+                .sum(),
+        };
+        Ok(Self {
+            manifest_digest: Some(state.manifest_digest.clone()),
+            created: state
+                .configuration
+                .as_ref()
+                .and_then(|c| c.created().clone()),
+            base_commit: Some(state.base_commit.clone()),
+            n_layers: Some(state.manifest.layers().len()),
+            is_layered: Some(state.is_layered),
+            size: Some(size),
+            image,
+            error: None,
+        })
+    }
+}
+
+/// List all stored images, sorted by name.  Images whose stored metadata can't
+/// be read are included with [`ImageListEntry::error`] set.
+pub fn list_images(repo: &ostree::Repo) -> Result<Vec<ImageListEntry>> {
+    list_images_with_prefix(repo, &Default::default())
+}
+
+/// Like [`list_images`], for the images stored under the given ref prefix only.
+pub fn list_images_with_prefix(
+    repo: &ostree::Repo,
+    prefix: &RefPrefix,
+) -> Result<Vec<ImageListEntry>> {
+    Ok(list_image_names(repo, prefix)?
+        .into_iter()
+        .map(|image| {
+            ImageListEntry::new(repo, prefix, image.clone()).unwrap_or_else(|e| ImageListEntry {
+                image,
+                error: Some(format!("{:#}", e)),
+                ..Default::default()
+            })
+        })
+        .collect())
+}
+
+/// Load the statistics recorded by [`crate::tar::write_tar`] in a layer commit.
+fn layer_stats_from_commit(
+    repo: &ostree::Repo,
+    commit: &str,
+) -> Result<Option<crate::tar::WriteTarStats>> {
+    let commit = repo.load_commit(commit)?.0;
+    let commit_meta = &glib::VariantDict::new(Some(&commit.child_value(0)));
+    commit_meta
+        .lookup::<String>(crate::tar::META_TAR_STATS)?
+        .map(|v| serde_json::from_str(&v).map_err(anyhow::Error::msg))
+        .transpose()
+}
+
+/// Parse the creation timestamp of an image; this is ignored if invalid.
+fn parse_created(created: &str) -> Option<chrono::DateTime<chrono::Utc>> {
+    chrono::DateTime::parse_from_rfc3339(created)
+        .map_err(|e| tracing::debug!("Invalid creation timestamp {}: {}", created, e))
+        .ok()
+        .map(|t| t.with_timezone(&chrono::Utc))
 }
 
 /// Query metadata for a pulled image.
@@ -618,7 +1915,38 @@ pub fn query_image(
     repo: &ostree::Repo,
     imgref: &OstreeImageReference,
 ) -> Result<Option<Box<LayeredImageState>>> {
-    let ostree_ref = &ref_for_image(&imgref.imgref)?;
+    query_image_with_prefix(repo, &Default::default(), imgref)
+}
+
+/// Like [`query_image`], for an image stored under the given ref prefix.
+pub fn query_image_with_prefix(
+    repo: &ostree::Repo,
+    prefix: &RefPrefix,
+    imgref: &OstreeImageReference,
+) -> Result<Option<Box<LayeredImageState>>> {
+    let mut state = query_image_ref_with_prefix(repo, prefix, &imgref.imgref)?;
+    if let Some(state) = state.as_mut() {
+        state.pinned_imgref = imgref.with_digest(&state.manifest_digest).ok();
+    }
+    Ok(state)
+}
+
+/// Query metadata for a pulled image, by its image reference alone; as the
+/// signature verification is unknown, [`LayeredImageState::pinned_imgref`] is unset.
+pub fn query_image_ref(
+    repo: &ostree::Repo,
+    imgref: &ImageReference,
+) -> Result<Option<Box<LayeredImageState>>> {
+    query_image_ref_with_prefix(repo, &Default::default(), imgref)
+}
+
+/// Like [`query_image_ref`], for an image stored under the given ref prefix.
+pub fn query_image_ref_with_prefix(
+    repo: &ostree::Repo,
+    prefix: &RefPrefix,
+    imgref: &ImageReference,
+) -> Result<Option<Box<LayeredImageState>>> {
+    let ostree_ref = &ref_for_image(prefix, imgref)?;
     let merge_rev = repo.resolve_rev(ostree_ref, true)?;
     let (merge_commit, merge_commit_obj) = if let Some(r) = merge_rev {
         (r.to_string(), repo.load_commit(r.as_str())?.0)
@@ -629,15 +1957,43 @@ pub fn query_image(
     let commit_meta = &ostree::glib::VariantDict::new(Some(commit_meta));
     let (manifest, manifest_digest) = manifest_data_from_commitmeta(commit_meta)?;
     let configuration = image_config_from_commitmeta(commit_meta)?;
+    let content_info = ContentInfo::from_manifest(&manifest)?;
+    // Images stored by older versions only have these in the configuration, if at all.
+    let created = commit_meta
+        .lookup::<String>(META_CREATED)?
+        .or_else(|| configuration.as_ref()?.created().clone())
+        .as_deref()
+        .and_then(parse_created);
+    let version = match commit_meta.lookup::<String>(META_VERSION)? {
+        Some(v) => Some(v),
+        None => ImageMetadata::new(&manifest, configuration.as_ref())
+            .version()
+            .map(ToOwned::to_owned),
+    };
+    let last_used = last_used(repo, &merge_commit, &merge_commit_obj)?;
     let mut layers = manifest.layers().iter().cloned();
     // We require a base layer.
     let base_layer = layers.next().ok_or_else(|| anyhow!("No layers found"))?;
-    let base_layer = query_layer(repo, base_layer)?;
+    let base_layer = query_layer(repo, prefix, base_layer)?;
     let base_commit = base_layer
         .commit
         .ok_or_else(|| anyhow!("Missing base image ref"))?;
-    // If there are more layers after the base, then we're layered.
-    let is_layered = layers.count() > 0;
+    let base_image_ref = match ref_for_base_image(prefix, &manifest)? {
+        Some(r) if repo.resolve_rev(&r, true)?.as_deref() == Some(base_commit.as_str()) => Some(r),
+        _ => None,
+    };
+    let mut is_layered = false;
+    let mut layer_stats = BTreeMap::new();
+    for layer in layers {
+        // If there are more layers after the base, then we're layered.
+        is_layered = true;
+        let layer = query_layer(repo, prefix, layer)?;
+        if let Some(commit) = layer.commit.as_deref() {
+            if let Some(stats) = layer_stats_from_commit(repo, commit)? {
+                layer_stats.insert(layer.digest().to_string(), stats);
+            }
+        }
+    }
     let state = Box::new(LayeredImageState {
         base_commit,
         merge_commit,
@@ -645,56 +2001,1226 @@ pub fn query_image(
         manifest_digest,
         manifest,
         configuration,
+        layer_stats,
+        content_info,
+        pinned_imgref: None,
+        created,
+        version,
+        last_used,
+        base_image_ref,
+        warnings: Vec::new(),
     });
     tracing::debug!(state = ?state);
     Ok(Some(state))
 }
 
+/// When an image was last used; the merge commit is written when pulling, so its
+/// timestamp is used if the image was not used since.
+fn last_used(
+    repo: &ostree::Repo,
+    merge_commit: &str,
+    merge_commit_obj: &glib::Variant,
+) -> Result<chrono::DateTime<chrono::Utc>> {
+    let detached = repo.read_commit_detached_metadata(merge_commit, gio::NONE_CANCELLABLE)?;
+    let recorded = detached
+        .map(|v| glib::VariantDict::new(Some(&v)).lookup::<u64>(META_LAST_USED))
+        .transpose()?
+        .flatten();
+    let ts = recorded.unwrap_or_else(|| ostree::commit_get_timestamp(merge_commit_obj));
+    let ts = i64::try_from(ts)?;
+    let ts = chrono::NaiveDateTime::from_timestamp_opt(ts, 0)
+        .ok_or_else(|| anyhow!("Invalid last used timestamp {}", ts))?;
+    Ok(chrono::DateTime::from_utc(ts, chrono::Utc))
+}
+
+/// Record that a stored image was used now, see [`LayeredImageState::last_used`].  This
+/// is done when pulling or deploying the image; images which are used otherwise (e.g.
+/// as a base for builds) should be touched so that [`prune_images`] retains them.
+pub fn touch_image(repo: &ostree::Repo, imgref: &ImageReference) -> Result<()> {
+    touch_image_with_prefix(repo, &Default::default(), imgref)
+}
+
+/// Like [`touch_image`], for an image stored under the given ref prefix.
+#[context("Updating last use of {}", imgref)]
+pub fn touch_image_with_prefix(
+    repo: &ostree::Repo,
+    prefix: &RefPrefix,
+    imgref: &ImageReference,
+) -> Result<()> {
+    let cancellable = gio::NONE_CANCELLABLE;
+    let merge_commit = repo
+        .resolve_rev(&ref_for_image(prefix, imgref)?, true)?
+        .ok_or_else(|| anyhow!("Image not found: {}", imgref))?;
+    let detached = repo.read_commit_detached_metadata(&merge_commit, cancellable)?;
+    let detached = glib::VariantDict::new(detached.as_ref());
+    let now = u64::try_from(chrono::Utc::now().timestamp())?;
+    detached.insert_value(META_LAST_USED, &now.to_variant());
+    repo.write_commit_detached_metadata(&merge_commit, Some(&detached.end()), cancellable)?;
+    Ok(())
+}
+
+/// The maximum number of symbolic links followed when resolving a path in an image.
+const MAX_SYMLINK_HOPS: u32 = 40;
+
+/// Resolve a path in a commit, following symbolic links within it; absolute link
+/// targets are relative to the root of the commit.  Returns `None` if the path (or a
+/// link target) does not exist.
+fn resolve_in_commit(root: &gio::File, path: &str) -> Result<Option<gio::File>> {
+    let cancellable = gio::NONE_CANCELLABLE;
+    // Components yet to be resolved, in reverse order.
+    let mut remaining: Vec<String> = path.rsplit('/').map(ToOwned::to_owned).collect();
+    let mut hops = 0;
+    let mut current = root.clone();
+    while let Some(name) = remaining.pop() {
+        match name.as_str() {
+            "" | "." => continue,
+            ".." => {
+                current = current.parent().unwrap_or_else(|| root.clone());
+                continue;
+            }
+            _ => {}
+        }
+        let child = current.child(&name);
+        let info = match child.query_info(
+            "standard::type,standard::symlink-target",
+            gio::FileQueryInfoFlags::NOFOLLOW_SYMLINKS,
+            cancellable,
+        ) {
+            Ok(info) => info,
+            Err(e) if e.matches(gio::IOErrorEnum::NotFound) => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        match info.file_type() {
+            gio::FileType::SymbolicLink => {
+                hops += 1;
+                if hops > MAX_SYMLINK_HOPS {
+                    return Err(anyhow!("Too many levels of symbolic links: {}", path));
+                }
+                let target = info
+                    .symlink_target()
+                    .ok_or_else(|| anyhow!("Missing symlink target"))?;
+                let target = target.to_str().ok_or_else(|| anyhow!("Invalid UTF-8"))?;
+                if target.starts_with('/') {
+                    current = root.clone();
+                }
+                remaining.extend(target.rsplit('/').map(ToOwned::to_owned));
+            }
+            gio::FileType::Directory => current = child,
+            // A non-directory can't have children.
+            _ if remaining.iter().any(|n| !n.is_empty() && n != ".") => return Ok(None),
+            _ => current = child,
+        }
+    }
+    Ok(Some(current))
+}
+
+/// Open a file in a stored image for reading, without checking it out; symbolic links
+/// within the image are followed.  Returns `None` if the file does not exist.
+pub fn open_file(
+    repo: &ostree::Repo,
+    imgref: &ImageReference,
+    path: &str,
+) -> Result<Option<impl std::io::Read>> {
+    open_file_with_prefix(repo, &Default::default(), imgref, path)
+}
+
+/// Like [`open_file`], for an image stored under the given ref prefix.
+pub fn open_file_with_prefix(
+    repo: &ostree::Repo,
+    prefix: &RefPrefix,
+    imgref: &ImageReference,
+    path: &str,
+) -> Result<Option<impl std::io::Read>> {
+    use gio::prelude::InputStreamExtManual;
+    let f = match lookup_file(repo, prefix, imgref, path)? {
+        Some(f) => f,
+        None => return Ok(None),
+    };
+    let f = f
+        .read(gio::NONE_CANCELLABLE)
+        .with_context(|| format!("Reading {} from {}", path, imgref))?;
+    Ok(Some(f.into_read()))
+}
+
+/// Find a regular file in the merge commit of a stored image.
+#[context("Reading {} from {}", path, imgref)]
+fn lookup_file(
+    repo: &ostree::Repo,
+    prefix: &RefPrefix,
+    imgref: &ImageReference,
+    path: &str,
+) -> Result<Option<gio::File>> {
+    let cancellable = gio::NONE_CANCELLABLE;
+    let merge_commit = repo
+        .resolve_rev(&ref_for_image(prefix, imgref)?, true)?
+        .ok_or_else(|| anyhow!("Image not found: {}", imgref))?;
+    let (root, _) = repo.read_commit(&merge_commit, cancellable)?;
+    let f = match resolve_in_commit(&root, path)? {
+        Some(f) => f,
+        None => return Ok(None),
+    };
+    let ftype = f.query_file_type(gio::FileQueryInfoFlags::NOFOLLOW_SYMLINKS, cancellable);
+    if ftype != gio::FileType::Regular {
+        return Err(anyhow!("Not a regular file"));
+    }
+    Ok(Some(f))
+}
+
+/// Read the content of a file in a stored image; see [`open_file`].
+pub fn read_file(
+    repo: &ostree::Repo,
+    imgref: &ImageReference,
+    path: &str,
+) -> Result<Option<Vec<u8>>> {
+    read_file_with_prefix(repo, &Default::default(), imgref, path)
+}
+
+/// Like [`read_file`], for an image stored under the given ref prefix.
+pub fn read_file_with_prefix(
+    repo: &ostree::Repo,
+    prefix: &RefPrefix,
+    imgref: &ImageReference,
+    path: &str,
+) -> Result<Option<Vec<u8>>> {
+    use std::io::Read;
+    let mut f = match open_file_with_prefix(repo, prefix, imgref, path)? {
+        Some(f) => f,
+        None => return Ok(None),
+    };
+    let mut buf = Vec::new();
+    f.read_to_end(&mut buf)
+        .with_context(|| format!("Reading {} from {}", path, imgref))?;
+    Ok(Some(buf))
+}
+
+/// Whether a layer is part of the ostree-exported base image, see [`LayerRefInfo`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LayerKind {
+    /// A chunk of the base image, or the layer holding the ostree commit itself.
+    Base,
+    /// A layer derived from the base image, e.g. via a `Dockerfile`.
+    Derived,
+}
+
+/// How a layer of a stored image maps to an ostree ref, as returned by [`layer_refs`].
+#[derive(Debug, Clone, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct LayerRefInfo {
+    /// The digest of the layer blob.
+    pub digest: String,
+    /// The (possibly compressed) size of the layer blob.
+    pub size: u64,
+    /// The ostree ref which caches the layer.
+    pub ostree_ref: String,
+    /// The commit the ref resolves to; unset if the ref is missing.
+    pub commit: Option<String>,
+    /// Whether this is a base image chunk or a derived layer.
+    pub kind: LayerKind,
+}
+
+/// List the ostree ref and commit caching each layer of a stored image, e.g. to
+/// inspect it with `ostree` tooling.
+pub fn layer_refs(repo: &ostree::Repo, imgref: &ImageReference) -> Result<Vec<LayerRefInfo>> {
+    layer_refs_with_prefix(repo, &Default::default(), imgref)
+}
+
+/// Like [`layer_refs`], for an image stored under the given ref prefix.
+#[context("Listing layers of {}", imgref)]
+pub fn layer_refs_with_prefix(
+    repo: &ostree::Repo,
+    prefix: &RefPrefix,
+    imgref: &ImageReference,
+) -> Result<Vec<LayerRefInfo>> {
+    let ostree_ref = ref_for_image(prefix, imgref)?;
+    let rev = repo
+        .resolve_rev(&ostree_ref, true)?
+        .ok_or_else(|| anyhow!("Image not found: {}", imgref))?;
+    let (commit_obj, _) = repo.load_commit(rev.as_str())?;
+    let commit_meta = &glib::VariantDict::new(Some(&commit_obj.child_value(0)));
+    let (manifest, _) = manifest_data_from_commitmeta(commit_meta)?;
+    // Without a configuration (format v0), only the first layer is the base.
+    let commit_layer = match image_config_from_commitmeta(commit_meta)? {
+        Some(config) => ostree_commit_layer(&manifest, &config)?.digest().clone(),
+        None => manifest
+            .layers()
+            .first()
+            .ok_or_else(|| anyhow!("No layers found"))?
+            .digest()
+            .clone(),
+    };
+    let mut kind = LayerKind::Base;
+    manifest
+        .layers()
+        .iter()
+        .map(|layer| {
+            let state = query_layer(repo, prefix, layer.clone())?;
+            let info = LayerRefInfo {
+                digest: layer.digest().clone(),
+                size: layer.size() as u64,
+                ostree_ref: state.ostree_ref,
+                commit: state.commit,
+                kind,
+            };
+            if layer.digest() == &commit_layer {
+                kind = LayerKind::Derived;
+            }
+            Ok(info)
+        })
+        .collect()
+}
+
+/// Which objects [`verify_image`] checksums.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ObjectVerification {
+    /// Only check that the commits and objects exist.
+    None,
+    /// Checksum up to this many objects, spread evenly across the image.
+    Sample(usize),
+    /// Checksum all objects.
+    Full,
+}
+
+/// The result of [`verify_image`].
+#[derive(Debug, Default, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct VerifyResult {
+    /// The digests of layers whose ref or commit is missing.
+    pub missing_layers: Vec<String>,
+    /// The layers which have missing or corrupt content, by digest, with the first error found.
+    pub corrupt_layers: BTreeMap<String, String>,
+    /// An error with the merge commit or its own objects, if any.
+    pub merge_commit_error: Option<String>,
+    /// The corrupt objects, in the form `<checksum>.<objtype>`; these must be deleted
+    /// before pulling the affected layers again, as existing objects aren't rewritten.
+    pub corrupt_objects: Vec<String>,
+    /// The number of objects which were checksummed.
+    pub objects_verified: usize,
+}
+
+impl VerifyResult {
+    /// Whether no problems were found.
+    pub fn is_ok(&self) -> bool {
+        self.missing_layers.is_empty()
+            && self.corrupt_layers.is_empty()
+            && self.merge_commit_error.is_none()
+    }
+}
+
+/// Compute the checksum of a stored object, to compare with its name.
+fn checksum_object(
+    repo: &ostree::Repo,
+    checksum: &str,
+    objtype: ostree::ObjectType,
+) -> Result<String> {
+    let cancellable = gio::NONE_CANCELLABLE;
+    if objtype == ostree::ObjectType::File {
+        let (instream, info, xattrs) = repo.load_file(checksum, cancellable)?;
+        let info = info.ok_or_else(|| anyhow!("Missing metadata for object {}", checksum))?;
+        let r = ostree::checksum_file_from_input(
+            &info,
+            xattrs.as_ref(),
+            instream.as_ref(),
+            objtype,
+            cancellable,
+        )?;
+        Ok(r.to_hex())
+    } else {
+        let v = repo.load_variant(objtype, checksum)?;
+        let digest =
+            openssl::hash::hash(openssl::hash::MessageDigest::sha256(), &v.data_as_bytes())?;
+        Ok(hex::encode(digest))
+    }
+}
+
+/// Traverse a commit, checking the recorded layer digest (if any).
+fn verify_commit_objects(
+    repo: &ostree::Repo,
+    commit: &str,
+    layer: Option<&Descriptor>,
+) -> Result<HashSet<(String, ostree::ObjectType)>> {
+    let (commit_obj, _) = repo.load_commit(commit)?;
+    if let Some(layer) = layer {
+        let commit_meta = &glib::VariantDict::new(Some(&commit_obj.child_value(0)));
+        if let Some(digest) = commit_meta.lookup::<String>(META_LAYER_DIGEST)? {
+            if digest.as_str() != layer.digest().as_str() {
+                return Err(anyhow!("Commit {} records layer {}", commit, digest));
+            }
+        }
+    }
+    Ok(repo
+        .traverse_commit(commit, 0, gio::NONE_CANCELLABLE)?
+        .into_iter()
+        .map(|o| (o.checksum().to_string(), o.object_type()))
+        .collect())
+}
+
+/// Check that a stored image is intact, e.g. after a power loss: the ref of each
+/// layer resolves to a commit recording the same layer digest, the merge commit
+/// exists, and all of their objects are present.  Depending on `objects`, the
+/// checksums of objects are verified too.
+///
+/// Problems with the image content are returned in the [`VerifyResult`], so that
+/// just the affected layers may be pulled again; an error is only returned if the
+/// image is not found, or the repository can't be read.
+pub fn verify_image(
+    repo: &ostree::Repo,
+    imgref: &ImageReference,
+    objects: ObjectVerification,
+) -> Result<VerifyResult> {
+    verify_image_with_prefix(repo, &Default::default(), imgref, objects)
+}
+
+/// Like [`verify_image`], for an image stored under the given ref prefix.
+#[context("Verifying image {}", imgref)]
+pub fn verify_image_with_prefix(
+    repo: &ostree::Repo,
+    prefix: &RefPrefix,
+    imgref: &ImageReference,
+    objects: ObjectVerification,
+) -> Result<VerifyResult> {
+    let (merge_commit, manifest) = stored_image_manifest(repo, prefix, imgref)?;
+    let mut r = VerifyResult::default();
+    let mut layer_objects = Vec::new();
+    for layer in manifest.layers() {
+        let state = query_layer(repo, prefix, layer.clone())?;
+        let commit = match state.commit {
+            Some(c)
+                if repo.has_object(ostree::ObjectType::Commit, &c, gio::NONE_CANCELLABLE)? =>
+            {
+                c
+            }
+            _ => {
+                r.missing_layers.push(layer.digest().to_string());
+                continue;
+            }
+        };
+        match verify_commit_objects(repo, &commit, Some(layer)) {
+            Ok(objs) => layer_objects.push((layer.digest().as_str(), objs)),
+            Err(e) => {
+                r.corrupt_layers
+                    .insert(layer.digest().to_string(), format!("{:#}", e));
+            }
+        }
+    }
+    let merge_objects = match verify_commit_objects(repo, &merge_commit, None) {
+        Ok(objs) => objs,
+        Err(e) => {
+            r.merge_commit_error = Some(format!("{:#}", e));
+            HashSet::new()
+        }
+    };
+
+    let mut all: Vec<_> = layer_objects
+        .iter()
+        .flat_map(|(_, objs)| objs.iter())
+        .chain(merge_objects.iter())
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .collect();
+    all.sort_by(|a, b| a.0.cmp(&b.0));
+    let selected: Vec<_> = match objects {
+        ObjectVerification::None => Vec::new(),
+        ObjectVerification::Full => all,
+        ObjectVerification::Sample(n) => {
+            let stride = (all.len() / n.max(1)).max(1);
+            all.into_iter().step_by(stride).take(n).collect()
+        }
+    };
+    let mut corrupt = HashMap::new();
+    for obj in selected {
+        let (checksum, objtype) = obj;
+        r.objects_verified += 1;
+        let name = format!("{}.{}", checksum, crate::tar::object_suffix(*objtype));
+        let err = match checksum_object(repo, checksum, *objtype) {
+            Ok(found) if &found == checksum => continue,
+            Ok(found) => format!("Corrupt object {}: found {}", name, found),
+            Err(e) => format!("Corrupt object {}: {:#}", name, e),
+        };
+        r.corrupt_objects.push(name);
+        corrupt.insert(obj.clone(), err);
+    }
+
+    for (digest, objs) in layer_objects {
+        if let Some(err) = objs.iter().find_map(|o| corrupt.get(o)) {
+            r.corrupt_layers.insert(digest.to_string(), err.clone());
+        }
+    }
+    if r.merge_commit_error.is_none() {
+        r.merge_commit_error = merge_objects.iter().find_map(|o| corrupt.get(o)).cloned();
+    }
+    Ok(r)
+}
+
+/// The difference between two stored images, as computed by [`image_diff`].
+#[derive(Debug, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ImageDiff {
+    /// The differences between the layers.
+    pub layers: ManifestDiff,
+    /// The components (e.g. packages) of chunked layers which were added or removed;
+    /// see [`COMPONENTS_ANNOTATION`].
+    pub changed_components: BTreeSet<String>,
+    /// The differences between the merged commits.
+    pub files: crate::diff::FileTreeDiff,
+}
+
+/// Load the merge commit and manifest of a stored image.
+fn stored_image_manifest(
+    repo: &ostree::Repo,
+    prefix: &RefPrefix,
+    imgref: &ImageReference,
+) -> Result<(String, ImageManifest)> {
+    let ostree_ref = ref_for_image(prefix, imgref)?;
+    let rev = repo
+        .resolve_rev(&ostree_ref, true)?
+        .ok_or_else(|| anyhow!("Image not found: {}", imgref))?;
+    let (commit_obj, _) = repo.load_commit(rev.as_str())?;
+    let commit_meta = &glib::VariantDict::new(Some(&commit_obj.child_value(0)));
+    let (manifest, _) = manifest_data_from_commitmeta(commit_meta)?;
+    Ok((rev.to_string(), manifest))
+}
+
+/// Compare two stored images, by their layers and the content of their merged commits.
+pub fn image_diff(
+    repo: &ostree::Repo,
+    old: &ImageReference,
+    new: &ImageReference,
+) -> Result<ImageDiff> {
+    image_diff_with_prefix(repo, &Default::default(), old, new)
+}
+
+/// Like [`image_diff`], for images stored under the given ref prefix.
+#[context("Comparing images")]
+pub fn image_diff_with_prefix(
+    repo: &ostree::Repo,
+    prefix: &RefPrefix,
+    old: &ImageReference,
+    new: &ImageReference,
+) -> Result<ImageDiff> {
+    let (old_commit, old_manifest) = stored_image_manifest(repo, prefix, old)?;
+    let (new_commit, new_manifest) = stored_image_manifest(repo, prefix, new)?;
+    let layers = manifest_diff(&old_manifest, &new_manifest);
+    let changed_components = layers
+        .added
+        .iter()
+        .chain(layers.removed.iter())
+        .filter_map(|l| l.annotations().as_ref()?.get(COMPONENTS_ANNOTATION))
+        .flat_map(|v| v.split(','))
+        .filter(|v| !v.is_empty())
+        .map(ToOwned::to_owned)
+        .collect();
+    let files = crate::diff::diff(repo, &old_commit, &new_commit, None::<&str>)?;
+    Ok(ImageDiff {
+        layers,
+        changed_components,
+        files,
+    })
+}
+
 /// Copy a downloaded image from one repository to another.
+///
+/// This copies the merge commit, which holds the cached manifest and configuration,
+/// along with the commit and ref of every layer and of the preserved base image (see
+/// [`ImageImporter::set_preserve_base`]), so the destination is in the same state as
+/// if it had pulled the image itself.  Objects are copied locally, and those
+/// already present in the destination are skipped.
 pub async fn copy(
     src_repo: &ostree::Repo,
     dest_repo: &ostree::Repo,
     imgref: &OstreeImageReference,
 ) -> Result<()> {
-    let ostree_ref = ref_for_image(&imgref.imgref)?;
+    copy_with_prefix(src_repo, dest_repo, &Default::default(), imgref).await
+}
+
+/// Like [`copy`], for an image stored under the given ref prefix; it is stored
+/// under the same prefix in the destination.
+#[context("Copying image {}", imgref)]
+pub async fn copy_with_prefix(
+    src_repo: &ostree::Repo,
+    dest_repo: &ostree::Repo,
+    prefix: &RefPrefix,
+    imgref: &OstreeImageReference,
+) -> Result<()> {
+    let ostree_ref = ref_for_image(prefix, &imgref.imgref)?;
     let rev = src_repo.require_rev(&ostree_ref)?;
     let (commit_obj, _) = src_repo.load_commit(rev.as_str())?;
     let commit_meta = &glib::VariantDict::new(Some(&commit_obj.child_value(0)));
     let (manifest, _) = manifest_data_from_commitmeta(commit_meta)?;
-    // Create a task to copy each layer, plus the final ref
-    let layer_refs = manifest
+    // Copy each layer, plus the final ref
+    let mut refs = manifest
         .layers()
         .iter()
-        .map(ref_for_layer)
-        .chain(std::iter::once(Ok(ostree_ref)));
-    for ostree_ref in layer_refs {
-        let ostree_ref = ostree_ref?;
-        let src_repo = src_repo.clone();
-        let dest_repo = dest_repo.clone();
-        crate::tokio_util::spawn_blocking_cancellable_flatten(move |cancellable| -> Result<_> {
-            let cancellable = Some(cancellable);
-            let srcfd = &format!("file:///proc/self/fd/{}", src_repo.dfd());
-            let flags = ostree::RepoPullFlags::MIRROR;
-            let opts = glib::VariantDict::new(None);
-            let refs = [ostree_ref.as_str()];
-            // Some older archives may have bindings, we don't need to verify them.
-            opts.insert("disable-verify-bindings", &true);
-            opts.insert("refs", &&refs[..]);
-            opts.insert("flags", &(flags.bits() as i32));
-            let options = opts.to_variant();
-            dest_repo.pull_with_options(srcfd, &options, None, cancellable)?;
-            Ok(())
-        })
-        .await?;
+        .map(|l| ref_for_layer(prefix, l))
+        .collect::<Result<Vec<_>>>()?;
+    for r in refs.iter() {
+        // Fail early with a clear error rather than in the middle of the pull
+        src_repo
+            .require_rev(r)
+            .with_context(|| format!("Missing layer ref {}", r))?;
+    }
+    refs.sort();
+    refs.dedup();
+    // The preserved base image, if any, may be shared with other derived images
+    if let Some(base_ref) = ref_for_base_image(prefix, &manifest)? {
+        if src_repo.resolve_rev(&base_ref, true)?.is_some() {
+            refs.push(base_ref);
+        }
+    }
+    refs.push(ostree_ref);
+    let src_repo = src_repo.clone();
+    let dest_repo = dest_repo.clone();
+    crate::tokio_util::spawn_blocking_cancellable_flatten(move |cancellable| -> Result<_> {
+        let cancellable = Some(cancellable);
+        let srcfd = &format!("file:///proc/self/fd/{}", src_repo.dfd());
+        let flags = ostree::RepoPullFlags::MIRROR;
+        let opts = glib::VariantDict::new(None);
+        let refs = refs.iter().map(|s| s.as_str()).collect::<Vec<_>>();
+        // Some older archives may have bindings, we don't need to verify them.
+        opts.insert("disable-verify-bindings", &true);
+        opts.insert("refs", &&refs[..]);
+        opts.insert("flags", &(flags.bits() as i32));
+        let options = opts.to_variant();
+        dest_repo.pull_with_options(srcfd, &options, None, cancellable)?;
+        Ok(())
+    })
+    .await
+}
+
+/// A layer which was not reproduced exactly by [`export`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChangedLayer {
+    /// The digest of the layer in the stored manifest.
+    pub original: String,
+    /// The digest of the regenerated layer.
+    pub digest: String,
+}
+
+/// The result of [`export`].
+#[derive(Debug)]
+pub struct ExportedImage {
+    /// The digest of the exported manifest.
+    pub manifest_digest: String,
+    /// Layers whose digest changed.
+    pub changed_layers: Vec<ChangedLayer>,
+}
+
+/// Read the content object checksums from a commit written by
+/// [`crate::tar::Importer::finish_import_object_set`].
+fn object_set_from_commit(repo: &ostree::Repo, commit: &str) -> Result<BTreeSet<String>> {
+    let cancellable = gio::NONE_CANCELLABLE;
+    let (root, _) = repo.read_commit(commit, cancellable)?;
+    let e = root.enumerate_children(
+        "standard::name",
+        gio::FileQueryInfoFlags::NOFOLLOW_SYMLINKS,
+        cancellable,
+    )?;
+    let mut r = BTreeSet::new();
+    while let Some(info) = e.next_file(cancellable)? {
+        let name = info.name();
+        let name = name
+            .to_str()
+            .ok_or_else(|| anyhow!("Invalid non-UTF8 name {:?}", name))?;
+        r.insert(name.to_string());
+    }
+    Ok(r)
+}
+
+/// Find the commit caching a layer of a stored image.
+fn require_layer_commit(
+    repo: &ostree::Repo,
+    prefix: &RefPrefix,
+    layer: &Descriptor,
+) -> Result<String> {
+    query_layer(repo, prefix, layer.clone())?
+        .commit
+        .ok_or_else(|| anyhow!("Missing commit for layer {}", layer.digest()))
+}
+
+/// Regenerate the layers of a stored image into an OCI directory, returning
+/// the manifest digest and the layers which changed.
+#[context("Regenerating image")]
+fn export_oci(
+    repo: &ostree::Repo,
+    prefix: &RefPrefix,
+    manifest: &ImageManifest,
+    config: &ImageConfiguration,
+    ocidir_path: &Path,
+    compression: ocidir::Compression,
+) -> Result<(String, Vec<ChangedLayer>)> {
+    std::fs::create_dir(ocidir_path).context("Creating OCI dir")?;
+    let writer = ocidir::OciDir::create(Rc::new(openat::Dir::open(ocidir_path)?))?;
+
+    let commit_layer = ostree_commit_layer(manifest, config)?;
+    let commit_idx = manifest
+        .layers()
+        .iter()
+        .position(|l| l.digest() == commit_layer.digest())
+        .unwrap();
+    let (ostree_layers, derived_layers) = manifest.layers().split_at(commit_idx + 1);
+    let (commit_layer, chunk_layers) = ostree_layers.split_last().unwrap();
+    let commit = require_layer_commit(repo, prefix, commit_layer)?;
+
+    let mut layers = Vec::new();
+    if chunk_layers.is_empty() {
+        let mut w = writer.create_raw_layer_compressed(compression)?;
+        crate::tar::export_commit(repo, &commit, &mut w, None)?;
+        layers.push(w.complete()?);
+    } else {
+        let sets = chunk_layers
+            .iter()
+            .map(|l| object_set_from_commit(repo, &require_layer_commit(repo, prefix, l)?))
+            .collect::<Result<Vec<_>>>()?;
+        let mut chunking = crate::chunking::Chunking::from_object_sets(repo, &commit, sets)?;
+        for (i, chunk) in chunking.take_chunks().into_iter().enumerate() {
+            let mut w = writer.create_layer_compressed(compression)?;
+            crate::tar::export_chunk(repo, &chunk, &mut w)
+                .with_context(|| format!("Exporting chunk {}", i))?;
+            layers.push(w.into_inner()?.complete()?);
+        }
+        let mut w = writer.create_layer_compressed(compression)?;
+        crate::tar::export_final_chunk(repo, &chunking, &mut w)?;
+        layers.push(w.into_inner()?.complete()?);
+    }
+    for layer in derived_layers {
+        let layer_commit = require_layer_commit(repo, prefix, layer)?;
+        let mut w = writer.create_raw_layer_compressed(compression)?;
+        #[allow(clippy::needless_update)]
+        let options = crate::tar::ExportOptions {
+            format: crate::tar::ExportFormat::Rootfs,
+            deterministic: true,
+            ..Default::default()
+        };
+        crate::tar::export_commit(repo, &layer_commit, &mut w, Some(options))
+            .with_context(|| format!("Exporting layer {}", layer.digest()))?;
+        layers.push(w.complete()?);
+    }
+
+    let mut new_manifest = manifest.clone();
+    let mut new_config = config.clone();
+    let mut diff_ids = Vec::new();
+    let mut changed_layers = Vec::new();
+    new_manifest.layers_mut().clear();
+    for (original, layer) in manifest.layers().iter().zip(layers) {
+        let mut annotations = original.annotations().clone().unwrap_or_default();
+        annotations.extend(layer.annotations);
+        let mut builder = layer.blob.descriptor().media_type(layer.media_type);
+        if !annotations.is_empty() {
+            builder = builder.annotations(annotations);
+        }
+        let desc = builder.build().unwrap();
+        if desc.digest() != original.digest() {
+            changed_layers.push(ChangedLayer {
+                original: original.digest().to_string(),
+                digest: desc.digest().to_string(),
+            });
+        }
+        new_manifest.layers_mut().push(desc);
+        diff_ids.push(format!("sha256:{}", layer.uncompressed_sha256));
+    }
+    // The commit layer is found via its diffid, so this must follow a change
+    let commit_diffid = diff_ids[commit_idx].clone();
+    let mut rootfs = new_config.rootfs().clone();
+    *rootfs.diff_ids_mut() = diff_ids;
+    new_config.set_rootfs(rootfs);
+    if let Some(mut ctrcfg) = new_config.config().clone() {
+        if let Some(v) = ctrcfg
+            .labels_mut()
+            .as_mut()
+            .and_then(|l| l.get_mut(OSTREE_DIFFID_LABEL))
+        {
+            *v = commit_diffid;
+        }
+        new_config.set_config(Some(ctrcfg));
+    }
+
+    let platform = oci_image::PlatformBuilder::default()
+        .architecture(new_config.architecture().clone())
+        .os(new_config.os().clone())
+        .build()
+        .unwrap();
+    let config_desc = writer.write_config(new_config)?;
+    new_manifest.set_config(config_desc);
+    let manifest_desc = writer.write_manifest_blob(new_manifest, platform)?;
+    let digest = manifest_desc.digest().to_string();
+    writer.write_index(vec![manifest_desc])?;
+    Ok((digest, changed_layers))
+}
+
+/// Export an image previously stored via [`ImageImporter::import`] to `dest`, without
+/// access to the original image.
+///
+/// The layers are regenerated from their cached commits, keeping the image
+/// configuration, history and annotations.  As the original compressed blobs are
+/// not retained, a layer only keeps its digest if it is reproduced exactly, as is
+/// usually the case for layers generated by [`super::encapsulate()`] with the same
+/// compression.  Any other layers are listed in [`ExportedImage::changed_layers`],
+/// and the image then has a different configuration and manifest digest.
+///
+/// Of the options, the compression and the destination credentials are used.
+pub async fn export(
+    repo: &ostree::Repo,
+    imgref: &OstreeImageReference,
+    dest: &ImageReference,
+    opts: Option<ExportOpts>,
+) -> Result<ExportedImage> {
+    export_with_prefix(repo, &Default::default(), imgref, dest, opts).await
+}
+
+/// Like [`export`], for an image stored under the given ref prefix.
+pub async fn export_with_prefix(
+    repo: &ostree::Repo,
+    prefix: &RefPrefix,
+    imgref: &OstreeImageReference,
+    dest: &ImageReference,
+    opts: Option<ExportOpts>,
+) -> Result<ExportedImage> {
+    let opts = opts.unwrap_or_default();
+    if let Some(authfile) = opts.authfile.as_deref() {
+        skopeo::validate_authfile(authfile)?;
+    }
+    let state = query_image_with_prefix(repo, prefix, imgref)?
+        .ok_or_else(|| anyhow!("Image {} is not stored", imgref))?;
+    let config = state
+        .configuration
+        .as_ref()
+        .ok_or_else(|| anyhow!("Missing image configuration for {}", imgref))?;
+    let compress = opts.compress && dest.transport != Transport::ContainerStorage;
+    let compression = opts.compression.to_ocidir(compress);
+    let dest = if dest.transport == Transport::DockerArchive {
+        super::encapsulate::docker_archive_dest(dest)?
+    } else {
+        Cow::Borrowed(dest)
+    };
+    let (manifest_digest, changed_layers) = if dest.transport == Transport::OciDir {
+        export_oci(
+            repo,
+            prefix,
+            &state.manifest,
+            config,
+            Path::new(dest.name.as_str()),
+            compression,
+        )?
+    } else {
+        let tempdir = tempfile::tempdir_in("/var/tmp")?;
+        let tempdest = tempdir.path().join("d");
+        let (_, changed_layers) = export_oci(
+            repo,
+            prefix,
+            &state.manifest,
+            config,
+            &tempdest,
+            compression,
+        )?;
+        let src = ImageReference {
+            transport: Transport::OciDir,
+            name: tempdest.to_str().unwrap().to_string(),
+        };
+        let digest = super::encapsulate::push_image(&src, &dest, false, &opts).await?;
+        (digest, changed_layers)
+    };
+    for layer in changed_layers.iter() {
+        tracing::debug!("Layer {} changed to {}", layer.original, layer.digest);
+    }
+    Ok(ExportedImage {
+        manifest_digest,
+        changed_layers,
+    })
+}
+
+/// Statistics from [`gc_image_layers`] and [`remove_image`].
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct PruneStats {
+    /// The refs which were removed.
+    pub removed_refs: Vec<String>,
+    /// Number of objects no longer referenced by any ref.
+    pub objects_prunable: u64,
+    /// Approximate storage size of those objects, which is freed by a subsequent
+    /// prune of the repository.
+    pub bytes_prunable: u64,
+    /// Number of unused layer refs which were retained, as they belong to a pinned
+    /// image or a deployment.
+    pub skipped_pinned: u64,
+}
+
+/// The refs of the layers used by all stored images, except for `exclude`.
+fn referenced_layer_refs(
+    repo: &ostree::Repo,
+    prefix: &RefPrefix,
+    exclude: Option<&str>,
+) -> Result<BTreeSet<String>> {
+    let mut r = BTreeSet::new();
+    for imgname in list_image_names(repo, prefix)? {
+        if Some(imgname.as_str()) == exclude {
+            continue;
+        }
+        let ostree_ref = refescape::prefix_escape_for_ref(&prefix.images(), &imgname)?;
+        let rev = repo.require_rev(&ostree_ref)?;
+        let layers = layer_refs_for_commit(repo, prefix, rev.as_str())?
+            .ok_or_else(|| anyhow!("Missing {} metadata", META_MANIFEST))
+            .with_context(|| format!("Reading manifest for {}", imgname))?;
+        r.extend(layers);
+    }
+    Ok(r)
+}
+
+/// The refs of the layers of an image merge commit, from its stored manifest, along
+/// with the ref of its preserved base image; `None` if this is not a merge commit
+/// (e.g. the base commit of a non-layered image).
+fn layer_refs_for_commit(
+    repo: &ostree::Repo,
+    prefix: &RefPrefix,
+    commit: &str,
+) -> Result<Option<Vec<String>>> {
+    let (commit_obj, _) = repo.load_commit(commit)?;
+    let commit_meta = &glib::VariantDict::new(Some(&commit_obj.child_value(0)));
+    if commit_meta.lookup_value(META_MANIFEST, None).is_none() {
+        return Ok(None);
     }
+    let (manifest, _) = manifest_data_from_commitmeta(commit_meta)?;
+    let mut r = manifest
+        .layers()
+        .iter()
+        .map(|l| ref_for_layer(prefix, l))
+        .collect::<Result<Vec<_>>>()?;
+    r.extend(ref_for_base_image(prefix, &manifest)?);
+    Ok(Some(r))
+}
+
+/// Pin or unpin a stored image.  Pinning retains the current version of the image,
+/// i.e. its layers are not removed by [`gc_image_layers`] even after the image is
+/// updated, and [`remove_image`] fails; pin the image again to retain a newer version.
+pub fn set_image_pinned(repo: &ostree::Repo, imgref: &ImageReference, pinned: bool) -> Result<()> {
+    set_image_pinned_with_prefix(repo, &Default::default(), imgref, pinned)
+}
+
+/// Like [`set_image_pinned`], for an image stored under the given ref prefix.
+#[context("Pinning image {}", imgref)]
+pub fn set_image_pinned_with_prefix(
+    repo: &ostree::Repo,
+    prefix: &RefPrefix,
+    imgref: &ImageReference,
+    pinned: bool,
+) -> Result<()> {
+    let cancellable = gio::NONE_CANCELLABLE;
+    let pinned_ref = refescape::prefix_escape_for_ref(&prefix.pinned(), &imgref.to_string())?;
+    let commit = if pinned {
+        let commit = repo
+            .resolve_rev(&ref_for_image(prefix, imgref)?, true)?
+            .ok_or_else(|| anyhow!("Image not found: {}", imgref))?;
+        Some(commit)
+    } else {
+        None
+    };
+    repo.set_ref_immediate(None, &pinned_ref, commit.as_deref(), cancellable)?;
     Ok(())
 }
 
-/// Remove the specified images and their corresponding blobs.
-pub fn prune_images(_repo: &ostree::Repo, _imgs: &[&str]) -> Result<()> {
-    // Most robust approach is to iterate over all known images, load the
-    // manifest and build the set of reachable blobs, then compute the set
-    // Set(unreachable) = Set(all) - Set(reachable)
-    // And remove the unreachable ones.
-    unimplemented!()
+/// Whether the image is pinned, see [`set_image_pinned`].
+pub fn is_image_pinned(repo: &ostree::Repo, imgref: &ImageReference) -> Result<bool> {
+    is_image_pinned_with_prefix(repo, &Default::default(), imgref)
+}
+
+/// Like [`is_image_pinned`], for an image stored under the given ref prefix.
+pub fn is_image_pinned_with_prefix(
+    repo: &ostree::Repo,
+    prefix: &RefPrefix,
+    imgref: &ImageReference,
+) -> Result<bool> {
+    let pinned_ref = refescape::prefix_escape_for_ref(&prefix.pinned(), &imgref.to_string())?;
+    Ok(repo.resolve_rev(&pinned_ref, true)?.is_some())
+}
+
+/// The commits of all pinned images under the prefix.
+fn pinned_commits(repo: &ostree::Repo, prefix: &RefPrefix) -> Result<BTreeSet<String>> {
+    let refs = repo.list_refs_ext(
+        Some(&prefix.pinned()),
+        ostree::RepoListRefsExtFlags::empty(),
+        gio::NONE_CANCELLABLE,
+    )?;
+    Ok(refs.into_values().map(|c| c.to_string()).collect())
+}
+
+/// Record the merge commit of a newly deployed image by its manifest digest, so
+/// that its layers can be retained after the image is updated; the deployment
+/// itself may only reference the base commit.  The records of images whose
+/// manifest digest is not in `deployed` are removed.
+pub(crate) fn set_deployed_images(
+    repo: &ostree::Repo,
+    prefix: &RefPrefix,
+    state: &LayeredImageState,
+    deployed: &BTreeSet<String>,
+) -> Result<()> {
+    let cancellable = gio::NONE_CANCELLABLE;
+    let deployed_prefix = &prefix.deployed();
+    let refs = repo.list_refs_ext(
+        Some(deployed_prefix),
+        ostree::RepoListRefsExtFlags::empty(),
+        cancellable,
+    )?;
+    for ostree_ref in refs.keys() {
+        let digest = refescape::unprefix_unescape_ref(deployed_prefix, ostree_ref)?;
+        if !deployed.contains(&digest) {
+            repo.set_ref_immediate(None, ostree_ref, None, cancellable)?;
+        }
+    }
+    let ostree_ref = refescape::prefix_escape_for_ref(deployed_prefix, &state.manifest_digest)?;
+    repo.set_ref_immediate(
+        None,
+        &ostree_ref,
+        Some(state.merge_commit.as_str()),
+        cancellable,
+    )?;
+    Ok(())
+}
+
+/// The merge commit recorded by [`set_deployed_images`] for a manifest digest.
+pub(crate) fn deployed_image_commit(
+    repo: &ostree::Repo,
+    prefix: &RefPrefix,
+    manifest_digest: &str,
+) -> Result<Option<String>> {
+    let ostree_ref = refescape::prefix_escape_for_ref(&prefix.deployed(), manifest_digest)?;
+    Ok(repo.resolve_rev(&ostree_ref, true)?.map(|c| c.to_string()))
+}
+
+/// Remove the refs of layers which are not used by any stored image.
+///
+/// The objects of those layers are not deleted directly; the returned statistics
+/// describe what a subsequent prune of the repository will free.  This waits for
+/// imports in progress to complete, as their layers are not referenced by an image
+/// yet, so it may run concurrently with a pull.
+///
+/// The layers of pinned images are retained; see [`set_image_pinned`], and
+/// [`super::deploy::gc_image_layers`] to also retain those of deployments.
+pub fn gc_image_layers(repo: &ostree::Repo) -> Result<PruneStats> {
+    gc_image_layers_with_prefix(repo, &Default::default())
+}
+
+/// Like [`gc_image_layers`], for the layers and images under the given ref prefix
+/// only; layers under other prefixes are left alone.
+pub fn gc_image_layers_with_prefix(repo: &ostree::Repo, prefix: &RefPrefix) -> Result<PruneStats> {
+    gc_image_layers_pinned(repo, prefix, &[])
+}
+
+/// Like [`gc_image_layers`], also retaining the layers of the given commits, which may
+/// be merge commits or the base commits of non-layered images; for the latter, only
+/// the layer holding the commit is known.
+#[context("Pruning image layers")]
+pub(crate) fn gc_image_layers_pinned(
+    repo: &ostree::Repo,
+    prefix: &RefPrefix,
+    pinned: &[String],
+) -> Result<PruneStats> {
+    let cancellable = gio::NONE_CANCELLABLE;
+    let _lock = LayersLock::exclusive(repo)?;
+    let mut layer_refs = repo.list_refs_ext(
+        Some(&prefix.layers()),
+        ostree::RepoListRefsExtFlags::empty(),
+        cancellable,
+    )?;
+    // Preserved base images are retained as long as a derived image uses them.
+    layer_refs.extend(repo.list_refs_ext(
+        Some(&prefix.bases()),
+        ostree::RepoListRefsExtFlags::empty(),
+        cancellable,
+    )?);
+    let referenced = referenced_layer_refs(repo, prefix, None)?;
+    let mut pinned_commits = pinned_commits(repo, prefix)?;
+    pinned_commits.extend(pinned.iter().cloned());
+    let mut pinned_layers = BTreeSet::new();
+    for commit in pinned_commits.iter() {
+        pinned_layers.extend(layer_refs_for_commit(repo, prefix, commit)?.unwrap_or_default());
+    }
+    let mut unreferenced = Vec::new();
+    let mut skipped_pinned = 0;
+    for (ostree_ref, commit) in layer_refs {
+        let ostree_ref = ostree_ref.as_str();
+        if referenced.contains(ostree_ref) {
+            continue;
+        }
+        if pinned_layers.contains(ostree_ref) || pinned_commits.contains(commit.as_str()) {
+            skipped_pinned += 1;
+            continue;
+        }
+        unreferenced.push((ostree_ref.to_string(), commit.to_string()));
+    }
+    let mut stats = remove_refs(repo, unreferenced)?;
+    stats.skipped_pinned = skipped_pinned;
+    Ok(stats)
+}
+
+/// Remove the given refs, computing which objects only they referenced.
+fn remove_refs(repo: &ostree::Repo, refs: Vec<(String, String)>) -> Result<PruneStats> {
+    let cancellable = gio::NONE_CANCELLABLE;
+    let mut stats = PruneStats::default();
+    if refs.is_empty() {
+        return Ok(stats);
+    }
+    let mut removed_commits = Vec::new();
+    for (ostree_ref, commit) in refs {
+        tracing::debug!("Removing {}", ostree_ref);
+        repo.set_ref_immediate(None, &ostree_ref, None, cancellable)?;
+        stats.removed_refs.push(ostree_ref);
+        removed_commits.push(commit);
+    }
+
+    // Everything reachable from the remaining refs is retained.
+    let mut retained = HashSet::new();
+    for (_, commit) in repo.list_refs(None, cancellable)? {
+        for obj in repo.traverse_commit(commit.as_str(), 0, cancellable)? {
+            retained.insert((obj.checksum().to_string(), obj.object_type()));
+        }
+    }
+    let mut prunable = HashSet::new();
+    for commit in removed_commits {
+        for obj in repo.traverse_commit(commit.as_str(), 0, cancellable)? {
+            let obj = (obj.checksum().to_string(), obj.object_type());
+            if !retained.contains(&obj) {
+                prunable.insert(obj);
+            }
+        }
+    }
+    for (checksum, objtype) in prunable {
+        stats.objects_prunable += 1;
+        stats.bytes_prunable +=
+            repo.query_object_storage_size(objtype, checksum.as_str(), cancellable)?;
+    }
+    Ok(stats)
+}
+
+/// Remove a stored image.
+///
+/// This removes the ref for the merged commit, which also holds the cached manifest
+/// and configuration.  If `prune_layers` is set, the refs of its layers which are not
+/// used by any other stored image are removed too.  To check whether the image is
+/// deployed first, use [`super::deploy::remove_image`].  Pinned images can't be
+/// removed, see [`set_image_pinned`].
+pub fn remove_image(
+    repo: &ostree::Repo,
+    imgref: &ImageReference,
+    prune_layers: bool,
+) -> Result<PruneStats> {
+    remove_image_with_prefix(repo, &Default::default(), imgref, prune_layers)
+}
+
+/// Like [`remove_image`], for an image stored under the given ref prefix; only
+/// images under the same prefix are considered when pruning layers.
+#[context("Removing image {}", imgref)]
+pub fn remove_image_with_prefix(
+    repo: &ostree::Repo,
+    prefix: &RefPrefix,
+    imgref: &ImageReference,
+    prune_layers: bool,
+) -> Result<PruneStats> {
+    if is_image_pinned_with_prefix(repo, prefix, imgref)? {
+        return Err(anyhow!("Image is pinned"));
+    }
+    let ostree_ref = ref_for_image(prefix, imgref)?;
+    let merge_commit = repo
+        .resolve_rev(&ostree_ref, true)?
+        .ok_or_else(|| anyhow!("Image not found"))?
+        .to_string();
+    let mut refs = vec![(ostree_ref, merge_commit.clone())];
+    // Held until the refs are removed below.
+    let _lock = if prune_layers {
+        Some(LayersLock::exclusive(repo)?)
+    } else {
+        None
+    };
+    if prune_layers {
+        let (commit_obj, _) = repo.load_commit(&merge_commit)?;
+        let commit_meta = &glib::VariantDict::new(Some(&commit_obj.child_value(0)));
+        let (manifest, _) = manifest_data_from_commitmeta(commit_meta)?;
+        // Layers used by other images, or pinned versions of them, are retained
+        let mut referenced = referenced_layer_refs(repo, prefix, Some(&imgref.to_string()))?;
+        for commit in pinned_commits(repo, prefix)? {
+            referenced.extend(layer_refs_for_commit(repo, prefix, &commit)?.unwrap_or_default());
+        }
+        let layer_refs = manifest
+            .layers()
+            .iter()
+            .map(|l| ref_for_layer(prefix, l))
+            .chain(ref_for_base_image(prefix, &manifest).transpose());
+        for layer_ref in layer_refs {
+            let layer_ref = layer_ref?;
+            if referenced.contains(&layer_ref) || refs.iter().any(|(r, _)| *r == layer_ref) {
+                continue;
+            }
+            if let Some(commit) = repo.resolve_rev(&layer_ref, true)? {
+                refs.push((layer_ref, commit.to_string()));
+            }
+        }
+    }
+    remove_refs(repo, refs)
+}
+
+/// Remove least recently used images, along with their layers which are not used by any
+/// other image; see [`LayeredImageState::last_used`].
+///
+/// The `keep_last_n` most recently used images are always retained.  Of the others,
+/// those last used before `older_than` are removed, or all of them if unset.  Pinned
+/// images are retained, see [`set_image_pinned`]; use [`super::deploy::prune_images`]
+/// to also retain deployed images.
+///
+/// Returns the images which were removed, or with `dry_run`, would be removed.
+pub fn prune_images(
+    repo: &ostree::Repo,
+    keep_last_n: usize,
+    older_than: Option<chrono::DateTime<chrono::Utc>>,
+    dry_run: bool,
+) -> Result<Vec<ImageReference>> {
+    prune_images_with_prefix(repo, &Default::default(), keep_last_n, older_than, dry_run)
+}
+
+/// Like [`prune_images`], for the images stored under the given ref prefix only.
+pub fn prune_images_with_prefix(
+    repo: &ostree::Repo,
+    prefix: &RefPrefix,
+    keep_last_n: usize,
+    older_than: Option<chrono::DateTime<chrono::Utc>>,
+    dry_run: bool,
+) -> Result<Vec<ImageReference>> {
+    prune_images_retaining(repo, prefix, keep_last_n, older_than, dry_run, &[])
+}
+
+/// Like [`prune_images_with_prefix`], also retaining images whose merge commit or
+/// base commit is one of the given commits.
+#[context("Pruning images")]
+pub(crate) fn prune_images_retaining(
+    repo: &ostree::Repo,
+    prefix: &RefPrefix,
+    keep_last_n: usize,
+    older_than: Option<chrono::DateTime<chrono::Utc>>,
+    dry_run: bool,
+    retain: &[String],
+) -> Result<Vec<ImageReference>> {
+    let mut images = Vec::new();
+    for imgname in list_image_names(repo, prefix)? {
+        let imgref = ImageReference::try_from(imgname.as_str())?;
+        let state = query_image_ref_with_prefix(repo, prefix, &imgref)?
+            .ok_or_else(|| anyhow!("Image not found: {}", imgref))?;
+        images.push((imgref, state));
+    }
+    // Most recently used first
+    images.sort_by(|a, b| b.1.last_used.cmp(&a.1.last_used));
+    let mut candidates = Vec::new();
+    for (imgref, state) in images.into_iter().skip(keep_last_n) {
+        if older_than.map_or(false, |t| state.last_used >= t) {
+            continue;
+        }
+        let retained = retain
+            .iter()
+            .any(|c| *c == state.merge_commit || *c == state.base_commit);
+        if retained || is_image_pinned_with_prefix(repo, prefix, &imgref)? {
+            continue;
+        }
+        candidates.push(imgref);
+    }
+    if !dry_run {
+        for imgref in candidates.iter() {
+            remove_image_with_prefix(repo, prefix, imgref, true)?;
+        }
+    }
+    Ok(candidates)
 }
@@ -19,6 +19,9 @@
 //!
 //! Additionally, the proxy "upconverts" manifests into OCI, so we don't need to care
 //! about parsing the Docker manifest format (as used by most registries still).
+//! For a multi-architecture image index (such as generated via
+//! [`super::ExportOpts::additional_arches`]), the proxy selects the manifest
+//! matching the platform of the host.
 //!
 //! [`encapsulate`]: [`super::encapsulate()`]
 
@@ -36,18 +39,497 @@ use containers_image_proxy::{ImageProxy, OpenedImage};
 use fn_error_context::context;
 use futures_util::Future;
 use oci_spec::image as oci_image;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
 use tokio::io::{AsyncBufRead, AsyncRead};
 use tracing::instrument;
 
+/// Download progress of a single layer.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LayerProgress {
+    /// The digest of the layer.
+    pub digest: String,
+    /// Number of (compressed) bytes fetched so far.
+    pub fetched: u64,
+    /// The size of the layer, from its descriptor in the manifest; unset if unknown,
+    /// e.g. for an uncompressed layer from containers-storage.
+    pub total: Option<u64>,
+}
+
+/// The kind of an [`ImportWarning`]; see [`store::ImageImporter::set_fatal_warnings`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ImportWarningKind {
+    /// See [`ImportWarning::NotBootable`].
+    NotBootable,
+    /// See [`ImportWarning::UnexpectedLayer`].
+    UnexpectedLayer,
+    /// See [`ImportWarning::SkippedEntries`].
+    SkippedEntries,
+}
+
+/// A non-fatal issue found while importing an image.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ImportWarning {
+    /// The image doesn't have the `ostree.bootable` label, so it may not be deployable.
+    NotBootable,
+    /// A non-ostree layer without the [`EXTRA_LAYER_ANNOTATION`] was not imported,
+    /// as only the ostree commit was unencapsulated; its content is missing.
+    /// See [`UnencapsulateOptions::allow_unexpected_layers`].
+    UnexpectedLayer {
+        /// The digest of the layer.
+        digest: String,
+    },
+    /// Entries of an unsupported type were skipped in a derived layer; see
+    /// [`crate::tar::UnsupportedFileTypePolicy`].
+    SkippedEntries {
+        /// The digest of the layer.
+        digest: String,
+        /// The number of skipped entries, by type.
+        skipped: BTreeMap<String, u32>,
+    },
+}
+
+impl ImportWarning {
+    /// The kind of this warning.
+    pub fn kind(&self) -> ImportWarningKind {
+        match self {
+            Self::NotBootable => ImportWarningKind::NotBootable,
+            Self::UnexpectedLayer { .. } => ImportWarningKind::UnexpectedLayer,
+            Self::SkippedEntries { .. } => ImportWarningKind::SkippedEntries,
+        }
+    }
+}
+
+impl std::fmt::Display for ImportWarning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NotBootable => write!(f, "Image does not have the ostree.bootable label"),
+            Self::UnexpectedLayer { digest } => write!(f, "Ignored non-ostree layer {}", digest),
+            Self::SkippedEntries { digest, skipped } => {
+                let skipped: Vec<_> = skipped
+                    .iter()
+                    .map(|(k, n)| format!("{} {}", n, k))
+                    .collect();
+                write!(
+                    f,
+                    "Skipped unsupported entries in layer {}: {}",
+                    digest,
+                    skipped.join(", ")
+                )
+            }
+        }
+    }
+}
+
+/// A failed layer fetch which is being retried; see [`RetryPolicy`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RetryEvent {
+    /// The digest of the layer.
+    pub digest: String,
+    /// The attempt which failed, starting from 1.
+    pub attempt: u32,
+    /// The error.
+    pub error: String,
+}
+
 /// The result of an import operation
-#[derive(Copy, Clone, Debug, Default)]
+#[derive(Clone, Debug, Default)]
 pub struct UnencapsulationProgress {
     /// Number of bytes downloaded (approximate)
     pub processed_bytes: u64,
+    /// The layers currently being fetched.
+    pub layers: Vec<LayerProgress>,
+    /// Number of retried layer fetches.
+    pub retries: u32,
+    /// The most recent retry, if any.
+    pub last_retry: Option<RetryEvent>,
+    /// Warnings found so far.
+    pub warnings: Vec<ImportWarning>,
+}
+
+impl UnencapsulationProgress {
+    /// Create a progress update with only a byte count, as used before per-layer
+    /// progress was added.
+    pub fn new(processed_bytes: u64) -> Self {
+        Self {
+            processed_bytes,
+            ..Default::default()
+        }
+    }
+}
+
+pub(crate) type Progress = tokio::sync::watch::Sender<UnencapsulationProgress>;
+
+/// How failed layer fetches are retried, e.g. due to a flaky registry.  Layers
+/// which were completely fetched are not fetched again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// The maximum number of attempts for each layer; 1 disables retries.
+    pub attempts: u32,
+    /// The delay before the first retry; it doubles for each further retry.
+    pub backoff: std::time::Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            attempts: 1,
+            backoff: std::time::Duration::from_secs(1),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// The delay after the given failed attempt.
+    fn delay(&self, attempt: u32) -> std::time::Duration {
+        let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+        self.backoff
+            .checked_mul(factor)
+            .unwrap_or(std::time::Duration::MAX)
+    }
+}
+
+/// Handle a failed attempt to fetch a layer: if the policy allows another attempt,
+/// report the retry and wait before returning `Ok`.  Otherwise, return the error.
+pub(crate) async fn wait_for_retry(
+    policy: &RetryPolicy,
+    layer: &oci_image::Descriptor,
+    attempt: u32,
+    err: anyhow::Error,
+    progress: Option<&Arc<Mutex<Progress>>>,
+) -> Result<()> {
+    // Fetching the same content again won't satisfy the policy.
+    let policy_violation = err
+        .downcast_ref::<crate::tar::ContentPolicyViolation>()
+        .is_some();
+    if attempt >= policy.attempts || policy_violation {
+        return Err(err);
+    }
+    let error = format!("{:#}", err);
+    tracing::warn!(
+        "Fetching layer {} failed (attempt {}/{}): {}",
+        layer.digest(),
+        attempt,
+        policy.attempts,
+        error
+    );
+    if let Some(progress) = progress {
+        update_progress(progress, |state| {
+            state.retries += 1;
+            state.last_retry = Some(RetryEvent {
+                digest: layer.digest().to_string(),
+                attempt,
+                error,
+            });
+        });
+    }
+    tokio::time::sleep(policy.delay(attempt)).await;
+    Ok(())
 }
 
-type Progress = tokio::sync::watch::Sender<UnencapsulationProgress>;
+/// Send a progress update for a layer at most this often, in bytes.
+const LAYER_PROGRESS_INTERVAL: u64 = 256 * 1024;
+
+/// Update the progress state, ignoring errors; if the caller disconnected from
+/// progress that's OK.
+fn update_progress(progress: &Mutex<Progress>, f: impl FnOnce(&mut UnencapsulationProgress)) {
+    let progress = progress.lock().unwrap();
+    let mut state = progress.borrow().clone();
+    f(&mut state);
+    let _ = progress.send(state);
+}
+
+/// A wrapper for the compressed stream of a layer, which reports how much
+/// of it was fetched.  The layer is removed from the progress when this is dropped.
+pub(crate) struct LayerProgressReader<T> {
+    reader: T,
+    progress: Arc<Mutex<Progress>>,
+    digest: String,
+    fetched: u64,
+    reported: u64,
+}
+
+impl<T> LayerProgressReader<T> {
+    fn new(reader: T, progress: Arc<Mutex<Progress>>, layer: &oci_image::Descriptor) -> Self {
+        let digest = layer.digest().to_string();
+        update_progress(&progress, |state| {
+            state.layers.push(LayerProgress {
+                digest: digest.clone(),
+                fetched: 0,
+                total: u64::try_from(layer.size()).ok(),
+            })
+        });
+        Self {
+            reader,
+            progress,
+            digest,
+            fetched: 0,
+            reported: 0,
+        }
+    }
+
+    fn add_fetched(&mut self, n: u64, eof: bool) {
+        self.fetched += n;
+        if !eof && self.fetched - self.reported < LAYER_PROGRESS_INTERVAL {
+            return;
+        }
+        self.reported = self.fetched;
+        let (digest, fetched) = (&self.digest, self.fetched);
+        update_progress(&self.progress, |state| {
+            if let Some(l) = state.layers.iter_mut().find(|l| &l.digest == digest) {
+                l.fetched = fetched;
+            }
+        });
+    }
+}
+
+impl<T> Drop for LayerProgressReader<T> {
+    fn drop(&mut self) {
+        let digest = &self.digest;
+        update_progress(&self.progress, |state| {
+            state.layers.retain(|l| &l.digest != digest)
+        });
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for LayerProgressReader<T> {
+    fn poll_read(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        let len = buf.filled().len();
+        let r = std::pin::Pin::new(&mut self.reader).poll_read(cx, buf);
+        if let std::task::Poll::Ready(Ok(())) = r {
+            let read = (buf.filled().len() - len) as u64;
+            self.add_fetched(read, read == 0);
+        }
+        r
+    }
+}
+
+impl<T: AsyncBufRead + Unpin> AsyncBufRead for LayerProgressReader<T> {
+    fn poll_fill_buf(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<&[u8]>> {
+        let this = self.get_mut();
+        let r = std::pin::Pin::new(&mut this.reader).poll_fill_buf(cx);
+        if let std::task::Poll::Ready(Ok(buf)) = &r {
+            if buf.is_empty() {
+                this.add_fetched(0, true);
+            }
+        }
+        r
+    }
+
+    fn consume(mut self: std::pin::Pin<&mut Self>, amt: usize) {
+        std::pin::Pin::new(&mut self.reader).consume(amt);
+        self.add_fetched(amt as u64, false);
+    }
+}
+
+/// A token bucket limiting the aggregate rate of layer fetches, in bytes per second.
+/// Up to one second worth of bytes may be read in a burst.
+#[derive(Debug)]
+pub(crate) struct RateLimiter {
+    rate: f64,
+    /// The available bytes, which may be negative after a large read, and when
+    /// this was last updated.
+    state: Mutex<(f64, std::time::Instant)>,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(bytes_per_sec: u64) -> Self {
+        let rate = bytes_per_sec.max(1) as f64;
+        Self {
+            rate,
+            state: Mutex::new((rate, std::time::Instant::now())),
+        }
+    }
+
+    /// How long to wait before reading more, if nothing is available.
+    fn delay(&self) -> Option<std::time::Duration> {
+        let mut state = self.state.lock().unwrap();
+        let now = std::time::Instant::now();
+        let elapsed = now.duration_since(state.1).as_secs_f64();
+        state.0 = (state.0 + elapsed * self.rate).min(self.rate);
+        state.1 = now;
+        if state.0 > 0.0 {
+            None
+        } else {
+            Some(std::time::Duration::from_secs_f64(
+                (1.0 - state.0) / self.rate,
+            ))
+        }
+    }
+
+    fn consume(&self, n: usize) {
+        self.state.lock().unwrap().0 -= n as f64;
+    }
+}
+
+/// A wrapper for the compressed stream of a layer, which waits on a shared
+/// [`RateLimiter`] before reading.
+pub(crate) struct RateLimitedReader<T> {
+    reader: T,
+    limiter: Arc<RateLimiter>,
+    sleep: Option<std::pin::Pin<Box<tokio::time::Sleep>>>,
+}
+
+impl<T> RateLimitedReader<T> {
+    fn new(reader: T, limiter: Arc<RateLimiter>) -> Self {
+        Self {
+            reader,
+            limiter,
+            sleep: None,
+        }
+    }
+
+    fn poll_wait(&mut self, cx: &mut std::task::Context<'_>) -> std::task::Poll<()> {
+        loop {
+            if let Some(sleep) = self.sleep.as_mut() {
+                futures_util::ready!(sleep.as_mut().poll(cx));
+                self.sleep = None;
+            }
+            match self.limiter.delay() {
+                Some(delay) => self.sleep = Some(Box::pin(tokio::time::sleep(delay))),
+                None => return std::task::Poll::Ready(()),
+            }
+        }
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for RateLimitedReader<T> {
+    fn poll_read(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        futures_util::ready!(self.poll_wait(cx));
+        let len = buf.filled().len();
+        let r = std::pin::Pin::new(&mut self.reader).poll_read(cx, buf);
+        if let std::task::Poll::Ready(Ok(())) = r {
+            self.limiter.consume(buf.filled().len() - len);
+        }
+        r
+    }
+}
+
+impl<T: AsyncBufRead + Unpin> AsyncBufRead for RateLimitedReader<T> {
+    fn poll_fill_buf(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<&[u8]>> {
+        let this = self.get_mut();
+        futures_util::ready!(this.poll_wait(cx));
+        std::pin::Pin::new(&mut this.reader).poll_fill_buf(cx)
+    }
+
+    fn consume(mut self: std::pin::Pin<&mut Self>, amt: usize) {
+        std::pin::Pin::new(&mut self.reader).consume(amt);
+        self.limiter.consume(amt);
+    }
+}
+
+/// The expected `diff_id` of a layer: its index in the manifest and the digest
+/// of its uncompressed content from the image configuration.
+pub(crate) type ExpectedDiffId = (usize, String);
+
+/// A wrapper for the uncompressed stream of a layer, which fails at the end of the
+/// stream if its digest doesn't match the `diff_id` from the image configuration.
+/// Consumers must read the stream to its end.
+pub(crate) struct DiffIdVerifier<T> {
+    reader: T,
+    expected: Option<ExpectedDiffId>,
+    hasher: openssl::sha::Sha256,
+}
+
+impl<T> DiffIdVerifier<T> {
+    /// If `expected` is `None`, the stream is passed through unverified.
+    pub(crate) fn new(reader: T, expected: Option<ExpectedDiffId>) -> Self {
+        Self {
+            reader,
+            expected,
+            hasher: openssl::sha::Sha256::new(),
+        }
+    }
+
+    fn finish(&mut self) -> std::io::Result<()> {
+        let (index, expected) = match self.expected.take() {
+            Some(v) => v,
+            None => return Ok(()),
+        };
+        let hasher = std::mem::replace(&mut self.hasher, openssl::sha::Sha256::new());
+        let found = format!("sha256:{}", hex::encode(hasher.finish()));
+        if found != expected {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "Layer {}: diff_id mismatch; expected {} but found {}",
+                    index, expected, found
+                ),
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for DiffIdVerifier<T> {
+    fn poll_read(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        let len = buf.filled().len();
+        let r = std::pin::Pin::new(&mut self.reader).poll_read(cx, buf);
+        if let std::task::Poll::Ready(Ok(())) = r {
+            if self.expected.is_some() {
+                let read = &buf.filled()[len..];
+                if read.is_empty() && buf.remaining() > 0 {
+                    return std::task::Poll::Ready(self.finish());
+                }
+                self.hasher.update(read);
+            }
+        }
+        r
+    }
+}
+
+/// Collects the warnings of an import, failing for those which are fatal.
+#[derive(Debug)]
+pub(crate) struct ImportWarnings {
+    fatal: Vec<ImportWarningKind>,
+    progress: Option<Arc<Mutex<Progress>>>,
+    pub(crate) warnings: Vec<ImportWarning>,
+}
+
+impl ImportWarnings {
+    pub(crate) fn new(
+        fatal: Vec<ImportWarningKind>,
+        progress: Option<Arc<Mutex<Progress>>>,
+    ) -> Self {
+        Self {
+            fatal,
+            progress,
+            warnings: Vec::new(),
+        }
+    }
+
+    /// Record a warning, or return it as an error if it is fatal.
+    pub(crate) fn warn(&mut self, warning: ImportWarning) -> Result<()> {
+        if self.fatal.contains(&warning.kind()) {
+            return Err(anyhow!("{}", warning));
+        }
+        tracing::warn!("{}", warning);
+        if let Some(progress) = self.progress.as_ref() {
+            update_progress(progress, |state| state.warnings.push(warning.clone()));
+        }
+        self.warnings.push(warning);
+        Ok(())
+    }
+}
 
 /// A read wrapper that updates the download progress.
 #[pin_project::pin_project]
@@ -70,17 +552,10 @@ impl<T: AsyncRead> AsyncRead for ProgressReader<T> {
         match this.reader.poll_read(cx, buf) {
             v @ std::task::Poll::Ready(Ok(_)) => {
                 if let Some(progress) = this.progress.as_ref().get_ref() {
-                    let progress = progress.lock().unwrap();
-                    let state = {
-                        let mut state = *progress.borrow();
-                        let newlen = buf.filled().len();
-                        debug_assert!(newlen >= len);
-                        let read = (newlen - len) as u64;
-                        state.processed_bytes += read;
-                        state
-                    };
-                    // Ignore errors, if the caller disconnected from progress that's OK.
-                    let _ = progress.send(state);
+                    let newlen = buf.filled().len();
+                    debug_assert!(newlen >= len);
+                    let read = (newlen - len) as u64;
+                    update_progress(progress, |state| state.processed_bytes += read);
                 }
                 v
             }
@@ -93,9 +568,11 @@ async fn fetch_manifest_impl(
     proxy: &mut ImageProxy,
     imgref: &OstreeImageReference,
 ) -> Result<(oci_spec::image::ImageManifest, String)> {
+    super::skopeo::validate_archive_ref(&imgref.imgref)?;
     let oi = &proxy.open_image(&imgref.imgref.to_string()).await?;
     let (digest, manifest) = proxy.fetch_manifest(oi).await?;
     proxy.close_image(oi).await?;
+    imgref.imgref.verify_digest(&digest)?;
     Ok((manifest, digest))
 }
 
@@ -108,6 +585,13 @@ pub async fn fetch_manifest(
     fetch_manifest_impl(&mut proxy, imgref).await
 }
 
+/// Fetch the summary of the encapsulated commit from the manifest annotations, if
+/// present; see [`ContentInfo`].
+pub async fn fetch_content_info(imgref: &OstreeImageReference) -> Result<Option<ContentInfo>> {
+    let (manifest, _) = fetch_manifest(imgref).await?;
+    ContentInfo::from_manifest(&manifest)
+}
+
 /// The result of an import operation
 #[derive(Debug)]
 pub struct Import {
@@ -115,6 +599,10 @@ pub struct Import {
     pub ostree_commit: String,
     /// The image digest retrieved
     pub image_digest: String,
+    /// The image reference pinned to [`Self::image_digest`], for registry images.
+    pub pinned_imgref: Option<OstreeImageReference>,
+    /// Non-fatal issues found while importing.
+    pub warnings: Vec<ImportWarning>,
 }
 
 /// Use this to process potential errors from a worker and a driver.
@@ -157,6 +645,22 @@ pub(crate) async fn join_fetch<T: std::fmt::Debug>(
 pub struct UnencapsulateOptions {
     /// Channel which will receive progress updates
     pub progress: Option<tokio::sync::watch::Sender<UnencapsulationProgress>>,
+    /// Path to a Docker-formatted authentication file for the registry.
+    pub authfile: Option<PathBuf>,
+    /// Username and password for the registry; this takes precedence over `authfile`.
+    pub credentials: Option<(String, String)>,
+    /// How to retry failed layer fetches; by default, the policy of the importer is used.
+    pub retry: Option<RetryPolicy>,
+    /// Network settings for registry fetches.
+    pub network: Option<store::NetworkConfig>,
+    /// Whether to verify each layer against its `diff_id`; by default, the setting of
+    /// the importer is used.  See [`store::ImageImporter::set_verify_diff_ids`].
+    pub verify_diff_ids: Option<bool>,
+    /// Limit the rate of layer fetches, in bytes per second.
+    pub rate_limit: Option<u64>,
+    /// Skip non-ostree layers without the [`EXTRA_LAYER_ANNOTATION`] instead of failing,
+    /// emitting an [`ImportWarning::UnexpectedLayer`] for each.
+    pub allow_unexpected_layers: bool,
 }
 
 /// Fetch a container image and import its embedded OSTree commit.
@@ -167,17 +671,36 @@ pub async fn unencapsulate(
     imgref: &OstreeImageReference,
     options: Option<UnencapsulateOptions>,
 ) -> Result<Import> {
-    let mut importer = super::store::ImageImporter::new(repo, imgref, Default::default()).await?;
+    let mut options = options.unwrap_or_default();
+    let mut config = store::ImageProxyConfig::default();
+    // This must live until the image has been fetched.
+    let creds_authfile = options
+        .credentials
+        .take()
+        .map(|creds| super::skopeo::authfile_for_credentials(&imgref.imgref, &creds))
+        .transpose()?;
+    if let Some(f) = creds_authfile.as_ref() {
+        config.authfile = Some(f.path().to_owned());
+    } else if let Some(authfile) = options.authfile.take() {
+        super::skopeo::validate_authfile(&authfile)?;
+        config.authfile = Some(authfile);
+    }
+    let network = options.network.take().unwrap_or_default();
+    let mut importer =
+        super::store::ImageImporter::new_with_network(repo, imgref, config, network).await?;
+    importer.set_rate_limit(options.rate_limit);
     let prep = match importer.prepare().await? {
         store::PrepareResult::AlreadyPresent(r) => {
             return Ok(Import {
                 ostree_commit: r.base_commit,
                 image_digest: r.manifest_digest,
+                pinned_imgref: r.pinned_imgref,
+                warnings: Vec::new(),
             });
         }
         store::PrepareResult::Ready(r) => r,
     };
-    importer.unencapsulate(prep, options).await
+    importer.unencapsulate(prep, Some(options)).await
 }
 
 /// Create a decompressor for this MIME type, given a stream of input.
@@ -189,25 +712,42 @@ fn new_async_decompressor<'a>(
         oci_image::MediaType::ImageLayerGzip => Ok(Box::new(tokio::io::BufReader::new(
             async_compression::tokio::bufread::GzipDecoder::new(src),
         ))),
+        oci_image::MediaType::ImageLayerZstd => {
+            let mut decoder = async_compression::tokio::bufread::ZstdDecoder::new(src);
+            // Layers may consist of multiple frames, e.g. in the zstd:chunked format.
+            decoder.multiple_members(true);
+            Ok(Box::new(tokio::io::BufReader::new(decoder)))
+        }
         oci_image::MediaType::ImageLayer => Ok(Box::new(src)),
         o => Err(anyhow::anyhow!("Unhandled layer type: {}", o)),
     }
 }
 
 /// A wrapper for [`get_blob`] which fetches a layer and decompresses it.
-#[instrument(skip(proxy, img, layer))]
+/// If `progress` is set, the fetched bytes of the layer are reported there.
+#[instrument(skip(proxy, img, layer, progress))]
 pub(crate) async fn fetch_layer_decompress<'a>(
     proxy: &'a mut ImageProxy,
     img: &OpenedImage,
     layer: &oci_image::Descriptor,
+    progress: Option<&Arc<Mutex<Progress>>>,
+    rate_limit: Option<&Arc<RateLimiter>>,
 ) -> Result<(
     Box<dyn AsyncBufRead + Send + Unpin>,
     impl Future<Output = Result<()>> + 'a,
 )> {
     tracing::debug!("fetching {}", layer.digest());
+    // An unknown size (-1) is passed through; the proxy reads it back as signed.
     let (blob, driver) = proxy
         .get_blob(img, layer.digest().as_str(), layer.size() as u64)
         .await?;
+    let mut blob: Box<dyn AsyncBufRead + Send + Unpin> = Box::new(blob);
+    if let Some(limiter) = rate_limit {
+        blob = Box::new(RateLimitedReader::new(blob, Arc::clone(limiter)));
+    }
+    if let Some(progress) = progress {
+        blob = Box::new(LayerProgressReader::new(blob, Arc::clone(progress), layer));
+    }
     let blob = new_async_decompressor(layer.media_type(), blob)?;
     Ok((blob, driver))
 }
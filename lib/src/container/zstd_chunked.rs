@@ -0,0 +1,324 @@
+//! Internal API to write layers in the `zstd:chunked` format, as understood by
+//! containers/storage.
+//!
+//! Such a layer is a regular zstd compressed tar stream, except that the content
+//! of each regular file is in a separate zstd frame.  A table of contents (TOC)
+//! naming each file and the offsets of its frame is appended in a skippable frame,
+//! followed by a fixed size footer locating the TOC.  This allows clients to
+//! fetch just the files they do not already have.
+
+use anyhow::{anyhow, Context, Result};
+use fn_error_context::context;
+use openssl::hash::{Hasher, MessageDigest};
+use ostree::glib;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryInto;
+use std::io::prelude::*;
+
+/// Layer annotation holding the digest of the compressed TOC.
+pub(crate) const MANIFEST_CHECKSUM_KEY: &str =
+    "io.github.containers.zstd-chunked.manifest-checksum";
+/// Layer annotation holding `offset:length:uncompressed length:type` of the TOC.
+pub(crate) const MANIFEST_INFO_KEY: &str = "io.github.containers.zstd-chunked.manifest-position";
+/// The TOC format, compatible with the one used by CRFS.
+const MANIFEST_TYPE_CRFS: u64 = 1;
+/// Size of the footer.
+const FOOTER_SIZE: usize = 40;
+/// Magic bytes of a zstd skippable frame, which decompressors ignore.
+const SKIPPABLE_FRAME_MAGIC: &[u8] = &[0x50, 0x2a, 0x4d, 0x18];
+/// Magic bytes at the end of the footer.
+const ZSTD_CHUNKED_FRAME_MAGIC: &[u8] = &[0x47, 0x6e, 0x55, 0x6c, 0x49, 0x6e, 0x55, 0x78];
+/// Prefix for PAX records holding extended attributes.
+const PAX_XATTR_PREFIX: &str = "SCHILY.xattr.";
+
+#[derive(Debug, Serialize)]
+struct Toc {
+    version: u32,
+    entries: Vec<TocEntry>,
+}
+
+fn is_zero(v: &u64) -> bool {
+    *v == 0
+}
+
+#[derive(Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct TocEntry {
+    #[serde(rename = "type")]
+    ty: &'static str,
+    name: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    link_name: String,
+    #[serde(skip_serializing_if = "is_zero")]
+    mode: u64,
+    #[serde(skip_serializing_if = "is_zero")]
+    size: u64,
+    #[serde(skip_serializing_if = "is_zero")]
+    uid: u64,
+    #[serde(skip_serializing_if = "is_zero")]
+    gid: u64,
+    #[serde(rename = "modtime")]
+    mod_time: String,
+    #[serde(skip_serializing_if = "is_zero")]
+    dev_major: u64,
+    #[serde(skip_serializing_if = "is_zero")]
+    dev_minor: u64,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    xattrs: BTreeMap<String, String>,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    digest: String,
+    #[serde(skip_serializing_if = "is_zero")]
+    offset: u64,
+    #[serde(skip_serializing_if = "is_zero")]
+    end_offset: u64,
+}
+
+/// A writer which tracks the number of bytes written.
+struct CountingWriter<W> {
+    inner: W,
+    count: u64,
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.count += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// An entry in the tar stream, and the location of its content.
+#[derive(Debug)]
+struct Entry {
+    toc: TocEntry,
+    file_position: u64,
+}
+
+fn entry_type(t: tar::EntryType) -> Option<&'static str> {
+    let r = match t {
+        tar::EntryType::Regular | tar::EntryType::Continuous => "reg",
+        tar::EntryType::Link => "hardlink",
+        tar::EntryType::Symlink => "symlink",
+        tar::EntryType::Char => "char",
+        tar::EntryType::Block => "block",
+        tar::EntryType::Directory => "dir",
+        tar::EntryType::Fifo => "fifo",
+        _ => return None,
+    };
+    Some(r)
+}
+
+/// Parse the tar stream, gathering the TOC entries.
+fn read_entries(src: impl Read) -> Result<Vec<Entry>> {
+    let mut archive = tar::Archive::new(src);
+    let mut r = Vec::new();
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let header = entry.header();
+        let ty = match entry_type(header.entry_type()) {
+            Some(t) => t,
+            None => continue,
+        };
+        let mtime = header.mtime()?;
+        let mod_time = mtime
+            .try_into()
+            .ok()
+            .and_then(|t| chrono::NaiveDateTime::from_timestamp_opt(t, 0))
+            .ok_or_else(|| anyhow!("Invalid mtime {}", mtime))?;
+        let mod_time = chrono::DateTime::<chrono::Utc>::from_utc(mod_time, chrono::Utc)
+            .to_rfc3339_opts(chrono::SecondsFormat::Secs, true);
+        let mut toc = TocEntry {
+            ty,
+            name: String::from_utf8_lossy(&entry.path_bytes()).into_owned(),
+            link_name: entry
+                .link_name_bytes()
+                .map(|l| String::from_utf8_lossy(&l).into_owned())
+                .unwrap_or_default(),
+            mode: u64::from(header.mode()?),
+            size: entry.size(),
+            uid: header.uid()?,
+            gid: header.gid()?,
+            mod_time,
+            dev_major: header.device_major()?.map(u64::from).unwrap_or_default(),
+            dev_minor: header.device_minor()?.map(u64::from).unwrap_or_default(),
+            ..Default::default()
+        };
+        if let Some(extensions) = entry.pax_extensions()? {
+            for ext in extensions {
+                let ext = ext?;
+                let key = ext.key().map_err(|e| anyhow!("Invalid PAX key: {}", e))?;
+                if let Some(name) = key.strip_prefix(PAX_XATTR_PREFIX) {
+                    let value = glib::base64_encode(ext.value_bytes());
+                    toc.xattrs.insert(name.to_string(), value.to_string());
+                }
+            }
+        }
+        r.push(Entry {
+            toc,
+            file_position: entry.raw_file_position(),
+        });
+    }
+    Ok(r)
+}
+
+fn append_skippable_frame(dest: &mut impl Write, data: &[u8]) -> Result<()> {
+    let len: u32 = data.len().try_into()?;
+    dest.write_all(SKIPPABLE_FRAME_MAGIC)?;
+    dest.write_all(&len.to_le_bytes())?;
+    dest.write_all(data)?;
+    Ok(())
+}
+
+/// Compress the uncompressed tar stream in `src` into `dest` in the `zstd:chunked`
+/// format.  The returned annotations must be added to the layer descriptor.
+#[context("Compressing zstd:chunked layer")]
+pub(crate) fn compress(
+    mut src: impl Read + Seek,
+    dest: impl Write,
+    level: i32,
+) -> Result<HashMap<String, String>> {
+    let mut entries = read_entries(&mut src)?;
+    src.seek(std::io::SeekFrom::Start(0))?;
+
+    let mut dest = CountingWriter {
+        inner: dest,
+        count: 0,
+    };
+    let mut encoder = zstd::stream::write::Encoder::new(&mut dest, level)?;
+    let mut pos = 0u64;
+    for entry in entries.iter_mut() {
+        // Headers (and padding of the previous entry) go in the current frame.
+        std::io::copy(
+            &mut (&mut src).take(entry.file_position - pos),
+            &mut encoder,
+        )?;
+        pos = entry.file_position;
+        if entry.toc.ty != "reg" || entry.toc.size == 0 {
+            continue;
+        }
+        // File content gets its own frame.
+        let dest = encoder.finish()?;
+        let offset = dest.count;
+        let mut payload_encoder = zstd::stream::write::Encoder::new(dest, level)?;
+        let mut hasher = Hasher::new(MessageDigest::sha256())?;
+        let mut payload = (&mut src).take(entry.toc.size);
+        let mut buf = [0u8; 8192];
+        loop {
+            let n = payload.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buf[..n])?;
+            payload_encoder.write_all(&buf[..n])?;
+        }
+        pos += entry.toc.size;
+        let dest = payload_encoder.finish()?;
+        entry.toc.digest = format!("sha256:{}", hex::encode(hasher.finish()?));
+        entry.toc.offset = offset;
+        entry.toc.end_offset = dest.count;
+        encoder = zstd::stream::write::Encoder::new(dest, level)?;
+    }
+    // The remaining padding and the end of archive marker.
+    std::io::copy(&mut src, &mut encoder)?;
+    let dest = encoder.finish()?;
+
+    let toc = Toc {
+        version: 1,
+        entries: entries.into_iter().map(|e| e.toc).collect(),
+    };
+    let toc = serde_json::to_vec(&toc)?;
+    let compressed_toc = zstd::stream::encode_all(toc.as_slice(), level)?;
+    let mut hasher = Hasher::new(MessageDigest::sha256())?;
+    hasher.update(&compressed_toc)?;
+    let toc_checksum = format!("sha256:{}", hex::encode(hasher.finish()?));
+    // Skip the header of the skippable frame.
+    let toc_offset = dest.count + 8;
+    append_skippable_frame(&mut *dest, &compressed_toc)?;
+
+    let mut footer = Vec::with_capacity(FOOTER_SIZE);
+    for v in [
+        toc_offset,
+        compressed_toc.len() as u64,
+        toc.len() as u64,
+        MANIFEST_TYPE_CRFS,
+    ] {
+        footer.extend_from_slice(&v.to_le_bytes());
+    }
+    footer.extend_from_slice(ZSTD_CHUNKED_FRAME_MAGIC);
+    append_skippable_frame(&mut *dest, &footer)?;
+    dest.flush().context("Flushing")?;
+
+    let mut annotations = HashMap::new();
+    annotations.insert(MANIFEST_CHECKSUM_KEY.to_string(), toc_checksum);
+    annotations.insert(
+        MANIFEST_INFO_KEY.to_string(),
+        format!(
+            "{}:{}:{}:{}",
+            toc_offset,
+            compressed_toc.len(),
+            toc.len(),
+            MANIFEST_TYPE_CRFS
+        ),
+    );
+    Ok(annotations)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compress() -> Result<()> {
+        let mut src = tar::Builder::new(Vec::new());
+        let mut h = tar::Header::new_gnu();
+        h.set_entry_type(tar::EntryType::Directory);
+        h.set_mode(0o755);
+        h.set_size(0);
+        src.append_data(&mut h, "usr/", std::io::empty())?;
+        for (path, contents) in [("usr/foo", "foo content"), ("usr/empty", "")] {
+            let mut h = tar::Header::new_gnu();
+            h.set_entry_type(tar::EntryType::Regular);
+            h.set_mode(0o644);
+            h.set_mtime(1);
+            h.set_size(contents.len() as u64);
+            src.append_data(&mut h, path, contents.as_bytes())?;
+        }
+        let src = src.into_inner()?;
+
+        let mut dest = Vec::new();
+        let annotations = compress(std::io::Cursor::new(&src), &mut dest, 3)?;
+        // The result is a regular zstd stream.
+        assert_eq!(zstd::stream::decode_all(dest.as_slice())?, src);
+
+        let footer = &dest[dest.len() - FOOTER_SIZE..];
+        assert_eq!(&footer[32..], ZSTD_CHUNKED_FRAME_MAGIC);
+        let field = |i: usize| u64::from_le_bytes(footer[i * 8..(i + 1) * 8].try_into().unwrap());
+        let (toc_offset, toc_len, toc_uncompressed_len) = (field(0), field(1), field(2));
+        assert_eq!(
+            annotations.get(MANIFEST_INFO_KEY).unwrap(),
+            &format!("{}:{}:{}:1", toc_offset, toc_len, toc_uncompressed_len)
+        );
+        let compressed_toc = &dest[toc_offset as usize..(toc_offset + toc_len) as usize];
+        let toc: serde_json::Value =
+            serde_json::from_slice(&zstd::stream::decode_all(compressed_toc)?)?;
+        let entries = toc["entries"].as_array().unwrap();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[0]["type"], "dir");
+        assert_eq!(entries[1]["name"], "usr/foo");
+        assert_eq!(entries[1]["modtime"], "1970-01-01T00:00:01Z");
+        // The content of a file can be decompressed independently.
+        let offset = entries[1]["offset"].as_u64().unwrap() as usize;
+        let end_offset = entries[1]["endOffset"].as_u64().unwrap() as usize;
+        assert_eq!(
+            zstd::stream::decode_all(&dest[offset..end_offset])?,
+            b"foo content"
+        );
+        assert!(entries[2].get("offset").is_none());
+        Ok(())
+    }
+}
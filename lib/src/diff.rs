@@ -39,7 +39,8 @@ fn query_info_optional(
 pub type FileSet = BTreeSet<String>;
 
 /// Diff between two ostree commits.
-#[derive(Debug, Default)]
+#[derive(Debug, Default, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
 pub struct FileTreeDiff {
     /// The prefix passed for diffing, e.g. /usr
     pub subdir: Option<String>,
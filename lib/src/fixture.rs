@@ -342,7 +342,7 @@ pub struct Fixture {
     srcrepo: ostree::Repo,
     destrepo: ostree::Repo,
 
-    pub format_version: u32,
+    pub format_version: crate::tar::ExportFormatVersion,
     pub selinux: bool,
 }
 
@@ -389,7 +389,7 @@ impl Fixture {
             path,
             srcrepo,
             destrepo,
-            format_version: 0,
+            format_version: crate::tar::ExportFormatVersion::V0,
             selinux: true,
         })
     }
@@ -582,7 +582,7 @@ impl Fixture {
         let mut outf = std::io::BufWriter::new(self.dir.create(path)?);
         #[allow(clippy::needless_update)]
         let options = crate::tar::ExportOptions {
-            format_version: self.format_version,
+            version: self.format_version,
             ..Default::default()
         };
         crate::tar::export_commit(&self.srcrepo, rev.as_str(), &mut outf, Some(options))?;
@@ -25,7 +25,7 @@ use std::{convert::TryInto, io::Seek};
 
 /// Extended attribute keys used for IMA.
 const IMA_XATTRS: &[&str] = &["security.ima", "security.evm"];
-const SELINUX_XATTR: &[u8] = b"security.selinux\0";
+pub(crate) const SELINUX_XATTR: &[u8] = b"security.selinux\0";
 
 /// Attributes to configure IMA signatures.
 #[derive(Debug, Clone)]
@@ -38,7 +38,7 @@ pub struct ImaOpts {
 }
 
 /// Convert a GVariant of type `a(ayay)` to a mutable map
-fn xattrs_to_map(v: &glib::Variant) -> BTreeMap<Vec<u8>, Vec<u8>> {
+pub(crate) fn xattrs_to_map(v: &glib::Variant) -> BTreeMap<Vec<u8>, Vec<u8>> {
     let v = v.data_as_bytes();
     let v = v.try_as_aligned().unwrap();
     let v = gv!("a(ayay)").cast(v);
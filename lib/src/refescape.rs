@@ -79,6 +79,12 @@ pub fn prefix_escape_for_ref(prefix: &str, s: &str) -> Result<String> {
     Ok(format!("{}/{}", prefix, escape_for_ref(s)?))
 }
 
+/// Check that `s` is a valid ostree ref, as generated by [`prefix_escape_for_ref`]
+/// or written by hand.
+pub fn validate_ref(s: &str) -> Result<()> {
+    ostree::validate_rev(s).map_err(|e| anyhow::anyhow!("Invalid ref {:?}: {}", s, e))
+}
+
 /// Reverse the effect of [`escape_for_ref()`].
 fn unescape_for_ref(s: &str) -> Result<String> {
     let mut r = String::new();
@@ -179,6 +185,16 @@ mod test {
         );
     }
 
+    #[test]
+    fn validate() {
+        for &v in UNCHANGED {
+            validate_ref(v).unwrap();
+        }
+        for &v in CORNERCASES.iter().chain(&["", "foo bar", ROUNDTRIP[0]]) {
+            assert!(validate_ref(v).is_err(), "{}", v);
+        }
+    }
+
     fn roundtrip(s: String) -> TestResult {
         // Ensure we only try strings which match the predicates.
         let r = prefix_escape_for_ref(TESTPREFIX, &s);
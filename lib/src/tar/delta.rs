@@ -0,0 +1,239 @@
+//! Static deltas as tar archives
+//!
+//! A static delta between two commits is generated as a single superblock
+//! file with its parts inline, and written into a tar stream after a small
+//! JSON document naming the commits it connects.
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use fn_error_context::context;
+use gio::prelude::*;
+use ostree::{gio, glib};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::Path;
+use tracing::instrument;
+
+/// The entry describing the delta.
+const DELTA_META: &str = "delta/meta.json";
+/// The entry holding the delta superblock, with inline parts.
+const DELTA_SUPERBLOCK: &str = "delta/superblock";
+
+/// The commits connected by a static delta.
+#[derive(Debug, Serialize, Deserialize)]
+struct DeltaMeta {
+    from: String,
+    to: String,
+}
+
+/// The commit object embedded in a delta superblock.
+type DeltaCommit = (
+    HashMap<String, glib::Variant>,
+    Vec<u8>,
+    Vec<(String, Vec<u8>)>,
+    String,
+    String,
+    u64,
+    Vec<u8>,
+    Vec<u8>,
+);
+
+/// A static delta superblock: metadata (which holds inline parts), timestamp,
+/// the from and to commit checksums, the commit object, dependencies, the
+/// descriptions of the parts and the fallback objects.
+type DeltaSuperblock = (
+    HashMap<String, glib::Variant>,
+    u64,
+    Vec<u8>,
+    Vec<u8>,
+    DeltaCommit,
+    Vec<Vec<u8>>,
+    Vec<(u32, Vec<u8>, u64, u64, Vec<u8>)>,
+    Vec<(u8, Vec<u8>, u64, u64)>,
+);
+
+/// The type of an inline delta part: the compression and the payload.
+const INLINE_PART_TYPE: &str = "(yay)";
+
+/// Check that a superblock connects the expected commits, and holds all of
+/// its parts inline.  The content of the parts is verified when applying it.
+#[context("Invalid static delta superblock")]
+fn check_superblock(path: &Path, from: &str, to: &str) -> Result<()> {
+    let data = std::fs::read(path).context("Reading superblock")?;
+    let v = glib::Variant::from_bytes::<DeltaSuperblock>(&glib::Bytes::from_owned(data));
+    for (i, expected) in [(2, from), (3, to)] {
+        let found = hex::encode(&*v.child_value(i).data_as_bytes());
+        ensure!(
+            found == expected,
+            "Expected commit {}, found {:?}",
+            expected,
+            found
+        );
+    }
+    let n_parts = v.child_value(6).n_children();
+    let metadata = v.child_value(0);
+    let n_inline = (0..metadata.n_children())
+        .filter_map(|i| metadata.child_value(i).child_value(1).as_variant())
+        .filter(|v| v.type_().to_str() == INLINE_PART_TYPE)
+        .count();
+    ensure!(
+        n_inline >= n_parts,
+        "Missing {} of {} parts",
+        n_parts - n_inline,
+        n_parts
+    );
+    Ok(())
+}
+
+/// Whether an error applying a delta means that its content failed verification,
+/// as opposed to e.g. an I/O error writing to the repository.
+fn is_verification_error(e: &glib::Error) -> bool {
+    matches!(
+        e.kind::<gio::IOErrorEnum>(),
+        Some(gio::IOErrorEnum::Failed)
+            | Some(gio::IOErrorEnum::InvalidData)
+            | Some(gio::IOErrorEnum::PartialInput)
+    )
+}
+
+/// Create a temporary directory in the repository.
+fn repo_tempdir(repo: &ostree::Repo) -> Result<tempfile::TempDir> {
+    Ok(tempfile::tempdir_in(format!(
+        "/proc/self/fd/{}/tmp",
+        repo.dfd()
+    ))?)
+}
+
+/// Append a regular file with default permissions (root/root 0644).
+fn append_file<W: std::io::Write>(
+    out: &mut tar::Builder<W>,
+    path: &str,
+    size: u64,
+    data: impl Read,
+) -> Result<()> {
+    let mut h = tar::Header::new_gnu();
+    h.set_entry_type(tar::EntryType::Regular);
+    h.set_uid(0);
+    h.set_gid(0);
+    h.set_mode(0o644);
+    h.set_size(size);
+    out.append_data(&mut h, path, data)?;
+    Ok(())
+}
+
+/// Generate a static delta between two commits, and write it as a tar stream
+/// which can be applied via [`import_delta`].
+#[context("Exporting static delta")]
+pub fn export_delta(
+    repo: &ostree::Repo,
+    from_rev: &str,
+    to_rev: &str,
+    out: impl std::io::Write,
+) -> Result<()> {
+    let cancellable = gio::NONE_CANCELLABLE;
+    let from = repo.require_rev(from_rev)?.to_string();
+    let to = repo.require_rev(to_rev)?.to_string();
+
+    let tempdir = repo_tempdir(repo)?;
+    let path = tempdir.path().join("superblock");
+    let filename = path
+        .to_str()
+        .ok_or_else(|| anyhow!("Invalid non-UTF8 path {:?}", path))?;
+    // This is parsed as a bytestring, so it needs the trailing NUL.
+    let mut filename = filename.as_bytes().to_vec();
+    filename.push(0);
+    let params = glib::VariantDict::new(None);
+    params.insert("filename", &filename);
+    params.insert("inline-parts", &true);
+    repo.static_delta_generate(
+        ostree::StaticDeltaGenerateOpt::Major,
+        Some(from.as_str()),
+        to.as_str(),
+        None,
+        Some(&params.end()),
+        cancellable,
+    )
+    .context("Generating static delta")?;
+
+    let mut out = tar::Builder::new(out);
+    let meta = serde_json::to_vec(&DeltaMeta { from, to })?;
+    append_file(&mut out, DELTA_META, meta.len() as u64, meta.as_slice())?;
+    let superblock = std::fs::File::open(&path)?;
+    let size = superblock.metadata()?.len();
+    append_file(
+        &mut out,
+        DELTA_SUPERBLOCK,
+        size,
+        std::io::BufReader::new(superblock),
+    )?;
+    out.into_inner()?;
+    Ok(())
+}
+
+/// Return the next entry of the archive, which must be at `path`.
+fn next_entry<'a, R: Read>(
+    entries: &mut tar::Entries<'a, R>,
+    path: &str,
+) -> Result<tar::Entry<'a, R>> {
+    let entry = entries
+        .next()
+        .ok_or_else(|| anyhow!("Missing {}", path))??;
+    let entry_path = entry.path()?;
+    if entry_path.to_str() != Some(path) {
+        bail!("Expected {}, found {:?}", path, entry_path);
+    }
+    Ok(entry)
+}
+
+/// Apply a static delta from a tar stream generated by [`export_delta`], returning
+/// the checksum of the resulting commit.  The base commit must exist in the repository.
+#[instrument(skip(repo, src))]
+pub async fn import_delta(
+    repo: &ostree::Repo,
+    src: impl tokio::io::AsyncRead + Send + Unpin + 'static,
+) -> Result<String> {
+    let src = tokio_util::io::SyncIoBridge::new(src);
+    let repo = repo.clone();
+    // The tar code we use today is blocking, so we spawn a thread.
+    crate::tokio_util::spawn_blocking_cancellable_flatten(move |cancellable| {
+        let mut archive = tar::Archive::new(src);
+        let mut entries = archive.entries()?;
+
+        let meta: DeltaMeta = {
+            let entry = next_entry(&mut entries, DELTA_META)?;
+            serde_json::from_reader(entry).context("Parsing delta metadata")?
+        };
+        let from = super::import::validate_sha256(meta.from)?;
+        let to = super::import::validate_sha256(meta.to)?;
+        if !repo.has_object(ostree::ObjectType::Commit, &from, Some(cancellable))? {
+            bail!("Delta base commit {} is missing", from);
+        }
+
+        let tempdir = repo_tempdir(&repo)?;
+        let path = tempdir.path().join("superblock");
+        {
+            let mut entry = next_entry(&mut entries, DELTA_SUPERBLOCK)?;
+            std::io::copy(&mut entry, &mut std::fs::File::create(&path)?)
+                .context("Writing superblock")?;
+        }
+        check_superblock(&path, &from, &to)?;
+
+        let txn = repo.auto_transaction(Some(cancellable))?;
+        if let Err(e) =
+            repo.static_delta_execute_offline(&gio::File::for_path(&path), false, Some(cancellable))
+        {
+            let msg = if is_verification_error(&e) {
+                format!("Corrupt static delta from {} to {}", from, to)
+            } else {
+                format!("Applying static delta from {} to {}", from, to)
+            };
+            return Err(anyhow::Error::new(e).context(msg));
+        }
+        txn.commit(Some(cancellable))?;
+        if !repo.has_object(ostree::ObjectType::Commit, &to, Some(cancellable))? {
+            bail!("Corrupt static delta: commit {} was not written", to);
+        }
+        Ok::<_, anyhow::Error>(to)
+    })
+    .await
+}
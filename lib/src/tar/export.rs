@@ -1,20 +1,27 @@
 //! APIs for creating container images from OSTree commits
 
 use crate::chunking;
-use crate::chunking::Chunking;
+use crate::chunking::{Chunking, ObjectMetaSized, PackingStrategy};
 use crate::objgv::*;
+use crate::tar::manifest::{sha256_hex, DigestReader, Manifest, ManifestObject, MANIFEST_PATH};
 use anyhow::{anyhow, bail, ensure, Context, Result};
-use camino::{Utf8Path, Utf8PathBuf};
+use camino::{Utf8Component, Utf8Path, Utf8PathBuf};
 use fn_error_context::context;
 use gio::glib;
 use gio::prelude::*;
 use gvariant::aligned_bytes::TryAsAligned;
 use gvariant::{Marker, Structure};
+use ostree::cap_std::fs::Dir;
 use ostree::gio;
+use serde::{Deserialize, Serialize};
 use std::borrow::Borrow;
 use std::borrow::Cow;
-use std::collections::HashSet;
-use std::io::BufReader;
+use std::collections::{HashMap, HashSet};
+use std::convert::{TryFrom, TryInto};
+use std::io::{BufReader, Read};
+use std::num::NonZeroU32;
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::sync::{Arc, Mutex};
 
 /// The repository mode generated by a tar export stream.
 pub const BARE_SPLIT_XATTRS_MODE: &str = "bare-split-xattrs";
@@ -31,10 +38,78 @@ repo_version=1
 mode=bare-split-xattrs
 "#;
 
+/// In a delta export, this file in the repo holds the checksum of the base commit.
+pub(crate) const DELTA_BASE_NAME: &str = "delta-base";
+
+/// PAX extension key holding the original uid of an entry whose ownership
+/// was shifted by [`ExportOptions::uid_offset`].
+pub const PAX_ORIGINAL_UID: &str = "OSTREE.uid";
+/// PAX extension key holding the original gid of an entry whose ownership
+/// was shifted by [`ExportOptions::gid_offset`].
+pub const PAX_ORIGINAL_GID: &str = "OSTREE.gid";
+
+/// Prefix of the PAX extension keys holding commit metadata values
+/// selected by [`ExportOptions::metadata_pax_keys`].
+pub const PAX_COMMIT_METADATA_PREFIX: &str = "OSTREE.commit.";
+
+/// The size of the id range that must be representable after applying
+/// a uid/gid offset; this matches the usual 16 bit id range.
+const ID_RANGE: u32 = 1 << 16;
+
 /// A decently large buffer, as used by e.g. coreutils `cat`.
 /// System calls are expensive.
 const BUF_CAPACITY: usize = 131072;
 
+/// Serialize a single PAX extended header record, which has the form
+/// `<length> <key>=<value>\n` where the length includes its own digits.
+fn pax_record(key: &str, value: &[u8]) -> Vec<u8> {
+    // The space, `=` and trailing newline.
+    let rest = key.len() + value.len() + 3;
+    let mut len = rest + rest.to_string().len();
+    loop {
+        let next = rest + len.to_string().len();
+        if next == len {
+            break;
+        }
+        len = next;
+    }
+    let mut r = format!("{} {}=", len, key).into_bytes();
+    r.extend_from_slice(value);
+    r.push(b'\n');
+    debug_assert_eq!(r.len(), len);
+    r
+}
+
+/// A PAX extended header key and value.
+type PaxRecord = (String, Vec<u8>);
+
+/// Convert ostree xattrs (`a(ayay)`) to `SCHILY.xattr` PAX records, as
+/// understood by GNU tar and libarchive.
+fn xattrs_to_pax(xattrs: &glib::Variant) -> Result<Vec<PaxRecord>> {
+    let v = xattrs.data_as_bytes();
+    let v = v.try_as_aligned()?;
+    let v = gvariant::gv!("a(ayay)").cast(v);
+    v.iter()
+        .map(|e| {
+            let (k, v) = e.to_tuple();
+            // Ostree stores the names with their trailing NUL.
+            let k: &[u8] = k;
+            let k = k.strip_suffix(b"\0").unwrap_or(k);
+            let k = std::str::from_utf8(k).context("Non-UTF8 xattr name")?;
+            Ok((format!("SCHILY.xattr.{}", k), v.to_vec()))
+        })
+        .collect()
+}
+
+/// Add `offset` to an id, failing if the result does not fit in 32 bits.
+fn offset_id(id: u64, offset: u32, desc: &str) -> Result<u64> {
+    u32::try_from(id)
+        .ok()
+        .and_then(|id| id.checked_add(offset))
+        .map(u64::from)
+        .ok_or_else(|| anyhow!("Mapping {} {} with offset {} overflows", desc, id, offset))
+}
+
 /// Convert /usr/etc back to /etc
 fn map_path(p: &Utf8Path) -> std::borrow::Cow<Utf8Path> {
     match p.strip_prefix("./usr/etc") {
@@ -43,6 +118,163 @@ fn map_path(p: &Utf8Path) -> std::borrow::Cow<Utf8Path> {
     }
 }
 
+/// Convert /etc to /usr/etc, reversing [`map_path`] for a path relative to the root.
+pub(super) fn unmap_path(p: &Utf8Path) -> std::borrow::Cow<Utf8Path> {
+    match p.strip_prefix("etc") {
+        Ok(r) => Cow::Owned(Utf8Path::new("usr/etc").join(r)),
+        _ => Cow::Borrowed(p),
+    }
+}
+
+/// A reader for the content of a regular file object.
+type ContentReader = Box<dyn std::io::Read>;
+
+/// The metadata of a content object.
+struct ContentMeta {
+    uid: u32,
+    gid: u32,
+    mode: u32,
+    size: u64,
+    /// Set for symbolic links.
+    symlink_target: Option<String>,
+    xattrs: glib::Variant,
+}
+
+impl ContentMeta {
+    fn new(meta: &gio::FileInfo, xattrs: glib::Variant) -> Result<Self> {
+        let symlink_target = match meta.file_type() {
+            gio::FileType::Regular => None,
+            gio::FileType::SymbolicLink => Some(
+                meta.symlink_target()
+                    .ok_or_else(|| anyhow!("Missing symlink target"))?
+                    .to_string(),
+            ),
+            o => bail!("Unexpected content object type {:?}", o),
+        };
+        Ok(Self {
+            uid: meta.attribute_uint32("unix::uid"),
+            gid: meta.attribute_uint32("unix::gid"),
+            mode: meta.attribute_uint32("unix::mode"),
+            size: meta.size() as u64,
+            symlink_target,
+            xattrs,
+        })
+    }
+}
+
+/// A content object read by a worker; regular files hold their content.
+type Prefetched = (ContentMeta, Option<Vec<u8>>);
+/// The result of a worker, which is `None` for objects left to the writer.
+type PrefetchResult = Result<Option<Prefetched>>;
+type PrefetchJob = (String, SyncSender<PrefetchResult>);
+
+/// Larger content objects are streamed by the writer instead of being
+/// read into memory by a worker.
+const MAX_PREFETCH_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Read a content object into memory, unless it is too large.
+fn read_content(repo: &ostree::Repo, checksum: &str) -> PrefetchResult {
+    let (instream, meta, xattrs) = repo.load_file(checksum, gio::NONE_CANCELLABLE)?;
+    let meta = meta.ok_or_else(|| anyhow!("Missing metadata for object {}", checksum))?;
+    let xattrs = xattrs.ok_or_else(|| anyhow!("Missing xattrs for object {}", checksum))?;
+    let meta = ContentMeta::new(&meta, xattrs)?;
+    if meta.size > MAX_PREFETCH_SIZE {
+        return Ok(None);
+    }
+    let data = match instream {
+        Some(instream) => {
+            let mut buf = Vec::with_capacity(meta.size as usize);
+            instream.into_read().read_to_end(&mut buf)?;
+            Some(buf)
+        }
+        None => None,
+    };
+    Ok(Some((meta, data)))
+}
+
+/// A pool of threads reading (and for archive repositories, decompressing)
+/// content objects ahead of the writer, which consumes them in order.
+struct ContentPool {
+    jobs: Option<SyncSender<PrefetchJob>>,
+    pending: HashMap<String, Receiver<PrefetchResult>>,
+    /// The maximum number of pending objects.
+    window: usize,
+    workers: Vec<std::thread::JoinHandle<()>>,
+}
+
+impl ContentPool {
+    fn new(repo: &ostree::Repo, n_workers: u32) -> Result<Self> {
+        let (jobs, rx) = sync_channel::<PrefetchJob>(n_workers as usize);
+        let rx = Arc::new(Mutex::new(rx));
+        let workers = (0..n_workers)
+            .map(|_| -> Result<_> {
+                // Each worker uses its own handle to the repository.
+                let repo = ostree::Repo::open_at(repo.dfd(), ".", gio::NONE_CANCELLABLE)?;
+                let rx = Arc::clone(&rx);
+                Ok(std::thread::spawn(move || loop {
+                    let job = rx.lock().unwrap().recv();
+                    let (checksum, reply) = match job {
+                        Ok(job) => job,
+                        Err(_) => break,
+                    };
+                    // The writer may have gone away on error.
+                    let _ = reply.send(read_content(&repo, &checksum));
+                }))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            jobs: Some(jobs),
+            pending: HashMap::new(),
+            window: 2 * n_workers as usize,
+            workers,
+        })
+    }
+
+    /// Queue reading objects from `checksums` until the window is full.
+    fn prefetch(&mut self, checksums: &mut impl Iterator<Item = String>) -> Result<()> {
+        while self.pending.len() < self.window {
+            let checksum = match checksums.next() {
+                Some(c) => c,
+                None => break,
+            };
+            if self.pending.contains_key(&checksum) {
+                continue;
+            }
+            let (tx, rx) = sync_channel(1);
+            self.jobs
+                .as_ref()
+                .expect("jobs")
+                .send((checksum.clone(), tx))
+                .map_err(|_| anyhow!("Export workers exited"))?;
+            self.pending.insert(checksum, rx);
+        }
+        Ok(())
+    }
+
+    /// Wait for the result of a queued object; returns `None` if it
+    /// wasn't queued, or is too large.
+    fn take(&mut self, checksum: &str) -> PrefetchResult {
+        match self.pending.remove(checksum) {
+            Some(rx) => rx
+                .recv()
+                .map_err(|_| anyhow!("Export worker exited"))?
+                .with_context(|| format!("Reading {}", checksum)),
+            None => Ok(None),
+        }
+    }
+}
+
+impl Drop for ContentPool {
+    fn drop(&mut self) {
+        // Closing the queue stops the workers.
+        self.jobs.take();
+        self.pending.clear();
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
 struct OstreeTarWriter<'a, W: std::io::Write> {
     repo: &'a ostree::Repo,
     out: &'a mut tar::Builder<W>,
@@ -52,17 +284,48 @@ struct OstreeTarWriter<'a, W: std::io::Write> {
     wrote_dirmeta: HashSet<String>,
     wrote_content: HashSet<String>,
     wrote_xattrs: HashSet<String>,
+    objects_written: u64,
+    bytes_written: u64,
+    /// The timestamp used for entries in deterministic mode.
+    mtime: u64,
+    /// Set when exporting the difference from a base commit.
+    delta_base: Option<DeltaBase>,
+    /// The parent directories written for chunk content, with the checksum of
+    /// their dirtree.
+    wrote_parent_dirs: HashMap<Utf8PathBuf, String>,
+    /// In the rootfs format, the first path and hardlink header of each
+    /// content object.
+    rootfs_content: HashMap<String, (Utf8PathBuf, tar::Header)>,
+    /// Reads content objects in parallel, if enabled.
+    pool: Option<ContentPool>,
+    /// The objects written, if a manifest is appended.
+    manifest: Option<Vec<ManifestObject>>,
+    /// Dirtree objects rewritten without the excluded paths, by checksum.
+    filtered_dirtrees: HashMap<String, glib::Variant>,
 }
 
-fn object_path(objtype: ostree::ObjectType, checksum: &str) -> Utf8PathBuf {
-    let suffix = match objtype {
+/// The base commit of a delta export.
+struct DeltaBase {
+    /// The commit checksum.
+    commit: String,
+    /// The content objects referenced by the commit.
+    content: HashSet<String>,
+}
+
+/// The file name extension of an object type.
+pub(crate) fn object_suffix(objtype: ostree::ObjectType) -> &'static str {
+    match objtype {
         ostree::ObjectType::Commit => "commit",
         ostree::ObjectType::CommitMeta => "commitmeta",
         ostree::ObjectType::DirTree => "dirtree",
         ostree::ObjectType::DirMeta => "dirmeta",
         ostree::ObjectType::File => "file",
         o => panic!("Unexpected object type: {:?}", o),
-    };
+    }
+}
+
+fn object_path(objtype: ostree::ObjectType, checksum: &str) -> Utf8PathBuf {
+    let suffix = object_suffix(objtype);
     let (first, rest) = checksum.split_at(2);
     format!("{}/repo/objects/{}/{}.{}", OSTREEDIR, first, rest, suffix).into()
 }
@@ -90,6 +353,34 @@ fn v1_xattrs_link_object_path(checksum: &str) -> Utf8PathBuf {
     .into()
 }
 
+/// An entry in a dirtree object.
+enum DirTreeEntry {
+    /// A content object, by checksum.
+    File(String),
+    /// A directory, with the checksum of its dirtree and its dirmeta object.
+    Dir {
+        contents: String,
+        meta: glib::Variant,
+    },
+}
+
+/// A copy of the commit `commit_v` with the root dirtree `contents`, along
+/// with its checksum.
+fn filtered_commit(commit_v: &glib::Variant, contents: &str) -> Result<(String, glib::Variant)> {
+    let contents = hex::decode(contents)?.to_variant();
+    let children: Vec<_> = (0..commit_v.n_children())
+        .map(|i| {
+            if i == 6 {
+                contents.clone()
+            } else {
+                commit_v.child_value(i)
+            }
+        })
+        .collect();
+    let v = glib::Variant::from_tuple(&children);
+    Ok((sha256_hex(&v.data_as_bytes()), v))
+}
+
 /// Check for "denormal" symlinks which contain "//"
 // See https://github.com/fedora-sysv/chkconfig/pull/67
 // [root@cosa-devsh ~]# rpm -qf /usr/lib/systemd/systemd-sysv-install
@@ -112,6 +403,44 @@ impl<'a, W: std::io::Write> OstreeTarWriter<'a, W> {
             wrote_dirtree: HashSet::new(),
             wrote_content: HashSet::new(),
             wrote_xattrs: HashSet::new(),
+            objects_written: 0,
+            bytes_written: 0,
+            mtime: 0,
+            delta_base: None,
+            wrote_parent_dirs: HashMap::new(),
+            rootfs_content: HashMap::new(),
+            pool: None,
+            manifest: None,
+            filtered_dirtrees: HashMap::new(),
+        }
+    }
+
+    /// Whether the content object is part of the base commit of a delta.
+    fn in_delta_base(&self, checksum: &str) -> bool {
+        self.delta_base
+            .as_ref()
+            .map(|b| b.content.contains(checksum))
+            .unwrap_or_default()
+    }
+
+    /// Account for a written object, and notify the progress callback if any.
+    fn report_progress(
+        &mut self,
+        objtype: ostree::ObjectType,
+        checksum: &str,
+        path: &Utf8Path,
+        size: u64,
+    ) {
+        self.objects_written += 1;
+        self.bytes_written += size;
+        if let Some(progress) = self.options.progress.as_ref() {
+            (progress.0)(&ExportProgress {
+                objects_written: self.objects_written,
+                bytes_written: self.bytes_written,
+                objtype,
+                checksum: checksum.to_string(),
+                path: path.to_owned(),
+            });
         }
     }
 
@@ -119,46 +448,160 @@ impl<'a, W: std::io::Write> OstreeTarWriter<'a, W> {
     /// The ostree mode bits include the format, tar does not.
     /// Historically in format version 0 we injected them, so we need to keep doing so.
     fn filter_mode(&self, mode: u32) -> u32 {
-        if self.options.format_version == 0 {
+        if self.options.format == ExportFormat::Repo
+            && self.options.version == ExportFormatVersion::V0
+        {
             mode
         } else {
             mode & !libc::S_IFMT
         }
     }
 
+    /// Create a new tar header.  GNU headers start out with an empty user
+    /// and group name, and a zero mtime unless we're in deterministic mode,
+    /// where the commit timestamp is used.
+    fn new_header(&self) -> tar::Header {
+        let mut h = tar::Header::new_gnu();
+        if self.options.deterministic {
+            h.set_mtime(self.mtime);
+        }
+        h
+    }
+
+    /// Write a PAX extended header applying to the next entry.
+    fn append_pax_extensions(&mut self, records: &[PaxRecord]) -> Result<()> {
+        let data: Vec<u8> = records.iter().flat_map(|(k, v)| pax_record(k, v)).collect();
+        let mut h = self.new_header();
+        h.set_entry_type(tar::EntryType::XHeader);
+        h.set_mode(0o644);
+        h.set_size(data.len() as u64);
+        self.out
+            .append_data(&mut h, "@PaxHeader", data.as_slice())?;
+        Ok(())
+    }
+
+    /// Apply the configured uid/gid offsets to a header, adding PAX
+    /// records holding the original ids.
+    fn remap_ids(&self, h: &mut tar::Header, pax: &mut Vec<PaxRecord>) -> Result<()> {
+        let (uid_offset, gid_offset) = (self.options.uid_offset, self.options.gid_offset);
+        if uid_offset == 0 && gid_offset == 0 {
+            return Ok(());
+        }
+        let (uid, gid) = (h.uid()?, h.gid()?);
+        h.set_uid(offset_id(uid, uid_offset, "uid")?);
+        h.set_gid(offset_id(gid, gid_offset, "gid")?);
+        pax.push((PAX_ORIGINAL_UID.to_string(), uid.to_string().into_bytes()));
+        pax.push((PAX_ORIGINAL_GID.to_string(), gid.to_string().into_bytes()));
+        Ok(())
+    }
+
+    /// Append an entry with data, remapping its ownership.
+    fn append_entry(
+        &mut self,
+        h: &mut tar::Header,
+        path: &Utf8Path,
+        data: impl std::io::Read,
+    ) -> Result<()> {
+        self.append_entry_pax(h, path, data, Vec::new())
+    }
+
+    /// Append an entry with data preceded by the given PAX records,
+    /// remapping its ownership.
+    fn append_entry_pax(
+        &mut self,
+        h: &mut tar::Header,
+        path: &Utf8Path,
+        data: impl std::io::Read,
+        mut pax: Vec<PaxRecord>,
+    ) -> Result<()> {
+        self.remap_ids(h, &mut pax)?;
+        if !pax.is_empty() {
+            self.append_pax_extensions(&pax)?;
+        }
+        self.out.append_data(h, path, data)?;
+        Ok(())
+    }
+
+    /// Append a link entry preceded by the given PAX records, remapping
+    /// its ownership.
+    fn append_entry_link(
+        &mut self,
+        h: &mut tar::Header,
+        path: &Utf8Path,
+        target: &str,
+        mut pax: Vec<PaxRecord>,
+    ) -> Result<()> {
+        self.remap_ids(h, &mut pax)?;
+        if !pax.is_empty() {
+            self.append_pax_extensions(&pax)?;
+        }
+        self.out.append_link(h, path, target)?;
+        Ok(())
+    }
+
+    /// Append a symlink entry.
+    fn append_symlink(
+        &mut self,
+        h: &mut tar::Header,
+        path: &Utf8Path,
+        target: &str,
+        pax: Vec<PaxRecord>,
+    ) -> Result<()> {
+        h.set_entry_type(tar::EntryType::Symlink);
+        h.set_size(0);
+        // Handle //chkconfig, see above
+        if symlink_is_denormal(target) {
+            h.set_link_name_literal(target)?;
+            self.append_entry_pax(h, path, std::io::empty(), pax)
+        } else {
+            self.append_entry_link(h, path, target, pax)
+        }
+    }
+
     /// Add a directory entry with default permissions (root/root 0755)
     fn append_default_dir(&mut self, path: &Utf8Path) -> Result<()> {
-        let mut h = tar::Header::new_gnu();
+        let mut h = self.new_header();
         h.set_entry_type(tar::EntryType::Directory);
         h.set_uid(0);
         h.set_gid(0);
         h.set_mode(0o755);
         h.set_size(0);
-        self.out.append_data(&mut h, &path, &mut std::io::empty())?;
+        self.append_entry(&mut h, path, std::io::empty())?;
         Ok(())
     }
 
     /// Add a regular file entry with default permissions (root/root 0644)
     fn append_default_data(&mut self, path: &Utf8Path, data: &[u8]) -> Result<()> {
-        let mut h = tar::Header::new_gnu();
+        self.append_default_data_pax(path, data, Vec::new())
+    }
+
+    /// Add a regular file entry with default permissions, preceded by
+    /// the given PAX records.
+    fn append_default_data_pax(
+        &mut self,
+        path: &Utf8Path,
+        data: &[u8],
+        pax: Vec<PaxRecord>,
+    ) -> Result<()> {
+        let mut h = self.new_header();
         h.set_entry_type(tar::EntryType::Regular);
         h.set_uid(0);
         h.set_gid(0);
         h.set_mode(0o644);
         h.set_size(data.len() as u64);
-        self.out.append_data(&mut h, &path, data)?;
+        self.append_entry_pax(&mut h, path, data, pax)?;
         Ok(())
     }
 
     /// Add an hardlink entry with default permissions (root/root 0644)
     fn append_default_hardlink(&mut self, path: &Utf8Path, link_target: &Utf8Path) -> Result<()> {
-        let mut h = tar::Header::new_gnu();
+        let mut h = self.new_header();
         h.set_entry_type(tar::EntryType::Link);
         h.set_uid(0);
         h.set_gid(0);
         h.set_mode(0o644);
         h.set_size(0);
-        self.out.append_link(&mut h, &path, &link_target)?;
+        self.append_entry_link(&mut h, path, link_target.as_str(), Vec::new())?;
         Ok(())
     }
 
@@ -204,17 +647,16 @@ impl<'a, W: std::io::Write> OstreeTarWriter<'a, W> {
         }
 
         // The special `repo/xattrs` directory used in v0 format.
-        if self.options.format_version == 0 {
+        if self.options.version == ExportFormatVersion::V0 {
             let path: Utf8PathBuf = format!("{}/repo/xattrs", OSTREEDIR).into();
             self.append_default_dir(&path)?;
         }
 
         // Repository configuration file.
         {
-            let path = match self.options.format_version {
-                0 => format!("{}/config", SYSROOT),
-                1 => format!("{}/repo/config", OSTREEDIR),
-                n => anyhow::bail!("Unsupported ostree tar format version {}", n),
+            let path = match self.options.version {
+                ExportFormatVersion::V0 => format!("{}/config", SYSROOT),
+                ExportFormatVersion::V1 => format!("{}/repo/config", OSTREEDIR),
             };
             self.append_default_data(Utf8Path::new(&path), REPO_CONFIG.as_bytes())?;
         }
@@ -229,6 +671,7 @@ impl<'a, W: std::io::Write> OstreeTarWriter<'a, W> {
 
         let (commit_v, _) = self.repo.load_commit(checksum)?;
         let commit_v = &commit_v;
+        self.mtime = ostree::commit_get_timestamp(commit_v);
 
         let commit_bytes = commit_v.data_as_bytes();
         let commit_bytes = commit_bytes.try_as_aligned()?;
@@ -239,25 +682,63 @@ impl<'a, W: std::io::Write> OstreeTarWriter<'a, W> {
         let metadata_v = self
             .repo
             .load_variant(ostree::ObjectType::DirMeta, metadata_checksum)?;
-        // Safety: We passed the correct variant type just above
-        let metadata = &ostree::DirMetaParsed::from_variant(&metadata_v).unwrap();
         let rootpath = Utf8Path::new("./");
+        self.check_etc_conflict(&contents)?;
 
         // We need to write the root directory, before we write any objects.  This should be the very
         // first thing.
-        self.append_dir(rootpath, metadata)?;
+        self.append_dir(rootpath, &metadata_v)?;
+
+        // A rootfs only has the tree itself.
+        if self.options.format == ExportFormat::Rootfs {
+            if let Some(subpath) = self.options.subpath.clone() {
+                return self.append_subpath(contents, &subpath, cancellable);
+            }
+            return self.append_dirtree(rootpath, contents, true, cancellable);
+        }
 
         // Now, we create sysroot/ and everything under it
         self.write_repo_structure()?;
 
-        self.append(ostree::ObjectType::Commit, checksum, commit_v)?;
-        if let Some(commitmeta) = self
-            .repo
-            .read_commit_detached_metadata(checksum, cancellable)?
-        {
-            self.append(ostree::ObjectType::CommitMeta, checksum, &commitmeta)?;
+        // A delta records the commit it applies to before the commit object.
+        if let Some(base) = self.delta_base.as_ref() {
+            let base = base.commit.clone();
+            let path: Utf8PathBuf = format!("{}/repo/{}", OSTREEDIR, DELTA_BASE_NAME).into();
+            self.append_default_data(&path, base.as_bytes())?;
+        }
+
+        // When exporting a subtree, the commit and the metadata objects
+        // outside of it are skipped; the result is not importable as a commit.
+        if let Some(subpath) = self.options.subpath.clone() {
+            return self.append_subpath(contents, &subpath, cancellable);
         }
 
+        let contents = if self.options.exclude.is_empty() {
+            let pax = self.commit_metadata_pax(commit_v);
+            self.append_with_pax(ostree::ObjectType::Commit, checksum, commit_v, pax)?;
+            if let Some(commitmeta) = self
+                .repo
+                .read_commit_detached_metadata(checksum, cancellable)?
+            {
+                self.append(ostree::ObjectType::CommitMeta, checksum, &commitmeta)?;
+            }
+            contents
+        } else {
+            // Excluding content changes the tree, so write a new commit for it.
+            // The detached metadata (e.g. signatures) is for the original
+            // commit, and is not written.
+            let filtered = self.filter_dirtree(rootpath, &contents)?;
+            let (filtered_checksum, filtered_v) = filtered_commit(commit_v, &filtered)?;
+            let pax = self.commit_metadata_pax(&filtered_v);
+            self.append_with_pax(
+                ostree::ObjectType::Commit,
+                &filtered_checksum,
+                &filtered_v,
+                pax,
+            )?;
+            filtered
+        };
+
         // The ostree dirmeta object for the root.
         self.append(ostree::ObjectType::DirMeta, metadata_checksum, &metadata_v)?;
 
@@ -266,12 +747,292 @@ impl<'a, W: std::io::Write> OstreeTarWriter<'a, W> {
         Ok(())
     }
 
+    /// Write the kernel and initramfs of a bootable commit as `boot/vmlinuz-<kver>`
+    /// and `boot/initramfs-<kver>.img`, linked to their content in the module directory.
+    #[context("Exporting kernel to /boot")]
+    fn append_boot_kernel(&mut self, checksum: &str) -> Result<()> {
+        let cancellable = gio::NONE_CANCELLABLE;
+        let (commit_v, _) = self.repo.load_commit(checksum)?;
+        let meta = glib::VariantDict::new(Some(&commit_v.child_value(0)));
+        if !meta
+            .lookup::<bool>(*ostree::METADATA_KEY_BOOTABLE)?
+            .unwrap_or_default()
+        {
+            tracing::warn!("Commit {} is not bootable; not exporting kernel", checksum);
+            return Ok(());
+        }
+        let (root, _) = self.repo.read_commit(checksum, cancellable)?;
+        let kdir = crate::bootabletree::find_kernel_dir(&root, cancellable)?
+            .ok_or_else(|| anyhow!("No kernel found in bootable commit {}", checksum))?;
+        let kver = kdir
+            .basename()
+            .and_then(|b| b.to_str().map(ToOwned::to_owned))
+            .ok_or_else(|| anyhow!("Invalid kernel directory"))?;
+        let moddir = Utf8Path::new("./usr/lib/modules").join(&kver);
+        for (name, dest) in [
+            ("vmlinuz", format!("vmlinuz-{kver}")),
+            ("initramfs.img", format!("initramfs-{kver}.img")),
+        ] {
+            let src = moddir.join(name);
+            if self.is_excluded(&src) {
+                continue;
+            }
+            let f = kdir.child(name);
+            if !f.query_exists(cancellable) {
+                continue;
+            }
+            let f = f.downcast::<ostree::RepoFile>().expect("downcast");
+            f.ensure_resolved()?;
+            let content = f
+                .checksum()
+                .ok_or_else(|| anyhow!("Missing checksum for {}", src))?;
+            if self.in_delta_base(&content) {
+                continue;
+            }
+            self.append_content_at(&content, &Utf8Path::new("./boot").join(dest))?;
+        }
+        Ok(())
+    }
+
+    /// PAX records holding the configured commit metadata keys; non-string
+    /// values are serialized in the GVariant text format.
+    fn commit_metadata_pax(&self, commit_v: &glib::Variant) -> Vec<PaxRecord> {
+        let meta = glib::VariantDict::new(Some(&commit_v.child_value(0)));
+        self.options
+            .metadata_pax_keys
+            .iter()
+            .filter_map(|k| {
+                let v = meta.lookup_value(k, None)?;
+                let v = match v.str() {
+                    Some(s) => s.to_string(),
+                    None => v.print(false).to_string(),
+                };
+                Some((
+                    format!("{}{}", PAX_COMMIT_METADATA_PREFIX, k),
+                    v.into_bytes(),
+                ))
+            })
+            .collect()
+    }
+
+    /// Write the content under `subpath`, along with the parent directories
+    /// needed to reach it.
+    #[context("Exporting subpath {}", subpath)]
+    fn append_subpath<C: IsA<gio::Cancellable>>(
+        &mut self,
+        root_contents: String,
+        subpath: &Utf8Path,
+        cancellable: Option<&C>,
+    ) -> Result<()> {
+        let mut names = Vec::new();
+        for component in subpath.components() {
+            match component {
+                Utf8Component::RootDir | Utf8Component::CurDir => {}
+                Utf8Component::Normal(name) => names.push(name),
+                _ => bail!("Invalid component in subpath"),
+            }
+        }
+        let (last, parents) = names
+            .split_last()
+            .ok_or_else(|| anyhow!("Invalid empty subpath"))?;
+
+        let mut dirpath = Utf8PathBuf::from("./");
+        let mut contents = root_contents;
+        for &name in parents {
+            match self.lookup_dirtree(&contents, name)? {
+                Some(DirTreeEntry::Dir { contents: c, meta }) => {
+                    dirpath.push(name);
+                    self.append_dir_mapped(&dirpath, &meta)?;
+                    contents = c;
+                }
+                Some(DirTreeEntry::File(_)) => bail!("Not a directory: {}", dirpath.join(name)),
+                None => bail!("No such file or directory: {}", dirpath.join(name)),
+            }
+        }
+
+        let path = &dirpath.join(last);
+        if self.is_excluded(path) {
+            return Ok(());
+        }
+        match self.lookup_dirtree(&contents, last)? {
+            Some(DirTreeEntry::Dir { contents, meta }) => {
+                self.append_dir_mapped(path, &meta)?;
+                self.append_dirtree(path, contents, false, cancellable)?;
+            }
+            Some(DirTreeEntry::File(checksum)) => {
+                self.append_content_mapped(&checksum, path)?;
+            }
+            None => bail!("No such file or directory: {}", path),
+        }
+        Ok(())
+    }
+
+    /// Find the directory named `name` in a dirtree object, returning the
+    /// checksum of its dirtree.
+    fn find_subdir(&self, checksum: &str, name: &str) -> Result<Option<String>> {
+        Ok(self.find_subdir_meta(checksum, name)?.map(|d| d.0))
+    }
+
+    /// Find the directory named `name` in a dirtree object, returning the
+    /// checksums of its dirtree and dirmeta.
+    fn find_subdir_meta(&self, checksum: &str, name: &str) -> Result<Option<(String, String)>> {
+        let v = self
+            .repo
+            .load_variant(ostree::ObjectType::DirTree, checksum)?;
+        let v = v.data_as_bytes();
+        let v = v.try_as_aligned()?;
+        let v = gv_dirtree!().cast(v);
+        let (_, dirs) = v.to_tuple();
+        Ok(dirs
+            .into_iter()
+            .map(|d| d.to_tuple())
+            .find(|d| d.0.to_str() == name)
+            .map(|d| (hex::encode(d.1), hex::encode(d.2))))
+    }
+
+    /// Verify that mapping `/usr/etc` to `/etc` won't produce duplicate entries.
+    fn check_etc_conflict(&self, root_contents: &str) -> Result<()> {
+        if self.options.map_etc == EtcMapping::None {
+            return Ok(());
+        }
+        let has_usr_etc = match self.find_subdir(root_contents, "usr")? {
+            Some(usr) => self.find_subdir(&usr, "etc")?.is_some(),
+            None => false,
+        };
+        if has_usr_etc && self.find_subdir(root_contents, "etc")?.is_some() {
+            bail!("Cannot map /usr/etc to /etc: the commit has both");
+        }
+        Ok(())
+    }
+
+    /// The paths at which the commit path `p` is written, per [`EtcMapping`].
+    fn output_paths<'p>(&self, p: &'p Utf8Path) -> Vec<Cow<'p, Utf8Path>> {
+        match self.options.map_etc {
+            EtcMapping::None => vec![Cow::Borrowed(p)],
+            EtcMapping::UsrEtcToEtc => vec![map_path(p)],
+            EtcMapping::Both => match map_path(p) {
+                Cow::Borrowed(p) => vec![Cow::Borrowed(p)],
+                mapped => vec![Cow::Borrowed(p), mapped],
+            },
+        }
+    }
+
+    /// Write a directory at each of its output paths.
+    fn append_dir_mapped(&mut self, dirpath: &Utf8Path, meta_v: &glib::Variant) -> Result<()> {
+        for path in self.output_paths(dirpath) {
+            self.append_dir(&path, meta_v)?;
+        }
+        Ok(())
+    }
+
+    /// Write a content object at each of its output paths.
+    fn append_content_mapped(&mut self, checksum: &str, path: &Utf8Path) -> Result<()> {
+        for path in self.output_paths(path) {
+            self.append_content_at(checksum, &path)?;
+        }
+        Ok(())
+    }
+
+    /// Load a dirtree object, which may have been rewritten by [`Self::filter_dirtree`].
+    fn load_dirtree(&self, checksum: &str) -> Result<glib::Variant> {
+        if let Some(v) = self.filtered_dirtrees.get(checksum) {
+            return Ok(v.clone());
+        }
+        Ok(self
+            .repo
+            .load_variant(ostree::ObjectType::DirTree, checksum)?)
+    }
+
+    /// Rewrite the dirtree at `dirpath` and those below it without the
+    /// excluded paths, returning the checksum of the result.  Dirtrees with
+    /// nothing excluded below them are unchanged.
+    fn filter_dirtree(&mut self, dirpath: &Utf8Path, checksum: &str) -> Result<String> {
+        let relpath = dirpath.strip_prefix("./").unwrap_or(dirpath);
+        if !self.options.exclude.iter().any(|e| e.starts_with(relpath)) {
+            return Ok(checksum.to_string());
+        }
+        let v = self.load_dirtree(checksum)?;
+        let v = v.data_as_bytes();
+        let v = v.try_as_aligned()?;
+        let v = gv_dirtree!().cast(v);
+        let (files, dirs) = v.to_tuple();
+        let mut new_files = Vec::new();
+        for file in files {
+            let (name, csum) = file.to_tuple();
+            let name = name.to_str();
+            if !self.is_excluded(&dirpath.join(name)) {
+                new_files.push((name.to_string(), csum.to_vec()));
+            }
+        }
+        let mut new_dirs = Vec::new();
+        for item in dirs {
+            let (name, contents_csum, meta_csum) = item.to_tuple();
+            let name = name.to_str();
+            let subpath = &dirpath.join(name);
+            if self.is_excluded(subpath) {
+                continue;
+            }
+            let contents = self.filter_dirtree(subpath, &hex::encode(contents_csum))?;
+            new_dirs.push((name.to_string(), hex::decode(contents)?, meta_csum.to_vec()));
+        }
+        let v = (new_files, new_dirs).to_variant();
+        let checksum = sha256_hex(&v.data_as_bytes());
+        self.filtered_dirtrees.insert(checksum.clone(), v);
+        Ok(checksum)
+    }
+
+    /// Find the entry named `name` in a dirtree object.  For directories,
+    /// the dirmeta object is written too.
+    fn lookup_dirtree(&mut self, checksum: &str, name: &str) -> Result<Option<DirTreeEntry>> {
+        let v = self.load_dirtree(checksum)?;
+        let v = v.data_as_bytes();
+        let v = v.try_as_aligned()?;
+        let v = gv_dirtree!().cast(v);
+        let (files, dirs) = v.to_tuple();
+        for file in files {
+            let (fname, csum) = file.to_tuple();
+            if fname.to_str() == name {
+                return Ok(Some(DirTreeEntry::File(hex::encode(csum))));
+            }
+        }
+        for item in dirs {
+            let (dname, contents_csum, meta_csum) = item.to_tuple();
+            if dname.to_str() == name {
+                let meta_csum = &hex::encode(meta_csum);
+                let meta_v = &self
+                    .repo
+                    .load_variant(ostree::ObjectType::DirMeta, meta_csum)?;
+                self.append(ostree::ObjectType::DirMeta, meta_csum, meta_v)?;
+                return Ok(Some(DirTreeEntry::Dir {
+                    contents: hex::encode(contents_csum),
+                    meta: meta_v.clone(),
+                }));
+            }
+        }
+        Ok(None)
+    }
+
     fn append(
         &mut self,
         objtype: ostree::ObjectType,
         checksum: &str,
         v: &glib::Variant,
     ) -> Result<()> {
+        self.append_with_pax(objtype, checksum, v, Vec::new())
+    }
+
+    /// Write a metadata object, preceded by the given PAX records.
+    fn append_with_pax(
+        &mut self,
+        objtype: ostree::ObjectType,
+        checksum: &str,
+        v: &glib::Variant,
+        pax: Vec<PaxRecord>,
+    ) -> Result<()> {
+        // There are no ostree objects in a rootfs.
+        if self.options.format == ExportFormat::Rootfs {
+            return Ok(());
+        }
         let set = match objtype {
             ostree::ObjectType::Commit | ostree::ObjectType::CommitMeta => None,
             ostree::ObjectType::DirTree => Some(&mut self.wrote_dirtree),
@@ -288,17 +1049,54 @@ impl<'a, W: std::io::Write> OstreeTarWriter<'a, W> {
 
         let data = v.data_as_bytes();
         let data = data.as_ref();
-        self.append_default_data(&object_path(objtype, checksum), data)
+        let path = object_path(objtype, checksum);
+        self.append_default_data_pax(&path, data, pax)
             .with_context(|| format!("Writing object {checksum}"))?;
+        self.report_progress(objtype, checksum, &path, data.len() as u64);
+        let digest = self.manifest.is_some().then(|| sha256_hex(data));
+        self.add_to_manifest(objtype, checksum, data.len() as u64, digest);
         Ok(())
     }
 
+    /// Record a written object in the manifest, if enabled.
+    fn add_to_manifest(
+        &mut self,
+        objtype: ostree::ObjectType,
+        checksum: &str,
+        size: u64,
+        sha256: Option<String>,
+    ) {
+        if let (Some(manifest), Some(sha256)) = (self.manifest.as_mut(), sha256) {
+            manifest.push(ManifestObject {
+                checksum: checksum.to_string(),
+                objtype: object_suffix(objtype).to_string(),
+                size,
+                sha256,
+            });
+        }
+    }
+
+    /// Write the manifest of the objects written for `commit`.
+    fn append_manifest(&mut self, commit: &str) -> Result<()> {
+        let objects = match self.manifest.take() {
+            Some(objects) => objects,
+            None => return Ok(()),
+        };
+        let manifest = Manifest {
+            commit: commit.to_string(),
+            objects,
+        };
+        let data = serde_json::to_vec(&manifest)?;
+        self.append_default_data(Utf8Path::new(MANIFEST_PATH), &data)
+            .context("Writing manifest")
+    }
+
     /// Export xattrs to the tar stream, return whether content was written.
     #[context("Writing xattrs")]
     fn append_xattrs(&mut self, checksum: &str, xattrs: &glib::Variant) -> Result<bool> {
         let xattrs_data = xattrs.data_as_bytes();
         let xattrs_data = xattrs_data.as_ref();
-        if xattrs_data.is_empty() && self.options.format_version == 0 {
+        if xattrs_data.is_empty() && self.options.version == ExportFormatVersion::V0 {
             return Ok(false);
         }
 
@@ -307,56 +1105,112 @@ impl<'a, W: std::io::Write> OstreeTarWriter<'a, W> {
             hex::encode(digest)
         };
 
-        if self.options.format_version == 0 {
-            let path = v0_xattrs_path(&xattrs_checksum);
+        match self.options.version {
+            ExportFormatVersion::V0 => {
+                let path = v0_xattrs_path(&xattrs_checksum);
 
-            // Write xattrs content into a separate directory.
-            if !self.wrote_xattrs.contains(&xattrs_checksum) {
-                let inserted = self.wrote_xattrs.insert(xattrs_checksum);
-                debug_assert!(inserted);
-                self.append_default_data(&path, xattrs_data)?;
-            }
-            // Hardlink the object in the repo.
-            {
-                let objpath = v0_xattrs_object_path(checksum);
-                self.append_default_hardlink(&objpath, &path)?;
-            }
-        } else if self.options.format_version == 1 {
-            let path = v1_xattrs_object_path(&xattrs_checksum);
-
-            // Write xattrs content into a separate `.file-xattrs` object.
-            if !self.wrote_xattrs.contains(&xattrs_checksum) {
-                let inserted = self.wrote_xattrs.insert(xattrs_checksum);
-                debug_assert!(inserted);
-                self.append_default_data(&path, xattrs_data)?;
+                // Write xattrs content into a separate directory.
+                if !self.wrote_xattrs.contains(&xattrs_checksum) {
+                    let inserted = self.wrote_xattrs.insert(xattrs_checksum);
+                    debug_assert!(inserted);
+                    self.append_default_data(&path, xattrs_data)?;
+                }
+                // Hardlink the object in the repo.
+                {
+                    let objpath = v0_xattrs_object_path(checksum);
+                    self.append_default_hardlink(&objpath, &path)?;
+                }
             }
-            // Write a `.file-xattrs-link` which links the file object to
-            // the corresponding detached xattrs.
-            {
-                let link_obj_path = v1_xattrs_link_object_path(checksum);
-                self.append_default_hardlink(&link_obj_path, &path)?;
+            ExportFormatVersion::V1 => {
+                let path = v1_xattrs_object_path(&xattrs_checksum);
+
+                // Write xattrs content into a separate `.file-xattrs` object.
+                if !self.wrote_xattrs.contains(&xattrs_checksum) {
+                    let inserted = self.wrote_xattrs.insert(xattrs_checksum);
+                    debug_assert!(inserted);
+                    self.append_default_data(&path, xattrs_data)?;
+                }
+                // Write a `.file-xattrs-link` which links the file object to
+                // the corresponding detached xattrs.
+                {
+                    let link_obj_path = v1_xattrs_link_object_path(checksum);
+                    self.append_default_hardlink(&link_obj_path, &path)?;
+                }
             }
-        } else {
-            bail!("Unknown format version '{}'", self.options.format_version);
         }
 
         Ok(true)
     }
 
+    /// Create the header for a content object.
+    fn content_header(&self, meta: &ContentMeta) -> tar::Header {
+        let mut h = self.new_header();
+        h.set_uid(meta.uid as u64);
+        h.set_gid(meta.gid as u64);
+        h.set_mode(self.filter_mode(meta.mode));
+        h
+    }
+
+    /// Load a content object, using the result of a worker if it was prefetched.
+    fn load_content(&mut self, checksum: &str) -> Result<(ContentMeta, Option<ContentReader>)> {
+        if let Some(pool) = self.pool.as_mut() {
+            if let Some((meta, data)) = pool.take(checksum)? {
+                let data = data.map(|d| Box::new(std::io::Cursor::new(d)) as ContentReader);
+                return Ok((meta, data));
+            }
+        }
+        let (instream, meta, xattrs) = self.repo.load_file(checksum, gio::NONE_CANCELLABLE)?;
+        let meta = meta.ok_or_else(|| anyhow!("Missing metadata for object {}", checksum))?;
+        let xattrs = xattrs.ok_or_else(|| anyhow!("Missing xattrs for object {}", checksum))?;
+        let meta = ContentMeta::new(&meta, xattrs)?;
+        let data = instream.map(|s| {
+            Box::new(BufReader::with_capacity(BUF_CAPACITY, s.into_read())) as ContentReader
+        });
+        Ok((meta, data))
+    }
+
+    /// Write the entry for a regular file or symlink at `path`.
+    fn append_content_entry(
+        &mut self,
+        checksum: &str,
+        path: &Utf8Path,
+        data: Option<ContentReader>,
+        meta: &ContentMeta,
+        mut h: tar::Header,
+        pax: Vec<PaxRecord>,
+    ) -> Result<()> {
+        if let Some(target) = meta.symlink_target.as_deref() {
+            self.append_symlink(&mut h, path, target, pax)
+                .with_context(|| format!("Writing content symlink: {}", checksum))?;
+            self.report_progress(ostree::ObjectType::File, checksum, path, 0);
+            let digest = self
+                .manifest
+                .is_some()
+                .then(|| sha256_hex(target.as_bytes()));
+            let size = target.len() as u64;
+            self.add_to_manifest(ostree::ObjectType::File, checksum, size, digest);
+        } else {
+            let data = data.ok_or_else(|| anyhow!("Missing content for {}", checksum))?;
+            let mut data = DigestReader::new(data, self.manifest.is_some());
+            h.set_entry_type(tar::EntryType::Regular);
+            h.set_size(meta.size);
+            self.append_entry_pax(&mut h, path, &mut data, pax)
+                .with_context(|| format!("Writing regfile {}", checksum))?;
+            self.report_progress(ostree::ObjectType::File, checksum, path, meta.size);
+            let digest = data.finish();
+            self.add_to_manifest(ostree::ObjectType::File, checksum, meta.size, digest);
+        }
+        Ok(())
+    }
+
     /// Write a content object, returning the path/header that should be used
     /// as a hard link to it in the target path. This matches how ostree checkouts work.
     fn append_content(&mut self, checksum: &str) -> Result<(Utf8PathBuf, tar::Header)> {
         let path = object_path(ostree::ObjectType::File, checksum);
 
-        let (instream, meta, xattrs) = self.repo.load_file(checksum, gio::NONE_CANCELLABLE)?;
-        let meta = meta.ok_or_else(|| anyhow!("Missing metadata for object {}", checksum))?;
-        let xattrs = xattrs.ok_or_else(|| anyhow!("Missing xattrs for object {}", checksum))?;
+        let (meta, data) = self.load_content(checksum)?;
 
-        let mut h = tar::Header::new_gnu();
-        h.set_uid(meta.attribute_uint32("unix::uid") as u64);
-        h.set_gid(meta.attribute_uint32("unix::gid") as u64);
-        let mode = meta.attribute_uint32("unix::mode");
-        h.set_mode(self.filter_mode(mode));
+        let h = self.content_header(&meta);
         let mut target_header = h.clone();
         target_header.set_size(0);
 
@@ -367,54 +1221,129 @@ impl<'a, W: std::io::Write> OstreeTarWriter<'a, W> {
             // The xattrs objects need to be exported before the regular object they
             // refer to. Otherwise the importing logic won't have the xattrs available
             // when importing file content.
-            self.append_xattrs(checksum, &xattrs)?;
+            self.append_xattrs(checksum, &meta.xattrs)?;
 
-            if let Some(instream) = instream {
-                ensure!(meta.file_type() == gio::FileType::Regular);
+            self.append_content_entry(checksum, &path, data, &meta, h, Vec::new())?;
+        }
 
-                h.set_entry_type(tar::EntryType::Regular);
-                h.set_size(meta.size() as u64);
-                let mut instream = BufReader::with_capacity(BUF_CAPACITY, instream.into_read());
-                self.out
-                    .append_data(&mut h, &path, &mut instream)
-                    .with_context(|| format!("Writing regfile {}", checksum))?;
-            } else {
-                ensure!(meta.file_type() == gio::FileType::SymbolicLink);
-
-                let target = meta
-                    .symlink_target()
-                    .ok_or_else(|| anyhow!("Missing symlink target"))?;
-                let context = || format!("Writing content symlink: {}", checksum);
-                h.set_entry_type(tar::EntryType::Symlink);
-                h.set_size(0);
-                // Handle //chkconfig, see above
-                if symlink_is_denormal(&target) {
-                    h.set_link_name_literal(meta.symlink_target().unwrap().as_str())
-                        .with_context(context)?;
-                    self.out
-                        .append_data(&mut h, &path, &mut std::io::empty())
-                        .with_context(context)?;
-                } else {
-                    self.out
-                        .append_link(&mut h, &path, target.as_str())
-                        .with_context(context)?;
+        Ok((path, target_header))
+    }
+
+    /// Write a content object directly at `dest`, or as a hardlink to the
+    /// first path it was written at.
+    fn append_rootfs_content(&mut self, checksum: &str, dest: &Utf8Path) -> Result<()> {
+        if let Some((first, h)) = self.rootfs_content.get(checksum).cloned() {
+            return self.append_content_hardlink(&first, h, dest);
+        }
+
+        let (meta, data) = self.load_content(checksum)?;
+
+        let h = self.content_header(&meta);
+        let mut target_header = h.clone();
+        target_header.set_size(0);
+        let pax = if self.options.omit_xattrs {
+            Vec::new()
+        } else {
+            xattrs_to_pax(&meta.xattrs)?
+        };
+        self.append_content_entry(checksum, dest, data, &meta, h, pax)?;
+
+        let dest = dest.strip_prefix("./").unwrap_or(dest);
+        self.rootfs_content
+            .insert(checksum.to_string(), (dest.to_owned(), target_header));
+        Ok(())
+    }
+
+    /// Whether the commit path `path` is excluded from the export.
+    fn is_excluded(&self, path: &Utf8Path) -> bool {
+        let path = path.strip_prefix("./").unwrap_or(path);
+        self.options.exclude.iter().any(|e| path.starts_with(e))
+    }
+
+    /// Whether a content object was already written.
+    fn content_written(&self, checksum: &str) -> bool {
+        match self.options.format {
+            ExportFormat::Repo => self.wrote_content.contains(checksum),
+            ExportFormat::Rootfs => self.rootfs_content.contains_key(checksum),
+        }
+    }
+
+    /// Write a content object at `dest`, as appropriate for the format.
+    fn append_content_at(&mut self, checksum: &str, dest: &Utf8Path) -> Result<()> {
+        match self.options.format {
+            ExportFormat::Repo => {
+                let (objpath, h) = self.append_content(checksum)?;
+                self.append_content_hardlink(&objpath, h, dest)
+            }
+            ExportFormat::Rootfs => self.append_rootfs_content(checksum, dest),
+        }
+    }
+
+    /// Write the content objects of a chunk, along with a hardlink for each
+    /// of their paths.  If the root dirtree of the commit is given, also write
+    /// the parent directories of those paths.
+    fn append_chunk_content(
+        &mut self,
+        chunk: &chunking::Chunk,
+        parent_dirs: Option<&str>,
+    ) -> Result<()> {
+        for (checksum, (_size, paths)) in chunk.content.iter() {
+            let (objpath, h) = self.append_content(checksum.borrow())?;
+            for path in paths.iter() {
+                let path = path.strip_prefix("/").unwrap_or(path);
+                if let Some(root_contents) = parent_dirs {
+                    self.append_parent_dirs(root_contents, path)?;
                 }
+                let h = h.clone();
+                self.append_content_hardlink(&objpath, h, path)?;
             }
         }
+        Ok(())
+    }
 
-        Ok((path, target_header))
+    /// Write directory entries, with their metadata from the commit whose root
+    /// dirtree is `root_contents`, for all parents of `path` which have not
+    /// been written yet.
+    fn append_parent_dirs(&mut self, root_contents: &str, path: &Utf8Path) -> Result<()> {
+        let parent = match path.parent() {
+            Some(p) => p,
+            None => return Ok(()),
+        };
+        let mut contents = root_contents.to_string();
+        let mut dirpath = Utf8PathBuf::new();
+        for name in parent.components().map(|c| c.as_str()) {
+            dirpath.push(name);
+            if let Some(c) = self.wrote_parent_dirs.get(&dirpath) {
+                contents = c.clone();
+                continue;
+            }
+            let (c, meta) = self
+                .find_subdir_meta(&contents, name)?
+                .ok_or_else(|| anyhow!("No such directory: {}", dirpath))?;
+            let meta_v = self.repo.load_variant(ostree::ObjectType::DirMeta, &meta)?;
+            self.append_dir(&dirpath, &meta_v)?;
+            self.wrote_parent_dirs.insert(dirpath.clone(), c.clone());
+            contents = c;
+        }
+        Ok(())
     }
 
-    /// Write a directory using the provided metadata.
-    fn append_dir(&mut self, dirpath: &Utf8Path, meta: &ostree::DirMetaParsed) -> Result<()> {
-        let mut header = tar::Header::new_gnu();
+    /// Write a directory using the provided dirmeta object.
+    fn append_dir(&mut self, dirpath: &Utf8Path, meta_v: &glib::Variant) -> Result<()> {
+        // Safety: Callers load the variant as a dirmeta object
+        let meta = ostree::DirMetaParsed::from_variant(meta_v).unwrap();
+        let mut header = self.new_header();
         header.set_entry_type(tar::EntryType::Directory);
         header.set_size(0);
         header.set_uid(meta.uid as u64);
         header.set_gid(meta.gid as u64);
         header.set_mode(self.filter_mode(meta.mode));
-        self.out
-            .append_data(&mut header, dirpath, std::io::empty())?;
+        let pax = if self.options.format == ExportFormat::Rootfs && !self.options.omit_xattrs {
+            xattrs_to_pax(&meta_v.child_value(3))?
+        } else {
+            Vec::new()
+        };
+        self.append_entry_pax(&mut header, dirpath, std::io::empty(), pax)?;
         Ok(())
     }
 
@@ -427,8 +1356,7 @@ impl<'a, W: std::io::Write> OstreeTarWriter<'a, W> {
         dest: &Utf8Path,
     ) -> Result<()> {
         h.set_entry_type(tar::EntryType::Link);
-        h.set_link_name(srcpath)?;
-        self.out.append_data(&mut h, dest, &mut std::io::empty())?;
+        self.append_entry_link(&mut h, dest, srcpath.as_str(), Vec::new())?;
         Ok(())
     }
 
@@ -440,32 +1368,55 @@ impl<'a, W: std::io::Write> OstreeTarWriter<'a, W> {
         is_root: bool,
         cancellable: Option<&C>,
     ) -> Result<()> {
-        let v = &self
-            .repo
-            .load_variant(ostree::ObjectType::DirTree, &checksum)?;
+        let v = &self.load_dirtree(&checksum)?;
         self.append(ostree::ObjectType::DirTree, &checksum, v)?;
         drop(checksum);
         let v = v.data_as_bytes();
         let v = v.try_as_aligned()?;
         let v = gv_dirtree!().cast(v);
         let (files, dirs) = v.to_tuple();
+        let mut files: Vec<_> = files.into_iter().map(|f| f.to_tuple()).collect();
+        let mut dirs: Vec<_> = dirs.into_iter().map(|d| d.to_tuple()).collect();
+        // ostree already canonicalizes dirtree objects, but be explicit about it.
+        if self.options.deterministic {
+            files.sort_by(|a, b| a.0.to_str().cmp(b.0.to_str()));
+            dirs.sort_by(|a, b| a.0.to_str().cmp(b.0.to_str()));
+        }
+        files.retain(|f| !self.is_excluded(&dirpath.join(f.0.to_str())));
+        dirs.retain(|d| !self.is_excluded(&dirpath.join(d.0.to_str())));
 
         if let Some(c) = cancellable {
             c.set_error_if_cancelled()?;
         }
 
-        for file in files {
-            let (name, csum) = file.to_tuple();
+        // Queue the objects we will write for the workers, in order.
+        let mut to_prefetch = if self.pool.is_some() {
+            let mut seen = HashSet::new();
+            files
+                .iter()
+                .map(|f| hex::encode(f.1))
+                .filter(|c| !self.in_delta_base(c) && !self.content_written(c))
+                .filter(|c| seen.insert(c.clone()))
+                .collect()
+        } else {
+            Vec::new()
+        }
+        .into_iter();
+
+        for (name, csum) in files {
+            if let Some(pool) = self.pool.as_mut() {
+                pool.prefetch(&mut to_prefetch)?;
+            }
             let name = name.to_str();
             let checksum = &hex::encode(csum);
-            let (objpath, h) = self.append_content(checksum)?;
+            if self.in_delta_base(checksum) {
+                continue;
+            }
             let subpath = &dirpath.join(name);
-            let subpath = map_path(subpath);
-            self.append_content_hardlink(&objpath, h, &*subpath)?;
+            self.append_content_mapped(checksum, subpath)?;
         }
 
-        for item in dirs {
-            let (name, contents_csum, meta_csum) = item.to_tuple();
+        for (name, contents_csum, meta_csum) in dirs {
             let name = name.to_str();
             let metadata = {
                 let meta_csum = &hex::encode(meta_csum);
@@ -473,18 +1424,16 @@ impl<'a, W: std::io::Write> OstreeTarWriter<'a, W> {
                     .repo
                     .load_variant(ostree::ObjectType::DirMeta, meta_csum)?;
                 self.append(ostree::ObjectType::DirMeta, meta_csum, meta_v)?;
-                // Safety: We passed the correct variant type just above
-                ostree::DirMetaParsed::from_variant(meta_v).unwrap()
+                meta_v.clone()
             };
             // Special hack because tar stream for containers can't have duplicates.
-            if is_root && name == SYSROOT {
+            if is_root && name == SYSROOT && self.options.format == ExportFormat::Repo {
                 continue;
             }
             let dirtree_csum = hex::encode(contents_csum);
             let subpath = &dirpath.join(name);
-            let subpath = map_path(subpath);
-            self.append_dir(&*subpath, &metadata)?;
-            self.append_dirtree(&*subpath, dirtree_csum, false, cancellable)?;
+            self.append_dir_mapped(subpath, &metadata)?;
+            self.append_dirtree(subpath, dirtree_csum, false, cancellable)?;
         }
 
         Ok(())
@@ -498,22 +1447,306 @@ impl<'a, W: std::io::Write> OstreeTarWriter<'a, W> {
 fn impl_export<W: std::io::Write>(
     repo: &ostree::Repo,
     commit_checksum: &str,
+    base: Option<&str>,
     out: &mut tar::Builder<W>,
-    options: ExportOptions,
+    mut options: ExportOptions,
 ) -> Result<()> {
+    options.resolve_version()?;
+    // Excluded paths are matched against relative paths.
+    for path in options.exclude.iter_mut() {
+        let relative = path
+            .as_str()
+            .trim_start_matches('/')
+            .trim_start_matches("./");
+        *path = relative.into();
+    }
+    match options.format {
+        // Content object checksums cover the xattrs, so they can't be dropped
+        // without rewriting the whole commit.
+        ExportFormat::Repo if options.omit_xattrs => {
+            bail!(
+                "Omitting xattrs is not supported with format version {}",
+                u32::from(options.version)
+            );
+        }
+        ExportFormat::Rootfs if base.is_some() => {
+            bail!("Delta exports are not supported for the rootfs format");
+        }
+        ExportFormat::Rootfs if options.append_manifest => {
+            bail!("A manifest is not supported for the rootfs format");
+        }
+        _ => {}
+    }
+    // Reject offsets which can't map the whole 16 bit id range before
+    // writing anything; larger ids are checked as they are encountered.
+    for (offset, desc) in [(options.uid_offset, "uid"), (options.gid_offset, "gid")] {
+        offset_id((ID_RANGE - 1).into(), offset, desc)?;
+    }
+    let n_workers = options.n_workers;
+    let append_manifest = options.append_manifest;
+    let boot_kernel = options.boot_kernel;
     let writer = &mut OstreeTarWriter::new(repo, out, options);
+    if append_manifest {
+        writer.manifest = Some(Vec::new());
+    }
+    if n_workers > 1 {
+        writer.pool = Some(ContentPool::new(repo, n_workers)?);
+    }
+    if let Some(base) = base {
+        let content = repo
+            .traverse_commit(base, 0, gio::NONE_CANCELLABLE)?
+            .into_iter()
+            .filter(|o| o.object_type() == ostree::ObjectType::File)
+            .map(|o| o.checksum().to_string())
+            .collect();
+        writer.delta_base = Some(DeltaBase {
+            commit: base.to_string(),
+            content,
+        });
+    }
     writer.write_commit(commit_checksum)?;
+    if boot_kernel {
+        writer.append_boot_kernel(commit_checksum)?;
+    }
+    writer.append_manifest(commit_checksum)?;
     Ok(())
 }
 
+/// The version of the ostree object layout in the repo format.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormatVersion {
+    /// The original format, with xattrs in a separate `xattrs` directory.
+    V0,
+    /// The format using `bare-split-xattrs` objects.
+    V1,
+}
+
+/// The newest supported format version.
+pub const LATEST_FORMAT_VERSION: ExportFormatVersion = ExportFormatVersion::V1;
+
+impl Default for ExportFormatVersion {
+    fn default() -> Self {
+        Self::V0
+    }
+}
+
+impl TryFrom<u32> for ExportFormatVersion {
+    type Error = anyhow::Error;
+
+    fn try_from(v: u32) -> Result<Self> {
+        match v {
+            0 => Ok(Self::V0),
+            1 => Ok(Self::V1),
+            n => Err(anyhow!("Unsupported ostree tar format version {}", n)),
+        }
+    }
+}
+
+impl From<ExportFormatVersion> for u32 {
+    fn from(v: ExportFormatVersion) -> Self {
+        match v {
+            ExportFormatVersion::V0 => 0,
+            ExportFormatVersion::V1 => 1,
+        }
+    }
+}
+
+/// Compression applied to an exported tar stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    /// gzip, with a level from 0 (none) to 9 (best).
+    Gzip {
+        /// The compression level.
+        level: u32,
+    },
+    /// zstd, with a level from 1 to 22; 0 selects the zstd default.
+    Zstd {
+        /// The compression level.
+        level: i32,
+    },
+}
+
+/// The layout of an exported tar stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    /// An ostree repository in `sysroot/ostree/repo` holding the objects of
+    /// the commit, along with hardlinks to them forming the checked out tree.
+    /// This can be imported again via [`crate::tar::import_tar`].
+    Repo,
+    /// Only the checked out tree, as in the repo format.  A content object
+    /// used at multiple paths is written at the first one, and as hardlinks to
+    /// it after that.  Extended attributes are stored as `SCHILY.xattr` PAX records.
+    Rootfs,
+}
+
+impl Default for ExportFormat {
+    fn default() -> Self {
+        Self::Repo
+    }
+}
+
+/// How the `/usr/etc` directory of a commit is exported.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EtcMapping {
+    /// Write it as is.
+    None,
+    /// Write it as `/etc` instead.
+    UsrEtcToEtc,
+    /// Write it both as is, and as `/etc`.
+    Both,
+}
+
+impl Default for EtcMapping {
+    fn default() -> Self {
+        Self::UsrEtcToEtc
+    }
+}
+
+/// Progress of a tar export, reported after each object is written.
+#[derive(Debug, Clone)]
+pub struct ExportProgress {
+    /// Number of objects written so far.
+    pub objects_written: u64,
+    /// Total size in bytes of the objects written so far.
+    pub bytes_written: u64,
+    /// The type of the object just written.
+    pub objtype: ostree::ObjectType,
+    /// The checksum of the object just written.
+    pub checksum: String,
+    /// The path of the object just written in the tar stream.
+    pub path: Utf8PathBuf,
+}
+
+/// A callback invoked with [`ExportProgress`]; it is always called from
+/// the thread performing the export.
+pub struct ExportProgressFn(pub Box<dyn Fn(&ExportProgress) + Send>);
+
+impl ExportProgressFn {
+    /// Wrap a progress callback.
+    pub fn new(f: impl Fn(&ExportProgress) + Send + 'static) -> Self {
+        Self(Box::new(f))
+    }
+}
+
+impl std::fmt::Debug for ExportProgressFn {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("ExportProgressFn")
+    }
+}
+
 /// Configuration for tar export.
-#[derive(Debug, Default, PartialEq, Eq)]
+#[derive(Debug, Default)]
 pub struct ExportOptions {
-    /// Format version; must be 0 or 1.
+    /// Format version; must be 0 or 1.  If nonzero, this takes
+    /// precedence over [`Self::version`].
+    #[deprecated(note = "Use `version` instead")]
     pub format_version: u32,
+    /// The version of the object layout.
+    pub version: ExportFormatVersion,
+    /// The layout of the output.
+    pub format: ExportFormat,
+    /// Compress the output stream.  Both compressors are single threaded
+    /// and hence produce reproducible output for a fixed commit and level.
+    pub compression: Option<Compression>,
+    /// Invoked once for every metadata and content object written.
+    pub progress: Option<ExportProgressFn>,
+    /// Only export the file or directory at this path, along with its parent
+    /// directories.  The commit object is omitted, so the result cannot be
+    /// imported via [`crate::tar::import_tar`].
+    pub subpath: Option<Utf8PathBuf>,
+    /// Sort directory entries, and set the modification time of all
+    /// entries to the commit timestamp, so that the output only depends
+    /// on the commit.
+    pub deterministic: bool,
+    /// Do not include extended attributes in the output.  This is rejected
+    /// for [`ExportFormat::Repo`], where object checksums cover the xattrs.
+    pub omit_xattrs: bool,
+    /// Added to the uid of every entry.  The original uid is recorded in
+    /// the [`PAX_ORIGINAL_UID`] extension, which [`crate::tar::import_tar`]
+    /// uses to reverse the mapping.
+    pub uid_offset: u32,
+    /// Added to the gid of every entry; see [`Self::uid_offset`].
+    pub gid_offset: u32,
+    /// Commit metadata keys to copy into PAX records on the commit object
+    /// entry, prefixed with [`PAX_COMMIT_METADATA_PREFIX`].  Missing keys are skipped.
+    pub metadata_pax_keys: Vec<String>,
+    /// How to write `/usr/etc`.  Mapping it is an error if the commit
+    /// also has a toplevel `/etc`.
+    pub map_etc: EtcMapping,
+    /// Read content objects on this many threads, which helps when they
+    /// need to be decompressed.  With 0 or 1, they are read by the writing
+    /// thread.  The output is the same either way.
+    pub n_workers: u32,
+    /// Omit these paths, and everything below them, along with the objects only
+    /// they reference.  Paths are as in the commit, e.g. `usr/etc`, and need not
+    /// exist.  The dirtrees above them are rewritten, and a new commit is written
+    /// for the result, without the detached metadata of the original commit.
+    pub exclude: Vec<Utf8PathBuf>,
+    /// End the stream with a manifest of the objects written, at
+    /// [`MANIFEST_PATH`], which can be checked via [`crate::tar::verify_export`].
+    pub append_manifest: bool,
+    /// For a commit with `ostree.bootable`, also write the kernel and initramfs
+    /// from `/usr/lib/modules/<kver>` as `boot/vmlinuz-<kver>` and
+    /// `boot/initramfs-<kver>.img`, as a deployment would.  Other commits are
+    /// exported as is, with a warning.
+    pub boot_kernel: bool,
 }
 
-/// Export an ostree commit to an (uncompressed) tar archive stream.
+impl ExportOptions {
+    /// Fold the deprecated `format_version` into `version`.
+    #[allow(deprecated)]
+    fn resolve_version(&mut self) -> Result<()> {
+        if self.format_version != 0 {
+            self.version = self.format_version.try_into()?;
+            self.format_version = 0;
+        }
+        Ok(())
+    }
+}
+
+/// Write the tar stream for a commit, returning the underlying writer.
+fn export_commit_to<W: std::io::Write>(
+    repo: &ostree::Repo,
+    commit_checksum: &str,
+    base: Option<&str>,
+    out: W,
+    options: ExportOptions,
+) -> Result<W> {
+    let mut tar = tar::Builder::new(out);
+    impl_export(repo, commit_checksum, base, &mut tar, options)?;
+    Ok(tar.into_inner()?)
+}
+
+/// Write the tar stream for a commit, compressed if configured.
+fn export_commit_compressed(
+    repo: &ostree::Repo,
+    commit_checksum: &str,
+    base: Option<&str>,
+    out: impl std::io::Write,
+    options: ExportOptions,
+) -> Result<()> {
+    match options.compression {
+        None => {
+            export_commit_to(repo, commit_checksum, base, out, options)?;
+        }
+        Some(Compression::Gzip { level }) => {
+            let out = flate2::write::GzEncoder::new(out, flate2::Compression::new(level));
+            export_commit_to(repo, commit_checksum, base, out, options)?.finish()?;
+        }
+        Some(Compression::Zstd { level }) => {
+            let out = zstd::stream::write::Encoder::new(out, level)?;
+            export_commit_to(repo, commit_checksum, base, out, options)?.finish()?;
+        }
+    }
+    Ok(())
+}
+
+/// Export an ostree commit to a tar archive stream, optionally compressed
+/// as configured by [`ExportOptions::compression`].
+///
+/// The detached metadata of the commit (which holds e.g. GPG signatures)
+/// is always included as a `commitmeta` object, and [`crate::tar::import_tar`]
+/// restores it onto the imported commit.
 #[context("Exporting commit")]
 pub fn export_commit(
     repo: &ostree::Repo,
@@ -522,44 +1755,114 @@ pub fn export_commit(
     options: Option<ExportOptions>,
 ) -> Result<()> {
     let commit = repo.require_rev(rev)?;
-    let mut tar = tar::Builder::new(out);
     let options = options.unwrap_or_default();
-    impl_export(repo, commit.as_str(), &mut tar, options)?;
-    tar.finish()?;
+    export_commit_compressed(repo, commit.as_str(), None, out, options)
+}
+
+/// Export an ostree commit to an asynchronous writer; this is otherwise
+/// the same as [`export_commit`].
+///
+/// The export runs on a blocking thread, so it does not stall the runtime.
+/// Its output is relayed through a bounded pipe, so a slow writer applies
+/// backpressure to the export.
+pub async fn export_commit_async(
+    repo: &ostree::Repo,
+    rev: &str,
+    mut out: impl tokio::io::AsyncWrite + Unpin,
+    options: Option<ExportOptions>,
+) -> Result<()> {
+    use std::io::Write;
+    use tokio::io::AsyncWriteExt;
+
+    let repo = repo.clone();
+    let rev = rev.to_string();
+    let (tx, rx) = tokio::io::duplex(BUF_CAPACITY);
+    let exporter = crate::tokio_util::spawn_blocking_cancellable_flatten(move |_| {
+        let tx = tokio_util::io::SyncIoBridge::new(tx);
+        let mut tx = std::io::BufWriter::with_capacity(BUF_CAPACITY, tx);
+        export_commit(&repo, &rev, &mut tx, options)?;
+        tx.flush()?;
+        Ok(())
+    });
+    // Move the reader into the copier, so that it is closed (and hence
+    // unblocks the exporter) if writing fails.
+    let copier = async {
+        let mut rx = rx;
+        tokio::io::copy(&mut rx, &mut out).await
+    };
+    let (exported, copied) = tokio::join!(exporter, copier);
+    // An error from the writer takes precedence, as it will likely also
+    // have caused the exporter to fail.
+    copied.context("Writing export")?;
+    exported?;
+    out.flush().await?;
     Ok(())
 }
 
+/// Export the changes from `from_rev` to `to_rev` as a tar archive stream.
+///
+/// This is like [`export_commit`] for `to_rev`, except that content objects
+/// which are also referenced by `from_rev` are omitted, along with their
+/// paths in the checkout.  All metadata objects are still included.
+///
+/// When the result is passed to [`crate::tar::import_tar`], the destination
+/// repository must already contain `from_rev`.
+#[context("Exporting commit diff")]
+pub fn export_commit_diff(
+    repo: &ostree::Repo,
+    from_rev: &str,
+    to_rev: &str,
+    out: impl std::io::Write,
+    options: Option<ExportOptions>,
+) -> Result<()> {
+    let from = repo.require_rev(from_rev)?;
+    let to = repo.require_rev(to_rev)?;
+    let options = options.unwrap_or_default();
+    export_commit_compressed(repo, to.as_str(), Some(from.as_str()), out, options)
+}
+
 /// Output a chunk.
 pub(crate) fn export_chunk<W: std::io::Write>(
     repo: &ostree::Repo,
     chunk: &chunking::Chunk,
     out: &mut tar::Builder<W>,
+) -> Result<()> {
+    export_chunk_impl(repo, chunk, out, None)
+}
+
+fn export_chunk_impl<W: std::io::Write>(
+    repo: &ostree::Repo,
+    chunk: &chunking::Chunk,
+    out: &mut tar::Builder<W>,
+    parent_dirs: Option<&str>,
 ) -> Result<()> {
     let writer = &mut OstreeTarWriter::new(repo, out, ExportOptions::default());
     writer.write_repo_structure()?;
-    for (checksum, (_size, paths)) in chunk.content.iter() {
-        let (objpath, h) = writer.append_content(checksum.borrow())?;
-        for path in paths.iter() {
-            let path = path.strip_prefix("/").unwrap_or(path);
-            let h = h.clone();
-            writer.append_content_hardlink(&objpath, h, path)?;
-        }
-    }
+    writer.append_chunk_content(chunk, parent_dirs)?;
     Ok(())
 }
 
 /// Output the last chunk in a chunking.
-#[context("Exporting final chunk")]
 pub(crate) fn export_final_chunk<W: std::io::Write>(
     repo: &ostree::Repo,
     chunking: &Chunking,
     out: &mut tar::Builder<W>,
+) -> Result<()> {
+    export_final_chunk_impl(repo, chunking, out, None)
+}
+
+#[context("Exporting final chunk")]
+fn export_final_chunk_impl<W: std::io::Write>(
+    repo: &ostree::Repo,
+    chunking: &Chunking,
+    out: &mut tar::Builder<W>,
+    parent_dirs: Option<&str>,
 ) -> Result<()> {
     let cancellable = gio::NONE_CANCELLABLE;
     // For chunking, we default to format version 1
     #[allow(clippy::needless_update)]
     let options = ExportOptions {
-        format_version: 1,
+        version: ExportFormatVersion::V1,
         ..Default::default()
     };
     let writer = &mut OstreeTarWriter::new(repo, out, options);
@@ -584,18 +1887,175 @@ pub(crate) fn export_final_chunk<W: std::io::Write>(
         writer.append(objtype, checksum, &v)?;
     }
 
-    for (checksum, (_size, paths)) in chunking.remainder.content.iter() {
-        let (objpath, h) = writer.append_content(checksum.borrow())?;
-        for path in paths.iter() {
-            let path = path.strip_prefix("/").unwrap_or(path);
-            let h = h.clone();
-            writer.append_content_hardlink(&objpath, h, path)?;
-        }
-    }
+    writer.append_chunk_content(&chunking.remainder, parent_dirs)?;
 
     Ok(())
 }
 
+/// The name of the manifest file written by [`export_chunked`].
+pub const CHUNKED_MANIFEST: &str = "manifest.json";
+
+/// Options for [`export_chunked`].
+#[derive(Debug, Default)]
+pub struct ExportChunkedOptions {
+    /// Maximum number of chunks, including the final ostree chunk.
+    pub max_chunks: Option<NonZeroU32>,
+    /// How to group components into chunks.
+    pub packing: PackingStrategy,
+}
+
+/// A tar archive written by [`export_chunked`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExportedChunk {
+    /// The file name of the archive in the target directory.
+    pub filename: String,
+    /// A human readable description of the content.
+    pub name: String,
+    /// The size of the archive.
+    pub size: u64,
+    /// The digest of the archive, in the form `sha256:<hex>`.
+    pub digest: String,
+}
+
+/// The manifest written by [`export_chunked`] as [`CHUNKED_MANIFEST`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExportedChunks {
+    /// The exported commit.
+    pub commit: String,
+    /// The chunks; the last one holds the commit and all metadata objects.
+    pub chunks: Vec<ExportedChunk>,
+}
+
+/// Wraps a writer, computing the sha256 and size of its output.
+struct DigestWriter<W> {
+    inner: W,
+    hasher: openssl::hash::Hasher,
+    size: u64,
+}
+
+impl<W: std::io::Write> DigestWriter<W> {
+    fn new(inner: W) -> Result<Self> {
+        let hasher = openssl::hash::Hasher::new(openssl::hash::MessageDigest::sha256())?;
+        Ok(Self {
+            inner,
+            hasher,
+            size: 0,
+        })
+    }
+
+    /// Flush the writer, returning the size and digest of the output.
+    fn finish(mut self) -> Result<(u64, String)> {
+        self.inner.flush()?;
+        let digest = hex::encode(self.hasher.finish()?);
+        Ok((self.size, format!("sha256:{}", digest)))
+    }
+}
+
+impl<W: std::io::Write> std::io::Write for DigestWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher
+            .update(&buf[..n])
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        self.size += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// The file name of the final chunk, holding the commit and metadata.
+const OSTREE_CHUNK_FILENAME: &str = "ostree.tar";
+
+/// Compute the file name of the archive for a chunk from its name, replacing
+/// anything which is not safe in a file name, and ensuring it is unique.
+fn chunk_filename(name: &str, used: &mut HashSet<String>) -> String {
+    let mut base: String = name
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' | '.' | '+' => c,
+            _ => '_',
+        })
+        .collect();
+    if base.is_empty() || base.starts_with('.') {
+        base.insert(0, '_');
+    }
+    let mut filename = format!("{}.tar", base);
+    let mut n = 1;
+    while filename == OSTREE_CHUNK_FILENAME || !used.insert(filename.clone()) {
+        n += 1;
+        filename = format!("{}-{}.tar", base, n);
+    }
+    filename
+}
+
+type ChunkWriter = tar::Builder<DigestWriter<std::io::BufWriter<ostree::cap_std::fs::File>>>;
+
+/// Create `filename` in `dir` and write a tar archive to it.
+fn write_chunk_file(
+    dir: &Dir,
+    filename: String,
+    name: &str,
+    f: impl FnOnce(&mut ChunkWriter) -> Result<()>,
+) -> Result<ExportedChunk> {
+    let out = std::io::BufWriter::new(dir.create(&filename)?);
+    let mut out = tar::Builder::new(DigestWriter::new(out)?);
+    f(&mut out).with_context(|| format!("Writing {}", filename))?;
+    let (size, digest) = out.into_inner()?.finish()?;
+    Ok(ExportedChunk {
+        filename,
+        name: name.to_string(),
+        size,
+        digest,
+    })
+}
+
+/// Export a commit as a set of tar archives, partitioned in the same way as
+/// the layers of a chunked container image (see [`crate::chunking`]).
+///
+/// One archive is written per chunk, named after the chunk, plus a final one
+/// named `ostree.tar` which holds the commit and all metadata objects.  Each
+/// archive is self contained, including the parent directories of its files
+/// with their metadata from the commit.  A manifest describing the archives
+/// is written as [`CHUNKED_MANIFEST`].
+#[context("Exporting chunked commit")]
+pub fn export_chunked(
+    repo: &ostree::Repo,
+    rev: &str,
+    meta: &ObjectMetaSized,
+    dir: &Dir,
+    opts: Option<ExportChunkedOptions>,
+) -> Result<ExportedChunks> {
+    let opts = opts.unwrap_or_default();
+    let mut chunking =
+        Chunking::from_mapping(repo, rev, meta.clone(), opts.max_chunks, opts.packing)?;
+    let (commit_v, _) = repo.load_commit(&chunking.commit)?;
+    let root_contents = &hex::encode(commit_v.child_value(6).data_as_bytes());
+    let mut chunks = Vec::new();
+    let mut filenames = HashSet::new();
+    for chunk in chunking.take_chunks() {
+        let filename = chunk_filename(&chunk.name, &mut filenames);
+        chunks.push(write_chunk_file(dir, filename, &chunk.name, |out| {
+            export_chunk_impl(repo, &chunk, out, Some(root_contents))
+        })?);
+    }
+    let name = format!("ostree commit {}", chunking.commit);
+    chunks.push(write_chunk_file(
+        dir,
+        OSTREE_CHUNK_FILENAME.into(),
+        &name,
+        |out| export_final_chunk_impl(repo, &chunking, out, Some(root_contents)),
+    )?);
+    let manifest = ExportedChunks {
+        commit: chunking.commit.to_string(),
+        chunks,
+    };
+    dir.write(CHUNKED_MANIFEST, serde_json::to_vec_pretty(&manifest)?)?;
+    Ok(manifest)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -609,6 +2069,29 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_unmap_path() {
+        assert_eq!(unmap_path("usr/bin".into()), Utf8Path::new("usr/bin"));
+        assert_eq!(unmap_path("etcetera".into()), Utf8Path::new("etcetera"));
+        assert_eq!(unmap_path("etc/blah".into()), Utf8Path::new("usr/etc/blah"));
+    }
+
+    #[test]
+    fn test_chunk_filename() {
+        let mut used = HashSet::new();
+        let cases = [
+            ("bash", "bash.tar"),
+            ("glibc and bash", "glibc_and_bash.tar"),
+            ("bash", "bash-2.tar"),
+            ("../etc/passwd", "_.._etc_passwd.tar"),
+            ("", "_.tar"),
+            ("ostree", "ostree-2.tar"),
+        ];
+        for (name, expected) in cases {
+            assert_eq!(chunk_filename(name, &mut used), expected);
+        }
+    }
+
     #[test]
     fn test_denormal_symlink() {
         let normal = ["/", "/usr", "../usr/bin/blah"];
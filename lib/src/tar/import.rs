@@ -1,5 +1,7 @@
 //! APIs for extracting OSTree commits from container images
 
+use super::export::{object_suffix, unmap_path};
+use crate::objgv::*;
 use crate::Result;
 use anyhow::{anyhow, bail, ensure, Context};
 use camino::Utf8Path;
@@ -8,11 +10,16 @@ use fn_error_context::context;
 use gio::glib;
 use gio::prelude::*;
 use glib::Variant;
+use gvariant::aligned_bytes::TryAsAligned;
+use gvariant::{Marker, Structure};
 use ostree::gio;
+use std::borrow::Cow;
 use std::collections::BTreeSet;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::convert::TryInto;
 use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 use tracing::{event, instrument, Level};
 
 /// Arbitrary limit on xattrs to avoid RAM exhaustion attacks. The actual filesystem limits are often much smaller.
@@ -28,7 +35,12 @@ const MAX_METADATA_SIZE: u32 = 10 * 1024 * 1024;
 pub(crate) const SMALL_REGFILE_SIZE: usize = 127 * 1024;
 
 // The prefix for filenames that contain content we actually look at.
-const REPO_PREFIX: &str = "sysroot/ostree/repo/";
+pub(super) const REPO_PREFIX: &str = "sysroot/ostree/repo/";
+// The toplevel directory holding the repository, as opposed to the checkout.
+const SYSROOT: &str = "sysroot";
+// The repository configuration file in format version 0.
+const V0_REPO_CONFIG: &str = "sysroot/config";
+
 /// Statistics from import.
 #[derive(Debug, Default)]
 struct ImportStats {
@@ -61,6 +73,154 @@ pub(crate) struct Importer {
 
     /// Additional state depending on whether we're importing an object set or a commit.
     data: ImporterMode,
+
+    /// Filters the paths of the checkout in a commit import, if set.
+    filter: Option<EntryFilter>,
+
+    /// Reports progress, if enabled.
+    progress: Option<Arc<ProgressState>>,
+
+    /// Reject unexpected entries.
+    strict: bool,
+    /// The content objects found so far in the stream, and the checksum of
+    /// their xattrs; used to verify repeated objects against the stored object.
+    seen_content: HashMap<String, String>,
+    /// Detached metadata for the commit, used if the stream has none.
+    detached_metadata: Option<glib::Variant>,
+}
+
+/// The progress of an import, shared between the importer and its input.
+struct ProgressState {
+    tx: tokio::sync::watch::Sender<ImportProgress>,
+    current: Mutex<ImportProgress>,
+}
+
+impl ProgressState {
+    fn new(tx: tokio::sync::watch::Sender<ImportProgress>) -> Self {
+        Self {
+            tx,
+            current: Default::default(),
+        }
+    }
+
+    /// Apply `f` to the current progress, and send the result.
+    fn update(&self, f: impl FnOnce(&mut ImportProgress)) {
+        let mut current = self.current.lock().unwrap();
+        f(&mut current);
+        // Ignore errors, if the caller disconnected from progress that's OK.
+        let _ = self.tx.send(*current);
+    }
+}
+
+/// A reader which accounts the bytes read in the import progress.
+struct CountingReader<R> {
+    inner: R,
+    progress: Arc<ProgressState>,
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.progress.update(|p| p.bytes_read += n as u64);
+        Ok(n)
+    }
+}
+
+/// The state of a [`TarImportOptions::filter`].
+struct EntryFilter {
+    filter: ImportFilter,
+    /// The paths skipped by the filter.
+    skipped: Vec<Utf8PathBuf>,
+    /// The number of entries dropped, including the children of skipped directories.
+    n_filtered: u64,
+}
+
+impl EntryFilter {
+    fn new(filter: ImportFilter) -> Self {
+        Self {
+            filter,
+            skipped: Vec::new(),
+            n_filtered: 0,
+        }
+    }
+
+    /// Pass an entry of the checkout (i.e. outside of `sysroot`) to the filter.
+    fn apply<R: std::io::Read>(&mut self, e: &tar::Entry<R>) -> Result<()> {
+        let path = entry_path(e)?;
+        if path.as_str().is_empty()
+            || path.starts_with(SYSROOT)
+            || path.as_str() == crate::tar::MANIFEST_PATH
+        {
+            return Ok(());
+        }
+        if self.skipped.iter().any(|p| path.starts_with(p)) {
+            self.n_filtered += 1;
+            return Ok(());
+        }
+        match (self.filter)(path.as_path(), e.header()) {
+            ImportFilterAction::Import => {}
+            ImportFilterAction::Skip => {
+                self.n_filtered += 1;
+                self.skipped.push(path);
+            }
+            ImportFilterAction::Abort => bail!("Import aborted by filter at {}", path),
+        }
+        Ok(())
+    }
+}
+
+/// Read a stream into `buf` until it is full or the stream ends, returning
+/// the number of bytes read.
+fn read_fill(r: &mut impl Read, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut n = 0;
+    while n < buf.len() {
+        match r.read(&mut buf[n..]) {
+            Ok(0) => break,
+            Ok(k) => n += k,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(n)
+}
+
+/// Whether two streams have the same content, compared in fixed-size chunks.
+fn streams_equal(mut a: impl Read, mut b: impl Read) -> std::io::Result<bool> {
+    let mut abuf = [0u8; 8192];
+    let mut bbuf = [0u8; 8192];
+    loop {
+        let n = read_fill(&mut a, &mut abuf)?;
+        let m = read_fill(&mut b, &mut bbuf)?;
+        if abuf[..n] != bbuf[..m] {
+            return Ok(false);
+        }
+        if n == 0 {
+            return Ok(true);
+        }
+    }
+}
+
+/// Return the path of a tar entry relative to the root of the stream.
+/// A leading `./` or `/` is stripped, as written by e.g. `tar -C dir .` or
+/// with absolute paths; paths containing `..` are rejected.
+pub(super) fn entry_path<R: std::io::Read>(e: &tar::Entry<R>) -> Result<Utf8PathBuf> {
+    let orig_path = e.path()?;
+    let path = Utf8Path::from_path(&*orig_path)
+        .ok_or_else(|| anyhow!("Invalid non-utf8 path {:?}", orig_path))?;
+    relative_path(path)
+}
+
+/// Strip a leading `./` or `/` from a path; paths containing `..` are rejected.
+pub(super) fn relative_path(path: &Utf8Path) -> Result<Utf8PathBuf> {
+    path.components()
+        .filter_map(|c| match c {
+            camino::Utf8Component::Normal(c) => Some(Ok(c)),
+            camino::Utf8Component::ParentDir => {
+                Some(Err(anyhow!("Invalid path with parent component: {}", path)))
+            }
+            _ => None,
+        })
+        .collect()
 }
 
 /// Validate size/type of a tar header for OSTree metadata object.
@@ -87,9 +247,30 @@ fn header_attrs(header: &tar::Header) -> Result<(u32, u32, u32)> {
     Ok((uid, gid, mode))
 }
 
+/// Like [`header_attrs`], but if the entry carries the original ownership
+/// in PAX extensions (as written by an export with uid/gid offsets), use that.
+fn entry_attrs<R: std::io::Read>(entry: &mut tar::Entry<R>) -> Result<(u32, u32, u32)> {
+    let (mut uid, mut gid, mode) = header_attrs(entry.header())?;
+    if let Some(extensions) = entry.pax_extensions()? {
+        for ext in extensions {
+            let ext = ext?;
+            let target = match ext.key() {
+                Ok(crate::tar::PAX_ORIGINAL_UID) => &mut uid,
+                Ok(crate::tar::PAX_ORIGINAL_GID) => &mut gid,
+                _ => continue,
+            };
+            let v = ext.value().map_err(anyhow::Error::msg)?;
+            *target = v
+                .parse()
+                .with_context(|| format!("Parsing {}", ext.key().unwrap_or_default()))?;
+        }
+    }
+    Ok((uid, gid, mode))
+}
+
 // The C function ostree_object_type_from_string aborts on
 // unknown strings, so we have a safe version here.
-fn objtype_from_string(t: &str) -> Option<ostree::ObjectType> {
+pub(super) fn objtype_from_string(t: &str) -> Option<ostree::ObjectType> {
     Some(match t {
         "commit" => ostree::ObjectType::Commit,
         "commitmeta" => ostree::ObjectType::CommitMeta,
@@ -101,7 +282,7 @@ fn objtype_from_string(t: &str) -> Option<ostree::ObjectType> {
 }
 
 /// Given a tar entry, read it all into a GVariant
-fn entry_to_variant<R: std::io::Read, T: StaticVariantType>(
+pub(super) fn entry_to_variant<R: std::io::Read, T: StaticVariantType>(
     mut entry: tar::Entry<R>,
     desc: &str,
 ) -> Result<glib::Variant> {
@@ -120,7 +301,7 @@ fn entry_to_variant<R: std::io::Read, T: StaticVariantType>(
 ///
 /// Normal ostree object paths look like 00/1234.commit.
 /// In the tar format, we may also see 00/1234.file.xattrs.
-fn parse_object_entry_path(path: &Utf8Path) -> Result<(&str, &Utf8Path, &str)> {
+pub(super) fn parse_object_entry_path(path: &Utf8Path) -> Result<(&str, &Utf8Path, &str)> {
     // The "sharded" commit directory.
     let parentname = path
         .parent()
@@ -140,7 +321,7 @@ fn parse_object_entry_path(path: &Utf8Path) -> Result<(&str, &Utf8Path, &str)> {
     Ok((parentname, name, objtype))
 }
 
-fn parse_checksum(parent: &str, name: &Utf8Path) -> Result<String> {
+pub(super) fn parse_checksum(parent: &str, name: &Utf8Path) -> Result<String> {
     let checksum_rest = name
         .file_stem()
         .ok_or_else(|| anyhow!("Invalid object path part {}", name))?;
@@ -171,9 +352,20 @@ impl Importer {
             next_xattrs: None,
             stats: Default::default(),
             data: ImporterMode::Commit(None),
+            filter: None,
+            progress: None,
+            strict: false,
+            seen_content: Default::default(),
+            detached_metadata: None,
         }
     }
 
+    /// Use the provided detached metadata (e.g. signatures) for the commit if the
+    /// stream does not include a `commitmeta` object.
+    pub(crate) fn set_detached_metadata(&mut self, v: glib::Variant) {
+        self.detached_metadata = Some(v);
+    }
+
     /// Create an importer to write an "object set"; a chunk of objects which is
     /// usually streamed from a separate storage system, such as an OCI container image layer.
     pub(crate) fn new_for_object_set(repo: &ostree::Repo) -> Self {
@@ -185,6 +377,11 @@ impl Importer {
             next_xattrs: None,
             stats: Default::default(),
             data: ImporterMode::ObjectSet(Default::default()),
+            filter: None,
+            progress: None,
+            strict: false,
+            seen_content: Default::default(),
+            detached_metadata: None,
         }
     }
 
@@ -192,15 +389,15 @@ impl Importer {
     // `/sysroot/ostree`.
     // It is an error if the filename is invalid UTF-8.  If it is valid UTF-8, return
     // an owned copy of the path.
+    // In strict mode, other files under `sysroot` are rejected.
     fn filter_entry<R: std::io::Read>(
         e: tar::Entry<R>,
+        strict: bool,
     ) -> Result<Option<(tar::Entry<R>, Utf8PathBuf)>> {
+        let path = entry_path(&e)?;
         if e.header().entry_type() == tar::EntryType::Directory {
             return Ok(None);
         }
-        let orig_path = e.path()?;
-        let path = Utf8Path::from_path(&*orig_path)
-            .ok_or_else(|| anyhow!("Invalid non-utf8 path {:?}", orig_path))?;
         // Ignore the regular non-object file hardlinks we inject
         if let Ok(path) = path.strip_prefix(REPO_PREFIX) {
             // Filter out the repo config file
@@ -209,6 +406,8 @@ impl Importer {
             }
             let path = path.into();
             Ok(Some((e, path)))
+        } else if strict && path.starts_with(SYSROOT) && path != V0_REPO_CONFIG {
+            Err(anyhow!("Unexpected entry {}", path))
         } else {
             Ok(None)
         }
@@ -222,6 +421,13 @@ impl Importer {
         Ok((checksum, objtype))
     }
 
+    /// Account for an imported object in the progress, if enabled.
+    fn object_imported(&self) {
+        if let Some(progress) = self.progress.as_ref() {
+            progress.update(|p| p.objects_written += 1);
+        }
+    }
+
     /// Import a metadata object.
     fn import_metadata<R: std::io::Read>(
         &mut self,
@@ -240,6 +446,16 @@ impl Importer {
             }
             o => return Err(anyhow!("Invalid metadata object type; {:?}", o)),
         };
+        // Existing objects (including those found earlier in the stream) are
+        // not rewritten, and so not verified either.
+        let actual = crate::tar::sha256_hex(&v.data_as_bytes());
+        ensure!(
+            actual == checksum,
+            "Corrupted {:?} object {}: found checksum {}",
+            objtype,
+            checksum,
+            actual
+        );
         // FIXME validate here that this checksum was in the set we expected.
         // https://github.com/ostreedev/ostree-rs-ext/issues/1
         let actual =
@@ -258,7 +474,7 @@ impl Importer {
         xattrs: glib::Variant,
         cancellable: Option<&gio::Cancellable>,
     ) -> Result<()> {
-        let (uid, gid, mode) = header_attrs(entry.header())?;
+        let (uid, gid, mode) = entry_attrs(&mut entry)?;
         let w = self.repo.write_regfile(
             Some(checksum),
             uid,
@@ -295,7 +511,7 @@ impl Importer {
         xattrs: glib::Variant,
         cancellable: Option<&gio::Cancellable>,
     ) -> Result<()> {
-        let (uid, gid, mode) = header_attrs(entry.header())?;
+        let (uid, gid, mode) = entry_attrs(&mut entry)?;
         assert!(size <= SMALL_REGFILE_SIZE);
         let mut buf = vec![0u8; size];
         entry.read_exact(&mut buf[..])?;
@@ -316,11 +532,11 @@ impl Importer {
     /// Import a content object, symlink flavour.
     fn import_symlink_object<R: std::io::Read>(
         &mut self,
-        entry: tar::Entry<R>,
+        mut entry: tar::Entry<R>,
         checksum: &str,
         xattrs: glib::Variant,
     ) -> Result<()> {
-        let (uid, gid, _) = header_attrs(entry.header())?;
+        let (uid, gid, _) = entry_attrs(&mut entry)?;
         let target = entry
             .link_name()?
             .ok_or_else(|| anyhow!("Invalid symlink"))?;
@@ -360,12 +576,22 @@ impl Importer {
             return Err(anyhow!("Object mismatch, found xattrs for {}", file_csum));
         }
 
-        if self
-            .repo
-            .has_object(ostree::ObjectType::File, checksum, cancellable)?
-        {
+        // A repeated object is not rewritten; check that it is the same.
+        if let Some(first_xattrs) = self.seen_content.get(checksum) {
+            ensure!(
+                *first_xattrs == xattrs_csum,
+                "Duplicate content object {} has different xattrs",
+                checksum
+            );
+            ensure!(
+                self.matches_stored_content(entry, checksum, None, cancellable)?,
+                "Duplicate content object {} differs from its first occurrence",
+                checksum
+            );
             return Ok(());
         }
+        self.seen_content
+            .insert(checksum.to_string(), xattrs_csum.clone());
 
         // Retrieve xattrs content from the cache.
         let xattrs = self
@@ -374,6 +600,19 @@ impl Importer {
             .cloned()
             .ok_or_else(|| anyhow!("Failed to find xattrs content {}", xattrs_csum,))?;
 
+        // An object which is already present is not rewritten either.
+        if self
+            .repo
+            .has_object(ostree::ObjectType::File, checksum, cancellable)?
+        {
+            ensure!(
+                self.matches_stored_content(entry, checksum, Some(&xattrs), cancellable)?,
+                "Content object {} differs from the stored object",
+                checksum
+            );
+            return Ok(());
+        }
+
         match entry.header().entry_type() {
             tar::EntryType::Regular => {
                 if size > SMALL_REGFILE_SIZE {
@@ -383,10 +622,54 @@ impl Importer {
                 }
             }
             tar::EntryType::Symlink => self.import_symlink_object(entry, checksum, xattrs),
+            // A hardlink to another object path can only be valid if it refers to
+            // this same object, which we would have found above.
+            tar::EntryType::Link => Err(anyhow!("Hardlinked object was not previously imported")),
             o => return Err(anyhow!("Invalid tar entry of type {:?}", o)),
         }
     }
 
+    /// Whether a content object entry matches the stored object, comparing the
+    /// content as a stream; the xattrs are only compared if given.
+    fn matches_stored_content<R: std::io::Read>(
+        &self,
+        mut entry: tar::Entry<R>,
+        checksum: &str,
+        xattrs: Option<&glib::Variant>,
+        cancellable: Option<&gio::Cancellable>,
+    ) -> Result<bool> {
+        let (instream, info, stored_xattrs) = self.repo.load_file(checksum, cancellable)?;
+        let info = info.ok_or_else(|| anyhow!("Missing metadata for object {}", checksum))?;
+        let (uid, gid, mode) = entry_attrs(&mut entry)?;
+        let mut same =
+            info.attribute_uint32("unix::uid") == uid && info.attribute_uint32("unix::gid") == gid;
+        if let Some(xattrs) = xattrs {
+            same &= stored_xattrs.as_ref() == Some(xattrs);
+        }
+        match entry.header().entry_type() {
+            tar::EntryType::Regular => {
+                let expected_mode = info.attribute_uint32("unix::mode");
+                same &= (expected_mode & !libc::S_IFMT) == (mode & !libc::S_IFMT);
+                same &= info.size() as u64 == entry.header().entry_size()?;
+                if same {
+                    same = match instream {
+                        Some(instream) => streams_equal(instream.into_read(), &mut entry)?,
+                        None => streams_equal(std::io::empty(), &mut entry)?,
+                    };
+                }
+            }
+            tar::EntryType::Symlink => {
+                let target = entry.link_name()?;
+                let target = target.as_deref().and_then(|t| t.to_str());
+                same &= target == info.symlink_target().as_deref();
+            }
+            // A hardlink can only refer to the first copy.
+            tar::EntryType::Link => {}
+            o => bail!("Invalid tar entry of type {:?}", o),
+        }
+        Ok(same)
+    }
+
     /// Given a tar entry that looks like an object (its path is under ostree/repo/objects/),
     /// determine its type and import it.
     #[context("Importing object {}", path)]
@@ -403,6 +686,7 @@ impl Importer {
             "commit" => Err(anyhow!("Found multiple commit objects")),
             "file" => {
                 self.import_content_object(entry, &checksum, cancellable)?;
+                self.object_imported();
                 // Track the objects we wrote
                 match &mut self.data {
                     ImporterMode::ObjectSet(imported) => {
@@ -430,7 +714,9 @@ impl Importer {
                     }
                     ImporterMode::Commit(_) => {}
                 }
-                self.import_metadata(entry, &checksum, objtype)
+                self.import_metadata(entry, &checksum, objtype)?;
+                self.object_imported();
+                Ok(())
             }
         }
     }
@@ -593,11 +879,16 @@ impl Importer {
         cancellable: Option<&gio::Cancellable>,
     ) -> Result<()> {
         for entry in ents {
+            if let Some(c) = cancellable {
+                c.set_error_if_cancelled()?;
+            }
             let (entry, path) = entry?;
             if let Ok(p) = path.strip_prefix("objects/") {
                 self.import_object(entry, p, cancellable)?;
             } else if path.strip_prefix("xattrs/").is_ok() {
                 self.process_split_xattrs_content(entry)?;
+            } else if self.strict {
+                bail!("Unexpected entry {}{}", REPO_PREFIX, path);
             }
         }
         Ok(())
@@ -608,8 +899,9 @@ impl Importer {
         archive: &mut tar::Archive<impl Read + Send + Unpin>,
         cancellable: Option<&gio::Cancellable>,
     ) -> Result<()> {
+        let strict = self.strict;
         let ents = archive.entries()?.filter_map(|e| match e {
-            Ok(e) => Self::filter_entry(e).transpose(),
+            Ok(e) => Self::filter_entry(e, strict).transpose(),
             Err(e) => Some(Err(anyhow::Error::msg(e))),
         });
         self.import_objects_impl(ents, cancellable)
@@ -620,18 +912,89 @@ impl Importer {
         archive: &mut tar::Archive<impl Read + Send + Unpin>,
         cancellable: Option<&gio::Cancellable>,
     ) -> Result<()> {
-        // This can only be invoked once
-        assert!(matches!(self.data, ImporterMode::Commit(None)));
+        // The checkout entries are only passed to the filter, if any.
+        let mut filter = self.filter.take();
+        let progress = self.progress.clone();
+        let strict = self.strict;
         // Create an iterator that skips over directories; we just care about the file names.
         let mut ents = archive.entries()?.filter_map(|e| match e {
-            Ok(e) => Self::filter_entry(e).transpose(),
+            Ok(e) => {
+                if let Some(progress) = progress.as_ref() {
+                    progress.update(|p| p.entries_processed += 1);
+                }
+                if let Some(filter) = filter.as_mut() {
+                    if let Err(e) = filter.apply(&e) {
+                        return Some(Err(e));
+                    }
+                }
+                Self::filter_entry(e, strict).transpose()
+            }
             Err(e) => Some(Err(anyhow::Error::msg(e))),
         });
+        self.import_commit_entries(ents, cancellable)?;
+        self.filter = filter;
+        Ok(())
+    }
+
+    /// Import an archive of a chunked export: either the one holding the commit
+    /// and metadata objects, or one with only content objects.
+    pub(crate) fn import_chunk(
+        &mut self,
+        archive: &mut tar::Archive<impl Read + Send + Unpin>,
+        cancellable: Option<&gio::Cancellable>,
+    ) -> Result<()> {
+        let strict = self.strict;
+        let mut ents = archive
+            .entries()?
+            .filter_map(|e| match e {
+                Ok(e) => Self::filter_entry(e, strict).transpose(),
+                Err(e) => Some(Err(anyhow::Error::msg(e))),
+            })
+            .peekable();
+        let has_commit = match ents.peek() {
+            Some(Ok((_, path))) => path.extension() == Some("commit"),
+            _ => false,
+        };
+        if !has_commit {
+            return self.import_objects_impl(ents, cancellable);
+        }
+        if let ImporterMode::Commit(Some(c)) = &self.data {
+            bail!("Found multiple commit objects; already imported {}", c);
+        }
+        self.import_commit_entries(ents, cancellable)
+    }
+
+    fn import_commit_entries<'a>(
+        &mut self,
+        mut ents: impl Iterator<
+            Item = Result<(tar::Entry<'a, impl Read + Send + Unpin + 'a>, Utf8PathBuf)>,
+        >,
+        cancellable: Option<&gio::Cancellable>,
+    ) -> Result<()> {
+        // This can only be invoked once
+        assert!(matches!(self.data, ImporterMode::Commit(None)));
         // Read the commit object.
-        let (commit_ent, commit_path) = ents
+        let (mut commit_ent, mut commit_path) = ents
             .next()
             .ok_or_else(|| anyhow!("Commit object not found"))??;
 
+        // A delta export is preceded by the checksum of its base commit, which must
+        // be present; the content objects it shares with the new commit are omitted.
+        if commit_path.as_str() == crate::tar::DELTA_BASE_NAME {
+            let base = Self::read_delta_base(commit_ent)?;
+            if !self
+                .repo
+                .has_object(ostree::ObjectType::Commit, &base, cancellable)?
+            {
+                bail!("Base commit {} of delta not found in repository", base);
+            }
+            let (ent, path) = ents
+                .next()
+                .ok_or_else(|| anyhow!("Commit object not found"))??;
+            commit_ent = ent;
+            commit_path = path;
+        }
+
         if commit_ent.header().entry_type() != tar::EntryType::Regular {
             return Err(anyhow!(
                 "Expected regular file for commit object, not {:?}",
@@ -649,25 +1012,32 @@ impl Importer {
             .ok_or_else(|| anyhow!("End of stream after commit object"))??;
         let (next_checksum, next_objtype) = Self::parse_metadata_entry(&nextent_path)?;
 
-        if let Some(remote) = self.remote.as_deref() {
-            if next_objtype != ostree::ObjectType::CommitMeta {
+        if let Some(remote) = self.remote.clone() {
+            let remote = remote.as_str();
+            // If the stream has no detached metadata, fall back to that provided
+            // out of band; the next entry is then an ordinary object.
+            let (commitmeta, next_ent) = if next_objtype == ostree::ObjectType::CommitMeta {
+                if next_checksum != checksum {
+                    return Err(anyhow!(
+                        "Expected commitmeta checksum {}, found {}",
+                        checksum,
+                        next_checksum
+                    ));
+                }
+                let commitmeta = entry_to_variant::<
+                    _,
+                    std::collections::HashMap<String, glib::Variant>,
+                >(next_ent, &next_checksum)?;
+                (commitmeta, None)
+            } else if let Some(commitmeta) = self.detached_metadata.take() {
+                (commitmeta, Some((next_ent, nextent_path)))
+            } else {
                 return Err(anyhow!(
                     "Using remote {} for verification; Expected commitmeta object, not {:?}",
                     remote,
-                    objtype
-                ));
-            }
-            if next_checksum != checksum {
-                return Err(anyhow!(
-                    "Expected commitmeta checksum {}, found {}",
-                    checksum,
-                    next_checksum
+                    next_objtype
                 ));
-            }
-            let commitmeta = entry_to_variant::<_, std::collections::HashMap<String, glib::Variant>>(
-                next_ent,
-                &next_checksum,
-            )?;
+            };
 
             // Now that we have both the commit and detached metadata in memory, verify that
             // the signatures in the detached metadata correctly sign the commit.
@@ -686,10 +1056,14 @@ impl Importer {
                     .write_metadata(objtype, Some(&checksum), &commit, cancellable)?;
             assert_eq!(actual_checksum.to_hex(), checksum);
             event!(Level::DEBUG, "Imported {}.commit", checksum);
+            self.object_imported();
 
             // Finally, write the detached metadata.
             self.repo
                 .write_commit_detached_metadata(&checksum, Some(&commitmeta), cancellable)?;
+            if let Some((next_ent, nextent_path)) = next_ent {
+                self.import_object(next_ent, &nextent_path, cancellable)?;
+            }
         } else {
             self.repo.mark_commit_partial(&checksum, true)?;
 
@@ -699,6 +1073,7 @@ impl Importer {
                     .write_metadata(objtype, Some(&checksum), &commit, cancellable)?;
             assert_eq!(actual_checksum.to_hex(), checksum);
             event!(Level::DEBUG, "Imported {}.commit", checksum);
+            self.object_imported();
 
             // Write the next object, whether it's commit metadata or not.
             let (meta_checksum, meta_objtype) = Self::parse_metadata_entry(&nextent_path)?;
@@ -715,6 +1090,13 @@ impl Importer {
                     )?;
                 }
                 _ => {
+                    if let Some(commitmeta) = self.detached_metadata.take() {
+                        self.repo.write_commit_detached_metadata(
+                            &checksum,
+                            Some(&commitmeta),
+                            cancellable,
+                        )?;
+                    }
                     self.import_object(next_ent, &nextent_path, cancellable)?;
                 }
             }
@@ -726,9 +1108,16 @@ impl Importer {
             ImporterMode::ObjectSet(_) => unreachable!(),
         }
 
-        self.import_objects_impl(ents, cancellable)?;
+        self.import_objects_impl(ents, cancellable)
+    }
 
-        Ok(())
+    /// Parse the base commit checksum of a delta export.
+    pub(super) fn read_delta_base<R: std::io::Read>(mut entry: tar::Entry<R>) -> Result<String> {
+        let size = entry.header().entry_size()?;
+        ensure!(size <= 64, "Invalid delta base of size {}", size);
+        let mut buf = String::new();
+        entry.read_to_string(&mut buf)?;
+        validate_sha256(buf)
     }
 
     pub(crate) fn finish_import_commit(self) -> String {
@@ -779,7 +1168,7 @@ impl Importer {
     }
 }
 
-fn validate_sha256(input: String) -> Result<String> {
+pub(super) fn validate_sha256(input: String) -> Result<String> {
     if input.len() != 64 {
         return Err(anyhow!("Invalid sha256 checksum (len) {}", input));
     }
@@ -789,36 +1178,275 @@ fn validate_sha256(input: String) -> Result<String> {
     Ok(input)
 }
 
+/// Verify the detached GPG signatures of a commit against a keyring file.
+#[context("Verifying commit {} with keyring {:?}", checksum, keyring)]
+fn verify_keyring(
+    repo: &ostree::Repo,
+    checksum: &str,
+    keyring: &Path,
+    cancellable: Option<&gio::Cancellable>,
+) -> Result<()> {
+    // An empty keyring directory, so that only the given keyring is used.
+    let keyringdir = tempfile::tempdir()?;
+    let keyringdir = gio::File::for_path(keyringdir.path());
+    let keyring = gio::File::for_path(keyring);
+    let result =
+        repo.verify_commit_ext(checksum, Some(&keyringdir), Some(&keyring), cancellable)?;
+    result.require_valid_signature()?;
+    Ok(())
+}
+
+/// Remove the paths skipped by a filter from an imported commit, writing a new
+/// commit with the same metadata.
+#[context("Writing filtered commit")]
+fn write_filtered_commit(
+    repo: &ostree::Repo,
+    checksum: &str,
+    skipped: &[Utf8PathBuf],
+    cancellable: Option<&gio::Cancellable>,
+) -> Result<String> {
+    let (commit_v, _) = repo.load_commit(checksum)?;
+    let (commit_root, _) = repo.read_commit(checksum, cancellable)?;
+    let root = ostree::MutableTree::from_commit(repo, checksum)?;
+    for path in skipped {
+        // By default the exporter writes /usr/etc as /etc.
+        let path = if commit_root
+            .resolve_relative_path(path)
+            .query_exists(cancellable)
+        {
+            Cow::Borrowed(path.as_path())
+        } else {
+            unmap_path(path)
+        };
+        let filename = path
+            .file_name()
+            .ok_or_else(|| anyhow!("Invalid path {}", path))?;
+        // The walk() API traverses the whole path, returning the parent.
+        let parts = path.components().map(|c| c.as_str()).collect::<Vec<_>>();
+        let parent = &root.walk(&parts, 0)?;
+        parent.remove(filename, true)?;
+        repo.write_mtree(parent, cancellable)?;
+    }
+    let tree = repo
+        .write_mtree(&root, cancellable)
+        .context("Writing mtree")?;
+    let tree = tree
+        .downcast_ref::<ostree::RepoFile>()
+        .ok_or_else(|| anyhow!("Written tree is not a RepoFile"))?;
+    let parent = ostree::commit_get_parent(&commit_v);
+    let subject = commit_v.child_value(3);
+    let body = commit_v.child_value(4);
+    let commit = repo.write_commit_with_time(
+        parent.as_deref(),
+        subject.str(),
+        body.str(),
+        Some(&commit_v.child_value(0)),
+        tree,
+        ostree::commit_get_timestamp(&commit_v),
+        cancellable,
+    )?;
+    Ok(commit.to_string())
+}
+
+/// The action to take for an entry, as returned by a [`TarImportOptions::filter`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImportFilterAction {
+    /// Keep the entry.
+    Import,
+    /// Drop the entry; for a directory, everything below it is dropped too.
+    Skip,
+    /// Fail the import.
+    Abort,
+}
+
+/// Progress of a tar import.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ImportProgress {
+    /// Number of tar entries processed so far.
+    pub entries_processed: u64,
+    /// Number of bytes of the tar stream read so far; for a compressed
+    /// stream, this is the decompressed size.
+    pub bytes_read: u64,
+    /// Number of ostree objects imported so far.
+    pub objects_written: u64,
+}
+
+/// A filter invoked with the path and header of each entry of a tar import.
+pub type ImportFilter = Box<dyn FnMut(&Utf8Path, &tar::Header) -> ImportFilterAction + Send>;
+
 /// Configuration for tar import.
-#[derive(Debug, Default)]
+#[derive(Default)]
 pub struct TarImportOptions {
     /// Name of the remote to use for signature verification.
     pub remote: Option<String>,
+    /// Invoked for each file and directory of the tree in the stream (not the
+    /// ostree objects), with paths relative to the root.  If any entries are
+    /// skipped, a new commit without them is written and returned; it has the
+    /// metadata of the original, but not its signatures.  The content objects of
+    /// skipped entries may still be imported.
+    pub filter: Option<ImportFilter>,
+    /// Updated as the stream is read.
+    pub progress: Option<tokio::sync::watch::Sender<ImportProgress>>,
+    /// Require a valid GPG signature on the commit from a key in this keyring
+    /// file, which may be binary or ASCII armored.  This does not depend on
+    /// any remote configuration, nor on the system keyrings.
+    pub verify_keyring: Option<PathBuf>,
+    /// Reject files under `sysroot` which are not part of the object layout;
+    /// by default, they are ignored.
+    pub strict: bool,
+    /// Cancels the import.  Any objects written so far are discarded, as they
+    /// are when the future returned by [`import_tar`] is dropped.
+    pub cancellable: Option<gio::Cancellable>,
+    /// Point this ref at the imported commit, in the same transaction as the
+    /// objects.  The name is validated before the stream is read.
+    pub ref_name: Option<String>,
+}
+
+impl std::fmt::Debug for TarImportOptions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TarImportOptions")
+            .field("remote", &self.remote)
+            .field("filter", &self.filter.is_some())
+            .field("progress", &self.progress)
+            .field("verify_keyring", &self.verify_keyring)
+            .field("strict", &self.strict)
+            .field("cancellable", &self.cancellable)
+            .field("ref_name", &self.ref_name)
+            .finish()
+    }
+}
+
+/// Magic bytes at the start of a gzip stream.
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+/// Magic bytes at the start of a zstd frame.
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+/// Sniff the first bytes of the input, and transparently wrap it in a decompressor
+/// if it is a gzip or zstd stream.  The name of the compression format is returned
+/// along with the stream; uncompressed input is passed through as is.
+async fn decompress_autodetect(
+    mut src: impl tokio::io::AsyncRead + Send + Unpin + 'static,
+) -> Result<(
+    Box<dyn tokio::io::AsyncRead + Send + Unpin>,
+    Option<&'static str>,
+)> {
+    use tokio::io::AsyncReadExt;
+    let mut prefix = Vec::with_capacity(ZSTD_MAGIC.len());
+    (&mut src)
+        .take(ZSTD_MAGIC.len() as u64)
+        .read_to_end(&mut prefix)
+        .await?;
+    let is_gzip = prefix.starts_with(GZIP_MAGIC);
+    let is_zstd = prefix.starts_with(ZSTD_MAGIC);
+    // Put back the bytes we consumed.
+    let src = std::io::Cursor::new(prefix).chain(src);
+    let r: Box<dyn tokio::io::AsyncRead + Send + Unpin> = if is_gzip {
+        Box::new(async_compression::tokio::bufread::GzipDecoder::new(
+            tokio::io::BufReader::new(src),
+        ))
+    } else if is_zstd {
+        Box::new(async_compression::tokio::bufread::ZstdDecoder::new(
+            tokio::io::BufReader::new(src),
+        ))
+    } else {
+        return Ok((Box::new(src), None));
+    };
+    let compression = if is_gzip { "gzip" } else { "zstd" };
+    Ok((r, Some(compression)))
+}
+
+/// The result of [`import_tar_with_stats`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TarImport {
+    /// The sha256 of the imported commit.
+    pub commit: String,
+    /// Number of entries dropped by [`TarImportOptions::filter`], including the
+    /// children of skipped directories.
+    pub n_filtered: u64,
 }
 
 /// Read the contents of a tarball and import the ostree commit inside.
+/// The tarball may be compressed with gzip or zstd; this is detected automatically.
+/// Objects which appear more than once in the stream must be identical.
 /// Returns the sha256 of the imported commit.
-#[instrument(skip(repo, src))]
 pub async fn import_tar(
     repo: &ostree::Repo,
     src: impl tokio::io::AsyncRead + Send + Unpin + 'static,
     options: Option<TarImportOptions>,
 ) -> Result<String> {
-    let options = options.unwrap_or_default();
+    Ok(import_tar_with_stats(repo, src, options).await?.commit)
+}
+
+/// Like [`import_tar`], also returning statistics on the import.
+#[instrument(skip(repo, src))]
+pub async fn import_tar_with_stats(
+    repo: &ostree::Repo,
+    src: impl tokio::io::AsyncRead + Send + Unpin + 'static,
+    options: Option<TarImportOptions>,
+) -> Result<TarImport> {
+    let mut options = options.unwrap_or_default();
+    if let Some(ref_name) = options.ref_name.as_deref() {
+        crate::refescape::validate_ref(ref_name)?;
+    }
+    let (src, compression) = decompress_autodetect(src).await?;
     let src = tokio_util::io::SyncIoBridge::new(src);
     let repo = repo.clone();
-    // The tar code we use today is blocking, so we spawn a thread.
-    crate::tokio_util::spawn_blocking_cancellable_flatten(move |cancellable| {
-        let mut archive = tar::Archive::new(src);
-        let txn = repo.auto_transaction(Some(cancellable))?;
-        let mut importer = Importer::new_for_commit(&repo, options.remote);
-        importer.import_commit(&mut archive, Some(cancellable))?;
-        let checksum = importer.finish_import_commit();
-        txn.commit(Some(cancellable))?;
-        repo.mark_commit_partial(&checksum, false)?;
-        Ok::<_, anyhow::Error>(checksum)
-    })
-    .await
+    let cancellable = options
+        .cancellable
+        .take()
+        .unwrap_or_else(gio::Cancellable::new);
+    // The tar code we use today is blocking, so we spawn a thread.  On error or
+    // cancellation, dropping the transaction discards the objects written so far.
+    let r = crate::tokio_util::spawn_blocking_with_cancellable_flatten(
+        cancellable,
+        move |cancellable| {
+            let progress = options.progress.map(|tx| Arc::new(ProgressState::new(tx)));
+            let src: Box<dyn Read + Send> = match progress.as_ref() {
+                Some(progress) => Box::new(CountingReader {
+                    inner: src,
+                    progress: Arc::clone(progress),
+                }),
+                None => Box::new(src),
+            };
+            let mut archive = tar::Archive::new(src);
+            let txn = repo.auto_transaction(Some(cancellable))?;
+            let mut importer = Importer::new_for_commit(&repo, options.remote);
+            importer.filter = options.filter.map(EntryFilter::new);
+            importer.progress = progress;
+            importer.strict = options.strict;
+            importer.import_commit(&mut archive, Some(cancellable))?;
+            let filter = importer.filter.take();
+            let checksum = importer.finish_import_commit();
+            if let Some(keyring) = options.verify_keyring.as_deref() {
+                verify_keyring(&repo, &checksum, keyring, Some(cancellable))?;
+            }
+            let n_filtered = filter.as_ref().map(|f| f.n_filtered).unwrap_or_default();
+            let r = match filter {
+                Some(filter) if !filter.skipped.is_empty() => {
+                    tracing::info!("Filtered {} entries", filter.n_filtered);
+                    write_filtered_commit(&repo, &checksum, &filter.skipped, Some(cancellable))?
+                }
+                _ => checksum.clone(),
+            };
+            if let Some(ref_name) = options.ref_name.as_deref() {
+                repo.transaction_set_ref(None, ref_name, Some(&r));
+            }
+            txn.commit(Some(cancellable))?;
+            // A filtered commit was never marked partial; the unfiltered one
+            // stays partial, as the skipped content may be missing.
+            repo.mark_commit_partial(&r, false)?;
+            Ok::<_, anyhow::Error>(TarImport {
+                commit: r,
+                n_filtered,
+            })
+        },
+    )
+    .await;
+    // A truncated or corrupt compressed stream otherwise just looks like a short tar.
+    match compression {
+        Some(compression) => r.with_context(|| format!("Importing {} compressed tar", compression)),
+        None => r,
+    }
 }
 
 /// Read the contents of a tarball and import the content objects inside.
@@ -843,6 +1471,136 @@ pub async fn import_tar_objects(
     .await
 }
 
+/// Options for [`import_chunked`].
+#[derive(Debug, Default)]
+pub struct ImportChunkedOptions {
+    /// Name of the remote to use for signature verification.
+    pub remote: Option<String>,
+}
+
+/// Find the objects referenced by a commit which are not in the repository,
+/// as `<checksum>.<objtype>`.
+fn find_missing_objects(
+    repo: &ostree::Repo,
+    commit: &str,
+    cancellable: Option<&gio::Cancellable>,
+) -> Result<Vec<String>> {
+    fn walk(
+        repo: &ostree::Repo,
+        dirtree: &str,
+        seen: &mut HashSet<String>,
+        missing: &mut Vec<String>,
+        cancellable: Option<&gio::Cancellable>,
+    ) -> Result<()> {
+        let mut check = |objtype: ostree::ObjectType, checksum: String| -> Result<bool> {
+            if !seen.insert(checksum.clone()) {
+                return Ok(false);
+            }
+            let found = repo.has_object(objtype, &checksum, cancellable)?;
+            if !found {
+                missing.push(format!("{}.{}", checksum, object_suffix(objtype)));
+            }
+            Ok(found)
+        };
+        if !check(ostree::ObjectType::DirTree, dirtree.to_string())? {
+            return Ok(());
+        }
+        let v = repo.load_variant(ostree::ObjectType::DirTree, dirtree)?;
+        let v = v.data_as_bytes();
+        let v = v.try_as_aligned()?;
+        let v = gv_dirtree!().cast(v);
+        let (files, dirs) = v.to_tuple();
+        for file in files {
+            let (_, csum) = file.to_tuple();
+            check(ostree::ObjectType::File, hex::encode(csum))?;
+        }
+        let mut subdirs = Vec::new();
+        for item in dirs {
+            let (_, contents_csum, meta_csum) = item.to_tuple();
+            check(ostree::ObjectType::DirMeta, hex::encode(meta_csum))?;
+            subdirs.push(hex::encode(contents_csum));
+        }
+        for subdir in subdirs {
+            walk(repo, &subdir, seen, missing, cancellable)?;
+        }
+        Ok(())
+    }
+
+    let (commit_v, _) = repo.load_commit(commit)?;
+    let commit_v = commit_v.data_as_bytes();
+    let commit_v = commit_v.try_as_aligned()?;
+    let commit = gv_commit!().cast(commit_v).to_tuple();
+    let mut seen = HashSet::new();
+    let mut missing = Vec::new();
+    let meta_checksum = hex::encode(commit.7);
+    if !repo.has_object(ostree::ObjectType::DirMeta, &meta_checksum, cancellable)? {
+        missing.push(format!(
+            "{}.{}",
+            meta_checksum,
+            object_suffix(ostree::ObjectType::DirMeta)
+        ));
+    }
+    walk(
+        repo,
+        &hex::encode(commit.6),
+        &mut seen,
+        &mut missing,
+        cancellable,
+    )?;
+    Ok(missing)
+}
+
+/// Import a commit exported with [`crate::tar::export_chunked`] from its
+/// archives: the one holding the commit, and those with the content objects,
+/// in any order.  Each archive may be compressed with gzip or zstd.
+///
+/// It is an error if, once all archives are read, objects referenced by the
+/// commit are missing.  Returns the checksum of the imported commit.
+#[instrument(skip(repo, srcs))]
+pub async fn import_chunked<R>(
+    repo: &ostree::Repo,
+    srcs: impl IntoIterator<Item = R>,
+    options: Option<ImportChunkedOptions>,
+) -> Result<String>
+where
+    R: tokio::io::AsyncRead + Send + Unpin + 'static,
+{
+    let options = options.unwrap_or_default();
+    let mut readers = Vec::new();
+    for src in srcs {
+        let (src, _) = decompress_autodetect(src).await?;
+        readers.push(tokio_util::io::SyncIoBridge::new(src));
+    }
+    let repo = repo.clone();
+    crate::tokio_util::spawn_blocking_cancellable_flatten(move |cancellable| {
+        let txn = repo.auto_transaction(Some(cancellable))?;
+        let mut importer = Importer::new_for_commit(&repo, options.remote);
+        for (i, src) in readers.into_iter().enumerate() {
+            let mut archive = tar::Archive::new(src);
+            importer
+                .import_chunk(&mut archive, Some(cancellable))
+                .with_context(|| format!("Importing archive {}", i))?;
+        }
+        if !matches!(importer.data, ImporterMode::Commit(Some(_))) {
+            bail!("Commit object not found in any archive");
+        }
+        let checksum = importer.finish_import_commit();
+        let missing = find_missing_objects(&repo, &checksum, Some(cancellable))?;
+        if !missing.is_empty() {
+            bail!(
+                "Missing {} objects referenced by commit {}: {}",
+                missing.len(),
+                checksum,
+                missing.join(", ")
+            );
+        }
+        txn.commit(Some(cancellable))?;
+        repo.mark_commit_partial(&checksum, false)?;
+        Ok::<_, anyhow::Error>(checksum)
+    })
+    .await
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -921,4 +1679,16 @@ mod tests {
             assert_eq!(output, expected);
         }
     }
+
+    #[test]
+    fn test_streams_equal() {
+        let a = vec![0x42u8; 20000];
+        let mut b = a.clone();
+        assert!(streams_equal(a.as_slice(), b.as_slice()).unwrap());
+        assert!(streams_equal(std::io::empty(), std::io::empty()).unwrap());
+        b[19999] = 0;
+        assert!(!streams_equal(a.as_slice(), b.as_slice()).unwrap());
+        assert!(!streams_equal(a.as_slice(), &a[..8192]).unwrap());
+        assert!(!streams_equal(std::io::empty(), &a[..1]).unwrap());
+    }
 }
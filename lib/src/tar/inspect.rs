@@ -0,0 +1,179 @@
+//! Inspect an exported tar stream without importing it
+//!
+//! The commit object is the first object in the stream, followed by its
+//! detached metadata if any, in both format versions.  So only the start of
+//! the stream needs to be read.
+
+use super::import::{
+    entry_path, entry_to_variant, parse_checksum, parse_object_entry_path, Importer, REPO_PREFIX,
+};
+use super::manifest::OBJECTS_PREFIX;
+use anyhow::{anyhow, ensure, Result};
+use camino::Utf8PathBuf;
+use fn_error_context::context;
+use gio::glib;
+use glib::translate::IntoGlib;
+use ostree::gio;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::Read;
+
+/// The commit metadata key holding the sizes of objects, as written by
+/// `ostree commit --generate-sizes`.
+const SIZES_KEY: &str = "ostree.sizes";
+
+/// The detached metadata key holding GPG signatures.
+const GPGSIGS_KEY: &str = "ostree.gpgsigs";
+
+/// Information about the commit in an exported tar stream, as returned by [`inspect`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TarCommitInfo {
+    /// The commit checksum.
+    pub checksum: String,
+    /// The parent commit, if any.
+    pub parent: Option<String>,
+    /// The commit subject.
+    pub subject: String,
+    /// The commit timestamp, in seconds since the epoch.
+    pub timestamp: u64,
+    /// The `version` metadata key.
+    pub version: Option<String>,
+    /// Whether the commit has `ostree.bootable` set.
+    pub bootable: bool,
+    /// The total size of the content objects, if the commit has `ostree.sizes`
+    /// metadata.
+    pub content_size: Option<u64>,
+    /// Whether the commit has GPG signatures in its detached metadata.
+    pub signed: bool,
+    /// For a delta export, the base commit it requires.
+    pub delta_base: Option<String>,
+}
+
+/// Read an unsigned LEB128 varint, as used in `ostree.sizes`.
+fn read_varint(buf: &mut &[u8]) -> Option<u64> {
+    let mut r = 0u64;
+    for shift in (0..64).step_by(7) {
+        let (&b, rest) = buf.split_first()?;
+        *buf = rest;
+        r |= u64::from(b & 0x7f) << shift;
+        if b & 0x80 == 0 {
+            return Some(r);
+        }
+    }
+    None
+}
+
+/// Sum the unpacked sizes of the content objects in `ostree.sizes`.
+fn content_size(sizes: &glib::Variant) -> Result<u64> {
+    let mut r = 0;
+    for entry in sizes.iter() {
+        let data = entry.child_value(1);
+        let data = data.data_as_bytes();
+        let mut buf = &data[..];
+        let sizes = read_varint(&mut buf).and_then(|_archived| read_varint(&mut buf));
+        let unpacked = sizes.ok_or_else(|| anyhow!("Invalid {} entry", SIZES_KEY))?;
+        // Newer versions also record metadata objects, with a trailing object type.
+        let is_content = buf
+            .first()
+            .map(|&t| i32::from(t) == ostree::ObjectType::File.into_glib())
+            .unwrap_or(true);
+        if is_content {
+            r += unpacked;
+        }
+    }
+    Ok(r)
+}
+
+/// Find the next entry which is an object, or the base of a delta, returning
+/// its path relative to the objects directory (or the repository, for the base).
+fn next_object<'a, R: Read + 'a>(
+    entries: &mut tar::Entries<'a, R>,
+) -> Result<Option<(tar::Entry<'a, R>, Utf8PathBuf)>> {
+    for e in entries {
+        let e = e?;
+        if e.header().entry_type() == tar::EntryType::Directory {
+            continue;
+        }
+        let path = entry_path(&e)?;
+        if let Ok(p) = path.strip_prefix(OBJECTS_PREFIX) {
+            return Ok(Some((e, p.to_owned())));
+        }
+        if let Ok(p) = path.strip_prefix(REPO_PREFIX) {
+            if p.as_str() == crate::tar::DELTA_BASE_NAME {
+                return Ok(Some((e, p.to_owned())));
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Read the commit information from an exported tar stream (of either
+/// format version), without requiring a repository.  The stream is only
+/// read up to the commit and its detached metadata; it must not be compressed.
+#[context("Inspecting tar stream")]
+pub fn inspect(src: impl Read) -> Result<TarCommitInfo> {
+    let mut archive = tar::Archive::new(src);
+    let entries = &mut archive.entries()?;
+    let (mut ent, mut path) =
+        next_object(entries)?.ok_or_else(|| anyhow!("Commit object not found"))?;
+    let mut delta_base = None;
+    if path.as_str() == crate::tar::DELTA_BASE_NAME {
+        delta_base = Some(Importer::read_delta_base(ent)?);
+        let next = next_object(entries)?.ok_or_else(|| anyhow!("Commit object not found"))?;
+        ent = next.0;
+        path = next.1;
+    }
+    let (parent, name, objtype) = parse_object_entry_path(&path)?;
+    ensure!(objtype == "commit", "Expected commit object, not {}", path);
+    let checksum = parse_checksum(parent, name)?;
+    let commit = entry_to_variant::<_, ostree::CommitVariantType>(ent, &checksum)?;
+    let actual = crate::tar::sha256_hex(&commit.data_as_bytes());
+    ensure!(
+        actual == checksum,
+        "Corrupted commit object {}: found checksum {}",
+        checksum,
+        actual
+    );
+
+    let mut signed = false;
+    if let Some((ent, path)) = next_object(entries)? {
+        let (parent, name, objtype) = parse_object_entry_path(&path)?;
+        if objtype == "commitmeta" && parse_checksum(parent, name)? == checksum {
+            let commitmeta = entry_to_variant::<_, HashMap<String, glib::Variant>>(ent, &checksum)?;
+            let commitmeta = glib::VariantDict::new(Some(&commitmeta));
+            signed = commitmeta.contains(GPGSIGS_KEY);
+        }
+    }
+
+    let meta = glib::VariantDict::new(Some(&commit.child_value(0)));
+    let content_size = meta
+        .lookup_value(SIZES_KEY, None)
+        .map(|v| content_size(&v))
+        .transpose()?;
+    Ok(TarCommitInfo {
+        parent: ostree::commit_get_parent(&commit).map(|p| p.to_string()),
+        subject: commit.child_value(3).str().unwrap_or_default().to_string(),
+        timestamp: ostree::commit_get_timestamp(&commit),
+        version: meta.lookup::<String>("version")?,
+        bootable: meta
+            .lookup::<bool>(*ostree::METADATA_KEY_BOOTABLE)?
+            .unwrap_or_default(),
+        content_size,
+        signed,
+        delta_base,
+        checksum,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_varint() {
+        let mut buf: &[u8] = &[0x05, 0xac, 0x02, 0x80];
+        assert_eq!(read_varint(&mut buf), Some(5));
+        assert_eq!(read_varint(&mut buf), Some(300));
+        assert_eq!(read_varint(&mut buf), None);
+    }
+}
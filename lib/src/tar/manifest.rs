@@ -0,0 +1,194 @@
+//! A manifest of the objects in an exported tar stream
+//!
+//! When enabled via [`crate::tar::ExportOptions::append_manifest`], the export
+//! ends with a JSON document listing the commit, and the checksum, size and
+//! SHA-256 digest of the data of every ostree object in the stream.  This allows
+//! checking the integrity of an archive via [`verify_export`] without an ostree
+//! repository.
+
+use super::import::{objtype_from_string, parse_checksum, parse_object_entry_path};
+use anyhow::{anyhow, Context, Result};
+use camino::Utf8Path;
+use fn_error_context::context;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::Read;
+
+/// The path of the manifest entry at the end of the tar stream.
+pub const MANIFEST_PATH: &str = "ostree.manifest.json";
+
+/// The prefix of object paths in the tar stream.
+pub(super) const OBJECTS_PREFIX: &str = "sysroot/ostree/repo/objects/";
+
+/// The serialized manifest.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub(crate) struct Manifest {
+    /// The exported commit.
+    pub(crate) commit: String,
+    /// The objects in the stream, in order.
+    pub(crate) objects: Vec<ManifestObject>,
+}
+
+/// An object in the manifest.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct ManifestObject {
+    /// The ostree checksum.
+    pub(crate) checksum: String,
+    /// The object type, as used in its file name.
+    pub(crate) objtype: String,
+    /// The size of the data in the stream.
+    pub(crate) size: u64,
+    /// The SHA-256 of the data in the stream; for symbolic links, of the target.
+    pub(crate) sha256: String,
+}
+
+impl ManifestObject {
+    /// The name used to identify the object in reports.
+    fn name(&self) -> String {
+        format!("{}.{}", self.checksum, self.objtype)
+    }
+}
+
+/// Compute the hex SHA-256 of a buffer.
+pub(crate) fn sha256_hex(data: &[u8]) -> String {
+    hex::encode(openssl::sha::sha256(data))
+}
+
+/// A reader which optionally computes the SHA-256 of the data read through it.
+pub(crate) struct DigestReader<R> {
+    inner: R,
+    hasher: Option<openssl::sha::Sha256>,
+}
+
+impl<R: Read> DigestReader<R> {
+    /// Wrap `inner`, only hashing if `enabled` is set.
+    pub(crate) fn new(inner: R, enabled: bool) -> Self {
+        let hasher = enabled.then(openssl::sha::Sha256::new);
+        Self { inner, hasher }
+    }
+
+    /// Return the hex digest of the data read, if hashing was enabled.
+    pub(crate) fn finish(self) -> Option<String> {
+        self.hasher.map(|h| hex::encode(h.finish()))
+    }
+}
+
+impl<R: Read> Read for DigestReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if let Some(hasher) = self.hasher.as_mut() {
+            hasher.update(&buf[..n]);
+        }
+        Ok(n)
+    }
+}
+
+/// The result of [`verify_export`].
+#[derive(Debug, Default)]
+pub struct ExportVerification {
+    /// The commit described by the manifest.
+    pub commit: String,
+    /// The number of objects which matched the manifest.
+    pub verified: u64,
+    /// Objects (as `<checksum>.<objtype>`) whose data differs from the manifest,
+    /// which do not match their own checksum, or which are not in the manifest.
+    pub mismatched: Vec<String>,
+    /// Objects (as `<checksum>.<objtype>`) listed in the manifest but not in the stream.
+    pub missing: Vec<String>,
+}
+
+impl ExportVerification {
+    /// Whether every object matched the manifest.
+    pub fn is_ok(&self) -> bool {
+        self.mismatched.is_empty() && self.missing.is_empty()
+    }
+}
+
+/// Parse an object path in the stream, returning `None` for entries which
+/// are not ostree objects (such as xattrs).
+fn parse_object_path(path: &Utf8Path) -> Result<Option<(String, String)>> {
+    let path = match path.strip_prefix(OBJECTS_PREFIX) {
+        Ok(p) => p,
+        Err(_) => return Ok(None),
+    };
+    let (parent, name, objtype) = parse_object_entry_path(path)?;
+    if objtype_from_string(objtype).is_none() {
+        return Ok(None);
+    }
+    let checksum = parse_checksum(parent, name)?;
+    Ok(Some((checksum, objtype.to_string())))
+}
+
+/// Read a tar stream generated with [`crate::tar::ExportOptions::append_manifest`],
+/// recomputing the digests of its objects and comparing them with the manifest.
+/// Mismatched or missing objects are reported in the result; it is an error if
+/// the stream has no manifest.
+#[context("Verifying export")]
+pub fn verify_export(src: impl Read) -> Result<ExportVerification> {
+    let mut archive = tar::Archive::new(src);
+    let mut found = HashMap::new();
+    let mut manifest: Option<Manifest> = None;
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = {
+            let path = entry.path()?;
+            Utf8Path::from_path(&path)
+                .ok_or_else(|| anyhow!("Invalid non-utf8 path {:?}", path))?
+                .to_owned()
+        };
+        if path.as_str() == MANIFEST_PATH {
+            manifest = Some(serde_json::from_reader(&mut entry).context("Parsing manifest")?);
+            continue;
+        }
+        let (checksum, objtype) = match parse_object_path(&path)? {
+            Some(o) => o,
+            None => continue,
+        };
+        let digest = match entry.header().entry_type() {
+            tar::EntryType::Regular => {
+                let mut data = DigestReader::new(&mut entry, true);
+                let size = std::io::copy(&mut data, &mut std::io::sink())?;
+                data.finish().map(|d| (size, d))
+            }
+            tar::EntryType::Symlink => entry
+                .link_name_bytes()
+                .map(|target| (target.len() as u64, sha256_hex(&target))),
+            _ => None,
+        };
+        found.insert(
+            format!("{}.{}", checksum, objtype),
+            (checksum, objtype, digest),
+        );
+    }
+    let manifest = manifest.ok_or_else(|| anyhow!("Missing {}", MANIFEST_PATH))?;
+
+    let mut r = ExportVerification {
+        commit: manifest.commit,
+        ..Default::default()
+    };
+    for object in manifest.objects {
+        let name = object.name();
+        let (checksum, objtype, digest) = match found.remove(&name) {
+            Some(v) => v,
+            None => {
+                r.missing.push(name);
+                continue;
+            }
+        };
+        // Metadata objects are named by the digest of their data; the
+        // commitmeta object is named after its commit instead.
+        let self_checksum_ok = match objtype.as_str() {
+            "file" | "commitmeta" => true,
+            _ => digest.as_ref().map(|d| d.1 == checksum).unwrap_or_default(),
+        };
+        if self_checksum_ok && digest == Some((object.size, object.sha256)) {
+            r.verified += 1;
+        } else {
+            r.mismatched.push(name);
+        }
+    }
+    let mut unexpected: Vec<_> = found.into_keys().collect();
+    unexpected.sort();
+    r.mismatched.extend(unexpected);
+    Ok(r)
+}
@@ -49,3 +49,9 @@ mod export;
 pub use export::*;
 mod write;
 pub use write::*;
+mod delta;
+pub use delta::*;
+mod manifest;
+pub use manifest::*;
+mod inspect;
+pub use inspect::*;
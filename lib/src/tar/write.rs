@@ -1,27 +1,21 @@
 //! APIs to write a tarball stream into an OSTree commit.
 //!
-//! This functionality already exists in libostree mostly,
-//! this API adds a higher level, more ergonomic Rust frontend
-//! to it.
-//!
-//! In the future, this may also evolve into parsing the tar
-//! stream in Rust, not in C.
+//! The tar stream is parsed in Rust, and its content written
+//! into the repository in-process via the libostree APIs,
+//! similar to `ostree commit --tree=tar=...`.
 
 use crate::Result;
 use anyhow::{anyhow, Context};
 use camino::{Utf8Component, Utf8Path, Utf8PathBuf};
-use cap_std_ext::cmdext::CapStdExtCommandExt;
-use cap_std_ext::rustix;
-use ostree::gio;
-use ostree::prelude::FileExt;
-use rustix::fd::FromFd;
-use std::collections::BTreeMap;
+use gio::prelude::*;
+use ostree::{gio, glib};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::convert::TryInto;
-use std::io::{BufWriter, Write};
+use std::io::{BufWriter, Read, Write};
 use std::path::Path;
-use std::process::Stdio;
 use std::sync::Arc;
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite};
+use tokio::io::{AsyncRead, AsyncWrite};
 use tracing::instrument;
 
 /// Configuration for tar layer commits.
@@ -30,8 +24,225 @@ pub struct WriteTarOptions {
     /// Base ostree commit hash
     pub base: Option<String>,
     /// Enable SELinux labeling from the base commit
-    /// Requires the `base` option.
+    /// Requires the `base` option.  Entries which carry a `security.selinux`
+    /// extended attribute in the tar stream keep that label.
     pub selinux: bool,
+    /// How to handle entries of a type which cannot be stored in ostree.
+    pub unsupported_file_types: UnsupportedFileTypePolicy,
+    /// If set, a message naming each path skipped via
+    /// [`UnsupportedFileTypePolicy::SkipWithWarning`] is sent here.
+    pub warnings: Option<tokio::sync::mpsc::UnboundedSender<String>>,
+    /// Translate the owner of entries, e.g. for layers built by rootless containers.
+    pub id_map: Option<IdMap>,
+    /// Additional metadata for the commit; keys starting with `ostree.` are reserved.
+    pub metadata: Option<glib::VariantDict>,
+    /// Fail if a regular file is larger than this many bytes.
+    pub max_file_size: Option<u64>,
+    /// How to handle content outside of `/usr` and `/etc`.
+    pub toplevel_content: ToplevelContentPolicy,
+    /// Policies all committed entries must satisfy; see [`ContentPolicy`].
+    pub content_policies: Vec<ContentPolicy>,
+}
+
+/// A tar entry checked by a [`ContentPolicy`].
+#[derive(Debug, Clone, Copy)]
+pub struct ContentPolicyEntry<'a> {
+    /// The path in the tar stream, relative to the root, e.g. `usr/bin/foo` or
+    /// `etc/foo.conf`; i.e. before `/etc` is moved to `/usr/etc`.
+    pub path: &'a Utf8Path,
+    /// The permission bits, including the setuid, setgid and sticky bits.
+    pub mode: u32,
+    /// The size of the content, for regular files.
+    pub size: u64,
+    /// The target of a symbolic link or hardlink.
+    pub link_target: Option<&'a Utf8Path>,
+}
+
+/// A check applied to each entry of a tar stream, e.g. to enforce a security
+/// policy on derived container image layers.  Violations are collected for the
+/// whole stream, which then fails with a [`ContentPolicyViolation`].
+#[derive(Clone)]
+pub struct ContentPolicy {
+    name: String,
+    check: Arc<dyn Fn(&ContentPolicyEntry<'_>) -> bool + Send + Sync>,
+}
+
+impl std::fmt::Debug for ContentPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ContentPolicy")
+            .field("name", &self.name)
+            .finish()
+    }
+}
+
+impl ContentPolicy {
+    /// A policy named `name`, for which `check` returns whether an entry is allowed.
+    pub fn new(
+        name: &str,
+        check: impl Fn(&ContentPolicyEntry<'_>) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            name: name.to_string(),
+            check: Arc::new(check),
+        }
+    }
+
+    /// Reject setuid and setgid files.
+    pub fn no_setuid() -> Self {
+        Self::new("no-setuid", |e| {
+            e.mode & (libc::S_ISUID | libc::S_ISGID) == 0
+        })
+    }
+
+    /// Reject the given paths and anything beneath them, e.g. `/usr/local`.
+    pub fn deny_paths<S: AsRef<str>>(paths: &[S]) -> Self {
+        let paths: Vec<Utf8PathBuf> = paths
+            .iter()
+            .map(|p| Utf8Path::new(p.as_ref().trim_start_matches('/')).to_owned())
+            .collect();
+        Self::new("deny-paths", move |e| {
+            !paths.iter().any(|p| e.path.starts_with(p))
+        })
+    }
+
+    /// The name of the policy, as reported in a [`ContentPolicyViolation`].
+    pub fn name(&self) -> &str {
+        self.name.as_str()
+    }
+}
+
+/// Returned (wrapped in an [`anyhow::Error`]) when entries of a tar stream
+/// violate a [`ContentPolicy`].
+#[derive(Debug, Clone)]
+pub struct ContentPolicyViolation {
+    /// The offending paths, with the name of the policy they violate.
+    pub violations: Vec<(String, String)>,
+}
+
+impl std::fmt::Display for ContentPolicyViolation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let max = crate::commit::MAX_REPORTED_PATHS;
+        write!(f, "Content policy violation: ")?;
+        for (i, (path, policy)) in self.violations.iter().take(max).enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{} ({})", path, policy)?;
+        }
+        if self.violations.len() > max {
+            write!(f, " (and {} more)", self.violations.len() - max)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ContentPolicyViolation {}
+
+/// A contiguous range of ids, with the semantics of an `/etc/subuid` entry:
+/// the `count` ids starting at `host_id` in the tar stream correspond to
+/// the ids starting at `container_id`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IdRange {
+    /// First id in the committed content.
+    pub container_id: u32,
+    /// First id in the tar stream.
+    pub host_id: u32,
+    /// Number of ids in the range.
+    pub count: u32,
+}
+
+impl IdRange {
+    /// Map all ids from `offset` onwards to ids starting at zero.
+    pub fn from_offset(offset: u32) -> Self {
+        Self {
+            container_id: 0,
+            host_id: offset,
+            count: u32::MAX - offset,
+        }
+    }
+
+    fn map(&self, id: u64) -> Option<u64> {
+        let start = u64::from(self.host_id);
+        (start..start + u64::from(self.count))
+            .contains(&id)
+            .then(|| id - start + u64::from(self.container_id))
+    }
+}
+
+/// Translation of the uids and gids of tar entries.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IdMap {
+    /// Ranges of user ids.
+    pub uids: Vec<IdRange>,
+    /// Ranges of group ids.
+    pub gids: Vec<IdRange>,
+    /// Use `0` for ids outside of all ranges, instead of failing.
+    pub clamp_to_root: bool,
+}
+
+impl IdMap {
+    /// Map user and group ids from `offset` onwards to ids starting at zero,
+    /// as used by e.g. rootless podman with a subordinate id range.
+    pub fn from_offset(offset: u32) -> Self {
+        Self {
+            uids: vec![IdRange::from_offset(offset)],
+            gids: vec![IdRange::from_offset(offset)],
+            clamp_to_root: false,
+        }
+    }
+
+    fn map_id(&self, ranges: &[IdRange], kind: &str, id: u64, path: &Utf8Path) -> Result<u64> {
+        match ranges.iter().find_map(|r| r.map(id)) {
+            Some(id) => Ok(id),
+            None if self.clamp_to_root => Ok(0),
+            None => Err(anyhow!("Unmapped {} {} for {}", kind, id, path)),
+        }
+    }
+
+    /// Translate the owner in a tar header.
+    fn apply(&self, header: &mut tar::Header, path: &Utf8Path) -> Result<()> {
+        let uid = self.map_id(&self.uids, "uid", header.uid()?, path)?;
+        let gid = self.map_id(&self.gids, "gid", header.gid()?, path)?;
+        header.set_uid(uid);
+        header.set_gid(gid);
+        Ok(())
+    }
+}
+
+/// How to handle tar entries that cannot be represented in an ostree commit,
+/// such as device nodes and FIFOs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnsupportedFileTypePolicy {
+    /// Fail the import.
+    Error,
+    /// Silently drop the entry.
+    Skip,
+    /// Drop the entry, and emit a warning naming its path.
+    SkipWithWarning,
+}
+
+impl Default for UnsupportedFileTypePolicy {
+    fn default() -> Self {
+        Self::Error
+    }
+}
+
+/// How to handle content in the tar stream outside of `/usr` and `/etc`,
+/// which is never part of the commit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ToplevelContentPolicy {
+    /// Emit a warning naming the discarded files; directories in `/var`
+    /// are instead created at boot via a generated `usr/lib/tmpfiles.d` entry.
+    Warn,
+    /// Fail the import, listing the discarded files.  Directories are
+    /// still translated as for [`ToplevelContentPolicy::Warn`].
+    Error,
+}
+
+impl Default for ToplevelContentPolicy {
+    fn default() -> Self {
+        Self::Warn
+    }
 }
 
 /// The result of writing a tar stream.
@@ -44,6 +255,135 @@ pub struct WriteTarResult {
     pub commit: String,
     /// Number of paths in a prefix (e.g. `/var` or `/boot`) which were discarded.
     pub filtered: BTreeMap<String, u32>,
+    /// Number of entries of an unsupported type (e.g. `fifo`) which were skipped.
+    pub skipped: BTreeMap<String, u32>,
+    /// Statistics on the committed content.
+    pub stats: WriteTarStats,
+}
+
+/// The commit metadata key holding the [`WriteTarStats`] of a commit from
+/// [`write_tar`], serialized as JSON.
+pub const META_TAR_STATS: &str = "ostree.tar-stats";
+
+/// Number of files reported in [`WriteTarStats::largest_files`].
+const N_LARGEST_FILES: usize = 10;
+
+/// Statistics on the content written from a tar stream.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct WriteTarStats {
+    /// Number of regular files, not including whiteouts.
+    pub regfiles: u64,
+    /// Number of symbolic links.
+    pub symlinks: u64,
+    /// Number of whiteouts.
+    pub whiteouts: u64,
+    /// Total size of the regular files.
+    pub total_bytes: u64,
+    /// The largest regular files and their size, largest first.
+    pub largest_files: Vec<(String, u64)>,
+}
+
+impl WriteTarStats {
+    fn add_regfile(&mut self, path: &Utf8Path, size: u64) {
+        self.regfiles += 1;
+        self.total_bytes += size;
+        let full = self.largest_files.len() >= N_LARGEST_FILES;
+        if full && self.largest_files.last().map(|v| v.1).unwrap_or_default() >= size {
+            return;
+        }
+        let idx = self.largest_files.partition_point(|v| v.1 >= size);
+        self.largest_files.insert(idx, (path.to_string(), size));
+        self.largest_files.truncate(N_LARGEST_FILES);
+    }
+}
+
+/// Configuration for [`filter_tar`].
+#[derive(Debug, Default, Clone)]
+pub(crate) struct TarFilterConfig {
+    /// How to handle device nodes and FIFOs.
+    pub(crate) unsupported_file_types: UnsupportedFileTypePolicy,
+    /// Destination for warnings about skipped entries.
+    pub(crate) warnings: Option<tokio::sync::mpsc::UnboundedSender<String>>,
+    /// Translation of entry ownership.
+    pub(crate) id_map: Option<IdMap>,
+    /// How to handle content outside of `/usr` and `/etc`.
+    pub(crate) toplevel_content: ToplevelContentPolicy,
+    /// Policies all committed entries must satisfy.
+    pub(crate) content_policies: Vec<ContentPolicy>,
+}
+
+impl TarFilterConfig {
+    fn warn(&self, msg: String) {
+        if let Some(warnings) = self.warnings.as_ref() {
+            // The receiver may have gone away; that's not fatal.
+            let _ = warnings.send(msg);
+        } else {
+            tracing::warn!("{}", msg);
+        }
+    }
+}
+
+/// Entries discarded by [`filter_tar`].
+#[derive(Debug, Default)]
+pub(crate) struct TarFilterResult {
+    /// Count of paths discarded, per toplevel directory.
+    pub(crate) filtered: BTreeMap<String, u32>,
+    /// Count of entries skipped, per unsupported type.
+    pub(crate) skipped: BTreeMap<String, u32>,
+}
+
+/// The pax extension holding the SELinux label of an entry.
+const PAX_SELINUX: &[u8] = b"SCHILY.xattr.security.selinux";
+
+/// Return the SELinux label carried by a tar entry, if any.
+fn entry_selinux_label<R: std::io::Read>(entry: &mut tar::Entry<R>) -> Result<Option<Vec<u8>>> {
+    if let Some(exts) = entry.pax_extensions()? {
+        for ext in exts {
+            let ext = ext?;
+            if ext.key_bytes() == PAX_SELINUX {
+                return Ok(Some(ext.value_bytes().to_vec()));
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Write a pax extended header carrying `label` as the SELinux label of the next entry.
+fn append_selinux_label<W: std::io::Write>(dest: &mut tar::Builder<W>, label: &[u8]) -> Result<()> {
+    // A record is `<length> <key>=<value>\n`, where the length includes its own digits.
+    let body_len = PAX_SELINUX.len() + label.len() + 3;
+    let mut len = body_len;
+    loop {
+        let n = body_len + len.to_string().len();
+        if n == len {
+            break;
+        }
+        len = n;
+    }
+    let mut record = format!("{} ", len).into_bytes();
+    record.extend_from_slice(PAX_SELINUX);
+    record.push(b'=');
+    record.extend_from_slice(label);
+    record.push(b'\n');
+    let mut header = tar::Header::new_ustar();
+    header.set_path("././@PaxHeader")?;
+    header.set_entry_type(tar::EntryType::XHeader);
+    header.set_mode(0o644);
+    header.set_size(record.len() as u64);
+    header.set_cksum();
+    dest.append(&header, record.as_slice())?;
+    Ok(())
+}
+
+/// Return a name for entry types we cannot store in ostree.
+fn unsupported_entry_type(t: tar::EntryType) -> Option<&'static str> {
+    match t {
+        tar::EntryType::Char => Some("char-device"),
+        tar::EntryType::Block => Some("block-device"),
+        tar::EntryType::Fifo => Some("fifo"),
+        _ => None,
+    }
 }
 
 // Copy of logic from https://github.com/ostreedev/ostree/pull/2447
@@ -67,6 +407,89 @@ fn sepolicy_from_base(repo: &ostree::Repo, base: &str) -> Result<tempfile::TempD
     Ok(tempdir)
 }
 
+/// The prefix of a whiteout file, which deletes the file without the prefix
+/// from the layers below.
+const WHITEOUT_PREFIX: &str = ".wh.";
+/// A whiteout marking its directory as opaque: the content of the layers
+/// below is hidden.
+const OPAQUE_WHITEOUT: &str = ".wh..wh..opq";
+
+/// Find the whiteouts in a layer tree, as (parent directory, file name).
+fn find_whiteouts(
+    dir: &gio::File,
+    path: &Utf8Path,
+    whiteouts: &mut Vec<(Utf8PathBuf, String)>,
+    cancellable: Option<&gio::Cancellable>,
+) -> Result<()> {
+    let e = dir.enumerate_children(
+        "standard::name,standard::type",
+        gio::FileQueryInfoFlags::NOFOLLOW_SYMLINKS,
+        cancellable,
+    )?;
+    while let Some(info) = e.next_file(cancellable)? {
+        let name = info.name();
+        let name = name
+            .to_str()
+            .ok_or_else(|| anyhow!("Invalid non-UTF8 name {:?}", name))?;
+        if info.file_type() == gio::FileType::Directory {
+            let child = dir.child(name);
+            find_whiteouts(&child, &path.join(name), whiteouts, cancellable)?;
+        } else if name.starts_with(WHITEOUT_PREFIX) {
+            whiteouts.push((path.to_owned(), name.to_string()));
+        }
+    }
+    Ok(())
+}
+
+/// Find the directory at `path` in a mutable tree, if it exists.
+fn mtree_lookup_dir(mt: &ostree::MutableTree, path: &Utf8Path) -> Option<ostree::MutableTree> {
+    // The walk() API traverses the whole path, returning the parent of the
+    // last component; add a trailing component to get the directory itself.
+    let parts: Vec<_> = path
+        .components()
+        .map(|c| c.as_str())
+        .chain(std::iter::once(""))
+        .collect();
+    mt.walk(&parts, 0).ok()
+}
+
+/// Apply a layer commit written by [`write_tar`] on top of `mt`, handling the
+/// overlayfs whiteouts of the layer: `.wh.<name>` deletes `<name>` from the
+/// lower layers, and `.wh..wh..opq` hides all of the lower content of its
+/// directory.  The content of the layer itself is kept, and the whiteout
+/// files are not part of the result.
+pub(crate) fn write_layer_to_mtree(
+    repo: &ostree::Repo,
+    mt: &ostree::MutableTree,
+    layer_commit: &str,
+    cancellable: Option<&gio::Cancellable>,
+) -> Result<()> {
+    let (layer_tree, _) = repo.read_commit(layer_commit, cancellable)?;
+    let mut whiteouts = Vec::new();
+    find_whiteouts(&layer_tree, Utf8Path::new(""), &mut whiteouts, cancellable)?;
+    for (dir, name) in whiteouts.iter() {
+        if name == OPAQUE_WHITEOUT {
+            // Replace the directory; the layer will recreate it, with its metadata.
+            let (parent, dirname) = match (dir.parent(), dir.file_name()) {
+                (Some(parent), Some(dirname)) => (parent, dirname),
+                _ => anyhow::bail!("Opaque whiteout of the root directory is not supported"),
+            };
+            if let Some(parent) = mtree_lookup_dir(mt, parent) {
+                parent.remove(dirname, true)?;
+            }
+        } else if let Some(parent) = mtree_lookup_dir(mt, dir) {
+            parent.remove(&name[WHITEOUT_PREFIX.len()..], true)?;
+        }
+    }
+    repo.write_directory_to_mtree(&layer_tree, mt, None, cancellable)?;
+    for (dir, name) in whiteouts.iter() {
+        if let Some(parent) = mtree_lookup_dir(mt, dir) {
+            parent.remove(name, true)?;
+        }
+    }
+    Ok(())
+}
+
 #[derive(Debug)]
 enum NormalizedPathResult<'a> {
     Filtered(&'a str),
@@ -121,6 +544,8 @@ fn normalize_validate_path(path: &Utf8Path) -> Result<NormalizedPathResult<'_>>
 /// Perform various filtering on imported tar archives.
 ///  - Move /etc to /usr/etc
 ///  - Entirely drop files not in /usr
+///  - Handle device nodes and FIFOs according to the configured policy
+///  - Translate uids and gids according to the configured mapping
 ///
 /// This also acts as a Rust "pre-parser" of the tar archive, hopefully
 /// catching anything corrupt that might be exploitable from the C libarchive side.
@@ -130,38 +555,109 @@ fn normalize_validate_path(path: &Utf8Path) -> Result<NormalizedPathResult<'_>>
 pub(crate) fn filter_tar(
     src: impl std::io::Read,
     dest: impl std::io::Write,
-) -> Result<BTreeMap<String, u32>> {
+    config: &TarFilterConfig,
+) -> Result<TarFilterResult> {
     let src = std::io::BufReader::new(src);
     let mut src = tar::Archive::new(src);
     let dest = BufWriter::new(dest);
     let mut dest = tar::Builder::new(dest);
-    let mut filtered = BTreeMap::new();
+    let mut r = TarFilterResult::default();
+    // Files outside of /usr and /etc which are discarded.
+    let mut toplevel_content = Vec::new();
+    // Directories in /var, which are created at boot instead.
+    let mut tmpfiles = String::new();
+    let mut violations = Vec::new();
 
     let ents = src.entries()?;
     for entry in ents {
-        let entry = entry?;
+        let mut entry = entry?;
+        let selinux_label = entry_selinux_label(&mut entry)?;
         let path = entry.path()?;
         let path: &Utf8Path = (&*path).try_into()?;
 
         let normalized = match normalize_validate_path(path)? {
-            NormalizedPathResult::Filtered(path) => {
-                if let Some(v) = filtered.get_mut(path) {
+            NormalizedPathResult::Filtered(toplevel) => {
+                if let Some(v) = r.filtered.get_mut(toplevel) {
                     *v += 1;
                 } else {
-                    filtered.insert(path.to_string(), 1);
+                    r.filtered.insert(toplevel.to_string(), 1);
+                }
+                let relpath = super::import::relative_path(path)?;
+                let is_whiteout = relpath
+                    .file_name()
+                    .map_or(false, |n| n.starts_with(WHITEOUT_PREFIX));
+                if is_whiteout {
+                    // Removing content which was never committed is a no-op.
+                } else if entry.header().entry_type() != tar::EntryType::Directory {
+                    toplevel_content.push(relpath);
+                } else if toplevel == "var" && relpath.as_str() != "var" {
+                    let mut header = entry.header().clone();
+                    if let Some(id_map) = config.id_map.as_ref() {
+                        id_map.apply(&mut header, path)?;
+                    }
+                    let line = crate::commit::tmpfiles_dir_entry(
+                        Utf8Path::new("/").join(&relpath).as_str(),
+                        header.mode()?,
+                        header.uid()?,
+                        header.gid()?,
+                    );
+                    tmpfiles.push_str(&line);
+                    tmpfiles.push('\n');
                 }
                 continue;
             }
             NormalizedPathResult::Normal(path) => path,
         };
 
+        if let Some(typename) = unsupported_entry_type(entry.header().entry_type()) {
+            match config.unsupported_file_types {
+                UnsupportedFileTypePolicy::Error => {
+                    return Err(anyhow!("Unsupported {} entry: {}", typename, path));
+                }
+                UnsupportedFileTypePolicy::Skip => {}
+                UnsupportedFileTypePolicy::SkipWithWarning => {
+                    config.warn(format!("Skipping unsupported {} entry: {}", typename, path));
+                }
+            }
+            *r.skipped.entry(typename.to_string()).or_default() += 1;
+            continue;
+        }
+
+        if !config.content_policies.is_empty() {
+            check_content_policies(&entry, &config.content_policies, &mut violations)?;
+        }
+
+        if let Some(label) = selinux_label {
+            append_selinux_label(&mut dest, &label)?;
+        }
+
         let mut header = entry.header().clone();
+        if let Some(id_map) = config.id_map.as_ref() {
+            id_map.apply(&mut header, path)?;
+        }
 
         // Need to use the entry.link_name() not the header.link_name()
         // api as the header api does not handle long paths:
         // https://github.com/alexcrichton/tar-rs/issues/192
         match entry.header().entry_type() {
-            tar::EntryType::Link | tar::EntryType::Symlink => {
+            tar::EntryType::Link => {
+                let target = entry.link_name()?.ok_or_else(|| anyhow!("Invalid link"))?;
+                let target: &Utf8Path = (&*target).try_into()?;
+                // Hardlink targets are paths in the stream, so they are subject to
+                // the same relocation as the entries themselves.
+                let target = match normalize_validate_path(target) {
+                    Ok(NormalizedPathResult::Normal(target)) => target,
+                    Ok(NormalizedPathResult::Filtered(_)) | Err(_) => {
+                        return Err(anyhow!(
+                            "Hardlink {} points outside of the layer content: {}",
+                            path,
+                            target
+                        ));
+                    }
+                };
+                dest.append_link(&mut header, &normalized, &target)?;
+            }
+            tar::EntryType::Symlink => {
                 let target = entry.link_name()?.ok_or_else(|| anyhow!("Invalid link"))?;
                 let target = target
                     .as_os_str()
@@ -174,21 +670,102 @@ pub(crate) fn filter_tar(
             }
         }
     }
+    // Consume any data after the end of the archive (e.g. padding), so that the
+    // source sees the complete stream.
+    std::io::copy(&mut src.into_inner(), &mut std::io::sink())?;
+
+    if !violations.is_empty() {
+        return Err(ContentPolicyViolation { violations }.into());
+    }
+
+    if !toplevel_content.is_empty() {
+        let n = toplevel_content.len();
+        let mut paths = toplevel_content
+            .iter()
+            .take(crate::commit::MAX_REPORTED_PATHS)
+            .map(|p| p.as_str())
+            .collect::<Vec<_>>()
+            .join(", ");
+        if n > crate::commit::MAX_REPORTED_PATHS {
+            paths.push_str(&format!(
+                " (and {} more)",
+                n - crate::commit::MAX_REPORTED_PATHS
+            ));
+        }
+        match config.toplevel_content {
+            ToplevelContentPolicy::Error => {
+                return Err(anyhow!("Found content outside of /usr and /etc: {}", paths));
+            }
+            ToplevelContentPolicy::Warn => {
+                config.warn(format!(
+                    "Discarding content outside of /usr and /etc: {}",
+                    paths
+                ));
+            }
+        }
+    }
+
+    if !tmpfiles.is_empty() {
+        let digest = super::manifest::sha256_hex(tmpfiles.as_bytes());
+        let path = format!("./usr/lib/tmpfiles.d/ostree-ext-var-{}.conf", &digest[..16]);
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Regular);
+        header.set_mode(0o644);
+        header.set_uid(0);
+        header.set_gid(0);
+        header.set_size(tmpfiles.len() as u64);
+        dest.append_data(&mut header, path, tmpfiles.as_bytes())?;
+    }
+
     dest.into_inner()?.flush()?;
-    Ok(filtered)
+    Ok(r)
+}
+
+/// Record the policies violated by a tar entry; whiteouts only remove content, so
+/// they are not checked.
+fn check_content_policies<R: std::io::Read>(
+    entry: &tar::Entry<R>,
+    policies: &[ContentPolicy],
+    violations: &mut Vec<(String, String)>,
+) -> Result<()> {
+    let path = entry.path()?;
+    let path = super::import::relative_path((&*path).try_into()?)?;
+    if path
+        .file_name()
+        .map_or(false, |n| n.starts_with(WHITEOUT_PREFIX))
+    {
+        return Ok(());
+    }
+    let link_target = entry.link_name()?;
+    let link_target: Option<&Utf8Path> =
+        link_target.as_deref().map(|t| t.try_into()).transpose()?;
+    let header = entry.header();
+    let checked = ContentPolicyEntry {
+        path: &path,
+        mode: header.mode()? & 0o7777,
+        size: header.entry_size()?,
+        link_target,
+    };
+    for policy in policies {
+        if !(policy.check)(&checked) {
+            violations.push((path.to_string(), policy.name.clone()));
+        }
+    }
+    Ok(())
 }
 
 /// Asynchronous wrapper for filter_tar()
 async fn filter_tar_async(
     src: impl AsyncRead + Send + 'static,
     mut dest: impl AsyncWrite + Send + Unpin,
-) -> Result<BTreeMap<String, u32>> {
+    config: TarFilterConfig,
+) -> Result<TarFilterResult> {
     let (tx_buf, mut rx_buf) = tokio::io::duplex(8192);
     let src = Box::pin(src);
     let tar_transformer = tokio::task::spawn_blocking(move || -> Result<_> {
         let src = tokio_util::io::SyncIoBridge::new(src);
         let dest = tokio_util::io::SyncIoBridge::new(tx_buf);
-        filter_tar(src, dest)
+        filter_tar(src, dest, &config)
     });
     let copier = tokio::io::copy(&mut rx_buf, &mut dest);
     let (r, v) = tokio::join!(tar_transformer, copier);
@@ -196,8 +773,321 @@ async fn filter_tar_async(
     r?
 }
 
+/// Mode of directories which are not in the tar stream.
+const DEFAULT_DIR_MODE: u32 = libc::S_IFDIR | 0o755;
+
+/// Writes the entries of a (filtered) tar stream into a mutable tree, like
+/// `ostree commit --tree=tar=... --tar-autocreate-parents`.
+struct TarCommitter<'a> {
+    repo: &'a ostree::Repo,
+    sepolicy: Option<&'a ostree::SePolicy>,
+    root: ostree::MutableTree,
+    /// Directories whose metadata is set.
+    dirs_with_meta: HashSet<Utf8PathBuf>,
+    /// Content object of each path, used to resolve hardlinks.
+    files: HashMap<Utf8PathBuf, String>,
+    max_file_size: Option<u64>,
+    stats: WriteTarStats,
+    buf: Vec<u8>,
+    cancellable: Option<&'a gio::Cancellable>,
+}
+
+impl<'a> TarCommitter<'a> {
+    fn new(
+        repo: &'a ostree::Repo,
+        sepolicy: Option<&'a ostree::SePolicy>,
+        max_file_size: Option<u64>,
+        cancellable: Option<&'a gio::Cancellable>,
+    ) -> Result<Self> {
+        let mut r = Self {
+            repo,
+            sepolicy,
+            root: ostree::MutableTree::new(),
+            dirs_with_meta: Default::default(),
+            files: Default::default(),
+            max_file_size,
+            stats: Default::default(),
+            buf: vec![0u8; 16384],
+            cancellable,
+        };
+        let root_meta = r.default_dirmeta(Utf8Path::new(""))?;
+        r.root.set_metadata_checksum(&root_meta);
+        r.dirs_with_meta.insert(Utf8PathBuf::new());
+        Ok(r)
+    }
+
+    /// Compute the extended attributes for a path; a label from the tar stream
+    /// takes precedence over the policy.
+    fn xattrs(
+        &self,
+        path: &Utf8Path,
+        mode: u32,
+        label: Option<Vec<u8>>,
+    ) -> Result<Option<glib::Variant>> {
+        let label = match (label, self.sepolicy) {
+            (Some(label), _) => label,
+            (None, Some(sepolicy)) => {
+                let abspath = format!("/{}", path);
+                match sepolicy.label(&abspath, mode, self.cancellable)? {
+                    Some(label) => [label.as_bytes(), &b"\0"[..]].concat(),
+                    None => return Ok(None),
+                }
+            }
+            (None, None) => return Ok(None),
+        };
+        let xattrs = [(crate::ima::SELINUX_XATTR, label.as_slice())];
+        Ok(Some(crate::ima::new_variant_a_ayay(xattrs)))
+    }
+
+    fn write_dirmeta(
+        &self,
+        uid: u32,
+        gid: u32,
+        mode: u32,
+        xattrs: Option<&glib::Variant>,
+    ) -> Result<String> {
+        let finfo = gio::FileInfo::new();
+        finfo.set_attribute_uint32("unix::uid", uid);
+        finfo.set_attribute_uint32("unix::gid", gid);
+        finfo.set_attribute_uint32("unix::mode", mode);
+        let dirmeta = ostree::create_directory_metadata(&finfo, xattrs)
+            .ok_or_else(|| anyhow!("Failed to create directory metadata"))?;
+        let csum = self.repo.write_metadata(
+            ostree::ObjectType::DirMeta,
+            None,
+            &dirmeta,
+            self.cancellable,
+        )?;
+        Ok(csum.to_hex())
+    }
+
+    fn default_dirmeta(&self, path: &Utf8Path) -> Result<String> {
+        let xattrs = self.xattrs(path, DEFAULT_DIR_MODE, None)?;
+        self.write_dirmeta(0, 0, DEFAULT_DIR_MODE, xattrs.as_ref())
+    }
+
+    /// Return the directory at `path`, creating it and its parents as needed.
+    fn ensure_dir(&mut self, path: &Utf8Path) -> Result<ostree::MutableTree> {
+        let mut dir = self.root.clone();
+        let mut cur = Utf8PathBuf::new();
+        for name in path.iter() {
+            cur.push(name);
+            dir = dir.ensure_dir(name)?;
+            if !self.dirs_with_meta.contains(&cur) {
+                let meta = self.default_dirmeta(&cur)?;
+                dir.set_metadata_checksum(&meta);
+                self.dirs_with_meta.insert(cur.clone());
+            }
+        }
+        Ok(dir)
+    }
+
+    fn add_file(&mut self, path: Utf8PathBuf, checksum: String) -> Result<()> {
+        let (parent, name) = match (path.parent(), path.file_name()) {
+            (Some(parent), Some(name)) => (parent, name),
+            _ => return Err(anyhow!("Invalid non-directory entry for {}", path)),
+        };
+        let dir = self.ensure_dir(parent)?;
+        dir.replace_file(name, &checksum)?;
+        self.files.insert(path, checksum);
+        Ok(())
+    }
+
+    fn write_regfile<R: std::io::Read>(
+        &mut self,
+        entry: &mut tar::Entry<R>,
+        uid: u32,
+        gid: u32,
+        mode: u32,
+        xattrs: Option<&glib::Variant>,
+    ) -> Result<String> {
+        let size = entry.header().size()?;
+        if size <= super::import::SMALL_REGFILE_SIZE as u64 {
+            let mut buf = vec![0u8; size as usize];
+            entry.read_exact(&mut buf)?;
+            let c = self.repo.write_regfile_inline(
+                None,
+                uid,
+                gid,
+                mode,
+                xattrs,
+                &buf,
+                self.cancellable,
+            )?;
+            return Ok(c.to_string());
+        }
+        let w = self
+            .repo
+            .write_regfile(None, uid, gid, mode, size, xattrs)?;
+        {
+            let w = w.clone().upcast::<gio::OutputStream>();
+            loop {
+                let n = entry.read(&mut self.buf[..])?;
+                if n == 0 {
+                    break;
+                }
+                w.write(&self.buf[0..n], self.cancellable)?;
+            }
+        }
+        Ok(w.finish(self.cancellable)?.to_string())
+    }
+
+    fn import_entry<R: std::io::Read>(&mut self, mut entry: tar::Entry<R>) -> Result<()> {
+        let label = entry_selinux_label(&mut entry)?;
+        let path = super::import::entry_path(&entry)?;
+        let header = entry.header();
+        let uid: u32 = header.uid()?.try_into()?;
+        let gid: u32 = header.gid()?.try_into()?;
+        let perms = header.mode()? & 0o7777;
+        match header.entry_type() {
+            tar::EntryType::Directory => {
+                let mode = libc::S_IFDIR | perms;
+                let xattrs = self.xattrs(&path, mode, label)?;
+                let meta = self.write_dirmeta(uid, gid, mode, xattrs.as_ref())?;
+                let dir = self.ensure_dir(&path)?;
+                dir.set_metadata_checksum(&meta);
+                self.dirs_with_meta.insert(path);
+            }
+            tar::EntryType::Regular | tar::EntryType::Continuous => {
+                let size = header.size()?;
+                if let Some(max) = self.max_file_size.filter(|&max| size > max) {
+                    return Err(anyhow!(
+                        "File {} of size {} exceeds the maximum of {}",
+                        path,
+                        size,
+                        max
+                    ));
+                }
+                let is_whiteout = path
+                    .file_name()
+                    .map(|n| n.starts_with(WHITEOUT_PREFIX))
+                    .unwrap_or_default();
+                if is_whiteout {
+                    self.stats.whiteouts += 1;
+                } else {
+                    self.stats.add_regfile(&path, size);
+                }
+                let mode = libc::S_IFREG | perms;
+                let xattrs = self.xattrs(&path, mode, label)?;
+                let checksum = self.write_regfile(&mut entry, uid, gid, mode, xattrs.as_ref())?;
+                self.add_file(path, checksum)?;
+            }
+            tar::EntryType::Symlink => {
+                let target = entry
+                    .link_name()?
+                    .ok_or_else(|| anyhow!("Invalid symlink {}", path))?;
+                let target = target
+                    .as_os_str()
+                    .to_str()
+                    .ok_or_else(|| anyhow!("Non-utf8 symlink {}", path))?;
+                let xattrs = self.xattrs(&path, libc::S_IFLNK | 0o777, label)?;
+                let checksum = self.repo.write_symlink(
+                    None,
+                    uid,
+                    gid,
+                    xattrs.as_ref(),
+                    target,
+                    self.cancellable,
+                )?;
+                self.stats.symlinks += 1;
+                self.add_file(path, checksum.to_string())?;
+            }
+            tar::EntryType::Link => {
+                let target = entry
+                    .link_name()?
+                    .ok_or_else(|| anyhow!("Invalid hardlink {}", path))?;
+                let target = Utf8Path::from_path(&*target)
+                    .ok_or_else(|| anyhow!("Non-utf8 hardlink {}", path))?;
+                let target = super::import::relative_path(target).with_context(|| {
+                    format!("Hardlink {} points outside of the layer content", path)
+                })?;
+                // Like regular files, hardlinks share the content object of their target.
+                let checksum = self
+                    .files
+                    .get(&target)
+                    .ok_or_else(|| {
+                        anyhow!(
+                            "Hardlink {} refers to {}, which is not a file earlier in the layer",
+                            path,
+                            target
+                        )
+                    })?
+                    .clone();
+                self.add_file(path, checksum)?;
+            }
+            tar::EntryType::XGlobalHeader => {}
+            o => return Err(anyhow!("Unsupported entry type {:?} for {}", o, path)),
+        }
+        Ok(())
+    }
+
+    /// Write the tree, and a commit of it including the `extra` metadata.
+    fn commit(
+        self,
+        parent: Option<&str>,
+        extra: Option<&glib::Variant>,
+    ) -> Result<(String, WriteTarStats)> {
+        let root = self.repo.write_mtree(&self.root, self.cancellable)?;
+        let root = root.downcast::<ostree::RepoFile>().unwrap();
+        let metadata = glib::VariantDict::new(extra);
+        metadata.insert_value(
+            "ostree.importer.version",
+            &env!("CARGO_PKG_VERSION").to_variant(),
+        );
+        let stats = serde_json::to_string(&self.stats)?;
+        metadata.insert_value(META_TAR_STATS, &stats.to_variant());
+        let metadata = metadata.end();
+        let commit =
+            self.repo
+                .write_commit(parent, None, None, Some(&metadata), &root, self.cancellable)?;
+        Ok((commit.to_string(), self.stats))
+    }
+}
+
+/// Commit a filtered tar stream, returning the commit checksum and statistics.
+fn commit_tar(
+    repo: &ostree::Repo,
+    src: impl std::io::Read,
+    parent: Option<&str>,
+    metadata: Option<&glib::Variant>,
+    sepolicy: Option<&ostree::SePolicy>,
+    max_file_size: Option<u64>,
+    cancellable: Option<&gio::Cancellable>,
+) -> Result<(String, WriteTarStats)> {
+    let txn = repo.auto_transaction(cancellable)?;
+    let mut committer = TarCommitter::new(repo, sepolicy, max_file_size, cancellable)?;
+    let mut archive = tar::Archive::new(src);
+    for entry in archive.entries()? {
+        committer.import_entry(entry?)?;
+    }
+    let commit = committer.commit(parent, metadata)?;
+    txn.commit(cancellable)?;
+    Ok(commit)
+}
+
+/// Convert additional commit metadata to a variant, rejecting reserved keys.
+fn validate_extra_metadata(metadata: &glib::VariantDict) -> Result<glib::Variant> {
+    let metadata = metadata.end();
+    for i in 0..metadata.n_children() {
+        let key = metadata.child_value(i).child_value(0);
+        let key = key
+            .get::<String>()
+            .ok_or_else(|| anyhow!("Invalid metadata key"))?;
+        if key.starts_with("ostree.") {
+            return Err(anyhow!("Reserved metadata key: {}", key));
+        }
+    }
+    Ok(metadata)
+}
+
+/// Whether an error was caused by writing to a closed pipe.
+fn is_broken_pipe(e: &anyhow::Error) -> bool {
+    e.chain()
+        .filter_map(|e| e.downcast_ref::<std::io::Error>())
+        .any(|e| e.kind() == std::io::ErrorKind::BrokenPipe)
+}
+
 /// Write the contents of a tarball as an ostree commit.
-#[allow(unsafe_code)] // For raw fd bits
 #[instrument(skip(repo, src))]
 pub async fn write_tar(
     repo: &ostree::Repo,
@@ -207,6 +1097,12 @@ pub async fn write_tar(
 ) -> Result<WriteTarResult> {
     let repo = repo.clone();
     let options = options.unwrap_or_default();
+    let max_file_size = options.max_file_size;
+    let metadata = options
+        .metadata
+        .as_ref()
+        .map(validate_extra_metadata)
+        .transpose()?;
     let sepolicy = if options.selinux {
         if let Some(base) = options.base {
             Some(sepolicy_from_base(&repo, &base).context("tar: Preparing sepolicy")?)
@@ -216,70 +1112,52 @@ pub async fn write_tar(
     } else {
         None
     };
-    let mut c = std::process::Command::new("ostree");
-    let repofd = repo.dfd_as_file()?;
-    let repofd = Arc::new(rustix::io::OwnedFd::from_into_fd(repofd));
-    {
-        let c = c
-            .stdin(Stdio::piped())
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped())
-            .args(&["commit"]);
-        c.take_fd_n(repofd.clone(), 3);
-        c.arg("--repo=/proc/self/fd/3");
-        if let Some(sepolicy) = sepolicy.as_ref() {
-            c.arg("--selinux-policy");
-            c.arg(sepolicy.path());
-        }
-        c.arg(&format!(
-            "--add-metadata-string=ostree.importer.version={}",
-            env!("CARGO_PKG_VERSION")
-        ));
-        c.args(&[
-            "--no-bindings",
-            "--tar-autocreate-parents",
-            "--tree=tar=/proc/self/fd/0",
-            "--branch",
-            refname,
-        ]);
-    }
-    let mut c = tokio::process::Command::from(c);
-    c.kill_on_drop(true);
-    let mut r = c.spawn()?;
-    // Safety: We passed piped() for all of these
-    let child_stdin = r.stdin.take().unwrap();
-    let mut child_stdout = r.stdout.take().unwrap();
-    let mut child_stderr = r.stderr.take().unwrap();
-    // Copy the filtered tar stream to child stdin
-    let filtered_result = filter_tar_async(src, child_stdin);
-    // Gather stdout/stderr to buffers
-    let output_copier = async move {
-        let mut child_stdout_buf = String::new();
-        let mut child_stderr_buf = String::new();
-        let (_a, _b) = tokio::try_join!(
-            child_stdout.read_to_string(&mut child_stdout_buf),
-            child_stderr.read_to_string(&mut child_stderr_buf)
-        )?;
-        Ok::<_, anyhow::Error>((child_stdout_buf, child_stderr_buf))
+    // Like `ostree commit --branch`, the previous commit is the parent.
+    let parent = repo.resolve_rev(refname, true)?.map(|s| s.to_string());
+    let filter_config = TarFilterConfig {
+        unsupported_file_types: options.unsupported_file_types,
+        warnings: options.warnings,
+        id_map: options.id_map,
+        toplevel_content: options.toplevel_content,
+        content_policies: options.content_policies,
     };
-
-    let (filtered_result, (child_stdout, child_stderr)) =
-        tokio::try_join!(filtered_result, output_copier)?;
-    let status = r.wait().await?;
-    // Ensure this lasted until the process exited
-    drop(sepolicy);
-    if !status.success() {
-        return Err(anyhow!(
-            "Failed to commit tar: {:?}: {}",
-            status,
-            child_stderr
-        ));
-    }
-    // TODO: trim string in place
-    let s = child_stdout.trim();
+    let (tx_buf, rx_buf) = tokio::io::duplex(8192);
+    let filtered_result = filter_tar_async(src, tx_buf, filter_config);
+    let committer = {
+        let repo = repo.clone();
+        crate::tokio_util::spawn_blocking_cancellable_flatten(move |cancellable| {
+            let cancellable = Some(cancellable);
+            let src = tokio_util::io::SyncIoBridge::new(rx_buf);
+            let sepolicy = sepolicy
+                .as_ref()
+                .map(|d| ostree::SePolicy::new(&gio::File::for_path(d.path()), cancellable))
+                .transpose()?;
+            commit_tar(
+                &repo,
+                src,
+                parent.as_deref(),
+                metadata.as_ref(),
+                sepolicy.as_ref(),
+                max_file_size,
+                cancellable,
+            )
+        })
+    };
+    let (filtered_result, commit) = tokio::join!(filtered_result, committer);
+    // If committing failed, the filter only sees the stream being closed; otherwise
+    // a filter error is the cause, and the commit may be incomplete.
+    let (filtered_result, (commit, stats)) = match (filtered_result, commit) {
+        (Ok(f), Ok(c)) => (f, c),
+        (Err(e), Err(c)) if is_broken_pipe(&e) => return Err(c.context("Failed to commit tar")),
+        (Err(e), _) => return Err(e),
+        (Ok(_), Err(c)) => return Err(c.context("Failed to commit tar")),
+    };
+    repo.set_ref_immediate(None, refname, Some(commit.as_str()), gio::NONE_CANCELLABLE)?;
     Ok(WriteTarResult {
-        commit: s.to_string(),
-        filtered: filtered_result,
+        commit,
+        filtered: filtered_result.filtered,
+        skipped: filtered_result.skipped,
+        stats,
     })
 }
 
@@ -342,7 +1220,7 @@ mod tests {
         let _ = rootfs_tar.into_inner()?;
         let mut dest = Vec::new();
         let src = tokio::io::BufReader::new(tokio::fs::File::open(rootfs_tar_path).await?);
-        filter_tar_async(src, &mut dest).await?;
+        filter_tar_async(src, &mut dest, Default::default()).await?;
         let dest = dest.as_slice();
         let mut final_tar = tar::Archive::new(Cursor::new(dest));
         let destdir = &tempd.path().join("destdir");
@@ -351,4 +1229,225 @@ mod tests {
         assert!(!destdir.join("blah").exists());
         Ok(())
     }
+
+    #[test]
+    fn tar_filter_unsupported() -> Result<()> {
+        let mut src = tar::Builder::new(Vec::new());
+        let mut h = tar::Header::new_gnu();
+        h.set_entry_type(tar::EntryType::Regular);
+        h.set_mode(0o644);
+        h.set_size(3);
+        src.append_data(&mut h, "usr/share/foo", &b"foo"[..])?;
+        for (t, path) in [
+            (tar::EntryType::Fifo, "usr/share/fifo"),
+            (tar::EntryType::Char, "usr/share/null"),
+            (tar::EntryType::Fifo, "usr/share/fifo2"),
+        ] {
+            let mut h = tar::Header::new_gnu();
+            h.set_entry_type(t);
+            h.set_mode(0o644);
+            h.set_size(0);
+            src.append_data(&mut h, path, std::io::empty())?;
+        }
+        let src = src.into_inner()?;
+
+        let e = filter_tar(src.as_slice(), Vec::new(), &Default::default())
+            .err()
+            .unwrap();
+        assert_eq!(e.to_string(), "Unsupported fifo entry: usr/share/fifo");
+
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let mut dest = Vec::new();
+        let config = TarFilterConfig {
+            unsupported_file_types: UnsupportedFileTypePolicy::SkipWithWarning,
+            warnings: Some(tx),
+            ..Default::default()
+        };
+        let r = filter_tar(src.as_slice(), &mut dest, &config)?;
+        assert_eq!(r.skipped.len(), 2);
+        assert_eq!(*r.skipped.get("fifo").unwrap(), 2);
+        assert_eq!(*r.skipped.get("char-device").unwrap(), 1);
+        assert_eq!(
+            rx.try_recv().unwrap(),
+            "Skipping unsupported fifo entry: usr/share/fifo"
+        );
+        assert_eq!(
+            rx.try_recv().unwrap(),
+            "Skipping unsupported char-device entry: usr/share/null"
+        );
+        let mut final_tar = tar::Archive::new(Cursor::new(dest));
+        let paths = final_tar
+            .entries()?
+            .map(|e| Ok(e?.path()?.to_string_lossy().into_owned()))
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(paths, &["usr/share/foo"]);
+
+        let config = TarFilterConfig {
+            unsupported_file_types: UnsupportedFileTypePolicy::Skip,
+            ..Default::default()
+        };
+        let r = filter_tar(src.as_slice(), Vec::new(), &config)?;
+        assert_eq!(r.skipped.values().sum::<u32>(), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn tar_filter_content_policy() -> Result<()> {
+        let mut src = tar::Builder::new(Vec::new());
+        for (path, mode) in [
+            ("usr/bin/foo", 0o755),
+            ("usr/bin/suid", 0o4755),
+            ("usr/bin/sgid", 0o2755),
+            ("usr/local/bin/bar", 0o755),
+            ("usr/local/.wh.baz", 0o644),
+            ("etc/shadow", 0o600),
+        ] {
+            let mut h = tar::Header::new_gnu();
+            h.set_entry_type(tar::EntryType::Regular);
+            h.set_mode(mode);
+            h.set_size(0);
+            src.append_data(&mut h, path, std::io::empty())?;
+        }
+        let src = src.into_inner()?;
+
+        let config = TarFilterConfig {
+            content_policies: vec![
+                ContentPolicy::no_setuid(),
+                ContentPolicy::deny_paths(&["/usr/local", "etc/shadow"]),
+            ],
+            ..Default::default()
+        };
+        let e = filter_tar(src.as_slice(), Vec::new(), &config)
+            .err()
+            .unwrap();
+        assert_eq!(
+            e.to_string(),
+            "Content policy violation: usr/bin/suid (no-setuid), usr/bin/sgid (no-setuid), \
+             usr/local/bin/bar (deny-paths), etc/shadow (deny-paths)"
+        );
+        let violation = e.downcast_ref::<ContentPolicyViolation>().unwrap();
+        assert_eq!(violation.violations.len(), 4);
+
+        let config = TarFilterConfig {
+            content_policies: vec![ContentPolicy::new("small", |e| e.size < 1)],
+            ..Default::default()
+        };
+        filter_tar(src.as_slice(), Vec::new(), &config)?;
+        Ok(())
+    }
+
+    #[test]
+    fn tar_filter_toplevel_content() -> Result<()> {
+        let mut src = tar::Builder::new(Vec::new());
+        for (path, ty, mode) in [
+            ("usr/share/foo", tar::EntryType::Regular, 0o644),
+            ("var/", tar::EntryType::Directory, 0o755),
+            ("var/lib/foo/", tar::EntryType::Directory, 0o700),
+            ("var/lib/foo/data", tar::EntryType::Regular, 0o644),
+            ("var/lib/.wh.bar", tar::EntryType::Regular, 0o644),
+            ("opt/", tar::EntryType::Directory, 0o755),
+            ("opt/bar", tar::EntryType::Regular, 0o755),
+        ] {
+            let mut h = tar::Header::new_gnu();
+            h.set_entry_type(ty);
+            h.set_mode(mode);
+            h.set_uid(100042);
+            h.set_gid(100042);
+            h.set_size(0);
+            src.append_data(&mut h, path, std::io::empty())?;
+        }
+        let src = src.into_inner()?;
+
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let mut config = TarFilterConfig {
+            warnings: Some(tx),
+            id_map: Some(IdMap::from_offset(100000)),
+            ..Default::default()
+        };
+        let mut dest = Vec::new();
+        let r = filter_tar(src.as_slice(), &mut dest, &config)?;
+        assert_eq!(*r.filtered.get("var").unwrap(), 4);
+        assert_eq!(*r.filtered.get("opt").unwrap(), 2);
+        assert_eq!(
+            rx.try_recv().unwrap(),
+            "Discarding content outside of /usr and /etc: var/lib/foo/data, opt/bar"
+        );
+        let mut final_tar = tar::Archive::new(Cursor::new(dest));
+        let mut entries = final_tar.entries()?;
+        let e = entries.next().unwrap()?;
+        assert_eq!(e.path()?.to_str().unwrap(), "usr/share/foo");
+        let mut e = entries.next().unwrap()?;
+        let path = e.path()?.to_str().unwrap().to_string();
+        assert!(path.starts_with("usr/lib/tmpfiles.d/ostree-ext-var-"));
+        let mut tmpfiles = String::new();
+        e.read_to_string(&mut tmpfiles)?;
+        assert_eq!(tmpfiles, "d /var/lib/foo 0700 42 42 - -\n");
+        assert!(entries.next().is_none());
+
+        config.toplevel_content = ToplevelContentPolicy::Error;
+        let e = filter_tar(src.as_slice(), Vec::new(), &config)
+            .err()
+            .unwrap();
+        assert_eq!(
+            e.to_string(),
+            "Found content outside of /usr and /etc: var/lib/foo/data, opt/bar"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn tar_filter_id_map() -> Result<()> {
+        let mut src = tar::Builder::new(Vec::new());
+        for (path, id) in [
+            ("usr/share/a", 100000),
+            ("usr/share/b", 100042),
+            ("usr/share/c", 5),
+        ] {
+            let mut h = tar::Header::new_gnu();
+            h.set_entry_type(tar::EntryType::Regular);
+            h.set_mode(0o644);
+            h.set_uid(id);
+            h.set_gid(id);
+            h.set_size(0);
+            src.append_data(&mut h, path, std::io::empty())?;
+        }
+        let src = src.into_inner()?;
+        let owners = |dest: Vec<u8>| -> Result<Vec<(u64, u64)>> {
+            tar::Archive::new(Cursor::new(dest))
+                .entries()?
+                .map(|e| {
+                    let e = e?;
+                    Ok((e.header().uid()?, e.header().gid()?))
+                })
+                .collect()
+        };
+
+        let mut config = TarFilterConfig {
+            id_map: Some(IdMap::from_offset(100000)),
+            ..Default::default()
+        };
+        let e = filter_tar(src.as_slice(), Vec::new(), &config)
+            .err()
+            .unwrap();
+        assert_eq!(e.to_string(), "Unmapped uid 5 for usr/share/c");
+
+        config.id_map.as_mut().unwrap().clamp_to_root = true;
+        let mut dest = Vec::new();
+        filter_tar(src.as_slice(), &mut dest, &config)?;
+        assert_eq!(owners(dest)?, &[(0, 0), (42, 42), (0, 0)]);
+
+        config.id_map = Some(IdMap {
+            uids: vec![IdRange {
+                container_id: 1000,
+                host_id: 100040,
+                count: 10,
+            }],
+            gids: vec![IdRange::from_offset(0)],
+            clamp_to_root: true,
+        });
+        let mut dest = Vec::new();
+        filter_tar(src.as_slice(), &mut dest, &config)?;
+        assert_eq!(owners(dest)?, &[(0, 100000), (1002, 100042), (0, 5)]);
+        Ok(())
+    }
 }
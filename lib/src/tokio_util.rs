@@ -73,6 +73,38 @@ where
     spawn_blocking_cancellable(f).map(flatten_anyhow)
 }
 
+/// Cancels the wrapped cancellable when dropped, unless it was disarmed.
+struct CancelGuard(Option<gio::Cancellable>);
+
+impl Drop for CancelGuard {
+    fn drop(&mut self) {
+        if let Some(c) = self.0.take() {
+            c.cancel();
+        }
+    }
+}
+
+/// Like [`spawn_blocking_cancellable_flatten`], but using the provided cancellable.
+///
+/// The cancellable is also triggered if the returned future is dropped before
+/// the function completes, so that dropping the future stops the blocking work.
+pub fn spawn_blocking_with_cancellable_flatten<F, T>(
+    cancellable: gio::Cancellable,
+    f: F,
+) -> impl Future<Output = Result<T>>
+where
+    F: FnOnce(&gio::Cancellable) -> Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    let mut guard = CancelGuard(Some(cancellable.clone()));
+    let handle = tokio::task::spawn_blocking(move || f(&cancellable));
+    async move {
+        let r = handle.await;
+        guard.0 = None;
+        flatten_anyhow(r)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;